@@ -0,0 +1,76 @@
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use picodox_proto::settings::Config;
+
+/// Config keys as `config get` shows them and `config set` takes them
+const KEYS: &[&str] = &[
+    "led-brightness",
+    "tapping-term-ms",
+    "debounce-ms",
+    "default-layer",
+    "idle-timeout-s",
+];
+
+/// Every setting as a `(key, value)` pair, in the order of `KEYS`
+pub fn entries(config: &Config) -> [(&'static str, u32); 5] {
+    [
+        (KEYS[0], config.led_brightness.into()),
+        (KEYS[1], config.tapping_term_ms.into()),
+        (KEYS[2], config.debounce_ms.into()),
+        (KEYS[3], config.default_layer.into()),
+        (KEYS[4], config.idle_timeout_s.into()),
+    ]
+}
+
+fn parse_value<T: FromStr>(key: &str, value: &str) -> Result<T> {
+    value
+        .parse()
+        .ok()
+        .with_context(|| format!("'{}' is not a valid value for {}", value, key))
+}
+
+/// Apply one `key=value` argument of `config set`
+pub fn apply(config: &mut Config, arg: &str) -> Result<()> {
+    let Some((key, value)) = arg.split_once('=') else {
+        bail!("'{}' is not a key=value pair", arg);
+    };
+    let (key, value) = (key.trim(), value.trim());
+    match key {
+        "led-brightness" => config.led_brightness = parse_value(key, value)?,
+        "tapping-term-ms" => config.tapping_term_ms = parse_value(key, value)?,
+        "debounce-ms" => config.debounce_ms = parse_value(key, value)?,
+        "default-layer" => config.default_layer = parse_value(key, value)?,
+        "idle-timeout-s" => config.idle_timeout_s = parse_value(key, value)?,
+        _ => bail!(
+            "Unknown config key '{}', expected one of {}",
+            key,
+            KEYS.join(", ")
+        ),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply_values() {
+        let mut config = Config::default();
+        apply(&mut config, "led-brightness=255").unwrap();
+        apply(&mut config, " tapping-term-ms = 150").unwrap();
+        assert_eq!(config.led_brightness, 255);
+        assert_eq!(config.tapping_term_ms, 150);
+        assert_eq!(entries(&config)[1], ("tapping-term-ms", 150));
+        apply(&mut config, "idle-timeout-s=0").unwrap();
+        assert_eq!(entries(&config)[4], ("idle-timeout-s", 0));
+
+        assert!(apply(&mut config, "led-brightness=256").is_err());
+        assert!(apply(&mut config, "debounce-ms").is_err());
+        assert!(apply(&mut config, "bogus=1").is_err());
+        // Failed arguments leave the config alone
+        assert_eq!(config.debounce_ms, Config::default().debounce_ms);
+    }
+}
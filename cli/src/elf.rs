@@ -0,0 +1,208 @@
+use anyhow::{bail, Context, Result};
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELF_CLASS_32: u8 = 1;
+const ELF_DATA_LSB: u8 = 1;
+const ELF_MACHINE_ARM: u16 = 40;
+const PT_LOAD: u32 = 1;
+
+/// The XIP window the rp2040 maps external flash into
+const FLASH_START: u32 = 0x1000_0000;
+const FLASH_END: u32 = 0x1100_0000;
+/// Gaps between segments are filled with what erased flash reads as
+const ERASED: u8 = 0xff;
+
+#[derive(Debug, FromBytes, Immutable, KnownLayout, IntoBytes)]
+#[repr(C)]
+struct Elf32Header {
+    ident: [u8; 16],
+    kind: u16,
+    machine: u16,
+    version: u32,
+    entry: u32,
+    phoff: u32,
+    shoff: u32,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+#[derive(Debug, FromBytes, Immutable, KnownLayout, IntoBytes)]
+#[repr(C)]
+struct Elf32ProgramHeader {
+    kind: u32,
+    offset: u32,
+    vaddr: u32,
+    paddr: u32,
+    filesz: u32,
+    memsz: u32,
+    flags: u32,
+    align: u32,
+}
+
+/// The bytes an ELF places in flash, as one contiguous image
+#[derive(Debug, PartialEq, Eq)]
+pub struct FwImage {
+    pub address: u32,
+    pub data: Vec<u8>,
+    /// (start, end) of each hole between segments that was padded
+    pub gaps: Vec<(u32, u32)>,
+}
+
+impl FwImage {
+    /// Collect the PT_LOAD segments of an ELF by their load (physical)
+    /// address, so initialized data is placed where the startup code copies
+    /// it from rather than where it ends up in RAM
+    pub fn from_elf(file: &[u8]) -> Result<Self> {
+        let (header, _) = Elf32Header::read_from_prefix(file)
+            .ok()
+            .context("File is too short for an ELF header")?;
+        if header.ident[..4] != ELF_MAGIC {
+            bail!("Not an ELF file (magic: {:x?})", &header.ident[..4]);
+        }
+        if header.ident[4] != ELF_CLASS_32 || header.ident[5] != ELF_DATA_LSB {
+            bail!("Only little endian 32 bit ELF files are supported");
+        }
+        if header.machine != ELF_MACHINE_ARM {
+            bail!("ELF is not for ARM (machine: {})", header.machine);
+        }
+        if header.phentsize as usize != size_of::<Elf32ProgramHeader>() {
+            bail!("Invalid ELF program header size ({})", header.phentsize);
+        }
+
+        let mut segments = Vec::new();
+        for idx in 0..header.phnum as usize {
+            let start = header.phoff as usize + idx * header.phentsize as usize;
+            let (ph, _) = file
+                .get(start..)
+                .and_then(|bytes| Elf32ProgramHeader::read_from_prefix(bytes).ok())
+                .with_context(|| format!("ELF program header {} is out of bounds", idx))?;
+            if ph.kind != PT_LOAD || ph.filesz == 0 {
+                continue;
+            }
+
+            let end = ph.paddr as u64 + ph.filesz as u64;
+            if ph.paddr < FLASH_START || end > FLASH_END as u64 {
+                bail!(
+                    "Segment at 0x{:08x} ({} bytes) is outside of flash",
+                    ph.paddr,
+                    ph.filesz
+                );
+            }
+            let data = file
+                .get(ph.offset as usize..ph.offset as usize + ph.filesz as usize)
+                .with_context(|| format!("ELF segment {} is out of bounds", idx))?;
+            segments.push((ph.paddr, data));
+        }
+
+        segments.sort_by_key(|&(address, _)| address);
+        let Some(&(address, _)) = segments.first() else {
+            bail!("ELF has no loadable segments");
+        };
+
+        let mut image = FwImage {
+            address,
+            data: Vec::new(),
+            gaps: Vec::new(),
+        };
+        for (seg_address, data) in segments {
+            let current = image.address + image.data.len() as u32;
+            if seg_address < current {
+                bail!("ELF segments overlap at 0x{:08x}", seg_address);
+            }
+            if seg_address > current {
+                image.gaps.push((current, seg_address));
+                image
+                    .data
+                    .resize((seg_address - image.address) as usize, ERASED);
+            }
+            image.data.extend_from_slice(data);
+        }
+
+        Ok(image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_elf(segments: &[(u32, u32, &[u8])]) -> Vec<u8> {
+        let phoff = size_of::<Elf32Header>();
+        let data_start = phoff + segments.len() * size_of::<Elf32ProgramHeader>();
+
+        let mut ident = [0u8; 16];
+        ident[..4].copy_from_slice(&ELF_MAGIC);
+        ident[4] = ELF_CLASS_32;
+        ident[5] = ELF_DATA_LSB;
+        let header = Elf32Header {
+            ident,
+            kind: 2,
+            machine: ELF_MACHINE_ARM,
+            version: 1,
+            entry: 0,
+            phoff: phoff as u32,
+            shoff: 0,
+            flags: 0,
+            ehsize: phoff as u16,
+            phentsize: size_of::<Elf32ProgramHeader>() as u16,
+            phnum: segments.len() as u16,
+            shentsize: 0,
+            shnum: 0,
+            shstrndx: 0,
+        };
+
+        let mut file = header.as_bytes().to_vec();
+        let mut data = Vec::new();
+        for &(vaddr, paddr, bytes) in segments {
+            let ph = Elf32ProgramHeader {
+                kind: PT_LOAD,
+                offset: (data_start + data.len()) as u32,
+                vaddr,
+                paddr,
+                filesz: bytes.len() as u32,
+                memsz: bytes.len() as u32,
+                flags: 0,
+                align: 4,
+            };
+            file.extend_from_slice(ph.as_bytes());
+            data.extend_from_slice(bytes);
+        }
+        file.extend_from_slice(&data);
+
+        file
+    }
+
+    #[test]
+    fn load_address() {
+        // .data runs from RAM but is stored in flash right after .text
+        let file = build_elf(&[
+            (0x2000_0000, 0x1000_0008, &[5, 6]),
+            (0x1000_0000, 0x1000_0000, &[1, 2, 3, 4, 0, 0, 0, 0]),
+        ]);
+        let image = FwImage::from_elf(&file).unwrap();
+        assert_eq!(image.address, 0x1000_0000);
+        assert_eq!(image.data, vec![1, 2, 3, 4, 0, 0, 0, 0, 5, 6]);
+        assert!(image.gaps.is_empty());
+    }
+
+    #[test]
+    fn gaps_are_padded() {
+        let file = build_elf(&[(0x1000_0000, 0x1000_0000, &[1]), (0, 0x1000_0004, &[2])]);
+        let image = FwImage::from_elf(&file).unwrap();
+        assert_eq!(image.data, vec![1, ERASED, ERASED, ERASED, 2]);
+        assert_eq!(image.gaps, vec![(0x1000_0001, 0x1000_0004)]);
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        assert!(FwImage::from_elf(&[0u8; 8]).is_err());
+        assert!(FwImage::from_elf(&build_elf(&[])).is_err());
+        assert!(FwImage::from_elf(&build_elf(&[(0, 0x2000_0000, &[1])])).is_err());
+    }
+}
@@ -0,0 +1,148 @@
+//! Reading the keyboard's HID reports the way the host sees them
+//!
+//! Goes through Linux hidraw, so it needs read access to the `/dev/hidraw*`
+//! node of the keyboard interface.
+
+use std::{
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use anyhow::{bail, Context, Result};
+use picodox_proto::key_codes::{code_name, mod_names, KeyCode};
+
+/// The IDs the firmware sets in main.rs
+pub const KEYBOARD_VID: u16 = 0x08b9;
+pub const KEYBOARD_PID: u16 = 0xbeef;
+
+/// Usage page generic desktop, usage keyboard. The keyboard interface's
+/// report descriptor starts with it, the mouse and system control ones
+/// have another usage and media keys another page.
+const KEYBOARD_USAGE: [u8; 4] = [0x05, 0x01, 0x09, 0x06];
+
+/// Modifiers, a reserved byte and six key codes
+const REPORT_LEN: usize = 8;
+
+const HIDRAW_CLASS: &str = "/sys/class/hidraw";
+
+/// Whether a hidraw device's uevent belongs to the keyboard, it has a line
+/// like `HID_ID=0003:000008B9:0000BEEF`
+fn is_keyboard_uevent(uevent: &str) -> bool {
+    uevent
+        .lines()
+        .filter_map(|line| line.strip_prefix("HID_ID="))
+        .any(|id| {
+            let mut parts = id.split(':').skip(1);
+            let mut next_id = || {
+                parts
+                    .next()
+                    .and_then(|part| u16::from_str_radix(part, 16).ok())
+            };
+            next_id() == Some(KEYBOARD_VID) && next_id() == Some(KEYBOARD_PID)
+        })
+}
+
+/// The hidraw node of the keyboard interface
+fn find_hidraw() -> Result<PathBuf> {
+    let entries = fs::read_dir(HIDRAW_CLASS)
+        .with_context(|| format!("Listing {}, is this Linux?", HIDRAW_CLASS))?;
+    for entry in entries {
+        let entry = entry.context("Listing hidraw devices")?;
+        let device = entry.path().join("device");
+        let Ok(uevent) = fs::read_to_string(device.join("uevent")) else {
+            continue;
+        };
+        let Ok(descriptor) = fs::read(device.join("report_descriptor")) else {
+            continue;
+        };
+        if is_keyboard_uevent(&uevent) && descriptor.starts_with(&KEYBOARD_USAGE) {
+            return Ok(Path::new("/dev").join(entry.file_name()));
+        }
+    }
+    bail!("No hidraw device for the keyboard interface, is the keyboard plugged in?");
+}
+
+/// A keyboard report as the names layout files use, e.g. `LCTRL + A C`
+fn describe(report: &[u8]) -> String {
+    let [modifier, _reserved, codes @ ..] = report else {
+        return format!("short report {:02x?}", report);
+    };
+    let mut names: Vec<String> = mod_names(*modifier).map(String::from).collect();
+    let keys: Vec<String> = codes
+        .iter()
+        .filter(|&&code| code != 0)
+        .map(|&code| match code_name(KeyCode(code)) {
+            Some(name) => String::from(name),
+            None => format!("0x{:02x}", code),
+        })
+        .collect();
+
+    if !names.is_empty() && !keys.is_empty() {
+        names.push(String::from("+"));
+    }
+    names.extend(keys);
+    if names.is_empty() {
+        String::from("(none)")
+    } else {
+        names.join(" ")
+    }
+}
+
+/// Print each keyboard report as it arrives, until interrupted
+pub fn monitor(path: Option<&str>) -> Result<()> {
+    let path = match path {
+        Some(path) => PathBuf::from(path),
+        None => find_hidraw()?,
+    };
+    let mut hidraw = File::open(&path).with_context(|| format!("Opening {}", path.display()))?;
+    println!("Reading reports from {}", path.display());
+
+    let start = Instant::now();
+    let mut last = start;
+    // Each read returns one report
+    let mut report = [0u8; 64];
+    loop {
+        let len = hidraw
+            .read(&mut report)
+            .with_context(|| format!("Reading {}", path.display()))?;
+        if len == 0 {
+            bail!("The keyboard went away");
+        }
+
+        let now = Instant::now();
+        println!(
+            "{:10.3}s  +{:6.1}ms  {}",
+            (now - start).as_secs_f64(),
+            (now - last).as_secs_f64() * 1000.0,
+            describe(&report[..len.min(REPORT_LEN)])
+        );
+        last = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyboard_uevent() {
+        let uevent = "DRIVER=hid-generic\nHID_ID=0003:000008B9:0000BEEF\nHID_NAME=picodox\n";
+        assert!(is_keyboard_uevent(uevent));
+        assert!(!is_keyboard_uevent("HID_ID=0003:0000046D:0000C52B\n"));
+        assert!(!is_keyboard_uevent("DRIVER=hid-generic\n"));
+    }
+
+    #[test]
+    fn describe_reports() {
+        assert_eq!(describe(&[0, 0, 0, 0, 0, 0, 0, 0]), "(none)");
+        assert_eq!(describe(&[0, 0, 0x04, 0, 0, 0, 0, 0]), "A");
+        assert_eq!(describe(&[0x03, 0, 0, 0, 0, 0, 0, 0]), "LCTRL LSHIFT");
+        assert_eq!(
+            describe(&[0x01, 0, 0x04, 0x06, 0xe8, 0, 0, 0]),
+            "LCTRL + A C 0xe8"
+        );
+        assert_eq!(describe(&[0x01]), "short report [01]");
+    }
+}
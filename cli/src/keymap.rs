@@ -0,0 +1,202 @@
+use anyhow::{bail, Context, Result};
+use picodox_proto::{
+    key_codes::*,
+    keymap::{LayerTable, Layout, LayoutError, LAYER_KEYS, NUM_LAYERS},
+    settings::MACRO_SLOTS,
+};
+
+fn parse_u8(arg: &str) -> Result<u8> {
+    let parsed = match arg.strip_prefix("0X") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => arg.parse(),
+    };
+    parsed.with_context(|| format!("'{}' is not a number from 0 to 255", arg))
+}
+
+/// A key code on the keyboard page, by name or usage
+fn parse_code(name: &str) -> Result<KeyCode> {
+    let usage = match name.as_bytes() {
+        [c @ b'A'..=b'Z'] => 0x04 + (c - b'A'),
+        [b'0'] => 0x27,
+        [c @ b'1'..=b'9'] => 0x1e + (c - b'1'),
+        _ if name.starts_with("0X") => parse_u8(name)?,
+        _ => match name.strip_prefix('F').map(str::parse::<u8>) {
+            Some(Ok(n @ 1..=12)) => 0x3a + n - 1,
+            Some(Ok(n @ 13..=24)) => 0x68 + n - 13,
+            _ => match NAMED.iter().find(|&&(named, _)| named == name) {
+                Some(&(_, Key::Code(code))) => return Ok(code),
+                Some(_) => bail!("'{}' is not a plain key", name),
+                None => bail!("Unknown key '{}'", name),
+            },
+        },
+    };
+    Ok(KeyCode(usage))
+}
+
+fn parse_mod(name: &str) -> Result<KeyMod> {
+    match NAMED.iter().find(|&&(named, _)| named == name) {
+        Some(&(_, Key::Mod(key_mod))) => Ok(key_mod),
+        _ => bail!("'{}' is not a modifier", name),
+    }
+}
+
+/// One key of a layout file, see keymaps/default.keymap for the syntax
+fn parse_key(token: &str) -> Result<Key> {
+    let token = token.to_ascii_uppercase();
+    if token == "_" {
+        return Ok(KEY_NONE);
+    }
+
+    let Some((func, arg)) = token.strip_suffix(')').and_then(|t| t.split_once('(')) else {
+        return match NAMED.iter().find(|&&(named, _)| named == token) {
+            Some(&(_, key)) => Ok(key),
+            None => parse_code(&token).map(Key::Code),
+        };
+    };
+    let key = match func {
+        "MO" => Key::LayerMomentary(parse_u8(arg)?),
+        "TG" => Key::LayerToggle(parse_u8(arg)?),
+        "MACRO" => Key::Macro(parse_u8(arg)?),
+        "OSM" => Key::OneShot(parse_mod(arg)?),
+        "MT" => {
+            let Some((hold, tap)) = arg.split_once(',') else {
+                bail!("mt takes a modifier and a key, like mt(LSHIFT,A)");
+            };
+            Key::TapHold {
+                tap: parse_code(tap)?,
+                hold: parse_mod(hold)?,
+            }
+        }
+        _ => bail!("Unknown key function '{}'", func.to_ascii_lowercase()),
+    };
+    Ok(key)
+}
+
+/// Parse a layout file into the keymap `UploadKeymap` sends
+pub fn parse(text: &str) -> Result<Layout> {
+    // (name, keys) of each layer in the file
+    let mut layers: Vec<(&str, Vec<Key>)> = Vec::new();
+    for (line_idx, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            layers.push((name.trim(), Vec::new()));
+            continue;
+        }
+
+        for token in line.split_whitespace() {
+            let Some((_, keys)) = layers.last_mut() else {
+                bail!("Line {}: keys before the first [layer]", line_idx + 1);
+            };
+            let key = parse_key(token).with_context(|| format!("Line {}", line_idx + 1))?;
+            keys.push(key);
+        }
+    }
+
+    if layers.len() != NUM_LAYERS {
+        bail!(
+            "Found {} layers, the keyboard has {}",
+            layers.len(),
+            NUM_LAYERS
+        );
+    }
+    let mut table: LayerTable = [[KEY_NONE; LAYER_KEYS]; NUM_LAYERS];
+    for (layer, (name, keys)) in table.iter_mut().zip(&layers) {
+        if keys.len() != LAYER_KEYS {
+            bail!(
+                "Layer {} has {} keys, it needs one for each of the {} positions",
+                name,
+                keys.len(),
+                LAYER_KEYS
+            );
+        }
+        layer.copy_from_slice(keys);
+    }
+
+    // Catch what the keyboard would refuse before sending anything
+    let layout = Layout::from_table(&table);
+    match layout.table() {
+        Ok(_) => Ok(layout),
+        Err(LayoutError::BadLayer(layer)) => bail!(
+            "There is no layer {}, layers go up to {}",
+            layer,
+            NUM_LAYERS - 1
+        ),
+        Err(LayoutError::BadMacro(slot)) => bail!(
+            "There is no macro slot {}, slots go up to {}",
+            slot,
+            MACRO_SLOTS - 1
+        ),
+        Err(err) => bail!("Invalid keymap: {:?}", err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use picodox_proto::{ENCODER_PRESS, NUM_KEYS};
+
+    const DEFAULT: &str = include_str!("../keymaps/default.keymap");
+
+    #[test]
+    fn keys() {
+        assert_eq!(parse_key("a").unwrap(), KEY_A);
+        assert_eq!(parse_key("0").unwrap(), KEY_0);
+        assert_eq!(parse_key("F13").unwrap(), KEY_F13);
+        assert_eq!(parse_key("0x2c").unwrap(), KEY_SPACE);
+        assert_eq!(parse_key("_").unwrap(), KEY_NONE);
+        assert_eq!(parse_key("rshift").unwrap(), KEY_MOD_RSHIFT);
+        assert_eq!(parse_key("media_mute").unwrap(), KEY_MEDIA_MUTE);
+        assert_eq!(
+            parse_key("mt(LSHIFT,F)").unwrap(),
+            mt(KEY_MOD_LSHIFT, KEY_F)
+        );
+        assert_eq!(parse_key("osm(lctrl)").unwrap(), osm(KEY_MOD_LCTRL));
+        assert_eq!(parse_key("macro(3)").unwrap(), Key::Macro(3));
+        assert_eq!(parse_key("leader").unwrap(), KEY_LEADER);
+
+        assert!(parse_key("F25").is_err());
+        assert!(parse_key("mt(A,LSHIFT)").is_err());
+        assert!(parse_key("osm(A)").is_err());
+        assert!(parse_key("mo(x)").is_err());
+        assert!(parse_key("bogus").is_err());
+    }
+
+    #[test]
+    fn names_parse_back() {
+        for usage in 0..=u8::MAX {
+            if let Some(name) = code_name(KeyCode(usage)) {
+                assert_eq!(parse_code(name).unwrap(), KeyCode(usage), "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn default_keymap() {
+        let table = parse(DEFAULT).unwrap().table().unwrap();
+        assert_eq!(table[0][1], KEY_5);
+        assert_eq!(table[0][28], KEY_ESC);
+        // Right half J, the mo(1) under the thumb and the nav arrows
+        assert_eq!(table[0][NUM_KEYS + 16], KEY_J);
+        assert_eq!(table[0][NUM_KEYS + 30], mo(1));
+        assert_eq!(table[1][NUM_KEYS + 15], KEY_LEFT);
+        assert_eq!(table[1][NUM_KEYS + 28], tg(1));
+        assert_eq!(table[1][0], KEY_NONE);
+        assert_eq!(table[0][ENCODER_PRESS], KEY_MEDIA_MUTE);
+        assert_eq!(table[1][ENCODER_PRESS], KEY_MEDIA_PLAYPAUSE);
+    }
+
+    #[test]
+    fn layout_errors() {
+        let missing = DEFAULT.replace("EQUAL", "");
+        assert!(parse(&missing).is_err());
+
+        let bad_layer = DEFAULT.replace("mo(1)", "mo(2)");
+        assert!(parse(&bad_layer).is_err());
+
+        let no_header = format!("A\n{}", DEFAULT);
+        assert!(parse(&no_header).is_err());
+
+        let one_layer = DEFAULT.split("[nav]").next().unwrap();
+        assert!(parse(one_layer).is_err());
+    }
+}
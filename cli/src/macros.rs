@@ -0,0 +1,136 @@
+use anyhow::{bail, Result};
+use picodox_proto::settings::{MacroData, MACRO_MOD_FIRST, MACRO_MOD_LAST};
+
+const LEFT_SHIFT: u8 = MACRO_MOD_FIRST + 1;
+
+// HID usages for the printable ASCII characters that aren't letters or digits,
+// as (unshifted, shifted, usage)
+const SYMBOLS: &[(char, char, u8)] = &[
+    (' ', ' ', 0x2c),
+    ('-', '_', 0x2d),
+    ('=', '+', 0x2e),
+    ('[', '{', 0x2f),
+    (']', '}', 0x30),
+    ('\\', '|', 0x31),
+    (';', ':', 0x33),
+    ('\'', '"', 0x34),
+    ('`', '~', 0x35),
+    (',', '<', 0x36),
+    ('.', '>', 0x37),
+    ('/', '?', 0x38),
+];
+
+const SHIFTED_DIGITS: &str = ")!@#$%^&*(";
+
+/// Returns (shifted, usage) for an ASCII character
+fn char_to_usage(c: char) -> Option<(bool, u8)> {
+    match c {
+        'a'..='z' => Some((false, 0x04 + (c as u8 - b'a'))),
+        'A'..='Z' => Some((true, 0x04 + (c as u8 - b'A'))),
+        '1'..='9' => Some((false, 0x1e + (c as u8 - b'1'))),
+        '0' => Some((false, 0x27)),
+        '\n' => Some((false, 0x28)),
+        '\t' => Some((false, 0x2b)),
+        _ => {
+            if let Some(idx) = SHIFTED_DIGITS.find(c) {
+                return char_to_usage(char::from(b'0' + idx as u8)).map(|(_, u)| (true, u));
+            }
+            SYMBOLS.iter().find_map(|&(plain, shifted, usage)| {
+                if c == plain {
+                    Some((false, usage))
+                } else if c == shifted {
+                    Some((true, usage))
+                } else {
+                    None
+                }
+            })
+        }
+    }
+}
+
+fn usage_to_char(shifted: bool, usage: u8) -> Option<char> {
+    let plain = match usage {
+        0x04..=0x1d => char::from(b'a' + (usage - 0x04)),
+        0x1e..=0x26 => char::from(b'1' + (usage - 0x1e)),
+        0x27 => '0',
+        0x28 => '\n',
+        0x2b => '\t',
+        _ => {
+            return SYMBOLS
+                .iter()
+                .find(|&&(_, _, u)| u == usage)
+                .map(|&(plain, shift, _)| if shifted { shift } else { plain })
+        }
+    };
+
+    if !shifted {
+        Some(plain)
+    } else if plain.is_ascii_lowercase() {
+        Some(plain.to_ascii_uppercase())
+    } else if let Some(digit) = plain.to_digit(10) {
+        SHIFTED_DIGITS.chars().nth(digit as usize)
+    } else {
+        None
+    }
+}
+
+/// Convert text into a macro that types it
+pub fn encode_text(text: &str) -> Result<MacroData> {
+    let mut data = MacroData::new();
+    for c in text.chars() {
+        let Some((shifted, usage)) = char_to_usage(c) else {
+            bail!("Character {:?} cannot be typed by a macro", c);
+        };
+        if shifted && data.push(LEFT_SHIFT).is_err() {
+            bail!("Macro is longer than {} keystrokes", data.capacity());
+        }
+        if data.push(usage).is_err() {
+            bail!("Macro is longer than {} keystrokes", data.capacity());
+        }
+    }
+
+    Ok(data)
+}
+
+/// Render a macro as the text it types, or as hex usages if it contains
+/// keystrokes that aren't plain text
+pub fn describe(data: &[u8]) -> String {
+    let mut text = String::new();
+    let mut shifted = false;
+    for &usage in data {
+        if usage == LEFT_SHIFT {
+            shifted = true;
+            continue;
+        }
+        let c = if (MACRO_MOD_FIRST..=MACRO_MOD_LAST).contains(&usage) {
+            None
+        } else {
+            usage_to_char(shifted, usage)
+        };
+        match c {
+            Some(c) => text.push(c),
+            None => return format!("{:02x?}", data),
+        }
+        shifted = false;
+    }
+
+    format!("{:?}", text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_round_trip() {
+        let text = "Hello, World! (a_b) 0-9\n";
+        let data = encode_text(text).unwrap();
+        assert_eq!(describe(&data), format!("{:?}", text));
+    }
+
+    #[test]
+    fn text_too_long() {
+        assert!(encode_text(&"a".repeat(MacroData::new().capacity() + 1)).is_err());
+        assert!(encode_text("é").is_err());
+    }
+}
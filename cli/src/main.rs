@@ -1,37 +1,221 @@
 use std::{
     cmp,
-    fmt::Debug,
+    fmt::{self, Debug},
     fs,
-    io::{BufRead, BufReader, Write},
+    io::{self, BufRead, BufReader, Read, Write},
+    process::{self, Stdio},
+    sync::{mpsc, OnceLock},
     thread,
     time::{Duration, Instant},
 };
 
+mod config;
+mod elf;
+mod hid;
+mod keymap;
+mod macros;
+mod ports;
+mod repl;
+mod trace;
 mod uf2;
 
 use anyhow::{anyhow, bail, Context, Result};
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 
-use crc::{Crc, CRC_8_BLUETOOTH};
-use picodox_proto::{Command, Response, DATA_COUNT};
+use elf::FwImage;
+use indicatif::{ProgressBar, ProgressStyle};
+use picodox_proto::{
+    proto_impl::{self, Crc8, CrcKind, Framing, FW_CRC},
+    settings::{Config, MACRO_SLOTS},
+    AckType, BuildInfo, Command, DataChunk, DeviceId, FlashCrc, Hand, KeyState, LedAnimation,
+    LogLevel, MatrixLoc, NackType, Response, SelfTestCheck, SelfTestResults, Version, WireSize,
+    CURRENT_VERSION, DATA_COUNT, FLASH_RESYNC_MS, NO_TAG, NUM_COLS, NUM_KEYS, NUM_ROWS,
+    PANIC_CHUNK,
+};
+use postcard::experimental::max_size::MaxSize;
 use serde::{de::DeserializeOwned, Serialize};
-use serialport::SerialPort;
-use uf2::{Uf2Block, Uf2Flags};
+use serialport::{ClearBuffer, SerialPort};
+use uf2::{Uf2Block, Uf2Region, RP2040_FAMILY_ID};
 
-const SERIAL_TIMEOUT: Duration = Duration::from_millis(100);
-const CRC: Crc<u8> = Crc::<u8>::new(&CRC_8_BLUETOOTH);
+const SERIAL_BAUD: u32 = 115_200;
+const SERIAL_TIMEOUT_MS: u64 = 100;
+// Damaged frames skipped while waiting for a response before giving up
+const MAX_DAMAGED_FRAMES: usize = 3;
+// Once part of a frame has arrived, how long the rest of it may take
+const FRAME_TIMEOUT: Duration = Duration::from_millis(500);
+// Times a request that is safe to repeat is sent before giving up
+const REQUEST_ATTEMPTS: u32 = 3;
+// A full Data frame fits in one usb packet, and the firmware only reads the
+// next packet once it has handled the frames it has. The rest of the window
+// waits in the host's buffers.
+const MAX_FLASH_WINDOW: usize = 8;
+const FLASH_FRAME_SIZE: usize = proto_impl::wire_max_size::<FlashCrc, Command>();
+// Tagged commands sent before waiting for their responses, see transact_all
+const MAX_IN_FLIGHT: usize = 8;
+const TAGGED_FRAME_SIZE: usize = proto_impl::tagged_wire_max_size::<Crc8, Command>();
+// Set once from --framing, COBS until then. Length prefixed frames are never
+// longer, so the COBS sizes above fit both.
+static FRAMING: OnceLock<Framing> = OnceLock::new();
+const FLASH_FINISH_TIMEOUT: Duration = Duration::from_secs(2);
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(5);
+// The DFU partition is erased a sector at a time, all 512 of them
+const ERASE_TIMEOUT: Duration = Duration::from_secs(60);
+// The self test erases a flash sector and waits for the other half
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(2);
+// The ack the keyboard sends right before it resets
+const RESET_ACK_TIMEOUT: Duration = Duration::from_millis(500);
+// How long the port may take to come back after the keyboard resets
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// First wait before opening a port that is coming back, it doubles with
+// each attempt up to RECONNECT_MAX_DELAY
+const RECONNECT_DELAY: Duration = Duration::from_millis(50);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(1);
+// Start of the DFU partition in firmware/memory.x, where flashed firmware lands
+const DFU_OFFSET: u32 = 0x20_1000;
+// Bytes asked for per ReadFlash command, its length is a u16
+const READ_FLASH_BLOCK: u32 = 0x8000;
+/// Most bytes a trace read is expected to return, well over the firmware's
+/// buffer
+const MAX_TRACE: usize = 0x1_0000;
+// USB ids of the bootrom's PICOBOOT interface on each chip
+const PICOBOOT_DEVICES: &[(u16, u16, &str)] =
+    &[(0x2e8a, 0x0003, "RP2040"), (0x2e8a, 0x000f, "RP2350")];
+const PICOBOOT_POLL: Duration = Duration::from_millis(100);
+// Used to decode the defmt frames from the logging interface
+const DEFMT_PRINT: &str = "defmt-print";
+// How long --with-logs keeps printing logs after the command is done
+const LOG_LINGER: Duration = Duration::from_millis(200);
+const PROGRESS_TEMPLATE: &str =
+    "{bar:40} {bytes}/{total_bytes} ({binary_bytes_per_sec}, {eta} left)";
 
 #[derive(Debug, Parser)]
 #[command(name = "picodox-cli")]
 #[command(about = "A cli for interacting with the picodox keyboard")]
 struct Cli {
+    #[command(flatten)]
+    port: PortArgs,
+    #[command(subcommand)]
+    command: SubCommand,
+}
+
+#[derive(Debug, Args)]
+struct PortArgs {
     #[arg(help = "The serial port connected to the keyboard")]
     #[arg(short, long)]
     // TODO: Read this from a .env file instead?
     #[arg(default_value_t = String::from("/dev/ttyACM0"))]
     device: String,
-    #[command(subcommand)]
-    command: SubCommand,
+    #[arg(help = "Run destructive commands even if the firmware version doesn't match")]
+    #[arg(long)]
+    force: bool,
+    #[arg(help = "Baud rate of the serial port, only matters when bridging through a UART")]
+    #[arg(long, default_value_t = SERIAL_BAUD)]
+    baud: u32,
+    #[arg(help = "How long to wait for each response, in milliseconds")]
+    #[arg(long, default_value_t = SERIAL_TIMEOUT_MS)]
+    timeout_ms: u64,
+    #[arg(
+        help = "Keep trying to open the serial port for this many milliseconds, for a keyboard that is still coming back from a reset"
+    )]
+    #[arg(long, default_value_t = 0)]
+    reconnect_ms: u64,
+    #[arg(help = "Don't show progress bars")]
+    #[arg(short, long)]
+    quiet: bool,
+    #[arg(
+        help = "Print the firmware logs while the command runs, from the logging interface of the same keyboard"
+    )]
+    #[arg(long)]
+    with_logs: bool,
+    #[arg(help = "The elf file of the running firmware, to decode the logs of --with-logs")]
+    #[arg(long, requires = "with_logs")]
+    log_elf: Option<String>,
+    #[arg(
+        help = "How frames are delimited on the serial link, has to match the firmware's len-framing feature"
+    )]
+    #[arg(long, value_enum, default_value_t = FramingArg::Cobs)]
+    framing: FramingArg,
+}
+
+impl PortArgs {
+    fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+
+    fn reconnect(&self) -> Duration {
+        Duration::from_millis(self.reconnect_ms)
+    }
+
+    /// Progress bar for a transfer of `len` bytes, hidden with `--quiet`
+    fn progress(&self, len: usize) -> Result<ProgressBar> {
+        if self.quiet {
+            return Ok(ProgressBar::hidden());
+        }
+        let style =
+            ProgressStyle::with_template(PROGRESS_TEMPLATE).context("Invalid progress template")?;
+        Ok(ProgressBar::new(len as u64).with_style(style))
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum LevelArg {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl From<LevelArg> for LogLevel {
+    fn from(level: LevelArg) -> Self {
+        match level {
+            LevelArg::Trace => LogLevel::Trace,
+            LevelArg::Debug => LogLevel::Debug,
+            LevelArg::Info => LogLevel::Info,
+            LevelArg::Warn => LogLevel::Warn,
+            LevelArg::Error => LogLevel::Error,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum FramingArg {
+    /// COBS encoded with a zero byte after each frame
+    Cobs,
+    /// A little endian u16 length in front of each frame
+    Len,
+}
+
+impl From<FramingArg> for Framing {
+    fn from(framing: FramingArg) -> Self {
+        match framing {
+            FramingArg::Cobs => Framing::Cobs,
+            FramingArg::Len => Framing::LenPrefix,
+        }
+    }
+}
+
+/// The framing picked with --framing
+fn framing() -> Framing {
+    FRAMING.get().copied().unwrap_or(Framing::Cobs)
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum HandArg {
+    Left,
+    Right,
+    /// Go back to reading the handedness jumper
+    Jumper,
+}
+
+impl From<HandArg> for Option<Hand> {
+    fn from(hand: HandArg) -> Self {
+        match hand {
+            HandArg::Left => Some(Hand::Left),
+            HandArg::Right => Some(Hand::Right),
+            HandArg::Jumper => None,
+        }
+    }
 }
 
 #[derive(Debug, Subcommand)]
@@ -39,9 +223,23 @@ enum SubCommand {
     #[command()]
     Debug,
     #[command(about = "Change the keyboard mcu into DFU flash mode")]
-    Dfu,
+    Dfu {
+        #[arg(help = "Seconds to wait for the bootloader to show up")]
+        #[arg(short, long, default_value_t = 5.0)]
+        timeout: f64,
+    },
     #[command(about = "Reset the keyboard mcu")]
-    Reset,
+    Reset {
+        #[arg(help = "Wait for the keyboard to come back and answer")]
+        #[arg(long)]
+        wait: bool,
+    },
+    #[command(about = "Show the protocol version of the firmware")]
+    Version {
+        #[arg(help = "Also show the git hash and build time of the firmware")]
+        #[arg(short, long)]
+        verbose: bool,
+    },
     #[command(about = "List all serial ports")]
     ListSerial,
     #[command(about = "Send data to the mcu over serial and read its response")]
@@ -49,6 +247,12 @@ enum SubCommand {
         #[arg(help = "The content to send")]
         msg: String,
     },
+    #[command(about = "Measure the round trip time of the serial link")]
+    Ping {
+        #[arg(help = "Number of pings to send")]
+        #[arg(short, long, default_value_t = 10)]
+        count: u16,
+    },
     #[command(about = "Analyze a UF2 file, showing its sections")]
     Uf2 {
         #[arg(help = "The file to analyze")]
@@ -56,306 +260,2457 @@ enum SubCommand {
         #[arg(short, long)]
         verbose: bool,
     },
+    #[command(about = "Convert an elf file into a UF2 file for the rp2040 bootloader")]
+    Uf2Pack {
+        #[arg(help = "The elf file to convert")]
+        input: String,
+        #[arg(help = "Where to write the UF2 file")]
+        output: String,
+    },
+    #[command(about = "Store a macro that types the given text")]
+    SetMacro {
+        #[arg(help = "The macro slot to store into")]
+        slot: u8,
+        #[arg(help = "The text to type, leave empty to clear the slot")]
+        #[arg(default_value_t = String::new())]
+        text: String,
+    },
+    #[command(about = "List the macros stored on the keyboard")]
+    ListMacros,
+    #[command(about = "Flash new firmware onto the keyboard")]
+    Flash {
+        #[arg(help = "The elf file to flash")]
+        path: String,
+        #[arg(help = "How many chunks can be sent before waiting for an ack")]
+        #[arg(short, long, default_value_t = 4)]
+        window: usize,
+        #[arg(help = "How many times a nacked chunk is resent before giving up")]
+        #[arg(short, long, default_value_t = 3)]
+        retries: u32,
+        #[arg(help = "Check the file and show what would be sent, without the keyboard")]
+        #[arg(long)]
+        dry_run: bool,
+    },
+    #[command(about = "Check that the firmware on the keyboard matches a file")]
+    Verify {
+        #[arg(help = "The elf file that was flashed")]
+        path: String,
+        #[arg(help = "Flash offset the firmware was written to")]
+        #[arg(short, long, default_value_t = DFU_OFFSET)]
+        offset: u32,
+    },
+    #[command(about = "Erase the DFU partition without flashing anything")]
+    Erase {
+        #[arg(help = "Erase without asking first")]
+        #[arg(long)]
+        force: bool,
+    },
+    #[command(about = "Save a region of the keyboard's flash to a file")]
+    Dump {
+        #[arg(help = "Flash offset to start reading at, 0x for hex")]
+        #[arg(value_parser = parse_number)]
+        offset: u32,
+        #[arg(help = "Number of bytes to read, 0x for hex")]
+        #[arg(value_parser = parse_number)]
+        len: u32,
+        #[arg(help = "Where to write the flash contents")]
+        output: String,
+    },
+    #[command(about = "Set the color of the keyboard LEDs")]
+    Led {
+        r: u8,
+        g: u8,
+        b: u8,
+        #[arg(help = "Slowly fade the color in and out instead of holding it")]
+        #[arg(long, conflicts_with = "reactive")]
+        breathe: bool,
+        #[arg(help = "Only light the keys that are pressed, fading out after release")]
+        #[arg(long)]
+        reactive: bool,
+    },
+    #[command(about = "Set the color of the LED under one key")]
+    KeyLed {
+        row: usize,
+        col: usize,
+        r: u8,
+        g: u8,
+        b: u8,
+    },
+    #[command(about = "Show or change the settings stored on the keyboard")]
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+    #[command(about = "Change the keymap without reflashing")]
+    Keymap {
+        #[command(subcommand)]
+        action: KeymapAction,
+    },
+    #[command(about = "Cycle the keyboard LEDs through the rainbow")]
+    LedRainbow {
+        #[arg(help = "Steps around the color wheel per 20ms, 256 steps go all the way around")]
+        #[arg(short, long, default_value_t = 1)]
+        speed: u8,
+    },
+    #[command(about = "Show the message of the last panic, if the keyboard crashed")]
+    Panic,
+    #[command(about = "Save the executor trace buffer as chrome://tracing JSON")]
+    Trace {
+        #[arg(help = "Where to write the JSON file")]
+        output: String,
+    },
+    #[command(about = "Follow the firmware logs until interrupted")]
+    Logs {
+        #[arg(
+            help = "The elf file of the running firmware, used to decode the logs. Leave it out for firmware built with the plain-log feature"
+        )]
+        elf: Option<String>,
+        #[arg(
+            help = "The serial port of the logging interface, the second one the keyboard exposes"
+        )]
+        #[arg(short, long, default_value_t = String::from("/dev/ttyACM1"))]
+        log_port: String,
+        #[arg(help = "Have the keyboard drop logs below this level")]
+        #[arg(long)]
+        level: Option<LevelArg>,
+    },
+    #[command(about = "Send raw bytes to the keyboard and dump the response frames")]
+    Raw {
+        #[arg(help = "The bytes to send as hex, spaces between bytes are optional")]
+        #[arg(required = true)]
+        hex: Vec<String>,
+        #[arg(help = "Send the bytes as they are instead of adding a CRC and COBS framing")]
+        #[arg(long)]
+        no_frame: bool,
+    },
+    #[command(about = "Keep the serial port open and run commands as they are typed")]
+    Repl,
+    #[command(about = "Show which keys of both halves are pressed, before the keymap")]
+    Matrix,
+    #[command(
+        about = "Show the matrix live while every key is pressed, then list the ones that never registered"
+    )]
+    Test,
+    #[command(about = "Drive matrix columns one at a time and show which rows read high")]
+    TestColumn {
+        #[arg(help = "Only test this column instead of stepping through all of them")]
+        #[arg(short, long)]
+        col: Option<u8>,
+    },
+    #[command(name = "selftest")]
+    #[command(about = "Check the flash, the link to the other half and USB of the connected half")]
+    SelfTest,
+    #[command(about = "Show the flash chip IDs of the connected half")]
+    DeviceId,
+    #[command(about = "Print the keyboard reports the host receives until interrupted")]
+    HidMonitor {
+        #[arg(help = "The hidraw node of the keyboard interface, found by USB ID if left out")]
+        #[arg(long)]
+        hidraw: Option<String>,
+    },
+    #[command(about = "Store which half the connected board is and reset it into that role")]
+    SetHand {
+        #[arg(help = "The half to act as, or jumper to go back to reading the handedness jumper")]
+        hand: HandArg,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum ConfigAction {
+    #[command(about = "Show the current settings")]
+    Get,
+    #[command(about = "Change settings and store them on the keyboard")]
+    Set {
+        #[arg(help = "Settings to change as key=value, see `config get` for the keys")]
+        #[arg(required = true)]
+        values: Vec<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum KeymapAction {
+    #[command(about = "Send a layout file to the keyboard, which stores and applies it")]
+    Upload {
+        #[arg(help = "The layout file, see cli/keymaps/default.keymap for the format")]
+        path: String,
+    },
 }
 
 fn main() {
     let args = Cli::parse();
+    FRAMING.get_or_init(|| args.port.framing.into());
+    let mut dev = Device::new(&args.port);
 
-    let res = match args.command {
-        SubCommand::Reset => reset(&args.device),
-        SubCommand::Dfu => usb_dfu(&args.device),
-        SubCommand::ListSerial => list_serial(),
-        SubCommand::Echo { msg } => send_echo(&args.device, &msg),
-        SubCommand::Uf2 { path, verbose } => analyze_uf2(&path, verbose),
-        SubCommand::Debug => debug(&args.device),
-    };
-
+    let res = spawn_logs(&args.port).and_then(|_| run(&mut dev, args.command));
+    if args.port.with_logs {
+        // The logs of the last thing the command did may still be on their way
+        thread::sleep(LOG_LINGER);
+    }
     if let Err(err) = res {
         println!("Error: {:#}", err);
-        std::process::exit(1);
+        process::exit(1);
+    }
+}
+
+fn run(dev: &mut Device, command: SubCommand) -> Result<()> {
+    match command {
+        SubCommand::Reset { wait } => reset(dev, wait),
+        SubCommand::Dfu { timeout } => usb_dfu(dev, timeout),
+        SubCommand::Version { verbose } => show_version(dev, verbose),
+        SubCommand::ListSerial => list_serial(),
+        SubCommand::Echo { msg } => send_echo(dev, &msg),
+        SubCommand::Ping { count } => ping(dev, count),
+        SubCommand::Uf2 { path, verbose } => show_uf2(&path, verbose),
+        SubCommand::Uf2Pack { input, output } => pack_uf2(&input, &output),
+        SubCommand::Debug => debug(dev),
+        SubCommand::SetMacro { slot, text } => set_macro(dev, slot, &text),
+        SubCommand::ListMacros => list_macros(dev),
+        SubCommand::Matrix => show_matrix(dev),
+        SubCommand::Test => test_matrix(dev),
+        SubCommand::TestColumn { col } => test_column(dev, col),
+        SubCommand::SelfTest => self_test(dev),
+        SubCommand::DeviceId => show_device_id(dev),
+        SubCommand::HidMonitor { hidraw } => hid::monitor(hidraw.as_deref()),
+        SubCommand::SetHand { hand } => set_hand(dev, hand.into()),
+        SubCommand::Flash {
+            path,
+            window,
+            retries,
+            dry_run,
+        } => flash_fw(dev, &path, window, retries, dry_run),
+        SubCommand::Verify { path, offset } => verify_fw(dev, &path, offset),
+        SubCommand::Erase { force } => erase_fw(dev, force),
+        SubCommand::Dump {
+            offset,
+            len,
+            output,
+        } => dump_flash(dev, offset, len, &output),
+        SubCommand::Led {
+            r,
+            g,
+            b,
+            breathe,
+            reactive,
+        } => {
+            let command = if breathe {
+                Command::SetAnimation(LedAnimation::Breathe { r, g, b })
+            } else if reactive {
+                Command::SetAnimation(LedAnimation::Reactive { r, g, b })
+            } else {
+                Command::SetLed { r, g, b }
+            };
+            set_led(dev, command)
+        }
+        SubCommand::KeyLed { row, col, r, g, b } => {
+            if row >= NUM_ROWS || col >= NUM_COLS {
+                bail!(
+                    "Key {},{} is outside of the {}x{} matrix",
+                    row,
+                    col,
+                    NUM_ROWS,
+                    NUM_COLS
+                );
+            }
+            let loc = MatrixLoc::new(row, col);
+            set_led(dev, Command::SetKeyLed { loc, r, g, b })
+        }
+        SubCommand::Config { action } => match action {
+            ConfigAction::Get => show_config(dev),
+            ConfigAction::Set { values } => set_config(dev, &values),
+        },
+        SubCommand::Keymap { action } => match action {
+            KeymapAction::Upload { path } => upload_keymap(dev, &path),
+        },
+        SubCommand::LedRainbow { speed } => {
+            set_led(dev, Command::SetAnimation(LedAnimation::Rainbow { speed }))
+        }
+        SubCommand::Panic => show_panic(dev),
+        SubCommand::Trace { output } => save_trace(dev, &output),
+        SubCommand::Logs {
+            elf,
+            log_port,
+            level,
+        } => {
+            if let Some(level) = level {
+                set_log_level(dev, level.into())?;
+            }
+            follow_logs(dev.args, elf.as_deref(), &log_port)
+        }
+        SubCommand::Raw { hex, no_frame } => send_raw(dev, &hex.concat(), no_frame),
+        SubCommand::Repl => repl::repl(dev),
+    }
+}
+
+fn debug(dev: &mut Device) -> Result<()> {
+    let _port = dev.port(false)?;
+
+    Ok(())
+}
+
+fn show_uf2(path: &str, verbose: bool) -> Result<()> {
+    let regions = analyze_uf2(path)?;
+
+    for region in regions {
+        print!("0x{:08x} ({} bytes)", region.address, region.length);
+        match region.family {
+            Some(family) if verbose => println!(" family 0x{:08x}", family),
+            _ => println!(),
+        }
     }
+
+    Ok(())
 }
 
-fn debug(path: &str) -> Result<()> {
-    let port = open_port(path)?;
+fn pack_uf2(input: &str, output: &str) -> Result<()> {
+    let image = read_fw_image(input)?;
+
+    let blocks = Uf2Block::from_image(image.address, &image.data, RP2040_FAMILY_ID);
+    let bytes: Vec<u8> = blocks
+        .iter()
+        .flat_map(|block| block.to_bytes())
+        .copied()
+        .collect();
+    fs::write(output, bytes).with_context(|| format!("Unable to write file '{}'", output))?;
+    println!("Wrote {} blocks to '{}'", blocks.len(), output);
 
     Ok(())
 }
 
-fn analyze_uf2(path: &str, verbose: bool) -> Result<()> {
+fn analyze_uf2(path: &str) -> Result<Vec<Uf2Region>> {
     let file_contents =
         fs::read(path).with_context(|| format!("Unable to open file '{}'", path))?;
 
     let blocks = Uf2Block::parse(&file_contents)?;
+    if blocks.is_empty() {
+        bail!("No blocks in file!");
+    }
 
-    let mut bounds = match blocks.first() {
-        Some(block) => block.get_bounds(),
-        None => bail!("No blocks in file!"),
-    };
-
-    if verbose {
-        println!("{:?}", blocks.first().unwrap());
+    let regions = Uf2Region::from_blocks(&blocks);
+    if regions.is_empty() {
+        bail!("No main flash blocks in file!");
     }
 
-    for block in blocks[1..]
-        .iter()
-        .filter(|b| !b.get_flags().contains(Uf2Flags::NotMainFlash))
-    {
-        let new_bounds = block.get_bounds();
-        bounds = if bounds.1 == new_bounds.0 {
-            (bounds.0, new_bounds.1)
-        } else {
-            println!("{:x} ({} bytes)", bounds.0, bounds.1 - bounds.0);
-            new_bounds
-        };
+    Ok(regions)
+}
+
+/// Reset the keyboard, with `wait` until it is back and answers again
+fn reset(dev: &mut Device, wait: bool) -> Result<()> {
+    let port = dev.port(false)?;
+    send_command(&mut port.get_mut(), &Command::Reset).context("Sending Reset command")?;
+    let acked = recv_reset_ack(port, AckType::AckReset)?;
+    dev.close();
+    if acked {
+        println!("Keyboard is resetting");
+    } else {
+        println!("WARNING: the keyboard didn't ack, it may not have received the command");
     }
 
-    println!("0x{:x} ({} bytes)", bounds.0, bounds.1 - bounds.0);
+    if wait {
+        let start = Instant::now();
+        let port = dev.serial()?;
+        let version = query_version(port)
+            .context("The serial port came back, but the keyboard didn't answer")?;
+        match version {
+            Some(version) => println!(
+                "Keyboard is back after {:.1}s, protocol version {}",
+                start.elapsed().as_secs_f64(),
+                version
+            ),
+            None => println!(
+                "Keyboard is back after {:.1}s",
+                start.elapsed().as_secs_f64()
+            ),
+        }
+    }
 
     Ok(())
 }
 
-fn reset(dev: &str) -> Result<()> {
-    let mut port = open_port(dev)?;
-    send_command(&mut port.get_mut(), &Command::Reset)?;
+fn set_hand(dev: &mut Device, hand: Option<Hand>) -> Result<()> {
+    let port = dev.port(true)?;
+    send_command(&mut port.get_mut(), &Command::SetHand(hand))
+        .context("Sending SetHand command")?;
+    let acked = recv_reset_ack(port, AckType::AckHand)?;
+    dev.close();
+    if !acked {
+        println!("WARNING: the keyboard didn't ack, it may not have received the command");
+        return Ok(());
+    }
+    match hand {
+        Some(hand) => println!(
+            "Keyboard is resetting as the {} half",
+            format!("{:?}", hand).to_lowercase()
+        ),
+        None => println!("Keyboard is resetting, the jumper picks the half again"),
+    }
 
     Ok(())
 }
 
-fn is_picoboot_connected() -> bool {
-    const RASPI_VID: u16 = 0x2e8a;
-    const PICOBOOT_PID: u16 = 0x0003;
+/// Wait for the ack the keyboard sends before resetting. Older firmware
+/// resets without one, so only a nack is an error and a missing ack is
+/// `false`.
+fn recv_reset_ack(port: &mut Port, ack: AckType) -> Result<bool> {
+    port.get_mut()
+        .set_timeout(RESET_ACK_TIMEOUT)
+        .context("Setting serial timeout")?;
+    match recv_exact::<_, Response>(port) {
+        Ok(Response::Ack(got)) if got == ack => Ok(true),
+        Ok(Response::Nack(err)) => bail!("Keyboard refused to reset: {}", err),
+        Ok(other) => bail!("Unexpected response: {:?}, expecting {:?}", other, ack),
+        Err(_) => Ok(false),
+    }
+}
 
-    usb_enumeration::enumerate(Some(RASPI_VID), Some(PICOBOOT_PID)).len() > 0
+/// The chip whose PICOBOOT interface is connected, if any
+fn find_picoboot() -> Option<&'static str> {
+    PICOBOOT_DEVICES
+        .iter()
+        .find(|&&(vid, pid, _)| !usb_enumeration::enumerate(Some(vid), Some(pid)).is_empty())
+        .map(|&(_, _, chip)| chip)
 }
 
-fn usb_dfu(dev: &str) -> Result<()> {
-    let mut port = open_port(dev)?;
-    send_command(&mut port.get_mut(), &Command::UsbDfu)?;
+fn usb_dfu(dev: &mut Device, timeout: f64) -> Result<()> {
+    let timeout = Duration::try_from_secs_f64(timeout).context("Invalid timeout")?;
+
+    let port = dev.port(false)?;
+    send_command(&mut port.get_mut(), &Command::UsbDfu).context("Sending UsbDfu command")?;
+    let acked = recv_reset_ack(port, AckType::AckUsbDfu)?;
+    dev.close();
+    if !acked {
+        println!("WARNING: the keyboard didn't ack, waiting for the bootloader anyway");
+    }
+
+    let start = Instant::now();
+    for spinner in ['|', '/', '-', '\\'].iter().cycle() {
+        print!(
+            "\r{} Waiting for the bootloader ({:.1}s)",
+            spinner,
+            start.elapsed().as_secs_f64()
+        );
+        io::stdout().flush().context("Flushing stdout")?;
 
-    let now = Instant::now();
-    while (Instant::now() - now) < Duration::from_secs(5) {
-        if is_picoboot_connected() {
+        if let Some(chip) = find_picoboot() {
+            println!();
+            println!(
+                "{} bootloader is up after {:.1}s",
+                chip,
+                start.elapsed().as_secs_f64()
+            );
             return Ok(());
-        } else {
-            thread::sleep(Duration::from_millis(100));
         }
+        if start.elapsed() >= timeout {
+            break;
+        }
+        thread::sleep(PICOBOOT_POLL);
+    }
+
+    println!();
+    if acked {
+        bail!(
+            "The keyboard acked, but no PICOBOOT device showed up within {:.1}s",
+            timeout.as_secs_f64()
+        );
     }
-    bail!("Timeout waiting for PICOBOOT device");
+    bail!(
+        "No PICOBOOT device showed up within {:.1}s, the keyboard may not have received the command",
+        timeout.as_secs_f64()
+    );
 }
 
-fn open_port(device: &str) -> Result<BufReader<Box<dyn SerialPort>>> {
-    let port = serialport::new(device, 115_200)
-        .timeout(SERIAL_TIMEOUT)
-        .open()
-        .with_context(|| format!("Failed to open serial port '{device}'"))?;
+type Port = BufReader<Box<dyn SerialPort>>;
 
-    Ok(BufReader::new(port))
+/// The serial connection to the keyboard. It is opened on first use and then
+/// kept open, so commands run from the repl share it.
+struct Device<'a> {
+    args: &'a PortArgs,
+    port: Option<Port>,
+    /// Set once the firmware version has been checked, with the reason the
+    /// firmware may not understand us if there is one
+    version_problem: Option<Option<String>>,
+    /// The keyboard reset since the port was last open, it takes a moment
+    /// to come back
+    reconnecting: bool,
 }
 
-fn send_command<W: Write, S: Serialize + Debug>(port: &mut W, command: &S) -> Result<()> {
-    // Serialize the command useing postcard
-    let mut bytes = postcard::to_stdvec(command)
-        .with_context(|| format!("Failed to serialize command: {:?}", command))?;
-    // Add the CRC byte
-    let crc_of_bytes = CRC.checksum(&bytes);
-    bytes.push(crc_of_bytes);
-    // COBS encode the command + crc
-    let mut cobs = cobs::encode_vec(&bytes);
-    // Add the and end of frame sentinel
-    cobs.push(0u8);
+impl<'a> Device<'a> {
+    fn new(args: &'a PortArgs) -> Self {
+        Device {
+            args,
+            port: None,
+            version_problem: None,
+            reconnecting: false,
+        }
+    }
 
-    port.write(&cobs)
-        .context("Unable to write command to serial port")?;
+    /// Open the serial port without checking the firmware version
+    fn serial(&mut self) -> Result<&mut Port> {
+        let port = match self.port.take() {
+            Some(port) => port,
+            None if self.reconnecting => open_serial_retry(
+                self.args,
+                cmp::max(RECONNECT_TIMEOUT, self.args.reconnect()),
+            )?,
+            None if !self.args.reconnect().is_zero() => {
+                open_serial_retry(self.args, self.args.reconnect())?
+            }
+            None => open_serial(self.args)?,
+        };
+        self.reconnecting = false;
+        let port = self.port.insert(port);
+        // Undo any longer timeout the previous command needed
+        port.get_mut()
+            .set_timeout(self.args.timeout())
+            .context("Setting serial timeout")?;
 
-    Ok(())
-}
+        Ok(port)
+    }
 
-fn recv_response<R: BufRead, D: DeserializeOwned>(port: &mut R) -> Result<D> {
-    let mut read_buf = Vec::new();
-    // Read until we get the end sentinel (/0 byte)
-    port.read_until(0u8, &mut read_buf)
-        .context("Error while reading the response body")?;
+    /// Open the serial port and check that the firmware speaks the same
+    /// protocol version. Destructive commands are refused on a mismatch
+    /// unless `--force` is given.
+    fn port(&mut self, destructive: bool) -> Result<&mut Port> {
+        if self.version_problem.is_none() {
+            let problem = version_problem(self.serial()?)?;
+            if let Some(problem) = &problem {
+                println!("WARNING: {}", problem);
+            }
+            self.version_problem = Some(problem);
+        }
 
-    // Extract the end sentinel
-    let end_sentinel = read_buf.pop().expect("invariant violated");
-    assert_eq!(end_sentinel, 0u8);
+        if let Some(Some(problem)) = &self.version_problem {
+            if destructive && !self.args.force {
+                bail!(
+                    "Refusing to continue, {} (use --force to override)",
+                    problem
+                );
+            }
+        }
 
-    // Decode COBS
-    let mut cobs_decoded = cobs::decode_vec(&read_buf)
-        .ok()
-        .ok_or_else(|| anyhow!("Invalid packet encountered (illegal cobs) {:0x?}", read_buf))?;
+        self.serial()
+    }
 
-    let actual_crc = if let Some(crc) = cobs_decoded.pop() {
-        crc
-    } else {
-        bail!("Invalid packet encountered (missing CRC) {:0x?}", read_buf)
-    };
+    /// Drop the connection, the port goes away when the keyboard resets.
+    /// The next command waits for it to come back.
+    fn close(&mut self) {
+        self.port = None;
+        self.version_problem = None;
+        self.reconnecting = true;
+    }
 
-    // Check the CRC
-    let expect_crc = CRC.checksum(&cobs_decoded);
-    if expect_crc != actual_crc {
-        bail!(
-            "Invalid packet CRC (actual: {actual_crc:x}, expected: {expect_crc:x}) {:0x?}",
-            read_buf
-        );
+    /// Throw away whatever a failed command left unread
+    fn recover(&mut self) {
+        if let Some(port) = &mut self.port {
+            let _ = clear_input(port);
+        }
     }
+}
+
+fn open_serial(dev: &PortArgs) -> Result<Port> {
+    let device = &dev.device;
+    let serial = serialport::new(device, dev.baud)
+        .timeout(dev.timeout())
+        .open()
+        .with_context(|| format!("Failed to open serial port '{device}'"))?;
 
-    // Finally, decode the response
-    postcard::from_bytes(&cobs_decoded)
-        .with_context(|| format!("Failed to deserialize response {:0x?}", read_buf))
+    Ok(BufReader::new(serial))
 }
 
-fn list_serial() -> Result<()> {
-    let ports =
-        serialport::available_ports().context("Unable to enumerate available serial ports")?;
-    if ports.len() > 0 {
-        for port in ports {
-            println!("{}", port.port_name);
+/// How long to wait before attempt `attempt` to open a port that is coming
+/// back
+fn reconnect_delay(attempt: u32) -> Duration {
+    RECONNECT_DELAY
+        .saturating_mul(1 << attempt.min(16))
+        .min(RECONNECT_MAX_DELAY)
+}
+
+/// Open the serial port, trying again with growing delays for up to `within`
+/// while it is missing. Even the first attempt waits, right after a reset
+/// the old port may not have gone away yet.
+fn open_serial_retry(dev: &PortArgs, within: Duration) -> Result<Port> {
+    let start = Instant::now();
+    let mut attempt = 0;
+    loop {
+        thread::sleep(reconnect_delay(attempt));
+        attempt += 1;
+        match open_serial(dev) {
+            Ok(port) => return Ok(port),
+            Err(err) if start.elapsed() + reconnect_delay(attempt) > within => {
+                return Err(err).context(format!(
+                    "The serial port didn't come back within {:.1}s",
+                    within.as_secs_f64()
+                ))
+            }
+            Err(_) => {}
         }
-    } else {
-        println!("No serial ports detected!");
     }
-
-    Ok(())
 }
 
-fn send_echo(dev: &str, content: &str) -> Result<()> {
-    let mut port = open_port(dev)?;
+fn version_problem(port: &mut Port) -> Result<Option<String>> {
+    Ok(match query_version(port)? {
+        Some(version) if version.major == CURRENT_VERSION.major => None,
+        Some(version) => Some(format!(
+            "firmware protocol version {} does not match the cli version {}",
+            version, CURRENT_VERSION
+        )),
+        None => Some(String::from(
+            "firmware does not report a protocol version, it is probably older than the cli",
+        )),
+    })
+}
 
-    println!("Sending '{}'", content);
-    let command = Command::EchoMsg {
-        count: content.len().try_into().context("Message is too long")?,
-    };
-    send_command(&mut port.get_mut(), &command).context("Sending EchoMsg command")?;
+fn query_version(port: &mut Port) -> Result<Option<Version>> {
+    let resp = transact(port, &Command::GetVersion)?;
+    match resp {
+        Response::Version(version) => Ok(Some(version)),
+        Response::Nack(_) => Ok(None),
+        other => bail!("Unexpected response: {:?}, expecting Version", other),
+    }
+}
 
-    for (idx, chunk) in content.as_bytes().chunks(DATA_COUNT).enumerate() {
-        let mut data = [0u8; DATA_COUNT];
-        data[..chunk.len()].copy_from_slice(chunk);
-        send_command(&mut port.get_mut(), &Command::Data(data))
-            .with_context(|| format!("Sending data command {}", idx))?;
+/// Largest firmware image and largest Data chunk the keyboard accepts, None
+/// if the firmware is too old to say
+fn query_flash_info(port: &mut Port) -> Result<Option<(u32, usize)>> {
+    let resp = transact(port, &Command::GetFlashInfo)?;
+    match resp {
+        Response::FlashInfo {
+            max_fw_size,
+            data_count,
+        } => Ok(Some((max_fw_size, data_count.into()))),
+        Response::Nack(_) => Ok(None),
+        other => bail!("Unexpected response: {:?}, expecting FlashInfo", other),
     }
+}
 
-    let resp: Response = recv_response(&mut port).context("Receiving EchoMsg response")?;
+fn send_command<W: Write>(port: &mut W, command: &Command) -> Result<()> {
+    send_command_with::<Crc8, _, _, { Command::WIRE_MAX_SIZE }>(port, command)
+}
 
-    let resp_count = match resp {
-        Response::EchoMsg { count } => count as usize,
-        Response::Nack(err) => bail!("Received nack waiting for EchoMsg: {:?}", err),
-        other => bail!("Unexpected response: {:?}, expecting EchoMsg", other),
-    };
+/// Frame `command` with the same encoder the firmware uses, `N` has to fit
+/// its largest wire size
+fn send_command_with<C, W, S, const N: usize>(port: &mut W, command: &S) -> Result<()>
+where
+    C: CrcKind,
+    W: Write,
+    S: Serialize + MaxSize + Debug,
+{
+    send_framed::<C, W, S, N>(port, command, framing())
+}
 
-    let mut resp_content = Vec::new();
-    for i in (0..resp_count).step_by(DATA_COUNT) {
-        let resp: Response = recv_response(&mut port)?;
-        let resp_data = match resp {
-            Response::Data(data) => data,
-            Response::Nack(err) => bail!("Received nack waiting for Data: {:?}", err),
-            other => bail!("Unexpected response: {:?}, expecting Data", other),
-        };
-        let copy_count = cmp::min(DATA_COUNT as usize, resp_count - i);
-        resp_content.extend_from_slice(&resp_data[..copy_count]);
-    }
+/// Like `send_command_with`, in `framing` instead of the one picked with
+/// --framing
+fn send_framed<C, W, S, const N: usize>(port: &mut W, command: &S, framing: Framing) -> Result<()>
+where
+    C: CrcKind,
+    W: Write,
+    S: Serialize + MaxSize + Debug,
+{
+    let frame = framing
+        .encode_with::<C, S, N>(command)
+        .map_err(|err| anyhow!("Failed to encode command {:?}: {}", command, err))?;
 
-    println!("Received '{}'", String::from_utf8_lossy(&resp_content));
+    port.write(&frame)
+        .context("Unable to write command to serial port")?;
 
     Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use std::fmt;
+fn recv_response<R: BufRead, D: DeserializeOwned>(port: &mut R) -> Result<D> {
+    recv_response_with::<Crc8, _, _>(port)
+}
 
-    use picodox_proto::{
-        errors::ProtoError,
-        proto_impl::{self},
-        KeyUpdate, MatrixLoc, NackType, WireSize,
-    };
+fn recv_response_with<C: CrcKind, R: BufRead, D: DeserializeOwned>(port: &mut R) -> Result<D> {
+    decode_response(&recv_intact::<C, _>(port)?)
+}
 
-    use super::*;
+/// Like `send_command`, with a tag the response comes back with
+fn send_tagged<W: Write>(port: &mut W, command: &Command, tag: u8) -> Result<()> {
+    let frame = framing()
+        .encode_tagged_with::<Crc8, _, TAGGED_FRAME_SIZE>(command, tag)
+        .map_err(|err| anyhow!("Failed to encode command {:?}: {}", command, err))?;
+
+    port.write_all(&frame)
+        .context("Unable to write command to serial port")?;
 
-    const COMMAND_CASES: &[Command] = &[
-        Command::Data([0, 0, 3, 4, 5, 6, 0, 0]),
-        Command::EchoMsg { count: 7 },
-    ];
+    Ok(())
+}
 
-    const RESPONSE_CASES: &[Response] = &[
-        Response::EchoMsg { count: 128 },
-        Response::Data([1, 2, 3, 4, 5, 6, 0, 0]),
-        Response::Nack(NackType::PacketErr(ProtoError::BufferSize)),
-    ];
+/// Receive the next intact response and its tag
+fn recv_tagged<R: BufRead>(port: &mut R) -> Result<(Response, u8)> {
+    let bytes = recv_intact::<Crc8, _>(port)?;
+    let (response, rest) = postcard::take_from_bytes(&bytes).map_err(|err| {
+        anyhow!(RecvError::Corrupt).context(format!(
+            "Failed to deserialize response {:0x?} ({})",
+            bytes, err
+        ))
+    })?;
+    let tag = proto_impl::take_tag(rest)
+        .map_err(|err| anyhow!("Bad tag after response {:0x?}: {}", bytes, err))?;
 
-    fn key_cases() -> Vec<KeyUpdate> {
-        vec![
-            KeyUpdate::keys([MatrixLoc::new(1, 2), MatrixLoc::new(4, 6)]),
-            KeyUpdate::no_keys(),
-        ]
+    Ok((response, tag))
+}
+
+/// Receive the payload of the next intact frame. Damaged frames are skipped,
+/// since reading resumes after the end of the damaged frame the stream is
+/// back in sync for the next one. A damaged length prefix can't be recovered
+/// from this way, the frames after it time out or fail too.
+fn recv_intact<C: CrcKind, R: BufRead>(port: &mut R) -> Result<Vec<u8>> {
+    for _ in 0..=MAX_DAMAGED_FRAMES {
+        match read_frame_with::<C, _>(port)? {
+            Ok(bytes) => return Ok(bytes),
+            Err(damage) => println!("WARNING: skipping damaged frame, {}", damage),
+        }
     }
+    Err(anyhow!(RecvError::Corrupt).context(format!(
+        "Gave up after {} damaged frames",
+        MAX_DAMAGED_FRAMES + 1
+    )))
+}
 
-    fn ser<S: Serialize + WireSize + fmt::Debug, const N: usize>(
-        case: usize,
-        command: &S,
-    ) -> Vec<u8> {
-        match case {
-            0 => {
-                let mut buffer = Vec::new();
-                send_command(&mut buffer, &command).context("Send").unwrap();
-                buffer
+/// Receive the next response, failing on a damaged frame instead of
+/// skipping it. Used where every response has to be accounted for.
+fn recv_exact<R: BufRead, D: DeserializeOwned>(port: &mut R) -> Result<D> {
+    let bytes = read_frame_with::<Crc8, _>(port)?.map_err(|damage| {
+        anyhow!(RecvError::Corrupt).context(format!("Damaged frame, {}", damage))
+    })?;
+    decode_response(&bytes)
+}
+
+/// Read one frame and check its CRC. The outer error is a failed read, the
+/// inner one describes a frame that was damaged in transit. Reads that time
+/// out or find the port closed fail with the `RecvError` for it.
+fn read_frame_with<C: CrcKind, R: BufRead>(port: &mut R) -> Result<Result<Vec<u8>, String>> {
+    read_frame_framed::<C, _>(port, framing())
+}
+
+/// Like `read_frame_with`, in `framing` instead of the one picked with
+/// --framing
+fn read_frame_framed<C: CrcKind, R: BufRead>(
+    port: &mut R,
+    framing: Framing,
+) -> Result<Result<Vec<u8>, String>> {
+    let mut read_buf = Vec::new();
+    // Frames longer than a usb packet arrive in pieces, and a read can time
+    // out between them. Once a frame has started, keep reading until its end
+    // shows up or FRAME_TIMEOUT has passed.
+    let mut deadline = None;
+    loop {
+        match read_until_frame(port, &mut read_buf, framing) {
+            Ok(_) => break,
+            Err(err) if err.kind() == io::ErrorKind::TimedOut && read_buf.is_empty() => {
+                return Err(RecvError::Timeout.into());
             }
-            1 => proto_impl::wire_encode::<_, N>(command).unwrap().to_vec(),
-            2 => proto_impl::cs_encode::<_, N>(command).unwrap().to_vec(),
-            _ => unimplemented!(),
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => {
+                let deadline = *deadline.get_or_insert_with(|| Instant::now() + FRAME_TIMEOUT);
+                if Instant::now() >= deadline {
+                    return Err(anyhow!(RecvError::Timeout).context(format!(
+                        "Timed out in the middle of a frame {:0x?}",
+                        read_buf
+                    )));
+                }
+            }
+            Err(err) if is_disconnect(&err) => {
+                return Err(anyhow!(RecvError::Disconnected)
+                    .context(format!("Error while reading the response body ({})", err)));
+            }
+            Err(err) => return Err(err).context("Error while reading the response body"),
         }
     }
 
-    fn des<D: DeserializeOwned + WireSize>(case: usize, mut buffer: Vec<u8>) -> D {
-        match case {
-            0 => recv_response(&mut BufReader::new(&buffer[..]))
-                .context("Recv")
-                .unwrap(),
-            1 => proto_impl::wire_decode(&mut buffer).unwrap(),
-            2 => proto_impl::cs_decode(&mut buffer).unwrap(),
-            _ => unimplemented!(),
-        }
+    if read_buf.is_empty() {
+        return Err(RecvError::Disconnected.into());
+    }
+    if framing.frame_len(&read_buf) != Some(read_buf.len()) {
+        return Err(anyhow!(RecvError::Disconnected).context(format!(
+            "Stream ended in the middle of a frame {:0x?}",
+            read_buf
+        )));
     }
 
-    fn round_trip<T, const N: usize>(ser_idx: usize, des_idx: usize, cases: &[T])
-    where
-        T: Serialize + DeserializeOwned + WireSize + fmt::Debug + PartialEq,
-    {
-        println!("=== ser: {ser_idx}, des: {des_idx} ===");
-        for case in cases {
-            println!("Case: {:?}", case);
-            let buffer: Vec<u8> = ser::<T, N>(ser_idx, &case);
-            assert!(buffer.len() > 0);
-            println!("Buffer: {:02x?}", buffer);
-            let round_trip = des(des_idx, buffer);
+    Ok(unframe_with::<C>(&read_buf, framing))
+}
 
-            assert_eq!(case, &round_trip);
-        }
+/// Like `read_until` with the end of a frame in `framing` as the delimiter,
+/// `buf` holds the frame read so far. Stops early at the end of the stream,
+/// and keeps what it read when a read fails.
+fn read_until_frame<R: BufRead>(
+    port: &mut R,
+    buf: &mut Vec<u8>,
+    framing: Framing,
+) -> io::Result<usize> {
+    if framing == Framing::Cobs {
+        return port.read_until(0u8, buf);
     }
 
-    #[test]
-    fn command_wire_cross() {
-        for ser_idx in 0..=1 {
-            for des_idx in 0..=1 {
-                round_trip::<Command, { Command::WIRE_MAX_SIZE }>(ser_idx, des_idx, COMMAND_CASES)
-            }
+    let start = buf.len();
+    loop {
+        // The prefix first, then the bytes it counts
+        let want = proto_impl::len_frame_size(buf).unwrap_or(proto_impl::LEN_PREFIX_BYTES);
+        if buf.len() >= want {
+            return Ok(buf.len() - start);
         }
+        let available = match port.fill_buf() {
+            Ok(available) => available,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        if available.is_empty() {
+            return Ok(buf.len() - start);
+        }
+        let take = available.len().min(want - buf.len());
+        buf.extend_from_slice(&available[..take]);
+        port.consume(take);
     }
+}
 
-    #[test]
-    fn command_cs() {
-        round_trip::<Command, { Command::CS_MAX_SIZE }>(2, 2, COMMAND_CASES)
-    }
+/// Whether a read failed because the port went away. Linux answers reads
+/// from a USB serial device that was unplugged with EIO.
+fn is_disconnect(err: &io::Error) -> bool {
+    const EIO: i32 = 5;
+    matches!(
+        err.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::UnexpectedEof
+    ) || (cfg!(unix) && err.raw_os_error() == Some(EIO))
+}
 
-    #[test]
+/// Check the framing and CRC of a whole frame, returning the payload or what
+/// is wrong with the frame
+fn unframe_with<C: CrcKind>(frame: &[u8], framing: Framing) -> Result<Vec<u8>, String> {
+    let mut buf = frame.to_vec();
+    framing
+        .unframe_with::<C>(&mut buf)
+        .map(<[u8]>::to_vec)
+        .map_err(|err| format!("{} {:0x?}", err, frame))
+}
+
+fn decode_response<D: DeserializeOwned>(bytes: &[u8]) -> Result<D> {
+    if let Ok(Response::Nack(NackType::UnsupportedCommand(variant))) = postcard::from_bytes(bytes) {
+        return Err(Unsupported(variant).into());
+    }
+    postcard::from_bytes(bytes).map_err(|err| {
+        anyhow!(RecvError::Corrupt).context(format!(
+            "Failed to deserialize response {:0x?} ({})",
+            bytes, err
+        ))
+    })
+}
+
+/// Why no response came, each with its own advice
+#[derive(Debug, PartialEq, Eq)]
+enum RecvError {
+    /// Nothing arrived before the port timed out
+    Timeout,
+    /// The port closed under us
+    Disconnected,
+    /// Frames arrived but were damaged or didn't decode
+    Corrupt,
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecvError::Timeout => write!(
+                f,
+                "The keyboard is not responding, check that --device is the command \
+                 interface or raise --timeout-ms"
+            ),
+            RecvError::Disconnected => {
+                write!(f, "The keyboard disconnected, it was unplugged or reset")
+            }
+            RecvError::Corrupt => write!(
+                f,
+                "Corrupt packet, the firmware may speak another protocol version \
+                 or the link is noisy"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RecvError {}
+
+/// The `RecvError` a failed receive came down to, if it was one. It is
+/// always the root cause, the details are context on top of it.
+fn recv_error(err: &anyhow::Error) -> Option<&RecvError> {
+    err.chain()
+        .find_map(|cause| cause.downcast_ref::<RecvError>())
+}
+
+/// The firmware didn't know the command with this variant index, which
+/// sending it again won't change
+#[derive(Debug)]
+struct Unsupported(u32);
+
+impl fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The firmware does not support command {}, it is probably older than the cli. \
+             Run `picodox-cli version` to compare them.",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for Unsupported {}
+
+/// Send a command that is safe to repeat and receive its response, sending
+/// it again if the response is lost or damaged
+fn transact(port: &mut Port, command: &Command) -> Result<Response> {
+    retry(port, &format!("{:?}", command), |port| {
+        send_command(port.get_mut(), command).context("Sending command")?;
+        recv_answer(port).context("Receiving response")
+    })
+}
+
+/// Receive the response to a command outside of a transfer. A transfer that
+/// was given up part-way can leave acks and echoes of its `Data` on their
+/// way, those are skipped. The firmware drops the transfer when the next
+/// command arrives and answers that.
+fn recv_answer<R: BufRead>(port: &mut R) -> Result<Response> {
+    loop {
+        match recv_response(port)? {
+            Response::Ack(AckType::AckData) | Response::Data(_) => {
+                println!("WARNING: skipping a response left over from an earlier transfer")
+            }
+            response => return Ok(response),
+        }
+    }
+}
+
+/// Send commands that are safe to repeat without waiting for each response,
+/// up to MAX_IN_FLIGHT at a time, and match the responses to them by tag.
+/// Commands whose response doesn't arrive are sent again one at a time.
+fn transact_all(port: &mut Port, commands: &[Command]) -> Result<Vec<Response>> {
+    let mut responses = Vec::with_capacity(commands.len());
+    for (window_idx, window) in commands.chunks(MAX_IN_FLIGHT).enumerate() {
+        // Tags keep counting up across windows, so a late response to an
+        // earlier window isn't taken for one of this window
+        let tags: Vec<u8> = (0..window.len())
+            .map(|idx| request_tag(window_idx * MAX_IN_FLIGHT + idx))
+            .collect();
+        for (command, &tag) in window.iter().zip(&tags) {
+            send_tagged(port.get_mut(), command, tag).context("Sending command")?;
+        }
+
+        let mut slots: Vec<Option<Response>> = window.iter().map(|_| None).collect();
+        if let Err(err) = collect_tagged(port, &tags, &mut slots) {
+            let missing = slots.iter().filter(|slot| slot.is_none()).count();
+            println!(
+                "WARNING: {} responses missing, resending those commands ({:#})",
+                missing, err
+            );
+            clear_input(port)?;
+        }
+        responses.extend(slots);
+    }
+
+    commands
+        .iter()
+        .zip(responses)
+        .map(|(command, response)| match response {
+            Some(response) => Ok(response),
+            None => transact(port, command),
+        })
+        .collect()
+}
+
+/// Tag of the request at `idx`, counting 1 to 255 and around again
+fn request_tag(idx: usize) -> u8 {
+    (idx % usize::from(u8::MAX)) as u8 + 1
+}
+
+/// Receive responses until each slot has one, a response goes to the slot
+/// with its tag. An untagged response answers the oldest slot still waiting,
+/// which is where firmware that predates tags puts it, and where a nack for
+/// a frame that didn't decode comes. Responses with a tag of no slot are
+/// left over from before and skipped.
+fn collect_tagged<R: BufRead>(
+    port: &mut R,
+    tags: &[u8],
+    slots: &mut [Option<Response>],
+) -> Result<()> {
+    while let Some(waiting) = slots.iter().position(Option::is_none) {
+        let (response, tag) = recv_tagged(port).context("Receiving response")?;
+        let slot = if tag == NO_TAG {
+            Some(waiting)
+        } else {
+            tags.iter().position(|&t| t == tag)
+        };
+        match slot {
+            Some(slot) => slots[slot] = Some(response),
+            None => println!("WARNING: skipping response with unknown tag {}", tag),
+        }
+    }
+
+    Ok(())
+}
+
+/// Run an exchange that is safe to repeat, clearing the input and starting
+/// over if it fails
+fn retry<T>(
+    port: &mut Port,
+    what: &str,
+    mut exchange: impl FnMut(&mut Port) -> Result<T>,
+) -> Result<T> {
+    let mut attempt = 1;
+    loop {
+        match exchange(port) {
+            Ok(value) => return Ok(value),
+            Err(err)
+                if attempt < REQUEST_ATTEMPTS
+                    && err.downcast_ref::<Unsupported>().is_none()
+                    && recv_error(&err) != Some(&RecvError::Disconnected) =>
+            {
+                println!("WARNING: {} failed, retrying ({:#})", what, err);
+                clear_input(port)?;
+                attempt += 1;
+            }
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("{} failed after {} attempts", what, attempt))
+            }
+        }
+    }
+}
+
+/// Drop everything received so far, including what is buffered on our side
+fn clear_input(port: &mut Port) -> Result<()> {
+    port.get_mut()
+        .clear(ClearBuffer::Input)
+        .context("Clearing serial input")?;
+    let buffered = port.buffer().len();
+    port.consume(buffered);
+
+    Ok(())
+}
+
+fn show_version(dev: &mut Device, verbose: bool) -> Result<()> {
+    let port = dev.serial()?;
+    match query_version(port)? {
+        Some(version) => println!("Firmware protocol version: {}", version),
+        None => println!("Firmware protocol version: unknown (GetVersion not supported)"),
+    }
+    println!("Cli protocol version: {}", CURRENT_VERSION);
+
+    if verbose {
+        match query_build_info(port)? {
+            Some(info) => {
+                println!("Firmware git hash: {}", info.git_hash);
+                println!("Firmware built: {}", utc_time(info.built));
+            }
+            None => println!("Firmware build: unknown (GetBuildInfo not supported)"),
+        }
+    }
+
+    Ok(())
+}
+
+/// The git hash and build time of the firmware, None if it is too old to say
+fn query_build_info(port: &mut Port) -> Result<Option<BuildInfo>> {
+    let resp = match transact(port, &Command::GetBuildInfo) {
+        Err(err) if err.downcast_ref::<Unsupported>().is_some() => return Ok(None),
+        resp => resp?,
+    };
+    match resp {
+        Response::BuildInfo(info) => Ok(Some(info)),
+        Response::Nack(_) => Ok(None),
+        other => bail!("Unexpected response: {:?}, expecting BuildInfo", other),
+    }
+}
+
+/// Seconds since the Unix epoch as a UTC date and time, e.g.
+/// `2024-02-29 13:05:09 UTC`
+fn utc_time(secs: u64) -> String {
+    const DAY: u64 = 24 * 60 * 60;
+    let (days, time) = (secs / DAY, secs % DAY);
+
+    // Days to a civil date, counted in 400 year eras starting on March 1st
+    // so the leap day comes last
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+fn list_serial() -> Result<()> {
+    let ports =
+        serialport::available_ports().context("Unable to enumerate available serial ports")?;
+    if !ports.is_empty() {
+        for port in ports {
+            println!("{}", port.port_name);
+        }
+    } else {
+        println!("No serial ports detected!");
+    }
+
+    Ok(())
+}
+
+fn send_echo(dev: &mut Device, content: &str) -> Result<()> {
+    let args = dev.args;
+    let port = dev.port(false)?;
+
+    println!("Sending '{}'", content);
+    let resp_content = retry(port, "Echo", |port| {
+        let progress = args.progress(content.len())?;
+        let resp = echo(port, content, &progress, args.timeout());
+        progress.finish_and_clear();
+        resp
+    })?;
+    println!("Received '{}'", String::from_utf8_lossy(&resp_content));
+
+    Ok(())
+}
+
+/// Send `count` pings one after another and show the round trip times
+fn ping(dev: &mut Device, count: u16) -> Result<()> {
+    let port = dev.port(false)?;
+
+    let mut rtts = Vec::new();
+    for seq in 0..count {
+        let start = Instant::now();
+        send_command(port.get_mut(), &Command::Ping { seq }).context("Sending Ping command")?;
+        // A pong that missed its timeout can still show up while waiting for
+        // the next one
+        let rtt = loop {
+            match recv_response(port) {
+                Ok(Response::Pong { seq: got }) if got == seq => break Some(start.elapsed()),
+                Ok(Response::Pong { seq: got }) => println!("Late pong {}", got),
+                Ok(Response::Nack(err)) => bail!("Received nack to ping: {}", err),
+                Ok(other) => bail!("Unexpected response: {:?}, expecting Pong", other),
+                Err(err) => {
+                    println!("Ping {} lost: {:#}", seq, err);
+                    break None;
+                }
+            }
+        };
+        if let Some(rtt) = rtt {
+            println!("Pong {}: {:.2} ms", seq, rtt.as_secs_f64() * 1000.0);
+        }
+        rtts.push(rtt);
+    }
+    println!("{}", ping_summary(&rtts));
+
+    Ok(())
+}
+
+/// Loss and min/avg/max round trip time of a ping run, None for pings that
+/// were never answered
+fn ping_summary(rtts: &[Option<Duration>]) -> String {
+    let received: Vec<f64> = rtts
+        .iter()
+        .flatten()
+        .map(|rtt| rtt.as_secs_f64() * 1000.0)
+        .collect();
+    let lost = rtts.len() - received.len();
+    let mut summary = format!(
+        "{} sent, {} received, {:.0}% loss",
+        rtts.len(),
+        received.len(),
+        100.0 * lost as f64 / cmp::max(rtts.len(), 1) as f64
+    );
+    if !received.is_empty() {
+        let min = received.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = received.iter().copied().fold(0.0, f64::max);
+        let avg = received.iter().sum::<f64>() / received.len() as f64;
+        summary += &format!(", rtt min/avg/max {:.2}/{:.2}/{:.2} ms", min, avg, max);
+    }
+
+    summary
+}
+
+fn echo(
+    port: &mut Port,
+    content: &str,
+    progress: &ProgressBar,
+    timeout: Duration,
+) -> Result<Vec<u8>> {
+    let command = Command::EchoMsg {
+        count: content.len().try_into().context("Message is too long")?,
+    };
+    send_command(&mut port.get_mut(), &command).context("Sending EchoMsg command")?;
+
+    let chunks = echo_chunks(content.as_bytes());
+    for (idx, chunk) in chunks.iter().enumerate() {
+        send_command(&mut port.get_mut(), &Command::Data(chunk.clone()))
+            .with_context(|| format!("Sending data command {}", idx))?;
+        progress.inc(chunk.len() as u64);
+    }
+
+    // Each packet of the reply gets the port timeout, on top of that a
+    // keyboard that keeps sending can't hold us past the deadline
+    let deadline = Instant::now() + timeout * (chunks.len() as u32 + 1);
+    recv_echo(port, content.len(), deadline)
+}
+
+/// The `Data` chunks an echo is sent as, none for an empty message
+fn echo_chunks(content: &[u8]) -> Vec<DataChunk> {
+    content
+        .chunks(DATA_COUNT)
+        .map(|chunk| DataChunk::from_slice(chunk).unwrap())
+        .collect()
+}
+
+/// Receive the reply to an echo of `sent` bytes
+fn recv_echo<R: BufRead>(port: &mut R, sent: usize, deadline: Instant) -> Result<Vec<u8>> {
+    let resp: Response = recv_response(port).context("Receiving EchoMsg response")?;
+    match resp {
+        Response::EchoMsg { count } if count as usize == sent => (),
+        Response::EchoMsg { count } => {
+            bail!("Keyboard is echoing {} bytes, {} were sent", count, sent)
+        }
+        Response::Nack(err) => bail!("Received nack waiting for EchoMsg: {}", err),
+        other => bail!("Unexpected response: {:?}, expecting EchoMsg", other),
+    }
+
+    let mut resp_content = Vec::with_capacity(sent);
+    while resp_content.len() < sent {
+        if Instant::now() >= deadline {
+            bail!(
+                "Timed out with {} of {} echoed bytes received",
+                resp_content.len(),
+                sent
+            );
+        }
+        let resp: Response = recv_response(port)?;
+        let resp_data = match resp {
+            Response::Data(data) if resp_content.len() + data.len() > sent => {
+                bail!("Keyboard echoed more than the {} bytes sent", sent)
+            }
+            Response::Data(data) => data,
+            Response::Nack(err) => bail!("Received nack waiting for Data: {}", err),
+            other => bail!("Unexpected response: {:?}, expecting Data", other),
+        };
+        resp_content.extend_from_slice(&resp_data);
+    }
+
+    Ok(resp_content)
+}
+
+fn set_macro(dev: &mut Device, slot: u8, text: &str) -> Result<()> {
+    let data = macros::encode_text(text)?;
+    let port = dev.port(true)?;
+
+    let resp = transact(port, &Command::SetMacro { slot, data })?;
+    match resp {
+        Response::Ack(AckType::AckMacro) => Ok(()),
+        Response::Nack(err) => bail!("Received nack storing macro {}: {}", slot, err),
+        other => bail!("Unexpected response: {:?}, expecting AckMacro", other),
+    }
+}
+
+fn list_macros(dev: &mut Device) -> Result<()> {
+    let port = dev.port(false)?;
+
+    let commands: Vec<Command> = (0..MACRO_SLOTS as u8)
+        .map(|slot| Command::GetMacro { slot })
+        .collect();
+    for (slot, resp) in (0u8..).zip(transact_all(port, &commands)?) {
+        match resp {
+            Response::Macro { slot, data } if data.is_empty() => println!("{}: (empty)", slot),
+            Response::Macro { slot, data } => println!("{}: {}", slot, macros::describe(&data)),
+            Response::Nack(err) => bail!("Received nack reading macro {}: {}", slot, err),
+            other => bail!("Unexpected response: {:?}, expecting Macro", other),
+        }
+    }
+
+    Ok(())
+}
+
+fn get_config(port: &mut Port) -> Result<Config> {
+    let resp = transact(port, &Command::GetConfig)?;
+    match resp {
+        Response::Config(config) => Ok(config),
+        Response::Nack(err) => bail!("Received nack reading config: {}", err),
+        other => bail!("Unexpected response: {:?}, expecting Config", other),
+    }
+}
+
+fn show_config(dev: &mut Device) -> Result<()> {
+    let config = get_config(dev.port(false)?)?;
+    for (key, value) in config::entries(&config) {
+        println!("{} = {}", key, value);
+    }
+
+    Ok(())
+}
+
+fn set_config(dev: &mut Device, values: &[String]) -> Result<()> {
+    let port = dev.port(true)?;
+    let mut config = get_config(port)?;
+    for value in values {
+        config::apply(&mut config, value)?;
+    }
+
+    let resp = transact(port, &Command::SetConfig(config))?;
+    match resp {
+        Response::Ack(AckType::AckConfig) => Ok(()),
+        Response::Nack(NackType::OutOfRange) => bail!(
+            "Keyboard refused the config, it has no layer {}",
+            config.default_layer
+        ),
+        Response::Nack(err) => bail!("Received nack storing config: {}", err),
+        other => bail!("Unexpected response: {:?}, expecting AckConfig", other),
+    }
+}
+
+fn upload_keymap(dev: &mut Device, path: &str) -> Result<()> {
+    let text = fs::read_to_string(path).with_context(|| format!("Reading {}", path))?;
+    let layout = keymap::parse(&text).with_context(|| format!("Parsing {}", path))?;
+    let bytes = postcard::to_stdvec(&layout).context("Encoding keymap")?;
+    let count = bytes.len() as u32;
+
+    let timeout = dev.args.timeout();
+    let port = dev.port(true)?;
+
+    send_command(port.get_mut(), &Command::UploadKeymap { count })
+        .context("Sending UploadKeymap command")?;
+    let resp: Response = recv_response(port).context("Receiving UploadKeymap response")?;
+    match resp {
+        Response::Ack(AckType::AckKeymap) => (),
+        Response::Nack(err) => bail!("Received nack starting keymap upload: {}", err),
+        other => bail!("Unexpected response: {:?}, expecting AckKeymap", other),
+    }
+
+    for (idx, chunk) in bytes.chunks(DATA_COUNT).enumerate() {
+        let data = Command::Data(DataChunk::from_slice(chunk).unwrap());
+        let mut attempt = 1;
+        loop {
+            send_command_with::<FlashCrc, _, _, FLASH_FRAME_SIZE>(port.get_mut(), &data)
+                .with_context(|| format!("Sending keymap chunk {}", idx))?;
+            // Like firmware chunks, a nack has to be seen to resend
+            let resp: Response = recv_exact(port)
+                .with_context(|| format!("Receiving ack for keymap chunk {}", idx))?;
+            match resp {
+                Response::Ack(AckType::AckData) => break,
+                Response::Nack(err) if attempt < REQUEST_ATTEMPTS => {
+                    println!("WARNING: resending keymap chunk {} ({})", idx, err);
+                    resync_flash(port, 0)?;
+                    attempt += 1;
+                }
+                Response::Nack(err) => bail!(
+                    "Keymap chunk {} was nacked {} times, last reason: {}",
+                    idx,
+                    attempt,
+                    err
+                ),
+                other => bail!("Unexpected response: {:?}, expecting AckData", other),
+            }
+        }
+    }
+
+    // The keymap is written to flash before the final ack
+    port.get_mut()
+        .set_timeout(cmp::max(FLASH_FINISH_TIMEOUT, timeout))
+        .context("Setting serial timeout")?;
+    let resp: Response = recv_response(port).context("Receiving final UploadKeymap response")?;
+    match resp {
+        Response::Ack(AckType::AckKeymap) => (),
+        Response::Nack(NackType::OutOfRange) => bail!(
+            "Keyboard refused the keymap, it doesn't match the keyboard's layers or macro slots"
+        ),
+        Response::Nack(err) => bail!("Received nack storing keymap: {}", err),
+        other => bail!("Unexpected response: {:?}, expecting AckKeymap", other),
+    }
+
+    println!("Uploaded keymap from {}", path);
+
+    Ok(())
+}
+
+fn show_matrix(dev: &mut Device) -> Result<()> {
+    let resp = transact(dev.port(false)?, &Command::GetMatrix)?;
+    match resp {
+        Response::Matrix(state) => print!("{}", matrix_grid(&state)),
+        Response::Nack(err) => bail!("Received nack reading the matrix: {}", err),
+        other => bail!("Unexpected response: {:?}, expecting Matrix", other),
+    }
+
+    Ok(())
+}
+
+fn self_test(dev: &mut Device) -> Result<()> {
+    let timeout = dev.args.timeout();
+    let port = dev.port(false)?;
+    port.get_mut()
+        .set_timeout(cmp::max(SELF_TEST_TIMEOUT, timeout))
+        .context("Setting serial timeout")?;
+
+    let results = match transact(port, &Command::SelfTest)? {
+        Response::SelfTest(results) => results,
+        Response::Nack(err) => bail!("Received nack running the self test: {}", err),
+        other => bail!("Unexpected response: {:?}, expecting SelfTest", other),
+    };
+    print!("{}", self_test_report(&results));
+
+    let failed = SelfTestCheck::ALL
+        .iter()
+        .filter(|&&check| !results.passed(check))
+        .count();
+    if failed > 0 {
+        bail!("{} of {} checks failed", failed, SelfTestCheck::ALL.len());
+    }
+
+    Ok(())
+}
+
+/// One line per check of a self test, with what it covers
+fn self_test_report(results: &SelfTestResults) -> String {
+    SelfTestCheck::ALL
+        .iter()
+        .map(|&check| {
+            let name = match check {
+                SelfTestCheck::Flash => "flash",
+                SelfTestCheck::Peer => "i2c peer",
+                SelfTestCheck::Usb => "usb",
+            };
+            let result = if results.passed(check) {
+                "pass"
+            } else {
+                "FAIL"
+            };
+            format!("{:<10}{}\n", name, result)
+        })
+        .collect()
+}
+
+fn show_device_id(dev: &mut Device) -> Result<()> {
+    let id = match transact(dev.port(false)?, &Command::GetDeviceId)? {
+        Response::DeviceId(id) => id,
+        Response::Nack(err) => bail!("Received nack reading the device ID: {}", err),
+        other => bail!("Unexpected response: {:?}, expecting DeviceId", other),
+    };
+    print!("{}", device_id_report(&id));
+
+    Ok(())
+}
+
+/// The IDs in hex, the unique ID as the keyboard uses it for its USB serial
+/// number
+fn device_id_report(id: &DeviceId) -> String {
+    let unique: String = id.unique.iter().map(|b| format!("{:02X}", b)).collect();
+    let jedec: Vec<String> = id.jedec.iter().map(|b| format!("{:02X}", b)).collect();
+    format!(
+        "Flash unique ID: {}\nFlash JEDEC ID:  {}\n",
+        unique,
+        jedec.join(" ")
+    )
+}
+
+/// The halves side by side by row and column, `#` for pressed keys
+fn matrix_grid(state: &KeyState) -> String {
+    key_grid(|idx| if state.is_pressed(idx) { '#' } else { '.' })
+}
+
+/// Like `matrix_grid`, with `+` for keys that were pressed before
+fn test_grid(state: &KeyState, seen: &KeyState) -> String {
+    key_grid(|idx| {
+        if state.is_pressed(idx) {
+            '#'
+        } else if seen.is_pressed(idx) {
+            '+'
+        } else {
+            '.'
+        }
+    })
+}
+
+/// The halves side by side by row and column, with the character `key`
+/// gives for each `KeyState` index
+fn key_grid(key: impl Fn(usize) -> char) -> String {
+    let half = |row: usize, offset: usize| -> String {
+        (0..NUM_COLS)
+            .map(|col| key(offset + MatrixLoc::new(row, col).index()))
+            .collect()
+    };
+
+    let mut grid = format!("{:<w$}   right\n", "left", w = NUM_COLS);
+    for row in 0..NUM_ROWS {
+        grid += &format!("{}   {}\n", half(row, 0), half(row, NUM_KEYS));
+    }
+    grid
+}
+
+/// Show the matrix live until enter is pressed, then list the keys that
+/// never registered
+fn test_matrix(dev: &mut Device) -> Result<()> {
+    let port = dev.port(false)?;
+    let resp = transact(port, &Command::EnterTestMode)?;
+    match resp {
+        Response::Ack(AckType::AckTestMode) => (),
+        Response::Nack(err) => bail!("Received nack entering test mode: {}", err),
+        other => bail!("Unexpected response: {:?}, expecting AckTestMode", other),
+    }
+
+    // Read on its own thread so the grid keeps updating in the meantime
+    let (stop_tx, stop) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = io::stdin().read_line(&mut String::new());
+        let _ = stop_tx.send(());
+    });
+
+    println!("Press every key, then enter to stop ('+' registered, '#' held)");
+    let mut state = KeyState::no_keys();
+    let mut seen = KeyState::no_keys();
+    let mut grid = test_grid(&state, &seen);
+    print!("{}", grid);
+    while stop.try_recv().is_err() {
+        let resp = match recv_response(port) {
+            Ok(resp) => resp,
+            Err(err) if is_timeout(&err) => continue,
+            Err(err) => return Err(err).context("Receiving matrix"),
+        };
+        match resp {
+            Response::Matrix(new) => {
+                for (idx, pressed) in new.iter().enumerate() {
+                    if pressed {
+                        seen.press(idx);
+                    }
+                }
+                state = new;
+            }
+            other => bail!("Unexpected response: {:?}, expecting Matrix", other),
+        }
+
+        // Draw over the last grid
+        let next = test_grid(&state, &seen);
+        print!("\x1b[{}A{}", grid.lines().count(), next);
+        grid = next;
+        io::stdout().flush().context("Flushing stdout")?;
+    }
+
+    // Changes may still be streamed until the firmware sees the exit
+    send_command(port.get_mut(), &Command::ExitTestMode).context("Sending ExitTestMode")?;
+    loop {
+        let resp: Response = recv_response(port).context("Receiving ExitTestMode response")?;
+        match resp {
+            Response::Matrix(_) => continue,
+            Response::Ack(AckType::AckTestMode) => break,
+            Response::Nack(err) => bail!("Received nack leaving test mode: {}", err),
+            other => bail!("Unexpected response: {:?}, expecting AckTestMode", other),
+        }
+    }
+
+    let missing = unregistered(&seen);
+    if missing.is_empty() {
+        println!("Every key registered");
+    } else {
+        println!("Keys that never registered: {}", missing.join(", "));
+    }
+
+    Ok(())
+}
+
+/// Keys that aren't in `seen`, as `<half> <row>,<col>`
+fn unregistered(seen: &KeyState) -> Vec<String> {
+    let mut missing = Vec::new();
+    for (half, offset) in [("left", 0), ("right", NUM_KEYS)] {
+        for row in 0..NUM_ROWS {
+            for col in 0..NUM_COLS {
+                if !seen.is_pressed(offset + MatrixLoc::new(row, col).index()) {
+                    missing.push(format!("{} {},{}", half, row, col));
+                }
+            }
+        }
+    }
+    missing
+}
+
+/// Whether reading failed because nothing arrived within the port timeout
+fn is_timeout(err: &anyhow::Error) -> bool {
+    recv_error(err) == Some(&RecvError::Timeout)
+}
+
+fn test_column(dev: &mut Device, only_col: Option<u8>) -> Result<()> {
+    let port = dev.port(false)?;
+    let cols = match only_col {
+        Some(col) => col..=col,
+        None => 0..=(NUM_COLS as u8 - 1),
+    };
+
+    let stdin = std::io::stdin();
+    for col in cols {
+        println!("Short a row to column {} and press enter (q to quit)", col);
+        let mut line = String::new();
+        stdin.read_line(&mut line).context("Reading from stdin")?;
+        if line.trim() == "q" {
+            break;
+        }
+
+        let resp = transact(port, &Command::TestColumn { col })?;
+        let rows = match resp {
+            Response::ColumnTest { rows, .. } => rows,
+            Response::Nack(err) => bail!("Received nack testing column {}: {}", col, err),
+            other => bail!("Unexpected response: {:?}, expecting ColumnTest", other),
+        };
+
+        let high: Vec<_> = (0..NUM_ROWS).filter(|row| rows & (1 << row) != 0).collect();
+        if high.is_empty() {
+            println!("Column {}: no rows high", col);
+        } else {
+            println!("Column {}: rows {:?} high", col, high);
+        }
+    }
+
+    Ok(())
+}
+
+/// Stream a firmware image to the keyboard. Up to `window` chunks are in
+/// flight before waiting for an ack, and a nacked chunk is resent up to
+/// `retries` times. With `dry_run` the image is only checked and described,
+/// the keyboard isn't touched.
+fn flash_fw(
+    dev: &mut Device,
+    path: &str,
+    window: usize,
+    retries: u32,
+    dry_run: bool,
+) -> Result<()> {
+    if !(1..=MAX_FLASH_WINDOW).contains(&window) {
+        bail!("Window must be between 1 and {}", MAX_FLASH_WINDOW);
+    }
+
+    let image = read_fw_image(path)?;
+    let count: u32 = image
+        .data
+        .len()
+        .try_into()
+        .context("Firmware image is too large")?;
+    let crc = FW_CRC.checksum(&image.data);
+    if dry_run {
+        println!("{}", describe_flash(&image, crc));
+        return Ok(());
+    }
+    let image = image.data;
+
+    let timeout = dev.args.timeout();
+    let progress = dev.args.progress(image.len())?;
+    let port = dev.port(true)?;
+
+    let chunk_size = match query_flash_info(port).context("Querying flash info")? {
+        Some((max, _)) if count > max => bail!(
+            "Firmware image is {} bytes, the update partition only fits {}",
+            count,
+            max
+        ),
+        Some((_, 0)) => bail!("Keyboard reports a chunk size of 0"),
+        Some((_, data_count)) => cmp::min(data_count, DATA_COUNT),
+        None => {
+            println!("WARNING: firmware does not report the update partition size");
+            DATA_COUNT
+        }
+    };
+    let chunks: Vec<DataChunk> = image
+        .chunks(chunk_size)
+        .map(|chunk| DataChunk::from_slice(chunk).unwrap())
+        .collect();
+
+    send_command(port.get_mut(), &Command::FlashFw { count, crc })
+        .context("Sending FlashFw command")?;
+    let resp: Response = recv_response(port).context("Receiving FlashFw response")?;
+    match resp {
+        Response::Ack(AckType::AckFlashFw) => (),
+        Response::Nack(NackType::OutOfRange) => bail!(
+            "Keyboard refused the firmware image, {} bytes does not fit in the update partition",
+            count
+        ),
+        Response::Nack(err) => bail!("Received nack starting flash: {}", err),
+        other => bail!("Unexpected response: {:?}, expecting AckFlashFw", other),
+    }
+
+    let mut sent = 0;
+    let mut acked = 0;
+    let mut attempts = 0;
+    while acked < chunks.len() {
+        while sent < chunks.len() && sent - acked < window {
+            send_command_with::<FlashCrc, _, _, FLASH_FRAME_SIZE>(
+                port.get_mut(),
+                &Command::Data(chunks[sent].clone()),
+            )
+            .with_context(|| format!("Sending firmware chunk {}", sent))?;
+            sent += 1;
+        }
+
+        // A damaged ack can't be skipped, it may have been a nack and
+        // chunks can't be written twice
+        let resp: Response = recv_exact(port)
+            .with_context(|| format!("Receiving ack for firmware chunk {}", acked))?;
+        match resp {
+            Response::Ack(AckType::AckData) => {
+                acked += 1;
+                progress.set_position(cmp::min(acked * chunk_size, image.len()) as u64);
+                attempts = 0;
+            }
+            Response::Nack(err) => {
+                attempts += 1;
+                if attempts > retries {
+                    bail!(
+                        "Firmware chunk {} was nacked {} times, last reason: {}",
+                        acked,
+                        attempts,
+                        err
+                    );
+                }
+                progress.println(format!("Resending firmware chunk {} ({})", acked, err));
+                resync_flash(port, sent - acked - 1)?;
+                sent = acked;
+            }
+            other => bail!("Unexpected response: {:?}, expecting AckData", other),
+        }
+    }
+
+    progress.finish();
+
+    // The last block is still being written when the firmware gets here
+    port.get_mut()
+        .set_timeout(cmp::max(FLASH_FINISH_TIMEOUT, timeout))
+        .context("Setting serial timeout")?;
+    let resp: Response = recv_response(port).context("Receiving final FlashFw response")?;
+    match resp {
+        Response::Ack(AckType::AckFlashFw) => (),
+        Response::Nack(NackType::ImageCrcMismatch { calculated, .. }) => bail!(
+            "Keyboard received firmware with CRC 0x{:08x} instead of 0x{:08x}, \
+             the update was dropped and the current firmware kept",
+            calculated,
+            crc
+        ),
+        Response::Nack(err) => bail!("Received nack finishing flash: {}", err),
+        other => bail!("Unexpected response: {:?}, expecting AckFlashFw", other),
+    }
+
+    println!("Flashed {} bytes of firmware", count);
+
+    Ok(())
+}
+
+/// What flashing `image` would send. The keyboard may ask for smaller
+/// chunks, so the chunk count is the fewest it can take.
+fn describe_flash(image: &FwImage, crc: u32) -> String {
+    let len = image.data.len() as u32;
+    format!(
+        "Would send {} bytes in {} chunks of up to {} bytes\n\
+         Image 0x{:08x}..0x{:08x}, written to the DFU partition at 0x{:x}..0x{:x}\n\
+         Image CRC 0x{:08x}, nothing was sent",
+        len,
+        image.data.len().div_ceil(DATA_COUNT),
+        DATA_COUNT,
+        image.address,
+        image.address + len,
+        DFU_OFFSET,
+        DFU_OFFSET + len,
+        crc
+    )
+}
+
+/// Read the bytes an ELF places in flash
+fn read_fw_image(path: &str) -> Result<FwImage> {
+    let file_contents =
+        fs::read(path).with_context(|| format!("Unable to open file '{}'", path))?;
+    let image = FwImage::from_elf(&file_contents)
+        .with_context(|| format!("Unable to read firmware from '{}'", path))?;
+
+    for (start, end) in &image.gaps {
+        println!(
+            "WARNING: segments are not contiguous, padding 0x{:08x}..0x{:08x}",
+            start, end
+        );
+    }
+    println!(
+        "Firmware image: 0x{:08x} ({} bytes)",
+        image.address,
+        image.data.len()
+    );
+
+    Ok(image)
+}
+
+/// Compare the CRC of the firmware image against what the keyboard reads
+/// back from flash
+fn verify_fw(dev: &mut Device, path: &str, offset: u32) -> Result<()> {
+    let image = read_fw_image(path)?.data;
+    let len: u32 = image
+        .len()
+        .try_into()
+        .context("Firmware image is too large")?;
+    let expected = FW_CRC.checksum(&image);
+
+    let timeout = dev.args.timeout();
+    let port = dev.port(false)?;
+    port.get_mut()
+        .set_timeout(cmp::max(VERIFY_TIMEOUT, timeout))
+        .context("Setting serial timeout")?;
+
+    let resp = transact(port, &Command::VerifyFw { offset, len })?;
+    let actual = match resp {
+        Response::FwCrc(crc) => crc,
+        Response::Nack(err) => bail!("Received nack verifying firmware: {}", err),
+        other => bail!("Unexpected response: {:?}, expecting FwCrc", other),
+    };
+
+    if actual != expected {
+        bail!(
+            "Firmware mismatch at 0x{:x} ({} bytes): flash crc 0x{:08x}, file crc 0x{:08x}",
+            offset,
+            len,
+            actual,
+            expected
+        );
+    }
+    println!("Firmware at 0x{:x} matches ({} bytes)", offset, len);
+
+    Ok(())
+}
+
+/// Erase the DFU partition, asking first unless `force` is set
+fn erase_fw(dev: &mut Device, force: bool) -> Result<()> {
+    let timeout = dev.args.timeout();
+    let port = dev.port(true)?;
+    let question = "Erase the DFU partition? Firmware flashed but not booted yet is lost";
+    if !force && !confirm(question)? {
+        println!("Nothing was erased");
+        return Ok(());
+    }
+
+    port.get_mut()
+        .set_timeout(cmp::max(ERASE_TIMEOUT, timeout))
+        .context("Setting serial timeout")?;
+    println!("Erasing, this takes around half a minute");
+    let resp = transact(port, &Command::EraseFw)?;
+    match resp {
+        Response::Ack(AckType::AckErase) => (),
+        Response::Nack(err) => bail!("Received nack erasing firmware: {}", err),
+        other => bail!("Unexpected response: {:?}, expecting AckErase", other),
+    }
+    println!("DFU partition erased");
+
+    Ok(())
+}
+
+/// Ask a yes or no question on stdin
+fn confirm(question: &str) -> Result<bool> {
+    print!("{} [y/N] ", question);
+    io::stdout().flush().context("Flushing stdout")?;
+    let mut answer = String::new();
+    io::stdin()
+        .read_line(&mut answer)
+        .context("Reading from stdin")?;
+    Ok(is_yes(&answer))
+}
+
+/// Anything but a yes is a no
+fn is_yes(answer: &str) -> bool {
+    matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes")
+}
+
+fn dump_flash(dev: &mut Device, offset: u32, len: u32, output: &str) -> Result<()> {
+    if offset.checked_add(len).is_none() {
+        bail!(
+            "Region of {} bytes at 0x{:x} is past the end of flash",
+            len,
+            offset
+        );
+    }
+
+    let progress = dev.args.progress(len as usize)?;
+    let port = dev.port(false)?;
+    let mut buf = Vec::with_capacity(len as usize);
+    while (buf.len() as u32) < len {
+        let pos = offset + buf.len() as u32;
+        let count = cmp::min(READ_FLASH_BLOCK, len - buf.len() as u32);
+        let command = Command::ReadFlash {
+            offset: pos,
+            len: count as u16,
+        };
+        send_command(port.get_mut(), &command).context("Sending ReadFlash command")?;
+        let block = recv_data_stream(port, count as usize, &progress)
+            .with_context(|| format!("Reading {} bytes of flash at 0x{:x}", count, pos))?;
+        if block.len() != count as usize {
+            bail!(
+                "Keyboard sent {} bytes of flash at 0x{:x}, {} were asked for",
+                block.len(),
+                pos,
+                count
+            );
+        }
+        buf.extend_from_slice(&block);
+    }
+    progress.finish_and_clear();
+
+    fs::write(output, &buf).with_context(|| format!("Unable to write dump file '{output}'"))?;
+    println!(
+        "Wrote {} bytes of flash at 0x{:x} to {}",
+        len, offset, output
+    );
+
+    Ok(())
+}
+
+/// Receive a `DataStream` of at most `max` bytes and put it back together
+fn recv_data_stream<R: BufRead>(
+    port: &mut R,
+    max: usize,
+    progress: &ProgressBar,
+) -> Result<Vec<u8>> {
+    let resp: Response = recv_response(port).context("Receiving DataStream response")?;
+    let total = match resp {
+        Response::DataStream { total } if total as usize <= max => total as usize,
+        Response::DataStream { total } => {
+            bail!(
+                "Keyboard is sending {} bytes, expected at most {}",
+                total,
+                max
+            )
+        }
+        Response::Nack(err) => bail!("Received nack waiting for DataStream: {}", err),
+        other => bail!("Unexpected response: {:?}, expecting DataStream", other),
+    };
+
+    let mut buf = Vec::with_capacity(total);
+    loop {
+        let resp: Response = recv_response(port)
+            .with_context(|| format!("Receiving data after {} of {} bytes", buf.len(), total))?;
+        match resp {
+            Response::Data(data) if buf.len() + data.len() > total => {
+                bail!("Keyboard sent more than the {} bytes it announced", total)
+            }
+            Response::Data(data) => {
+                buf.extend_from_slice(&data);
+                progress.inc(data.len() as u64);
+            }
+            Response::DataEnd if buf.len() == total => return Ok(buf),
+            Response::DataEnd => {
+                bail!("Transfer ended after {} of {} bytes", buf.len(), total)
+            }
+            Response::Nack(err) => bail!("Received nack waiting for Data: {}", err),
+            other => bail!("Unexpected response: {:?}, expecting Data", other),
+        }
+    }
+}
+
+/// After a nack the firmware nacks the rest of the window and waits for the
+/// link to go quiet before accepting the retransmission
+fn resync_flash(port: &mut Port, in_flight: usize) -> Result<()> {
+    for _ in 0..in_flight {
+        // Chunks mangled by the same error may not get a response at all
+        if recv_exact::<_, Response>(port).is_err() {
+            break;
+        }
+    }
+
+    thread::sleep(Duration::from_millis(2 * FLASH_RESYNC_MS));
+    clear_input(port)
+}
+
+fn set_led(dev: &mut Device, command: Command) -> Result<()> {
+    let port = dev.port(false)?;
+
+    let resp = transact(port, &command)?;
+    match resp {
+        Response::Ack(AckType::AckLed) => Ok(()),
+        Response::Nack(err) => bail!("Received nack setting LED: {}", err),
+        other => bail!("Unexpected response: {:?}, expecting AckLed", other),
+    }
+}
+
+fn set_log_level(dev: &mut Device, level: LogLevel) -> Result<()> {
+    let port = dev.port(false)?;
+
+    let resp = transact(port, &Command::SetLogLevel(level))?;
+    match resp {
+        Response::Ack(AckType::AckLogLevel) => Ok(()),
+        Response::Nack(err) => bail!("Received nack setting log level: {}", err),
+        other => bail!("Unexpected response: {:?}, expecting AckLogLevel", other),
+    }
+}
+
+fn show_panic(dev: &mut Device) -> Result<()> {
+    let port = dev.port(false)?;
+
+    let mut message = Vec::new();
+    loop {
+        let offset = u16::try_from(message.len()).context("Panic message is too long")?;
+        send_command(port.get_mut(), &Command::GetPanic { offset })
+            .context("Sending GetPanic command")?;
+        let resp: Response = recv_response(port).context("Receiving Panic response")?;
+        let Response::Panic(chunk) = resp else {
+            bail!("Unexpected response to GetPanic: {:?}", resp);
+        };
+        message.extend_from_slice(&chunk);
+        if chunk.len() < PANIC_CHUNK {
+            break;
+        }
+    }
+
+    if message.is_empty() {
+        println!("No panic recorded since the last report");
+    } else {
+        print!("{}", String::from_utf8_lossy(&message));
+    }
+
+    Ok(())
+}
+
+fn save_trace(dev: &mut Device, output: &str) -> Result<()> {
+    let port = dev.port(false)?;
+    send_command(port.get_mut(), &Command::ReadTrace).context("Sending ReadTrace command")?;
+    let buf = recv_data_stream(port, MAX_TRACE, &ProgressBar::hidden())
+        .context("Reading the trace buffer")?;
+
+    let (events, skipped) = trace::parse_trace(&buf);
+    fs::write(output, trace::to_chrome_json(&events))
+        .with_context(|| format!("Unable to write trace file '{output}'"))?;
+    println!(
+        "Wrote {} trace events to {} ({} partial records skipped)",
+        events.len(),
+        output,
+        skipped
+    );
+
+    Ok(())
+}
+
+/// Send arbitrary bytes and dump every frame that comes back until the
+/// keyboard goes quiet, without deserializing anything
+fn send_raw(dev: &mut Device, hex: &str, no_frame: bool) -> Result<()> {
+    const MAX_RAW_FRAME: usize = 1024;
+
+    let bytes = parse_hex(hex)?;
+    let bytes = if no_frame {
+        bytes
+    } else {
+        framing()
+            .frame_with::<Crc8, MAX_RAW_FRAME>(&bytes)
+            .map_err(|err| anyhow!("Unable to frame {} bytes: {}", bytes.len(), err))?
+            .to_vec()
+    };
+
+    let port = dev.serial()?;
+    port.get_mut()
+        .write_all(&bytes)
+        .context("Unable to write to serial port")?;
+    println!("Sent {} bytes: {:02x?}", bytes.len(), bytes);
+
+    loop {
+        let mut frame = Vec::new();
+        let res = read_until_frame(port, &mut frame, framing());
+        if framing().frame_len(&frame) != Some(frame.len()) {
+            if !frame.is_empty() {
+                println!(
+                    "Received {} bytes that don't make a whole frame: {:02x?}",
+                    frame.len(),
+                    frame
+                );
+            }
+            match res {
+                Err(err) if err.kind() != io::ErrorKind::TimedOut => {
+                    return Err(err).context("Error while reading from serial port")
+                }
+                _ => return Ok(()),
+            }
+        }
+
+        println!("Received {} bytes: {:02x?}", frame.len(), frame);
+        match unframe_with::<Crc8>(&frame, framing()) {
+            Ok(payload) => println!("  payload: {:02x?}", payload),
+            Err(damage) => println!("  damaged: {}", damage),
+        }
+    }
+}
+
+/// Parse hex bytes, ignoring whitespace
+fn parse_hex(hex: &str) -> Result<Vec<u8>> {
+    let digits: String = hex.chars().filter(|c| !c.is_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("'{}' is not a list of hex bytes", hex);
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(&digits[idx..idx + 2], 16).context("Invalid hex byte"))
+        .collect()
+}
+
+/// Parse a decimal number, or a hex one with a 0x prefix
+fn parse_number(arg: &str) -> Result<u32> {
+    match arg.strip_prefix("0x").or_else(|| arg.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(&hex.replace('_', ""), 16)
+            .with_context(|| format!("'{}' is not a hex number", arg)),
+        None => arg
+            .parse()
+            .with_context(|| format!("'{}' is not a number", arg)),
+    }
+}
+
+/// Stream the defmt frames from the logging interface through defmt-print,
+/// prefixing each decoded line with the time since the cli started
+fn follow_logs(dev: &PortArgs, elf: Option<&str>, log_port: &str) -> Result<()> {
+    let serial = open_logs(dev, elf, log_port)?;
+    match elf {
+        Some(elf) => follow_defmt(serial, elf, log_port),
+        None => follow_plain(serial, log_port),
+    }
+}
+
+/// Check the elf file and open the logging interface, so problems with
+/// either show up before any logs are followed
+fn open_logs(dev: &PortArgs, elf: Option<&str>, log_port: &str) -> Result<Box<dyn SerialPort>> {
+    if let Some(elf) = elf {
+        fs::metadata(elf).with_context(|| format!("Unable to read elf file '{elf}'"))?;
+    }
+    serialport::new(log_port, dev.baud)
+        .timeout(dev.timeout())
+        .open()
+        .with_context(|| format!("Failed to open serial port '{log_port}'"))
+}
+
+/// With `--with-logs`, follow the logging interface of the keyboard on
+/// `--device` in the background. Its lines are printed in between the
+/// output of the command.
+fn spawn_logs(dev: &PortArgs) -> Result<()> {
+    if !dev.with_logs {
+        return Ok(());
+    }
+    let log_port = ports::logging_port(&dev.device).context("Finding the logging interface")?;
+    let serial = open_logs(dev, dev.log_elf.as_deref(), &log_port)?;
+    let elf = dev.log_elf.clone();
+
+    thread::spawn(move || {
+        let res = match elf {
+            Some(elf) => follow_defmt(serial, &elf, &log_port),
+            None => follow_plain(serial, &log_port),
+        };
+        if let Err(err) = res {
+            println!("Stopped following logs: {:#}", err);
+        }
+    });
+
+    Ok(())
+}
+
+/// A log line as it is printed, `elapsed` since the logs were opened
+fn log_line(elapsed: Duration, line: &str) -> String {
+    format!("[{:>10.3}] {}", elapsed.as_secs_f64(), line)
+}
+
+/// Decode the defmt frames of the logging interface with defmt-print
+fn follow_defmt(mut serial: Box<dyn SerialPort>, elf: &str, log_port: &str) -> Result<()> {
+    let mut decoder = process::Command::new(DEFMT_PRINT)
+        .args(["-e", elf])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| {
+            format!("Unable to run {DEFMT_PRINT}, install it with `cargo install {DEFMT_PRINT}`")
+        })?;
+    let mut frames = decoder.stdin.take().context("defmt-print has no stdin")?;
+    let lines = BufReader::new(decoder.stdout.take().context("defmt-print has no stdout")?);
+
+    let start = Instant::now();
+    let printer = thread::spawn(move || {
+        for line in lines.lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            println!("{}", log_line(start.elapsed(), &line));
+        }
+    });
+
+    println!("Following logs on {log_port}, press Ctrl-C to stop");
+    let mut buf = [0u8; 64];
+    let res = loop {
+        let len = match serial.read(&mut buf) {
+            Ok(len) => len,
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => continue,
+            Err(err) => break Err(err).context("Reading from the logging interface"),
+        };
+        if let Err(err) = frames.write_all(&buf[..len]).and_then(|_| frames.flush()) {
+            break Err(err).context("defmt-print stopped accepting logs");
+        }
+    };
+
+    drop(frames);
+    let _ = decoder.wait();
+    let _ = printer.join();
+    res
+}
+
+/// Print the text lines of firmware built with the plain-log feature
+fn follow_plain(serial: Box<dyn SerialPort>, log_port: &str) -> Result<()> {
+    let mut serial = BufReader::new(serial);
+    let mut partial = Vec::new();
+    let start = Instant::now();
+    println!("Following logs on {log_port}, press Ctrl-C to stop");
+    loop {
+        if let Some(line) = read_log_line(&mut serial, &mut partial)? {
+            println!("{}", log_line(start.elapsed(), &line));
+        }
+    }
+}
+
+/// Read the rest of a log line, None if the read timed out first. The bytes
+/// read so far are kept in `partial` for the next call.
+fn read_log_line<R: BufRead>(port: &mut R, partial: &mut Vec<u8>) -> Result<Option<String>> {
+    match port.read_until(b'\n', partial) {
+        Ok(0) => bail!("The logging interface closed"),
+        Ok(_) if partial.ends_with(b"\n") => {
+            let line = String::from_utf8_lossy(partial).trim_end().to_string();
+            partial.clear();
+            Ok(Some(line))
+        }
+        Ok(_) => Ok(None),
+        Err(err) if err.kind() == io::ErrorKind::TimedOut => Ok(None),
+        Err(err) => Err(err).context("Reading from the logging interface"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt;
+
+    use picodox_proto::{
+        errors::ProtoError,
+        proto_impl::{self, Crc16},
+        KeyDelta, KeyFrame, KeyUpdate, LinkFrame, MatrixLoc, PointerMotion, MAX_DELTA_KEYS,
+    };
+
+    use super::*;
+
+    fn command_cases() -> Vec<Command> {
+        vec![
+            Command::Data(DataChunk::from_slice(&[0, 0, 3, 4, 5, 6, 0, 0]).unwrap()),
+            Command::Data(DataChunk::from_slice(&[0xa5; DATA_COUNT]).unwrap()),
+            Command::EchoMsg { count: 7 },
+            Command::GetVersion,
+            Command::FlashFw {
+                count: 70_000,
+                crc: 0xcbf4_3926,
+            },
+            Command::VerifyFw {
+                offset: 0x20_1000,
+                len: 70_000,
+            },
+            Command::ReadTrace,
+            Command::GetPanic { offset: 64 },
+            Command::SetLed { r: 255, g: 0, b: 8 },
+            Command::SetAnimation(LedAnimation::Rainbow { speed: 3 }),
+            Command::SetLogLevel(LogLevel::Warn),
+            Command::GetFlashInfo,
+            Command::Ping { seq: 513 },
+            Command::SetKeyLed {
+                loc: MatrixLoc::new(2, 3),
+                r: 0,
+                g: 64,
+                b: 255,
+            },
+            Command::SetAnimation(LedAnimation::Reactive { r: 1, g: 2, b: 3 }),
+            Command::ReadFlash {
+                offset: 0x10_0003,
+                len: READ_FLASH_BLOCK as u16,
+            },
+            Command::GetConfig,
+            Command::GetMatrix,
+            Command::UploadKeymap { count: 423 },
+            Command::EnterTestMode,
+            Command::ExitTestMode,
+            Command::GetDeviceId,
+            Command::EraseFw,
+            Command::SetHand(Some(Hand::Right)),
+            Command::SetHand(None),
+            Command::GetBuildInfo,
+            Command::SetConfig(Config {
+                led_brightness: 255,
+                tapping_term_ms: 180,
+                debounce_ms: 0,
+                default_layer: 1,
+                idle_timeout_s: 600,
+            }),
+        ]
+    }
+
+    fn response_cases() -> Vec<Response> {
+        vec![
+            Response::EchoMsg { count: 128 },
+            Response::Data(DataChunk::from_slice(&[1, 2, 3]).unwrap()),
+            Response::Nack(NackType::PacketErr(ProtoError::BufferSize)),
+            Response::Version(CURRENT_VERSION),
+            Response::Ack(AckType::AckData),
+            Response::FwCrc(0xdead_beef),
+            Response::DataStream { total: 0x8000 },
+            Response::DataEnd,
+            Response::Ack(AckType::AckLed),
+            Response::Ack(AckType::AckLogLevel),
+            Response::FlashInfo {
+                max_fw_size: 0x1f_f000,
+                data_count: DATA_COUNT as u16,
+            },
+            Response::Pong { seq: 513 },
+            Response::Config(Config::default()),
+            Response::Ack(AckType::AckConfig),
+            Response::Ack(AckType::AckErase),
+            Response::Ack(AckType::AckHand),
+            Response::Nack(NackType::StoreFailed),
+            Response::Matrix(KeyState::from_update(
+                &KeyUpdate::keys([MatrixLoc::new(0, 0)]),
+                &KeyUpdate::keys([MatrixLoc::new(4, 6)]),
+            )),
+            Response::DeviceId(DeviceId {
+                unique: [0xff; 8],
+                jedec: [0xef, 0x40, 0x18],
+            }),
+            Response::BuildInfo(BuildInfo {
+                git_hash: "2a2c1de2-dirty".into(),
+                built: 1_792_158_574,
+            }),
+        ]
+    }
+
+    fn panic_response() -> Response {
+        Response::Panic(b"Panic: at key_map.rs".as_slice().try_into().unwrap())
+    }
+
+    fn key_cases() -> Vec<KeyUpdate> {
+        vec![
+            KeyUpdate::keys([MatrixLoc::new(1, 2), MatrixLoc::new(4, 6)]),
+            KeyUpdate::no_keys(),
+        ]
+    }
+
+    fn ser<C: CrcKind, S: Serialize + MaxSize + fmt::Debug, const N: usize>(
+        case: usize,
+        command: &S,
+    ) -> Vec<u8> {
+        match case {
+            0 => {
+                let mut buffer = Vec::new();
+                send_command_with::<C, _, _, N>(&mut buffer, &command)
+                    .context("Send")
+                    .unwrap();
+                buffer
+            }
+            1 => proto_impl::wire_encode_with::<C, _, N>(command)
+                .unwrap()
+                .to_vec(),
+            2 => proto_impl::cs_encode_with::<C, _, N>(command)
+                .unwrap()
+                .to_vec(),
+            3 => {
+                let mut buffer = Vec::new();
+                send_framed::<C, _, _, N>(&mut buffer, &command, Framing::LenPrefix)
+                    .context("Send")
+                    .unwrap();
+                buffer
+            }
+            4 => proto_impl::len_encode_with::<C, _, N>(command)
+                .unwrap()
+                .to_vec(),
+            _ => unimplemented!(),
+        }
+    }
+
+    fn des<C: CrcKind, D: DeserializeOwned>(case: usize, mut buffer: Vec<u8>) -> D {
+        match case {
+            0 => recv_response_with::<C, _, _>(&mut BufReader::new(&buffer[..]))
+                .context("Recv")
+                .unwrap(),
+            1 => proto_impl::wire_decode_with::<C, _>(&mut buffer).unwrap(),
+            2 => proto_impl::cs_decode_with::<C, _>(&mut buffer).unwrap(),
+            3 => {
+                let frame =
+                    read_frame_framed::<C, _>(&mut BufReader::new(&buffer[..]), Framing::LenPrefix)
+                        .context("Recv")
+                        .unwrap()
+                        .unwrap();
+                decode_response(&frame).unwrap()
+            }
+            4 => proto_impl::len_decode_with::<C, _>(&buffer).unwrap(),
+            _ => unimplemented!(),
+        }
+    }
+
+    fn round_trip<C, T, const N: usize>(ser_idx: usize, des_idx: usize, cases: &[T])
+    where
+        C: CrcKind,
+        T: Serialize + DeserializeOwned + MaxSize + fmt::Debug + PartialEq,
+    {
+        println!("=== ser: {ser_idx}, des: {des_idx} ===");
+        for case in cases {
+            println!("Case: {:?}", case);
+            let buffer: Vec<u8> = ser::<C, T, N>(ser_idx, case);
+            assert!(!buffer.is_empty());
+            println!("Buffer: {:02x?}", buffer);
+            let round_trip = des::<C, T>(des_idx, buffer);
+
+            assert_eq!(case, &round_trip);
+        }
+    }
+
+    #[test]
+    fn command_wire_cross() {
+        for ser_idx in 0..=1 {
+            for des_idx in 0..=1 {
+                round_trip::<Crc8, Command, { Command::WIRE_MAX_SIZE }>(
+                    ser_idx,
+                    des_idx,
+                    &command_cases(),
+                )
+            }
+        }
+        // Length prefixed framing only crosses with itself
+        for ser_idx in 3..=4 {
+            for des_idx in 3..=4 {
+                round_trip::<Crc8, Command, { Command::LEN_MAX_SIZE }>(
+                    ser_idx,
+                    des_idx,
+                    &command_cases(),
+                )
+            }
+        }
+    }
+
+    #[test]
+    fn command_cs() {
+        round_trip::<Crc8, Command, { Command::CS_MAX_SIZE }>(2, 2, &command_cases())
+    }
+
+    #[test]
     fn response_wire_cross() {
         for ser_idx in 0..=1 {
             for des_idx in 0..=1 {
-                round_trip::<Response, { Response::WIRE_MAX_SIZE }>(
+                round_trip::<Crc8, Response, { Response::WIRE_MAX_SIZE }>(
+                    ser_idx,
+                    des_idx,
+                    &response_cases(),
+                )
+            }
+        }
+        for ser_idx in 3..=4 {
+            for des_idx in 3..=4 {
+                round_trip::<Crc8, Response, { Response::LEN_MAX_SIZE }>(
                     ser_idx,
                     des_idx,
-                    RESPONSE_CASES,
+                    &response_cases(),
                 )
             }
         }
@@ -363,11 +2718,651 @@ mod tests {
 
     #[test]
     fn response_cs() {
-        round_trip::<Response, { Response::CS_MAX_SIZE }>(2, 2, RESPONSE_CASES)
+        round_trip::<Crc8, Response, { Response::CS_MAX_SIZE }>(2, 2, &response_cases())
+    }
+
+    #[test]
+    fn panic_response_wire() {
+        round_trip::<Crc8, Response, { Response::WIRE_MAX_SIZE }>(0, 1, &[panic_response()])
     }
 
     #[test]
     fn key_response_cs() {
-        round_trip::<KeyUpdate, { KeyUpdate::CS_MAX_SIZE }>(2, 2, &key_cases())
+        round_trip::<Crc8, KeyUpdate, { KeyUpdate::CS_MAX_SIZE }>(2, 2, &key_cases())
+    }
+
+    #[test]
+    fn link_frame_cs() {
+        let mut frames: Vec<LinkFrame> = key_cases()
+            .into_iter()
+            .enumerate()
+            .map(|(idx, update)| {
+                LinkFrame::Keys(KeyFrame {
+                    seq: 255 - idx as u8,
+                    update,
+                })
+            })
+            .collect();
+        frames.push(LinkFrame::Heartbeat);
+        frames.push(LinkFrame::Pointer(PointerMotion { dx: -128, dy: 127 }));
+        frames.push(LinkFrame::KeyDelta(KeyDelta {
+            seq: 7,
+            base: 6,
+            toggled: (0..MAX_DELTA_KEYS)
+                .map(|col| MatrixLoc::new(NUM_ROWS - 1, col))
+                .collect(),
+        }));
+        frames.push(LinkFrame::Resync);
+        round_trip::<Crc8, LinkFrame, { LinkFrame::CS_MAX_SIZE }>(2, 2, &frames)
+    }
+
+    #[test]
+    fn command_wire_cross_crc16() {
+        for ser_idx in 0..=1 {
+            for des_idx in 0..=1 {
+                round_trip::<Crc16, Command, { proto_impl::wire_max_size::<Crc16, Command>() }>(
+                    ser_idx,
+                    des_idx,
+                    &command_cases(),
+                )
+            }
+        }
+    }
+
+    #[test]
+    fn response_wire_cross_crc16() {
+        for ser_idx in 0..=1 {
+            for des_idx in 0..=1 {
+                round_trip::<Crc16, Response, { proto_impl::wire_max_size::<Crc16, Response>() }>(
+                    ser_idx,
+                    des_idx,
+                    &response_cases(),
+                )
+            }
+        }
+    }
+
+    #[test]
+    fn skips_damaged_frames() {
+        let good =
+            ser::<Crc8, Response, { Response::WIRE_MAX_SIZE }>(1, &Response::EchoMsg { count: 3 });
+        let mut damaged = good.clone();
+        damaged[1] ^= 0x01;
+        // Line noise without a sentinel ends up in front of the damaged frame
+        let stream = [&[0x12, 0x34][..], &damaged, &good].concat();
+
+        let resp: Response = recv_response(&mut BufReader::new(&stream[..])).unwrap();
+        assert_eq!(resp, Response::EchoMsg { count: 3 });
+        assert!(recv_exact::<_, Response>(&mut BufReader::new(&stream[..])).is_err());
+    }
+
+    #[test]
+    fn gives_up_on_damaged_frames() {
+        let stream = [0x01u8, 0x00].repeat(MAX_DAMAGED_FRAMES + 1);
+        assert!(recv_response::<_, Response>(&mut BufReader::new(&stream[..])).is_err());
+        // A frame cut off by the end of the stream is an error, not a panic
+        assert!(recv_response::<_, Response>(&mut BufReader::new(&[0x03, 0x01][..])).is_err());
+    }
+
+    /// Hands out `reads` one per call, like a serial port that times out
+    /// whenever nothing new has arrived
+    struct Stutter {
+        reads: Vec<Option<Vec<u8>>>,
+    }
+
+    impl Read for Stutter {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.reads.is_empty() {
+                return Ok(0);
+            }
+            match self.reads.remove(0) {
+                Some(bytes) => {
+                    buf[..bytes.len()].copy_from_slice(&bytes);
+                    Ok(bytes.len())
+                }
+                None => Err(io::ErrorKind::TimedOut.into()),
+            }
+        }
+    }
+
+    #[test]
+    fn frames_across_reads() {
+        let resp = || Response::Data(DataChunk::from_slice(&[0x5a; DATA_COUNT]).unwrap());
+        let frame = frames(&[resp()]);
+        let (first, rest) = frame.split_at(frame.len() / 2);
+
+        // Timeouts between the usb packets of one frame are waited out
+        let mut port = BufReader::new(Stutter {
+            reads: vec![Some(first.to_vec()), None, None, Some(rest.to_vec())],
+        });
+        assert_eq!(recv_response::<_, Response>(&mut port).unwrap(), resp());
+
+        // Before a frame starts a timeout is passed on as one
+        let mut port = BufReader::new(Stutter {
+            reads: vec![None, Some(frame.clone())],
+        });
+        let err = recv_response::<_, Response>(&mut port).unwrap_err();
+        assert!(is_timeout(&err));
+        assert_eq!(recv_response::<_, Response>(&mut port).unwrap(), resp());
+
+        // The stream ending mid frame is still an error
+        let mut port = BufReader::new(Stutter {
+            reads: vec![Some(first.to_vec()), None],
+        });
+        assert!(recv_response::<_, Response>(&mut port).is_err());
+
+        // Length prefixed frames too, even with the prefix split, and
+        // without reading into the frame after
+        const N: usize = Response::LEN_MAX_SIZE;
+        let len_frame = proto_impl::len_encode::<_, N>(&resp()).unwrap();
+        let mut rest = len_frame[1..].to_vec();
+        rest.extend_from_slice(&len_frame);
+        let mut port = BufReader::new(Stutter {
+            reads: vec![Some(len_frame[..1].to_vec()), None, Some(rest)],
+        });
+        for _ in 0..2 {
+            let bytes = read_frame_framed::<Crc8, _>(&mut port, Framing::LenPrefix)
+                .unwrap()
+                .unwrap();
+            assert_eq!(decode_response::<Response>(&bytes).unwrap(), resp());
+        }
+        let mut port = BufReader::new(&len_frame[..len_frame.len() - 1]);
+        let err = read_frame_framed::<Crc8, _>(&mut port, Framing::LenPrefix).unwrap_err();
+        assert!(matches!(recv_error(&err), Some(RecvError::Disconnected)));
+    }
+
+    #[test]
+    fn recv_error_kinds() {
+        let recv = |port: &mut dyn BufRead| {
+            let err = recv_response::<_, Response>(&mut &mut *port).unwrap_err();
+            recv_error(&err).map(|kind| format!("{:?}", kind))
+        };
+        let timeout = Some(String::from("Timeout"));
+        let disconnected = Some(String::from("Disconnected"));
+        let corrupt = Some(String::from("Corrupt"));
+
+        let mut port = BufReader::new(Stutter { reads: vec![None] });
+        assert_eq!(recv(&mut port), timeout);
+        assert_eq!(recv(&mut BufReader::new(&[][..])), disconnected);
+        assert_eq!(recv(&mut BufReader::new(&[0x03, 0x01][..])), disconnected);
+
+        let unplugged = io::Error::from(io::ErrorKind::BrokenPipe);
+        assert!(is_disconnect(&unplugged));
+        assert!(!is_disconnect(&io::ErrorKind::TimedOut.into()));
+
+        let damaged = [0x01u8, 0x00].repeat(MAX_DAMAGED_FRAMES + 1);
+        assert_eq!(recv(&mut BufReader::new(&damaged[..])), corrupt);
+        // Intact, but not a response
+        let frame = proto_impl::wire_frame_with::<Crc8, 16>(&[0xff, 0xff]).unwrap();
+        assert_eq!(recv(&mut BufReader::new(&frame[..])), corrupt);
+    }
+
+    const MAX_PACKET: usize = 64;
+
+    /// Bulk IN packets as the host sees them. Full packets are held until a
+    /// short one ends the transfer, reads time out while nothing has been
+    /// handed over.
+    struct UsbHost {
+        packets: Vec<Vec<u8>>,
+        transfer: Vec<u8>,
+    }
+
+    impl Read for UsbHost {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            while !self.packets.is_empty() {
+                let packet = self.packets.remove(0);
+                self.transfer.extend_from_slice(&packet);
+                if packet.len() < MAX_PACKET {
+                    let len = self.transfer.len();
+                    buf[..len].copy_from_slice(&self.transfer);
+                    self.transfer.clear();
+                    return Ok(len);
+                }
+            }
+            Err(io::ErrorKind::TimedOut.into())
+        }
+    }
+
+    #[test]
+    fn frames_on_packet_boundaries() {
+        let packets = |frame: &[u8]| -> Vec<Vec<u8>> {
+            proto_impl::usb_packets(frame, MAX_PACKET)
+                .map(<[u8]>::to_vec)
+                .collect()
+        };
+        assert!(packets(&[]).is_empty());
+
+        for len in [MAX_PACKET - 1, MAX_PACKET, MAX_PACKET + 1] {
+            // The CRC, COBS code and sentinel add 3 bytes to a short payload
+            let payload = vec![0x5a; len - 3];
+            let frame = proto_impl::wire_frame_with::<Crc8, 128>(&payload).unwrap();
+            assert_eq!(frame.len(), len);
+
+            let sent = packets(&frame);
+            assert_eq!(sent.concat(), &frame[..]);
+            let mut port = BufReader::new(UsbHost {
+                packets: sent,
+                transfer: Vec::new(),
+            });
+            let read = read_frame_with::<Crc8, _>(&mut port).unwrap();
+            assert_eq!(read, Ok(payload), "frame of {} bytes", len);
+        }
+
+        // Without the zero length packet a full frame never arrives
+        let frame = proto_impl::wire_frame_with::<Crc8, 128>(&[0x5a; 61]).unwrap();
+        let mut port = BufReader::new(UsbHost {
+            packets: vec![frame.to_vec()],
+            transfer: Vec::new(),
+        });
+        assert!(read_frame_with::<Crc8, _>(&mut port).is_err());
+    }
+
+    #[test]
+    fn plain_log_lines() {
+        let mut port = BufReader::new(Stutter {
+            reads: vec![
+                Some(b"INFO  Other half con".to_vec()),
+                None,
+                Some(b"nected\r\nWARN  \xffbad\n".to_vec()),
+            ],
+        });
+        let mut partial = Vec::new();
+        assert_eq!(read_log_line(&mut port, &mut partial).unwrap(), None);
+        assert_eq!(
+            read_log_line(&mut port, &mut partial).unwrap().unwrap(),
+            "INFO  Other half connected"
+        );
+        assert_eq!(
+            read_log_line(&mut port, &mut partial).unwrap().unwrap(),
+            "WARN  \u{fffd}bad"
+        );
+        // The port closing ends the logs
+        assert!(read_log_line(&mut port, &mut partial).is_err());
+
+        assert_eq!(
+            log_line(Duration::from_millis(1500), "INFO  hi"),
+            "[     1.500] INFO  hi"
+        );
+    }
+
+    #[test]
+    fn unsupported_command() {
+        let stream = frames(&[Response::Nack(NackType::UnsupportedCommand(40))]);
+        let err = recv_response::<_, Response>(&mut BufReader::new(&stream[..])).unwrap_err();
+        assert!(err.downcast_ref::<Unsupported>().is_some());
+        assert!(err.to_string().contains("picodox-cli version"));
+
+        // Other nacks are responses like any other
+        let stream = frames(&[Response::Nack(NackType::OutOfRange)]);
+        let resp: Response = recv_response(&mut BufReader::new(&stream[..])).unwrap();
+        assert_eq!(resp, Response::Nack(NackType::OutOfRange));
+    }
+
+    #[test]
+    fn port_args() {
+        let args = Cli::try_parse_from(["picodox-cli", "version"]).unwrap();
+        assert_eq!(args.port.baud, SERIAL_BAUD);
+        assert_eq!(
+            args.port.timeout(),
+            Duration::from_millis(SERIAL_TIMEOUT_MS)
+        );
+
+        let args = Cli::try_parse_from([
+            "picodox-cli",
+            "--baud",
+            "9600",
+            "--timeout-ms",
+            "2000",
+            "version",
+        ])
+        .unwrap();
+        assert_eq!(args.port.baud, 9600);
+        assert_eq!(args.port.timeout(), Duration::from_secs(2));
+
+        let args = Cli::try_parse_from(["picodox-cli", "-q", "echo", "hi"]).unwrap();
+        assert!(args.port.progress(2).unwrap().is_hidden());
+
+        let args = Cli::try_parse_from(["picodox-cli", "--with-logs", "version"]).unwrap();
+        assert!(args.port.with_logs);
+        assert_eq!(Framing::from(args.port.framing), Framing::Cobs);
+        let args = Cli::try_parse_from(["picodox-cli", "--framing", "len", "version"]).unwrap();
+        assert_eq!(Framing::from(args.port.framing), Framing::LenPrefix);
+        assert!(Cli::try_parse_from(["picodox-cli", "--log-elf", "fw.elf", "version"]).is_err());
+    }
+
+    #[test]
+    fn reconnect_backoff() {
+        let delays: Vec<u64> = (0..7)
+            .map(|attempt| reconnect_delay(attempt).as_millis() as u64)
+            .collect();
+        assert_eq!(delays, [50, 100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(reconnect_delay(u32::MAX), RECONNECT_MAX_DELAY);
+
+        let args =
+            Cli::try_parse_from(["picodox-cli", "--reconnect-ms", "3000", "reset", "--wait"])
+                .unwrap();
+        assert_eq!(args.port.reconnect(), Duration::from_secs(3));
+        assert!(matches!(args.command, SubCommand::Reset { wait: true }));
+    }
+
+    #[test]
+    fn flash_dry_run() {
+        let args = Cli::try_parse_from(["picodox-cli", "flash", "--dry-run", "fw.elf"]).unwrap();
+        assert!(matches!(
+            args.command,
+            SubCommand::Flash { dry_run: true, .. }
+        ));
+
+        let image = FwImage {
+            address: 0x1000_0100,
+            data: vec![0xa5; 2 * DATA_COUNT + 1],
+            gaps: Vec::new(),
+        };
+        assert_eq!(
+            describe_flash(&image, 0xcbf4_3926),
+            "Would send 113 bytes in 3 chunks of up to 56 bytes\n\
+             Image 0x10000100..0x10000171, written to the DFU partition at 0x201000..0x201071\n\
+             Image CRC 0xcbf43926, nothing was sent"
+        );
+    }
+
+    #[test]
+    fn erase_confirm() {
+        assert!(is_yes("y\n"));
+        assert!(is_yes(" YES \n"));
+        assert!(!is_yes("\n"));
+        assert!(!is_yes("no\n"));
+        assert!(!is_yes("yep\n"));
+
+        let args = Cli::try_parse_from(["picodox-cli", "erase", "--force"]).unwrap();
+        assert!(matches!(args.command, SubCommand::Erase { force: true }));
+        assert!(!args.port.force);
+    }
+
+    #[test]
+    fn ping_stats() {
+        let rtts = [
+            Some(Duration::from_millis(2)),
+            None,
+            Some(Duration::from_millis(4)),
+            Some(Duration::from_millis(9)),
+        ];
+        assert_eq!(
+            ping_summary(&rtts),
+            "4 sent, 3 received, 25% loss, rtt min/avg/max 2.00/5.00/9.00 ms"
+        );
+        assert_eq!(ping_summary(&[None]), "1 sent, 0 received, 100% loss");
+        assert_eq!(ping_summary(&[]), "0 sent, 0 received, 0% loss");
+    }
+
+    fn frames(responses: &[Response]) -> Vec<u8> {
+        responses
+            .iter()
+            .flat_map(|resp| {
+                proto_impl::wire_encode::<_, { Response::WIRE_MAX_SIZE }>(resp)
+                    .unwrap()
+                    .to_vec()
+            })
+            .collect()
+    }
+
+    fn tagged_frames(responses: &[(Response, u8)]) -> Vec<u8> {
+        const N: usize = proto_impl::tagged_wire_max_size::<Crc8, Response>();
+        responses
+            .iter()
+            .flat_map(|(resp, tag)| {
+                proto_impl::wire_encode_tagged_with::<Crc8, _, N>(resp, *tag)
+                    .unwrap()
+                    .to_vec()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tagged_out_of_order() {
+        let pong = |seq| Response::Pong { seq };
+        let tags = [request_tag(0), request_tag(1), request_tag(2)];
+        assert_eq!(tags, [1, 2, 3]);
+        assert_eq!(request_tag(255), 1);
+
+        // Matched by tag in whatever order they come, a tag of no slot is
+        // skipped
+        let bytes = tagged_frames(&[(pong(3), 3), (pong(9), 9), (pong(1), 1), (pong(2), 2)]);
+        let mut slots: Vec<Option<Response>> = vec![None, None, None];
+        collect_tagged(&mut BufReader::new(&bytes[..]), &tags, &mut slots).unwrap();
+        assert_eq!(slots, [Some(pong(1)), Some(pong(2)), Some(pong(3))]);
+
+        // Firmware that predates tags answers untagged, in order
+        let bytes = frames(&[pong(1), pong(2), pong(3)]);
+        let mut slots: Vec<Option<Response>> = vec![None, None, None];
+        collect_tagged(&mut BufReader::new(&bytes[..]), &tags, &mut slots).unwrap();
+        assert_eq!(slots, [Some(pong(1)), Some(pong(2)), Some(pong(3))]);
+
+        // Lost responses leave their slots empty, to be sent again
+        let bytes = tagged_frames(&[(pong(2), 2)]);
+        let mut slots: Vec<Option<Response>> = vec![None, None, None];
+        assert!(collect_tagged(&mut BufReader::new(&bytes[..]), &tags, &mut slots).is_err());
+        assert_eq!(slots, [None, Some(pong(2)), None]);
+    }
+
+    #[test]
+    fn answer_after_broken_off_transfer() {
+        // The host gave up on a firmware transfer with chunks still in
+        // flight, their acks come in ahead of the next command's answer
+        let bytes = frames(&[
+            Response::Ack(AckType::AckData),
+            Response::Ack(AckType::AckData),
+            Response::Version(CURRENT_VERSION),
+            Response::Pong { seq: 1 },
+        ]);
+        let mut port = BufReader::new(&bytes[..]);
+        assert_eq!(
+            recv_answer(&mut port).unwrap(),
+            Response::Version(CURRENT_VERSION)
+        );
+        assert_eq!(recv_answer(&mut port).unwrap(), Response::Pong { seq: 1 });
+
+        // Same for an echo, and a nack is still an answer
+        let data = DataChunk::from_slice(b"abc").unwrap();
+        let bytes = frames(&[Response::Data(data), Response::Nack(NackType::OutOfRange)]);
+        assert_eq!(
+            recv_answer(&mut BufReader::new(&bytes[..])).unwrap(),
+            Response::Nack(NackType::OutOfRange)
+        );
+
+        // Nothing but leftovers is still a timeout
+        let bytes = frames(&[Response::Ack(AckType::AckData)]);
+        let mut port = BufReader::new(Stutter {
+            reads: vec![Some(bytes), None],
+        });
+        assert!(is_timeout(&recv_answer(&mut port).unwrap_err()));
+    }
+
+    #[test]
+    fn echo_boundaries() {
+        let later = Instant::now() + Duration::from_secs(60);
+        for len in [0, DATA_COUNT, DATA_COUNT + 1] {
+            let content: Vec<u8> = (0..len).map(|idx| b'a' + (idx % 26) as u8).collect();
+            let chunks = echo_chunks(&content);
+            assert_eq!(chunks.len(), len.div_ceil(DATA_COUNT));
+
+            let mut replies = vec![Response::EchoMsg { count: len as u16 }];
+            replies.extend(chunks.into_iter().map(Response::Data));
+            let bytes = frames(&replies);
+            let echoed = recv_echo(&mut BufReader::new(&bytes[..]), len, later).unwrap();
+            assert_eq!(echoed, content);
+        }
+
+        // A bogus count is refused before waiting for any data
+        let bytes = frames(&[Response::EchoMsg { count: u16::MAX }]);
+        assert!(recv_echo(&mut BufReader::new(&bytes[..]), 3, later).is_err());
+
+        // So is more data than was sent
+        let data = DataChunk::from_slice(&[1, 2, 3, 4]).unwrap();
+        let bytes = frames(&[Response::EchoMsg { count: 3 }, Response::Data(data)]);
+        assert!(recv_echo(&mut BufReader::new(&bytes[..]), 3, later).is_err());
+
+        // Empty packets don't keep it waiting past the deadline
+        let mut replies = vec![Response::EchoMsg { count: 3 }];
+        replies.extend((0..8).map(|_| Response::Data(DataChunk::new())));
+        let bytes = frames(&replies);
+        let err = recv_echo(&mut BufReader::new(&bytes[..]), 3, Instant::now()).unwrap_err();
+        assert!(err.to_string().starts_with("Timed out"), "{:#}", err);
+    }
+
+    #[test]
+    fn data_stream() {
+        let progress = ProgressBar::hidden();
+        let content: Vec<u8> = (0..DATA_COUNT * 2 + 5).map(|idx| idx as u8).collect();
+        let stream = |total: usize, content: &[u8]| {
+            let mut replies = vec![Response::DataStream {
+                total: total as u32,
+            }];
+            replies.extend(echo_chunks(content).into_iter().map(Response::Data));
+            replies.push(Response::DataEnd);
+            frames(&replies)
+        };
+
+        let bytes = stream(content.len(), &content);
+        let received = recv_data_stream(&mut BufReader::new(&bytes[..]), 1024, &progress).unwrap();
+        assert_eq!(received, content);
+
+        let bytes = stream(0, &[]);
+        let received = recv_data_stream(&mut BufReader::new(&bytes[..]), 0, &progress).unwrap();
+        assert!(received.is_empty());
+
+        // More than the caller allows is refused before reading any data
+        let bytes = stream(content.len(), &content);
+        assert!(recv_data_stream(&mut BufReader::new(&bytes[..]), 16, &progress).is_err());
+
+        // Short and long transfers are caught
+        let bytes = stream(content.len() + 1, &content);
+        let err = recv_data_stream(&mut BufReader::new(&bytes[..]), 1024, &progress).unwrap_err();
+        assert!(err.to_string().starts_with("Transfer ended"), "{:#}", err);
+        let bytes = stream(content.len() - 1, &content);
+        assert!(recv_data_stream(&mut BufReader::new(&bytes[..]), 1024, &progress).is_err());
+    }
+
+    #[test]
+    fn matrix() {
+        let state = KeyState::from_update(
+            &KeyUpdate::keys([MatrixLoc::new(0, 0), MatrixLoc::new(2, 3)]),
+            &KeyUpdate::keys([MatrixLoc::new(4, 6)]),
+        );
+        assert_eq!(
+            matrix_grid(&state),
+            "left      right\n\
+             #......   .......\n\
+             .......   .......\n\
+             ...#...   .......\n\
+             .......   .......\n\
+             .......   ......#\n"
+        );
+    }
+
+    #[test]
+    fn test_mode() {
+        let seen = KeyState::from_update(
+            &KeyUpdate::keys(core::array::from_fn::<_, NUM_KEYS, _>(|idx| {
+                MatrixLoc::new(idx / NUM_COLS, idx % NUM_COLS)
+            })),
+            &KeyUpdate::keys([MatrixLoc::new(0, 1), MatrixLoc::new(3, 2)]),
+        );
+        let state = KeyState::from_update(
+            &KeyUpdate::no_keys(),
+            &KeyUpdate::keys([MatrixLoc::new(0, 1)]),
+        );
+        assert_eq!(
+            test_grid(&state, &seen),
+            "left      right\n\
+             +++++++   .#.....\n\
+             +++++++   .......\n\
+             +++++++   .......\n\
+             +++++++   ..+....\n\
+             +++++++   .......\n"
+        );
+
+        let missing = unregistered(&seen);
+        assert_eq!(missing.len(), NUM_KEYS - 2);
+        assert_eq!(missing[0], "right 0,0");
+        assert_eq!(missing[1], "right 0,2");
+    }
+
+    #[test]
+    fn self_test_lines() {
+        let mut results = SelfTestResults::default();
+        results.set(SelfTestCheck::Flash, true);
+        results.set(SelfTestCheck::Usb, true);
+        assert_eq!(
+            self_test_report(&results),
+            "flash     pass\n\
+             i2c peer  FAIL\n\
+             usb       pass\n"
+        );
+    }
+
+    #[test]
+    fn device_id_lines() {
+        let id = DeviceId {
+            unique: [0xe6, 0x61, 0x38, 0x52, 0x83, 0x4d, 0x2a, 0x2f],
+            jedec: [0xef, 0x40, 0x18],
+        };
+        assert_eq!(
+            device_id_report(&id),
+            "Flash unique ID: E6613852834D2A2F\n\
+             Flash JEDEC ID:  EF 40 18\n"
+        );
+    }
+
+    #[test]
+    fn build_times() {
+        assert_eq!(utc_time(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(utc_time(951_829_509), "2000-02-29 13:05:09 UTC");
+        assert_eq!(utc_time(1_792_158_574), "2026-10-16 13:49:34 UTC");
+        // 2100 is no leap year
+        assert_eq!(utc_time(4_107_542_399), "2100-02-28 23:59:59 UTC");
+        assert_eq!(utc_time(4_107_542_400), "2100-03-01 00:00:00 UTC");
+
+        let args = Cli::try_parse_from(["picodox-cli", "version", "-v"]).unwrap();
+        assert!(matches!(
+            args.command,
+            SubCommand::Version { verbose: true }
+        ));
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(parse_number("4096").unwrap(), 4096);
+        assert_eq!(parse_number("0x20_1000").unwrap(), DFU_OFFSET);
+        assert_eq!(parse_number("0XfF").unwrap(), 0xff);
+        assert!(parse_number("0x").is_err());
+        assert!(parse_number("12k").is_err());
+        assert!(parse_number("0x1_0000_0000").is_err());
+    }
+
+    #[test]
+    fn raw_frames() {
+        assert_eq!(parse_hex("00 ff1A").unwrap(), vec![0x00, 0xff, 0x1a]);
+        assert!(parse_hex("123").is_err());
+        assert!(parse_hex("zz").is_err());
+        assert!(parse_hex("+1").is_err());
+
+        // Unframing what send_command writes gives back the postcard bytes
+        let mut sent = Vec::new();
+        send_command(&mut sent, &Command::GetVersion).unwrap();
+        assert_eq!(
+            unframe_with::<Crc8>(&sent, Framing::Cobs).unwrap(),
+            postcard::to_stdvec(&Command::GetVersion).unwrap()
+        );
+        assert!(unframe_with::<Crc8>(&[0x01, 0x00], Framing::Cobs).is_err());
+    }
+
+    #[test]
+    fn crc_width_mismatch() {
+        let mut buffer = ser::<Crc16, Command, { proto_impl::wire_max_size::<Crc16, Command>() }>(
+            1,
+            &Command::GetVersion,
+        );
+        assert!(proto_impl::wire_decode::<Command>(&mut buffer).is_err());
     }
 }
@@ -0,0 +1,120 @@
+//! Finding the keyboard's serial interfaces
+//!
+//! The keyboard is one composite USB device with two CDC ACM functions, the
+//! command interface that serial.rs answers on and the logging interface
+//! after it. The OS numbers their ports in whatever order it likes, so they
+//! are told apart by USB interface number instead.
+
+use std::{fs, path::PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serialport::{SerialPortInfo, SerialPortType, UsbPortInfo};
+
+use crate::hid::{KEYBOARD_PID, KEYBOARD_VID};
+
+/// Each CDC ACM function takes a communication and a data interface. Linux
+/// and Windows report the first and macOS the second, both map to the same
+/// function.
+const INTERFACES_PER_CDC: u8 = 2;
+/// Order the functions are added in the firmware's main.rs
+const LOGGING_CDC: u8 = 1;
+
+/// The path a port is known by, so `/dev/serial/by-id` links match the
+/// enumerated name
+fn resolve(port: &str) -> PathBuf {
+    fs::canonicalize(port).unwrap_or_else(|_| PathBuf::from(port))
+}
+
+fn keyboard_usb(info: &SerialPortInfo) -> Option<&UsbPortInfo> {
+    match &info.port_type {
+        SerialPortType::UsbPort(usb) if usb.vid == KEYBOARD_VID && usb.pid == KEYBOARD_PID => {
+            Some(usb)
+        }
+        _ => None,
+    }
+}
+
+/// The logging port of the keyboard whose command port is `command`, out of
+/// the enumerated `ports`
+fn logging_port_in(ports: &[SerialPortInfo], command: &str) -> Result<String> {
+    let command = resolve(command);
+    let info = ports
+        .iter()
+        .find(|info| resolve(&info.port_name) == command)
+        .with_context(|| format!("'{}' is not an enumerated serial port", command.display()))?;
+    let Some(usb) = keyboard_usb(info) else {
+        bail!("'{}' is not a picodox keyboard", command.display());
+    };
+
+    // Several keyboards can be plugged in, only the sibling of the command
+    // port belongs to it
+    ports
+        .iter()
+        .find(|info| {
+            keyboard_usb(info).is_some_and(|other| {
+                other.serial_number == usb.serial_number
+                    && other.interface.map(|num| num / INTERFACES_PER_CDC) == Some(LOGGING_CDC)
+            })
+        })
+        .map(|info| info.port_name.clone())
+        .context("The keyboard has no logging interface, or the OS doesn't report its number")
+}
+
+/// The logging port of the keyboard whose command port is `command`
+pub fn logging_port(command: &str) -> Result<String> {
+    let ports =
+        serialport::available_ports().context("Unable to enumerate available serial ports")?;
+    logging_port_in(&ports, command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn port(name: &str, pid: u16, serial: &str, interface: u8) -> SerialPortInfo {
+        SerialPortInfo {
+            port_name: String::from(name),
+            port_type: SerialPortType::UsbPort(UsbPortInfo {
+                vid: KEYBOARD_VID,
+                pid,
+                serial_number: Some(String::from(serial)),
+                manufacturer: None,
+                product: None,
+                interface: Some(interface),
+            }),
+        }
+    }
+
+    #[test]
+    fn find_logging_port() {
+        let ports = [
+            port("/dev/ttyACM1", KEYBOARD_PID, "E66138", 2),
+            port("/dev/ttyACM0", KEYBOARD_PID, "E66138", 0),
+            // Another keyboard
+            port("/dev/ttyACM2", KEYBOARD_PID, "A0B1C2", 0),
+            port("/dev/ttyACM3", KEYBOARD_PID, "A0B1C2", 2),
+        ];
+        assert_eq!(
+            logging_port_in(&ports, "/dev/ttyACM0").unwrap(),
+            "/dev/ttyACM1"
+        );
+        assert_eq!(
+            logging_port_in(&ports, "/dev/ttyACM2").unwrap(),
+            "/dev/ttyACM3"
+        );
+
+        // macOS reports the data interfaces
+        let ports = [
+            port("/dev/cu.usbmodem1", KEYBOARD_PID, "E66138", 1),
+            port("/dev/cu.usbmodem3", KEYBOARD_PID, "E66138", 3),
+        ];
+        assert_eq!(
+            logging_port_in(&ports, "/dev/cu.usbmodem1").unwrap(),
+            "/dev/cu.usbmodem3"
+        );
+
+        let ports = [port("/dev/ttyACM0", 0x1234, "E66138", 0)];
+        assert!(logging_port_in(&ports, "/dev/ttyACM0").is_err());
+        assert!(logging_port_in(&ports, "/dev/ttyACM5").is_err());
+    }
+}
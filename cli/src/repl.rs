@@ -0,0 +1,120 @@
+use std::io::{self, Write};
+
+use anyhow::{Context, Result};
+use clap::Parser;
+
+use crate::{run, Device, SubCommand};
+
+/// A line typed into the repl, parsed like the arguments of the cli
+#[derive(Debug, Parser)]
+#[command(name = "picodox", no_binary_name = true)]
+#[command(about = "Run a command over the open serial port, `exit` leaves the repl")]
+struct ReplLine {
+    #[command(subcommand)]
+    command: SubCommand,
+}
+
+/// Read commands from stdin and run them until `exit` or the end of input.
+/// The serial port stays open between commands.
+pub fn repl(dev: &mut Device) -> Result<()> {
+    println!("Type a command, `help` to list them or `exit` to leave");
+
+    // Some commands read from stdin themselves, so it can't stay locked
+    let stdin = io::stdin();
+    loop {
+        print!("picodox> ");
+        io::stdout().flush().context("Flushing stdout")?;
+
+        let mut line = String::new();
+        if stdin.read_line(&mut line).context("Reading from stdin")? == 0 {
+            println!();
+            return Ok(());
+        }
+
+        let words = match split_words(&line) {
+            Ok(words) => words,
+            Err(err) => {
+                println!("Error: {}", err);
+                continue;
+            }
+        };
+        match words.first().map(String::as_str) {
+            None => continue,
+            Some("exit" | "quit") => return Ok(()),
+            _ => {}
+        }
+
+        let command = match ReplLine::try_parse_from(&words) {
+            Ok(line) => line.command,
+            Err(err) => {
+                let _ = err.print();
+                continue;
+            }
+        };
+        if let SubCommand::Repl = command {
+            println!("Already in the repl");
+            continue;
+        }
+
+        if let Err(err) = run(dev, command) {
+            println!("Error: {:#}", err);
+            dev.recover();
+        }
+    }
+}
+
+/// Split a line into words at whitespace. Double quotes group words, so
+/// `set-macro 0 "hello world"` works like it does in a shell.
+fn split_words(line: &str) -> Result<Vec<String>, &'static str> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quoted = false;
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                word.get_or_insert_with(String::new);
+            }
+            c if c.is_whitespace() && !quoted => words.extend(word.take()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quoted {
+        return Err("Unterminated quote");
+    }
+    words.extend(word);
+
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split() {
+        assert_eq!(split_words("  echo  foo\n").unwrap(), ["echo", "foo"]);
+        assert_eq!(
+            split_words("set-macro 1 \"a b\" \"\"").unwrap(),
+            ["set-macro", "1", "a b", ""]
+        );
+        assert!(split_words("echo \"foo").is_err());
+        assert!(split_words("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn parse_line() {
+        let line = ReplLine::try_parse_from(split_words("led 255 0 0").unwrap()).unwrap();
+        assert!(matches!(
+            line.command,
+            SubCommand::Led {
+                r: 255,
+                g: 0,
+                b: 0,
+                breathe: false,
+                reactive: false
+            }
+        ));
+        assert!(ReplLine::try_parse_from(["bogus"]).is_err());
+    }
+}
@@ -0,0 +1,192 @@
+use std::fmt::Write;
+
+/// Marks the write head of the firmware trace ring buffer
+const SENTINEL: u8 = b'@';
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceKind {
+    /// A task was spawned
+    NewTask,
+    /// The executor started polling a task
+    ExecBegin,
+    /// The executor finished polling a task
+    ExecEnd,
+    /// A task was woken and queued to be polled
+    Ready,
+    /// The executor ran out of work
+    Idle,
+}
+
+impl TraceKind {
+    fn from_tag(tag: &str) -> Option<Self> {
+        Some(match tag {
+            "NT" => TraceKind::NewTask,
+            "TB" => TraceKind::ExecBegin,
+            "TE" => TraceKind::ExecEnd,
+            "TR" => TraceKind::Ready,
+            "EI" => TraceKind::Idle,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceEvent {
+    pub kind: TraceKind,
+    pub executor: u32,
+    pub task: Option<u32>,
+    /// Microseconds since boot, records written by older firmware have none
+    pub time_us: Option<u64>,
+}
+
+impl TraceEvent {
+    /// Parse one `{TB|exid:..,tid:..,us:..}` record
+    fn parse(record: &str) -> Option<Self> {
+        let body = record.strip_prefix('{')?.strip_suffix('}')?;
+        let (tag, fields) = body.split_once('|')?;
+
+        let mut executor = None;
+        let mut task = None;
+        let mut time_us = None;
+        for field in fields.split(',') {
+            let (key, value) = field.split_once(':')?;
+            match key {
+                "exid" => executor = Some(value.parse().ok()?),
+                "tid" => task = Some(value.parse().ok()?),
+                "us" => time_us = Some(value.parse().ok()?),
+                _ => return None,
+            }
+        }
+
+        let kind = TraceKind::from_tag(tag)?;
+        if kind != TraceKind::Idle && task.is_none() {
+            return None;
+        }
+        Some(TraceEvent {
+            kind,
+            executor: executor?,
+            task,
+            time_us,
+        })
+    }
+}
+
+/// Reorder a ring buffer snapshot oldest first, dropping the sentinel and
+/// any bytes that were never written
+pub fn unroll(buf: &[u8]) -> Vec<u8> {
+    let mut data = match buf.iter().rposition(|&b| b == SENTINEL) {
+        Some(head) => [&buf[head + 1..], &buf[..head]].concat(),
+        None => buf.to_vec(),
+    };
+    data.retain(|&b| b != 0);
+    data
+}
+
+/// Parse the records of a trace buffer snapshot. The oldest record is
+/// usually cut in half by the wraparound, records that don't parse are
+/// skipped and counted.
+pub fn parse_trace(buf: &[u8]) -> (Vec<TraceEvent>, usize) {
+    let data = unroll(buf);
+    let text = String::from_utf8_lossy(&data);
+
+    let mut events = Vec::new();
+    let mut skipped = 0;
+    for record in text.split('\n').filter(|r| !r.is_empty()) {
+        match TraceEvent::parse(record) {
+            Some(event) => events.push(event),
+            None => skipped += 1,
+        }
+    }
+
+    (events, skipped)
+}
+
+/// Render events in the chrome://tracing JSON format. Each executor is a
+/// process and each task a thread, so task polls show up as slices. Events
+/// without a timestamp are spaced 1us apart in buffer order.
+pub fn to_chrome_json(events: &[TraceEvent]) -> String {
+    let mut json = String::from("{\"traceEvents\":[");
+    for (idx, event) in events.iter().enumerate() {
+        let ts = event.time_us.unwrap_or(idx as u64);
+        let tid = event.task.unwrap_or(0);
+        let (ph, name) = match event.kind {
+            TraceKind::NewTask => ("i", "spawn"),
+            TraceKind::ExecBegin => ("B", "poll"),
+            TraceKind::ExecEnd => ("E", "poll"),
+            TraceKind::Ready => ("i", "ready"),
+            TraceKind::Idle => ("i", "idle"),
+        };
+        if idx > 0 {
+            json.push(',');
+        }
+        let _ = write!(
+            json,
+            "{{\"name\":\"{}\",\"ph\":\"{}\",\"ts\":{},\"pid\":{},\"tid\":{}",
+            name, ph, ts, event.executor, tid
+        );
+        if ph == "i" {
+            json.push_str(",\"s\":\"t\"");
+        }
+        json.push('}');
+    }
+    json.push_str("]}");
+
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unroll_wraparound() {
+        assert_eq!(unroll(b"DEF@ABC"), b"ABCDEF");
+        assert_eq!(unroll(b"ABC@\0\0\0"), b"ABC");
+        assert_eq!(unroll(b"\0\0\0\0"), b"");
+    }
+
+    #[test]
+    fn parse_records() {
+        let buf = b"|tid:2,us:10}\n{TB|exid:1,tid:2,us:20}\n{EI|exid:1,us:30}\n@xid:1,t";
+        let (events, skipped) = parse_trace(buf);
+        assert_eq!(skipped, 1);
+        assert_eq!(
+            events,
+            vec![
+                TraceEvent {
+                    kind: TraceKind::ExecBegin,
+                    executor: 1,
+                    task: Some(2),
+                    time_us: Some(20),
+                },
+                TraceEvent {
+                    kind: TraceKind::Idle,
+                    executor: 1,
+                    task: None,
+                    time_us: Some(30),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_without_timestamps() {
+        let (events, skipped) = parse_trace(b"{TR|exid:3,tid:4}\n@\0\0");
+        assert_eq!(skipped, 0);
+        assert_eq!(events[0].kind, TraceKind::Ready);
+        assert_eq!(events[0].time_us, None);
+        assert!(TraceEvent::parse("{TB|exid:1}").is_none());
+        assert!(TraceEvent::parse("{XX|exid:1,tid:2}").is_none());
+    }
+
+    #[test]
+    fn chrome_json() {
+        let (events, _) = parse_trace(b"{TB|exid:1,tid:2,us:20}\n{TE|exid:1,tid:2,us:25}\n@");
+        assert_eq!(
+            to_chrome_json(&events),
+            "{\"traceEvents\":[\
+            {\"name\":\"poll\",\"ph\":\"B\",\"ts\":20,\"pid\":1,\"tid\":2},\
+            {\"name\":\"poll\",\"ph\":\"E\",\"ts\":25,\"pid\":1,\"tid\":2}]}"
+        );
+    }
+}
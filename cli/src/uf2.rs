@@ -6,6 +6,13 @@ const UF2_MAGIC_START0: [u8; 4] = [0x55, 0x46, 0x32, 0x0A];
 const UF2_MAGIC_START1: [u8; 4] = [0x57, 0x51, 0x5D, 0x9E];
 const UF2_MAGIC_END: [u8; 4] = [0x30, 0x6F, 0xB1, 0x0A];
 const UF2_PAYLOAD_LEN: usize = 476;
+/// The rp2040 bootrom only accepts 256 byte payloads at 256 byte aligned
+/// addresses
+const RP2040_PAYLOAD_LEN: usize = 256;
+/// Padding for the ends of the image, matches erased flash
+const RP2040_PAD: u8 = 0xff;
+
+pub const RP2040_FAMILY_ID: u32 = 0xe48bff56;
 
 #[derive(Debug, FromBytes, Immutable, KnownLayout, IntoBytes)]
 #[repr(C)]
@@ -63,18 +70,22 @@ impl Uf2Block {
             .expect("This should be checked when Uf2Block is constructed")
     }
 
+    #[allow(dead_code)]
     pub fn get_payload(&self) -> &[u8] {
         &self.payload[..self.payload_size as usize]
     }
 
+    #[allow(dead_code)]
     pub fn get_block_num(&self) -> u32 {
         self.block_num
     }
 
+    #[allow(dead_code)]
     pub fn get_extra_data(&self) -> u32 {
         self.extra_data
     }
 
+    #[allow(dead_code)]
     pub fn get_num_blocks(&self) -> u32 {
         self.num_blocks
     }
@@ -83,8 +94,19 @@ impl Uf2Block {
         (self.target_addr, self.target_addr + self.payload_size)
     }
 
+    pub fn get_family(&self) -> Option<u32> {
+        if self.get_flags().contains(Uf2Flags::FamilyIdPres) {
+            Some(self.extra_data)
+        } else {
+            None
+        }
+    }
+
+    /// Parse and validate a UF2 file. Block numbers must count up from 0 to
+    /// `num_blocks - 1` with no gaps. NotMainFlash blocks are numbered like
+    /// any other, so they only leave gaps in the target addresses.
     pub fn parse(data: &[u8]) -> anyhow::Result<Vec<Self>> {
-        if data.len() % 512 != 0 {
+        if !data.len().is_multiple_of(512) {
             bail!(
                 "Invalid UF2 block size ({} % 512 == {})",
                 data.len(),
@@ -92,7 +114,8 @@ impl Uf2Block {
             );
         }
 
-        data.chunks_exact(512)
+        let blocks = data
+            .chunks_exact(512)
             .map(|chunk| {
                 let mut target = Uf2Buffer::new();
                 target.bytes.clone_from_slice(chunk);
@@ -119,7 +142,80 @@ impl Uf2Block {
 
                 Ok(block)
             })
-            .collect::<Result<Vec<_>, _>>()
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Self::check_numbering(&blocks)?;
+
+        Ok(blocks)
+    }
+
+    fn check_numbering(blocks: &[Self]) -> Result<()> {
+        let Some(first) = blocks.first() else {
+            return Ok(());
+        };
+
+        let num_blocks = first.num_blocks;
+        for (idx, block) in blocks.iter().enumerate() {
+            if block.num_blocks != num_blocks {
+                bail!(
+                    "Inconsistent UF2 block count (block {} has {}, expected {})",
+                    idx,
+                    block.num_blocks,
+                    num_blocks
+                );
+            }
+            if block.block_num >= num_blocks {
+                bail!(
+                    "UF2 block number {} is out of range ({} blocks)",
+                    block.block_num,
+                    num_blocks
+                );
+            }
+            if block.block_num != idx as u32 {
+                bail!(
+                    "UF2 blocks are out of order (block {} is numbered {})",
+                    idx,
+                    block.block_num
+                );
+            }
+        }
+
+        if blocks.len() as u32 != num_blocks {
+            bail!(
+                "UF2 file is truncated ({} of {} blocks)",
+                blocks.len(),
+                num_blocks
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Split a flash image into blocks the rp2040 bootrom will accept
+    pub fn from_image(address: u32, data: &[u8], family: u32) -> Vec<Self> {
+        let start = address & !(RP2040_PAYLOAD_LEN as u32 - 1);
+        let mut padded = vec![RP2040_PAD; (address - start) as usize];
+        padded.extend_from_slice(data);
+        padded.resize(
+            padded.len().next_multiple_of(RP2040_PAYLOAD_LEN),
+            RP2040_PAD,
+        );
+
+        let num_blocks = (padded.len() / RP2040_PAYLOAD_LEN) as u32;
+        padded
+            .chunks_exact(RP2040_PAYLOAD_LEN)
+            .enumerate()
+            .map(|(idx, payload)| {
+                Uf2Block::new(
+                    Uf2Flags::FamilyIdPres,
+                    start + (idx * RP2040_PAYLOAD_LEN) as u32,
+                    payload,
+                    idx as u32,
+                    num_blocks,
+                    family,
+                )
+            })
+            .collect()
     }
 
     pub fn to_bytes(&self) -> &[u8] {
@@ -128,6 +224,47 @@ impl Uf2Block {
     }
 }
 
+/// A contiguous run of main flash blocks with the same family
+#[derive(Debug, PartialEq, Eq)]
+pub struct Uf2Region {
+    pub address: u32,
+    pub length: u32,
+    pub family: Option<u32>,
+}
+
+impl Uf2Region {
+    /// The regions the main flash blocks cover, in address order. Blocks
+    /// don't have to be in address order, but a block that isn't main flash
+    /// ends a run even between two that would be contiguous.
+    pub fn from_blocks(blocks: &[Uf2Block]) -> Vec<Self> {
+        let mut regions: Vec<Uf2Region> = Vec::new();
+
+        for run in blocks.split(|b| b.get_flags().contains(Uf2Flags::NotMainFlash)) {
+            let mut run: Vec<&Uf2Block> = run.iter().collect();
+            run.sort_by_key(|block| block.get_bounds().0);
+
+            let first = regions.len();
+            for block in run {
+                let (start, end) = block.get_bounds();
+                let family = block.get_family();
+                match regions[first..].last_mut() {
+                    Some(last) if last.address + last.length == start && last.family == family => {
+                        last.length = end - last.address;
+                    }
+                    _ => regions.push(Uf2Region {
+                        address: start,
+                        length: end - start,
+                        family,
+                    }),
+                }
+            }
+        }
+        regions.sort_by_key(|region| region.address);
+
+        regions
+    }
+}
+
 #[repr(align(4))]
 #[derive(IntoBytes, Immutable, FromBytes)]
 struct Uf2Buffer {
@@ -150,6 +287,7 @@ bitflags! {
     }
 }
 
+#[allow(dead_code)]
 #[derive(FromBytes)]
 #[repr(C)]
 struct Uf2Checksum {
@@ -158,6 +296,7 @@ struct Uf2Checksum {
     checksum: [u8; 16],
 }
 
+#[allow(dead_code)]
 #[derive(FromBytes)]
 #[repr(C)]
 struct Uf2Tag {
@@ -165,3 +304,199 @@ struct Uf2Tag {
     designator: [u8; 3],
     payload: [u8],
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAMILY: u32 = RP2040_FAMILY_ID;
+
+    fn build_file(blocks: &[(Uf2Flags, u32, usize)]) -> Vec<u8> {
+        let num_blocks = blocks.len() as u32;
+        blocks
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, (flags, addr, len))| {
+                let block = Uf2Block::new(
+                    Uf2Flags::from_bits_retain(flags.bits()),
+                    *addr,
+                    &vec![0xAA; *len],
+                    idx as u32,
+                    num_blocks,
+                    FAMILY,
+                );
+                block.to_bytes().to_vec()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn multi_region() {
+        let file = build_file(&[
+            (Uf2Flags::FamilyIdPres, 0x1000_0000, 256),
+            (Uf2Flags::FamilyIdPres, 0x1000_0100, 256),
+            (Uf2Flags::NotMainFlash, 0x1000_0200, 256),
+            (Uf2Flags::FamilyIdPres, 0x1001_0000, 256),
+            (Uf2Flags::empty(), 0x1001_0100, 128),
+        ]);
+
+        let blocks = Uf2Block::parse(&file).unwrap();
+        assert_eq!(
+            Uf2Region::from_blocks(&blocks),
+            vec![
+                Uf2Region {
+                    address: 0x1000_0000,
+                    length: 512,
+                    family: Some(FAMILY)
+                },
+                Uf2Region {
+                    address: 0x1001_0000,
+                    length: 256,
+                    family: Some(FAMILY)
+                },
+                Uf2Region {
+                    address: 0x1001_0100,
+                    length: 128,
+                    family: None
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn out_of_order_regions() {
+        let file = build_file(&[
+            (Uf2Flags::FamilyIdPres, 0x1001_0000, 256),
+            (Uf2Flags::FamilyIdPres, 0x1000_0100, 256),
+            (Uf2Flags::FamilyIdPres, 0x1000_0000, 256),
+            (Uf2Flags::FamilyIdPres, 0x1001_0100, 256),
+            // Splits the run even though the addresses would continue it
+            (Uf2Flags::NotMainFlash, 0x2000_0000, 256),
+            (Uf2Flags::FamilyIdPres, 0x1001_0200, 256),
+            (Uf2Flags::FamilyIdPres, 0x0000_0000, 256),
+        ]);
+
+        let blocks = Uf2Block::parse(&file).unwrap();
+        let region = |address, length| Uf2Region {
+            address,
+            length,
+            family: Some(FAMILY),
+        };
+        assert_eq!(
+            Uf2Region::from_blocks(&blocks),
+            vec![
+                region(0x0000_0000, 256),
+                region(0x1000_0000, 512),
+                region(0x1001_0000, 512),
+                region(0x1001_0200, 256),
+            ]
+        );
+    }
+
+    #[test]
+    fn single_block() {
+        let file = build_file(&[(Uf2Flags::FamilyIdPres, 0x1000_0000, 128)]);
+        let blocks = Uf2Block::parse(&file).unwrap();
+        assert_eq!(
+            Uf2Region::from_blocks(&blocks),
+            vec![Uf2Region {
+                address: 0x1000_0000,
+                length: 128,
+                family: Some(FAMILY)
+            }]
+        );
+    }
+
+    #[test]
+    fn pack_image() {
+        let data: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        let blocks = Uf2Block::from_image(0x1000_0010, &data, FAMILY);
+
+        let file: Vec<u8> = blocks.iter().flat_map(|b| b.to_bytes()).copied().collect();
+        let parsed = Uf2Block::parse(&file).unwrap();
+        assert_eq!(parsed.len(), 2);
+        for (idx, block) in parsed.iter().enumerate() {
+            assert_eq!(block.get_block_num(), idx as u32);
+            assert_eq!(block.get_num_blocks(), 2);
+            assert_eq!(block.get_payload().len(), 256);
+        }
+        assert_eq!(&parsed[0].get_payload()[..0x10], &[0xff; 0x10]);
+        assert_eq!(&parsed[0].get_payload()[0x10..], &data[..240]);
+        assert_eq!(&parsed[1].get_payload()[..60], &data[240..]);
+        assert_eq!(
+            Uf2Region::from_blocks(&parsed),
+            vec![Uf2Region {
+                address: 0x1000_0000,
+                length: 512,
+                family: Some(FAMILY)
+            }]
+        );
+    }
+
+    fn build_block(block_num: u32, num_blocks: u32) -> Vec<u8> {
+        Uf2Block::new(
+            Uf2Flags::FamilyIdPres,
+            0x1000_0000 + block_num * 256,
+            &[0xAA; 256],
+            block_num,
+            num_blocks,
+            FAMILY,
+        )
+        .to_bytes()
+        .to_vec()
+    }
+
+    fn build_numbered(numbers: &[(u32, u32)]) -> Vec<u8> {
+        numbers
+            .iter()
+            .flat_map(|&(block_num, num_blocks)| build_block(block_num, num_blocks))
+            .collect()
+    }
+
+    #[test]
+    fn numbering_good() {
+        let file = build_numbered(&[(0, 3), (1, 3), (2, 3)]);
+        assert_eq!(Uf2Block::parse(&file).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn numbering_not_main_flash() {
+        let file = build_file(&[
+            (Uf2Flags::FamilyIdPres, 0x1000_0000, 256),
+            (Uf2Flags::NotMainFlash, 0x2000_0000, 256),
+            (Uf2Flags::FamilyIdPres, 0x1000_0100, 256),
+        ]);
+        assert_eq!(Uf2Block::parse(&file).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn numbering_truncated() {
+        let file = build_numbered(&[(0, 3), (1, 3)]);
+        assert!(Uf2Block::parse(&file).is_err());
+    }
+
+    #[test]
+    fn numbering_reordered() {
+        let file = build_numbered(&[(0, 3), (2, 3), (1, 3)]);
+        assert!(Uf2Block::parse(&file).is_err());
+    }
+
+    #[test]
+    fn numbering_inconsistent_count() {
+        let file = build_numbered(&[(0, 3), (1, 4), (2, 3)]);
+        assert!(Uf2Block::parse(&file).is_err());
+    }
+
+    #[test]
+    fn numbering_out_of_range() {
+        let file = build_numbered(&[(0, 2), (1, 2), (2, 2)]);
+        assert!(Uf2Block::parse(&file).is_err());
+    }
+
+    #[test]
+    fn no_regions() {
+        let file = build_file(&[(Uf2Flags::NotMainFlash, 0x1000_0000, 256)]);
+        let blocks = Uf2Block::parse(&file).unwrap();
+        assert_eq!(Uf2Region::from_blocks(&blocks), vec![]);
+    }
+}
@@ -0,0 +1,52 @@
+//! Bakes the git hash and build time into the firmware, `GetBuildInfo`
+//! answers with them
+
+use std::{
+    env,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Output of a git command, None if git isn't there or it failed
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn main() {
+    let dirty = git(&["status", "--porcelain"]).is_some_and(|status| !status.is_empty());
+    let git_hash = match git(&["rev-parse", "--short=8", "HEAD"]) {
+        Some(hash) if dirty => format!("{hash}-dirty"),
+        Some(hash) => hash,
+        None => String::from("unknown"),
+    };
+
+    // Reproducible builds pin the time
+    let built = match env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => epoch.parse().expect("SOURCE_DATE_EPOCH isn't a number"),
+        Err(_) => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs()),
+    };
+
+    println!("cargo:rustc-env=PICODOX_GIT_HASH={git_hash}");
+    println!("cargo:rustc-env=PICODOX_BUILT={built}");
+
+    // Run again when the sources or the checked out commit change, so the
+    // hash, its dirty flag and the time stay current
+    for path in [
+        "src",
+        "build.rs",
+        "Cargo.toml",
+        "memory.x",
+        "../proto/src",
+        "../.git/HEAD",
+        "../.git/index",
+    ] {
+        println!("cargo:rerun-if-changed={path}");
+    }
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
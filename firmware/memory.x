@@ -1,7 +1,13 @@
 MEMORY {
     BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
-    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x200
-    STATE : ORIGIN = ORIGIN(FLASH) + LENGTH(FLASH), LENGTH = 0x100
+    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100 - 4K
+    /* Partitions written at runtime must be aligned to the 4K erase sectors */
+    STATE : ORIGIN = ORIGIN(FLASH) + LENGTH(FLASH), LENGTH = 4K
+    /* One erase sector for persisted settings, see settings.rs */
+    SETTINGS : ORIGIN = ORIGIN(STATE) + LENGTH(STATE), LENGTH = 4K
+    /* Firmware updates land here, see dfu.rs. Must be at least one sector */
+    /* larger than FLASH */
+    DFU : ORIGIN = ORIGIN(SETTINGS) + LENGTH(SETTINGS), LENGTH = 2048K
 
     /* Pick one of the two options for RAM layout     */
 
@@ -17,3 +23,9 @@ MEMORY {
     /* SCRATCH_B: ORIGIN = 0x20041000, LENGTH = 4K    */
 }
 __state_offset = ORIGIN(STATE) - ORIGIN(BOOT2);
+__settings_offset = ORIGIN(SETTINGS) - ORIGIN(BOOT2);
+
+__bootloader_state_start = ORIGIN(STATE) - ORIGIN(BOOT2);
+__bootloader_state_end = ORIGIN(STATE) + LENGTH(STATE) - ORIGIN(BOOT2);
+__bootloader_dfu_start = ORIGIN(DFU) - ORIGIN(BOOT2);
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU) - ORIGIN(BOOT2);
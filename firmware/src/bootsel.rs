@@ -0,0 +1,73 @@
+//! Runtime polling of the BOOTSEL button
+//!
+//! BOOTSEL shares the QSPI chip select with the flash, so reading it means
+//! briefly disabling XIP. Each poll runs from RAM with interrupts disabled for
+//! roughly 4000 cycles (~32us at 125MHz), after waiting for any flash DMA to
+//! finish. To keep polls away from the settings/DFU flash accesses, the shared
+//! flash lock is held for the duration of each poll.
+
+use embassy_rp::{peripherals::BOOTSEL, rom_data};
+use embassy_time::Timer;
+
+use crate::settings::SharedFlash;
+
+const POLL_MS: u64 = 100;
+/// How long BOOTSEL must be held before the action fires
+const HOLD_POLLS: u32 = 10;
+
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+pub enum BootselAction {
+    /// Reboot into the USB bootloader
+    UsbDfu,
+    /// Reboot into the application
+    Reset,
+}
+
+pub struct BootselButton<'d> {
+    bootsel: BOOTSEL,
+    flash: &'d SharedFlash,
+    action: BootselAction,
+}
+
+impl<'d> BootselButton<'d> {
+    pub fn new(bootsel: BOOTSEL, flash: &'d SharedFlash, action: BootselAction) -> Self {
+        BootselButton {
+            bootsel,
+            flash,
+            action,
+        }
+    }
+
+    async fn is_pressed(&mut self) -> bool {
+        let _flash = self.flash.lock().await;
+        self.bootsel.is_pressed()
+    }
+
+    pub async fn run(mut self) -> ! {
+        let mut held = 0u32;
+        loop {
+            if self.is_pressed().await {
+                held += 1;
+            } else {
+                held = 0;
+            }
+
+            if held == HOLD_POLLS {
+                info!("BOOTSEL held, running action");
+                match self.action {
+                    BootselAction::UsbDfu => {
+                        crate::shutdown().await;
+                        rom_data::reset_to_usb_boot(0, 0);
+                    }
+                    BootselAction::Reset => {
+                        crate::shutdown().await;
+                        cortex_m::peripheral::SCB::sys_reset();
+                    }
+                }
+            }
+
+            Timer::after_millis(POLL_MS).await;
+        }
+    }
+}
@@ -1,49 +1,97 @@
-use core::future::pending;
+use core::ptr::addr_of;
 
-use defmt::{info, warn};
+use crc::{Crc, Digest};
 use embassy_boot::{AlignedBuffer, FirmwareUpdater, FirmwareUpdaterConfig};
-use embassy_rp::{
-    dma::AnyChannel,
-    flash::{self, Flash},
-    Peripheral, PeripheralRef,
-};
+use embassy_futures::yield_now;
+use embassy_rp::flash::ERASE_SIZE;
 use embassy_sync::{
     channel::{Channel, Receiver, Sender},
     mutex::{Mutex, MutexGuard},
+    signal::Signal,
 };
-use embedded_storage_async::nor_flash::NorFlash;
 use heapless::Vec;
-use picodox_proto::DATA_COUNT;
+use picodox_proto::proto_impl::FW_CRC;
 
-use crate::util::MutexType;
+use crate::{
+    settings::{SharedFlash, FLASH_SIZE},
+    util::MutexType,
+};
 
-const FLASH_SIZE: usize = 8 * 1024 * 1024;
-pub const FLASH_WRITE_BLOCK: usize = 4 * 1024;
+// Each block is erased right before it is written, so it must cover whole
+// erase sectors
+pub const FLASH_WRITE_BLOCK: usize = ERASE_SIZE;
+// Bytes read per step when checksumming flash, the executor gets a turn
+// between steps
+const CRC_CHUNK: usize = 1024;
+// A session's digest borrows it for as long as the session lives
+static IMAGE_CRC: Crc<u32> = FW_CRC;
+// Written to flash and read back by the self test
+const TEST_PATTERN: [u8; 256] = {
+    let mut pattern = [0u8; 256];
+    let mut idx = 0;
+    while idx < pattern.len() {
+        pattern[idx] = idx as u8 ^ 0xa5;
+        idx += 1;
+    }
+    pattern
+};
+
+// Bounds of the DFU partition from memory.x, the symbol addresses are the
+// flash offsets
+extern "C" {
+    static __bootloader_dfu_start: u8;
+    static __bootloader_dfu_end: u8;
+}
+
+/// Largest firmware image that can be written. The swap on the next boot
+/// needs the DFU partition to be one sector larger than the image.
+pub fn max_fw_size() -> u32 {
+    let start = addr_of!(__bootloader_dfu_start) as u32;
+    let end = addr_of!(__bootloader_dfu_end) as u32;
+    end - start - ERASE_SIZE as u32
+}
+
+/// Whether `len` bytes starting at `offset` fit in flash
+pub fn in_flash(offset: u32, len: u32) -> bool {
+    offset
+        .checked_add(len)
+        .is_some_and(|end| end as usize <= FLASH_SIZE)
+}
 
 pub struct FirmwareState {
     channel: Channel<MutexType, FirmwareCmd, 4>,
+    done: Signal<MutexType, ()>,
 }
 
 impl FirmwareState {
     pub fn new() -> Self {
         FirmwareState {
             channel: Channel::new(),
+            done: Signal::new(),
         }
     }
 
-    pub fn get_intf<'d>(&'d self) -> FirmwareIntf<'d> {
-        FirmwareIntf::new(self.channel.sender())
+    pub fn get_intf<'d>(&'d self, flash: &'d SharedFlash) -> FirmwareIntf<'d> {
+        FirmwareIntf::new(self.channel.sender(), &self.done, flash)
     }
 }
 
 pub struct FirmwareIntf<'d> {
     mutex: Mutex<MutexType, Sender<'d, MutexType, FirmwareCmd, 4>>,
+    done: &'d Signal<MutexType, ()>,
+    flash: &'d SharedFlash,
 }
 
 impl<'d> FirmwareIntf<'d> {
-    fn new(send: Sender<'d, MutexType, FirmwareCmd, 4>) -> Self {
+    fn new(
+        send: Sender<'d, MutexType, FirmwareCmd, 4>,
+        done: &'d Signal<MutexType, ()>,
+        flash: &'d SharedFlash,
+    ) -> Self {
         FirmwareIntf {
             mutex: Mutex::new(send),
+            done,
+            flash,
         }
     }
 
@@ -52,16 +100,126 @@ impl<'d> FirmwareIntf<'d> {
 
         FirmwareSession {
             guard,
+            done: self.done,
             offset: 0,
             data: Vec::new(),
+            digest: IMAGE_CRC.digest(),
         }
     }
+
+    /// CRC of `len` bytes of flash starting at `offset`, or None if the
+    /// region doesn't fit in flash
+    pub async fn crc(&self, offset: u32, len: u32) -> Option<u32> {
+        if !in_flash(offset, len) {
+            return None;
+        }
+        let end = offset + len;
+
+        // Holding the session lock keeps an update from being written
+        // underneath us
+        let _guard = self.mutex.lock().await;
+        let crc = &FW_CRC;
+        let mut digest = crc.digest();
+        let mut buf = [0u8; CRC_CHUNK];
+        let mut pos = offset;
+        while pos < end {
+            let count = core::cmp::min(CRC_CHUNK as u32, end - pos) as usize;
+            let read = self
+                .flash
+                .lock()
+                .await
+                .blocking_read(pos, &mut buf[..count]);
+            async_unwrap!(res read, "Failed to read flash at offset {}: {:?}", pos);
+            digest.update(&buf[..count]);
+            pos += count as u32;
+            yield_now().await;
+        }
+
+        Some(digest.finalize())
+    }
+
+    /// Erase, write and read back the last sector of the DFU partition, which
+    /// `max_fw_size` keeps images out of. The swap only passes through it
+    /// while booting an update, and it is left erased. Returns whether every
+    /// step worked.
+    pub async fn self_test(&self) -> bool {
+        let _guard = self.mutex.lock().await;
+        let end = addr_of!(__bootloader_dfu_end) as u32;
+        let start = end - ERASE_SIZE as u32;
+        let mut flash = self.flash.lock().await;
+
+        if let Err(e) = flash.blocking_erase(start, end) {
+            warn!("Self test erase at {} failed: {:?}", start, e);
+            return false;
+        }
+        let mut read = [0u8; TEST_PATTERN.len()];
+        let passed = match flash.blocking_write(start, &TEST_PATTERN) {
+            Ok(()) => match flash.blocking_read(start, &mut read) {
+                Ok(()) if read == TEST_PATTERN => true,
+                Ok(()) => {
+                    warn!("Self test read back different data at {}", start);
+                    false
+                }
+                Err(e) => {
+                    warn!("Self test read at {} failed: {:?}", start, e);
+                    false
+                }
+            },
+            Err(e) => {
+                warn!("Self test write at {} failed: {:?}", start, e);
+                false
+            }
+        };
+        if let Err(e) = flash.blocking_erase(start, end) {
+            warn!("Self test erase at {} failed: {:?}", start, e);
+            return false;
+        }
+
+        passed
+    }
+
+    /// Erase the whole DFU partition, one block at a time so the executor
+    /// gets a turn in between. Erasing it in one go stalls for seconds, long
+    /// enough for the watchdog to fire.
+    pub async fn erase(&self) {
+        let _guard = self.mutex.lock().await;
+        let start = addr_of!(__bootloader_dfu_start) as u32;
+        let end = addr_of!(__bootloader_dfu_end) as u32;
+        if (start | end) % FLASH_WRITE_BLOCK as u32 != 0 {
+            async_panic!(
+                "DFU partition {}..{} isn't aligned to {} byte blocks",
+                start,
+                end,
+                FLASH_WRITE_BLOCK
+            )
+        }
+
+        let mut pos = start;
+        while pos < end {
+            let next = pos + FLASH_WRITE_BLOCK as u32;
+            let erase = self.flash.lock().await.blocking_erase(pos, next);
+            async_unwrap!(res erase, "Failed to erase flash at offset {}: {:?}", pos);
+            pos = next;
+            yield_now().await;
+        }
+    }
+
+    /// Fill `buf` from flash starting at `offset`, which has to be checked
+    /// with `in_flash` first. Blocking reads have no alignment requirements.
+    pub async fn read(&self, offset: u32, buf: &mut [u8]) {
+        let _guard = self.mutex.lock().await;
+        let read = self.flash.lock().await.blocking_read(offset, buf);
+        async_unwrap!(res read, "Failed to read flash at offset {}: {:?}", offset);
+    }
 }
 
 pub struct FirmwareSession<'a, 'd> {
     guard: MutexGuard<'a, MutexType, Sender<'d, MutexType, FirmwareCmd, 4>>,
+    done: &'d Signal<MutexType, ()>,
     offset: u32,
     data: Vec<u8, FLASH_WRITE_BLOCK>,
+    /// Over every byte written so far
+    digest: Digest<'static, u32>,
 }
 
 impl<'a, 'd> FirmwareSession<'a, 'd> {
@@ -69,19 +227,50 @@ impl<'a, 'd> FirmwareSession<'a, 'd> {
         self.guard.send(FirmwareCmd::Begin).await;
     }
 
-    pub async fn finish(&mut self) {
+    /// Flush the last partial block and wait for the update to be written.
+    /// It is only marked for the next boot if the image matches `expected`,
+    /// otherwise the CRC that was calculated is returned and the running
+    /// firmware stays.
+    pub async fn finish(mut self, expected: u32) -> Result<(), u32> {
         if !self.data.is_empty() {
             self.write_block().await;
         }
-        self.guard.send(FirmwareCmd::Finish).await;
+        let calculated = self.digest.finalize();
+        let verified = calculated == expected;
+        self.end(verified).await;
+
+        if verified {
+            Ok(())
+        } else {
+            Err(calculated)
+        }
     }
 
-    pub async fn write(&mut self, data: &[u8; DATA_COUNT]) {
-        if self.data.is_full() {
-            self.write_block().await;
+    /// Drop an update that wasn't sent in full, the running firmware stays
+    pub async fn abort(self) {
+        self.end(false).await;
+    }
+
+    async fn end(&self, verified: bool) {
+        self.done.reset();
+        self.guard.send(FirmwareCmd::Finish { verified }).await;
+        self.done.wait().await;
+    }
+
+    pub async fn write(&mut self, mut data: &[u8]) {
+        self.digest.update(data);
+        // Chunks don't have to line up with the blocks, a chunk can straddle
+        // two of them
+        while !data.is_empty() {
+            if self.data.is_full() {
+                self.write_block().await;
+            }
+            let take = core::cmp::min(data.len(), self.data.capacity() - self.data.len());
+            let (now, rest) = data.split_at(take);
+            async_unwrap!(res self.data.extend_from_slice(now),
+                "Firmware block overflow {:?}");
+            data = rest;
         }
-        async_unwrap!(res self.data.extend_from_slice(data),
-            "Block size not divisible by DATA_COUNT {}");
     }
 
     async fn write_block(&mut self) {
@@ -100,7 +289,10 @@ impl<'a, 'd> FirmwareSession<'a, 'd> {
 
 enum FirmwareCmd {
     Begin,
-    Finish,
+    /// Mark the update for the next boot if `verified`, otherwise drop it
+    Finish {
+        verified: bool,
+    },
     Block(FirmwareBlock),
 }
 
@@ -109,69 +301,62 @@ struct FirmwareBlock {
     offset: u32,
 }
 
-pub struct FirmwareRecvr<'d, F: flash::Instance> {
-    flash: PeripheralRef<'d, F>,
-    dma: PeripheralRef<'d, AnyChannel>,
+pub struct FirmwareRecvr<'d> {
+    flash: &'d SharedFlash,
     cmd_recv: Receiver<'d, MutexType, FirmwareCmd, 4>,
+    done: &'d Signal<MutexType, ()>,
 }
 
-impl<'d, F> FirmwareRecvr<'d, F>
-where
-    F: flash::Instance + Peripheral<P = F>,
-{
-    pub fn new<'a>(
-        flash_p: F,
-        dma_p: impl Peripheral<P = AnyChannel> + 'd,
-        state: &'d FirmwareState,
-    ) -> Self {
-        let flash = flash_p.into_ref();
-        let dma = dma_p.into_ref();
-
+impl<'d> FirmwareRecvr<'d> {
+    pub fn new(flash: &'d SharedFlash, state: &'d FirmwareState) -> Self {
         let cmd_recv = state.channel.receiver();
 
         Self {
             flash,
-            dma,
             cmd_recv,
+            done: &state.done,
         }
     }
 
     pub async fn run(self) -> ! {
-        let flash = Flash::<_, _, FLASH_SIZE>::new(self.flash, self.dma);
-        let flash_mutex = Mutex::new(flash);
-        let config = FirmwareUpdaterConfig::from_linkerfile(&flash_mutex, &flash_mutex);
+        let config = FirmwareUpdaterConfig::from_linkerfile(self.flash, self.flash);
         let mut aligned = AlignedBuffer([0; 4]);
         let mut updater = FirmwareUpdater::new(config, &mut aligned.0);
         loop {
             loop {
                 match self.cmd_recv.receive().await {
                     FirmwareCmd::Begin => break,
-                    FirmwareCmd::Finish => warn!("Spurious FirmwareCmd::Finish received"),
+                    FirmwareCmd::Finish { .. } => {
+                        warn!("Spurious FirmwareCmd::Finish received")
+                    }
                     FirmwareCmd::Block(_) => warn!("Spurious FirmwareCmd::Block(_) received"),
                 }
             }
 
-            let writer = async_unwrap!(res updater.prepare_update().await,
-                "Error preparing for DFU update: {}");
-            loop {
+            let verified = loop {
                 match self.cmd_recv.receive().await {
                     FirmwareCmd::Begin => warn!("Second DFU started without finishing first"),
-                    FirmwareCmd::Finish => break,
+                    FirmwareCmd::Finish { verified } => break verified,
                     FirmwareCmd::Block(block) => {
                         info!("Writing block at offset {}", block.offset);
-                        // ITS THIS DAMN LINE
-                        //async_unwrap!(res writer.write(block.offset, &block.data.0[..]).await,
-                        //    "Failed to write block to offset {}: {}", block.offset);
+                        // Erases as it goes instead of prepare_update, for the
+                        // same reason as FirmwareIntf::erase
+                        async_unwrap!(res updater.write_firmware(block.offset as usize, &block.data.0).await,
+                            "Failed to write block to offset {}: {:?}", block.offset);
                     }
                 }
-            }
+            };
 
-            async_unwrap!(res updater.mark_updated().await,
-                "Failed to mark firmware as updated: {}");
+            if verified {
+                async_unwrap!(res updater.mark_updated().await,
+                    "Failed to mark firmware as updated: {:?}");
+                info!("Firmware update written");
+            } else {
+                // Left unmarked, the bootloader keeps booting the current
+                // firmware and the next update overwrites the partition
+                warn!("Firmware update dropped");
+            }
+            self.done.signal(());
         }
-
-        //loop {
-        //    pending::<()>().await;
-        //}
     }
 }
@@ -0,0 +1,52 @@
+//! Double tap of the reset button
+//!
+//! Pressing reset twice in quick succession reboots into the USB bootloader,
+//! so new firmware can be loaded without the serial interface. A flag is set
+//! on boot and cleared once the window has passed. It lives in RAM the
+//! runtime doesn't zero, so finding it still set means the last boot was cut
+//! short by another reset.
+
+use core::{mem::MaybeUninit, ptr::addr_of_mut};
+
+use embassy_rp::rom_data;
+use embassy_time::{Instant, Timer};
+
+/// Marks the window as open, anything else is leftover RAM
+const ARMED_MAGIC: u32 = 0x4442_4c52;
+
+#[no_mangle]
+#[link_section = ".uninit.DOUBLE_RESET"]
+static mut DOUBLE_RESET: MaybeUninit<u32> = MaybeUninit::uninit();
+
+fn flag() -> *mut u32 {
+    // Safety: only the address is taken, accesses go through volatile reads
+    // and writes of a u32, which any bit pattern is valid for
+    unsafe { (*addr_of_mut!(DOUBLE_RESET)).as_mut_ptr() }
+}
+
+/// Run first thing on boot. Enters the bootloader if the last boot was still
+/// within its window, otherwise opens the window for this one.
+pub fn check() {
+    // Safety: see flag, nothing else is running yet
+    unsafe {
+        if flag().read_volatile() == ARMED_MAGIC {
+            flag().write_volatile(0);
+            rom_data::reset_to_usb_boot(0, 0);
+            loop {}
+        }
+        flag().write_volatile(ARMED_MAGIC);
+    }
+}
+
+/// Close the window, a reset after this boots normally. Resets the firmware
+/// does on purpose close it first so they aren't taken for a tap.
+pub fn disarm() {
+    // Safety: see flag, a single aligned word is written
+    unsafe { flag().write_volatile(0) };
+}
+
+/// Close the window `window_ms` after boot
+pub async fn disarm_after(window_ms: u64) {
+    Timer::at(Instant::from_millis(window_ms)).await;
+    disarm();
+}
@@ -0,0 +1,130 @@
+//! Quadrature decoding for the rotary encoder
+//!
+//! Every edge on either channel is run through a transition table, so
+//! contact bounce shows up as a step forward and straight back and cancels
+//! out instead of needing a debounce delay. Only whole detents are passed on.
+//!
+//! The push switch has no second channel to cancel bounce against, it is
+//! read once it has been still for the configured debounce time. The keymap
+//! sees it as the key at `ENCODER_PRESS`.
+
+use core::{cell::Cell, future::pending};
+
+use embassy_futures::select::{select, select3, Either3};
+use embassy_rp::{
+    gpio::{Input, Pin, Pull},
+    Peripheral,
+};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::{settings::SharedConfig, util::MutexType};
+
+/// Steps between clicks, most encoders go through a full quadrature cycle
+/// per detent
+const STEPS_PER_DETENT: i32 = 4;
+
+/// Direction of a step, indexed by `previous AB << 2 | current AB`. Jumps
+/// that skip a state can't be decoded and count as 0.
+const TRANSITIONS: [i8; 16] = [0, -1, 1, 0, 1, 0, 0, -1, -1, 0, 0, 1, 0, 1, -1, 0];
+
+/// Detents turned that have not been reported yet, clockwise is positive,
+/// and whether the switch is pushed
+pub struct EncoderDelta {
+    detents: Mutex<MutexType, Cell<i32>>,
+    pressed: Mutex<MutexType, Cell<bool>>,
+}
+
+impl EncoderDelta {
+    pub const fn new() -> Self {
+        EncoderDelta {
+            detents: Mutex::new(Cell::new(0)),
+            pressed: Mutex::new(Cell::new(false)),
+        }
+    }
+
+    pub fn is_pressed(&self) -> bool {
+        self.pressed.lock(Cell::get)
+    }
+
+    fn add(&self, detents: i32) {
+        self.detents
+            .lock(|d| d.set(d.get().saturating_add(detents)));
+    }
+
+    /// Take everything turned since the last call
+    pub fn take(&self) -> i32 {
+        self.detents.lock(|d| d.replace(0))
+    }
+}
+
+pub struct Encoder<'d> {
+    a: Input<'d>,
+    b: Input<'d>,
+    /// Pulled low while pushed
+    switch: Input<'d>,
+    delta: &'d EncoderDelta,
+    /// Debounce time of the switch
+    config: &'d SharedConfig,
+}
+
+impl<'d> Encoder<'d> {
+    pub fn new(
+        a: impl Peripheral<P = impl Pin> + 'd,
+        b: impl Peripheral<P = impl Pin> + 'd,
+        switch: impl Peripheral<P = impl Pin> + 'd,
+        delta: &'d EncoderDelta,
+        config: &'d SharedConfig,
+    ) -> Self {
+        Encoder {
+            a: Input::new(a, Pull::Up),
+            b: Input::new(b, Pull::Up),
+            switch: Input::new(switch, Pull::Up),
+            delta,
+            config,
+        }
+    }
+
+    fn read(&self) -> u8 {
+        (u8::from(self.a.is_high()) << 1) | u8::from(self.b.is_high())
+    }
+
+    pub async fn run(mut self) -> ! {
+        let mut state = self.read();
+        let mut steps = 0i32;
+        // When the switch will have been still long enough to read it
+        let mut switch_settled = Some(Instant::now());
+        loop {
+            let settled = async move {
+                match switch_settled {
+                    Some(at) => Timer::at(at).await,
+                    None => pending().await,
+                }
+            };
+            let turned = select(self.a.wait_for_any_edge(), self.b.wait_for_any_edge());
+            match select3(turned, self.switch.wait_for_any_edge(), settled).await {
+                Either3::First(_) => {
+                    let next = self.read();
+                    steps += i32::from(TRANSITIONS[usize::from(state << 2 | next)]);
+                    state = next;
+
+                    let detents = steps / STEPS_PER_DETENT;
+                    if detents != 0 {
+                        self.delta.add(detents);
+                        steps -= detents * STEPS_PER_DETENT;
+                    }
+                }
+                Either3::Second(()) => {
+                    let debounce_ms = self.config.lock(Cell::get).debounce_ms;
+                    switch_settled =
+                        Some(Instant::now() + Duration::from_millis(debounce_ms.into()));
+                }
+                Either3::Third(()) => {
+                    switch_settled = None;
+                    let pressed = self.switch.is_low();
+                    self.delta.pressed.lock(|p| p.set(pressed));
+                }
+            }
+        }
+    }
+}
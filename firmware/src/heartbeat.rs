@@ -0,0 +1,103 @@
+use core::sync::atomic::Ordering;
+
+use embassy_rp::{pac, watchdog::Watchdog};
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+use portable_atomic::AtomicBool;
+
+use crate::{
+    i2c::PeerLink,
+    neopixel::{Color, LedUpdate},
+    util::MutexType,
+};
+
+/// How long the executor can go without running the heartbeat before the
+/// watchdog resets the chip. Erasing a flash sector blocks the executor for
+/// up to 400ms, and flash is only ever erased one sector at a time.
+const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(2);
+/// Left in a watchdog scratch register while the heartbeat runs the watchdog.
+/// The bootrom reboots through the watchdog too (reset_to_usb_boot, after a
+/// UF2 copy) but overwrites this register, the same trick the pico-sdk uses.
+const WATCHDOG_MAGIC: u32 = 0x6ab7_3121;
+const MAGIC_SCRATCH: usize = 4;
+const TICK_MS: u64 = 50;
+
+// Intensity of the heartbeat at each tick, a double pulse followed by a pause.
+// One cycle lasts PULSE.len() * TICK_MS = 1.2s
+const PULSE: [u8; 24] = [
+    64, 255, 64, 0, 64, 255, 64, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+];
+
+/// Set once something else takes over the LED
+static LED_RELEASED: AtomicBool = AtomicBool::new(false);
+
+/// Stop drawing the heartbeat so LED updates from the host aren't overwritten,
+/// the watchdog is still fed
+pub fn release_led() {
+    LED_RELEASED.store(true, Ordering::Relaxed);
+}
+
+/// Whether the last reset was the heartbeat watchdog running out, as
+/// opposed to power on, a reset command or a bootrom reboot
+pub fn watchdog_fired(watchdog: &mut Watchdog) -> bool {
+    let fired = pac::WATCHDOG.reason().read().timer()
+        && watchdog.get_scratch(MAGIC_SCRATCH) == WATCHDOG_MAGIC;
+    watchdog.set_scratch(MAGIC_SCRATCH, 0);
+    fired
+}
+
+pub struct Heartbeat<'d, const N: usize> {
+    watchdog: Watchdog,
+    led_signal: &'d Signal<MutexType, LedUpdate<N>>,
+    color: Option<Color>,
+    link: &'d PeerLink,
+    link_down_color: Color,
+}
+
+impl<'d, const N: usize> Heartbeat<'d, N> {
+    /// The watchdog is fed from the same loop that drives the first LED, so
+    /// if the pulse stops the watchdog reset is not far behind. Passing
+    /// `None` for `color` still feeds the watchdog but leaves the LED alone.
+    /// While the other half is disconnected the pulse uses `link_down_color`.
+    pub fn new(
+        watchdog: Watchdog,
+        led_signal: &'d Signal<MutexType, LedUpdate<N>>,
+        color: Option<Color>,
+        link: &'d PeerLink,
+        link_down_color: Color,
+    ) -> Self {
+        Heartbeat {
+            watchdog,
+            led_signal,
+            color,
+            link,
+            link_down_color,
+        }
+    }
+
+    pub async fn run(mut self) -> ! {
+        self.watchdog.set_scratch(MAGIC_SCRATCH, WATCHDOG_MAGIC);
+        self.watchdog.start(WATCHDOG_TIMEOUT);
+
+        loop {
+            for intensity in PULSE {
+                self.watchdog.feed();
+                let color = self
+                    .color
+                    .filter(|_| !LED_RELEASED.load(Ordering::Relaxed))
+                    .map(|color| {
+                        if self.link.is_connected() {
+                            color
+                        } else {
+                            self.link_down_color
+                        }
+                    });
+                if let Some(color) = color {
+                    self.led_signal
+                        .signal(LedUpdate::Pixel(0, color.scaled(intensity)));
+                }
+                Timer::after_millis(TICK_MS).await;
+            }
+        }
+    }
+}
@@ -1,19 +1,114 @@
-use defmt::warn;
+use core::sync::atomic::Ordering;
+
 use embassy_rp::{
-    i2c::{Async, Config, I2c, Instance, InterruptHandler, SclPin, SdaPin},
-    i2c_slave::{self, Command},
+    i2c::{AbortReason, Async, Config, Error, I2c, Instance, InterruptHandler, SclPin, SdaPin},
+    i2c_slave::{self, Command, ReadStatus},
     interrupt::typelevel::Binding,
     Peripheral,
 };
 use embassy_sync::signal::Signal;
+use embassy_time::{with_deadline, with_timeout, Duration, Instant, Timer};
 use heapless::Vec;
-use picodox_proto::{proto_impl, KeyUpdate, WireSize};
+use picodox_proto::{
+    key_delta::{DeltaDecoder, DeltaEncoder},
+    proto_impl, KeyDelta, KeyFrame, KeyUpdate, LinkFrame, PointerMotion, WireSize,
+};
+use portable_atomic::AtomicBool;
+
+use crate::{
+    idle::IdleState,
+    trackball::{self, PointerDelta},
+    util::MutexType,
+};
+
+/// Consecutive arbitration losses before we assume another master is on the bus
+const CONFLICT_THRESHOLD: u32 = 5;
+/// Delay between transmissions while the bus is contested
+const CONFLICT_BACKOFF_MS: u64 = 1000;
+/// Delay before resending an update that failed to transmit, doubled on each
+/// further attempt
+const RETRY_MS: u64 = 10;
+/// Attempts at sending an update before it is reported as stalled
+const MAX_ATTEMPTS: u32 = 3;
+/// How often a stalled update is retried while waiting for the bus to recover
+const RECOVERY_POLL_MS: u64 = 100;
+/// The master sends a heartbeat after being idle this long, so each half can
+/// tell an idle peer from a missing one
+const KEEPALIVE_MS: u64 = 100;
+
+/// Whether the other half has been heard from recently. Readable from any
+/// task, e.g. for status lighting.
+pub struct PeerLink {
+    connected: AtomicBool,
+    /// Signaled on every frame exchanged with the other half
+    heard: Signal<MutexType, ()>,
+}
+
+impl PeerLink {
+    pub const fn new() -> Self {
+        PeerLink {
+            connected: AtomicBool::new(false),
+            heard: Signal::new(),
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    /// Wait for the next frame exchanged with the other half, which happens
+    /// at least every `KEEPALIVE_MS` while it is connected. Returns false if
+    /// none came within `timeout`.
+    pub async fn wait_heard(&self, timeout: Duration) -> bool {
+        self.heard.reset();
+        with_timeout(timeout, self.heard.wait()).await.is_ok()
+    }
 
-use crate::util::MutexType;
+    /// Note a frame from the other half, returns true if the link just came
+    /// up
+    fn heard(&self) -> bool {
+        self.heard.signal(());
+        self.set(true)
+    }
+
+    /// Returns true if the state changed
+    fn set(&self, connected: bool) -> bool {
+        self.connected.swap(connected, Ordering::Relaxed) != connected
+    }
+}
+/// How often the count of suppressed duplicate updates is logged
+const SUPPRESSED_LOG_EVERY: u32 = 100;
 
+/// Only the right half should be the master, which is selected by PIN_10
+/// being pulled high or by a stored hand override. If both halves end up
+/// as the right hand, neither answers as a slave, so most writes fail with
+/// NoAcknowledge the same as with the other half unplugged. That can't be
+/// told apart from a missing half and only shows as unanswered heartbeats.
+/// Writes that overlap lose arbitration to each other though, and after
+/// `CONFLICT_THRESHOLD` of those in a row the master backs off and only
+/// transmits once per `CONFLICT_BACKOFF_MS` until a write succeeds again.
 pub struct I2cMaster<'d, T: Instance> {
     bus: I2c<'d, T, Async>,
+    /// Address of the slave half
+    addr: u16,
+    /// Address of a trackball on the bus, if there is one
+    trackball: Option<u16>,
+    /// The trackball answered its last poll
+    trackball_ok: bool,
     signal: &'d Signal<MutexType, KeyUpdate>,
+    link: &'d PeerLink,
+    arbitration_losses: u32,
+    /// The last update the slave acknowledged
+    last_sent: Option<KeyUpdate>,
+    suppressed: u32,
+    /// Updates that ran out of attempts
+    stalled: u32,
+    /// Sequence number of the newest update
+    seq: u8,
+    /// Heartbeats in a row the slave didn't answer
+    missed_heartbeats: u32,
+    /// Set when updates are sent as deltas where that is shorter
+    delta: Option<DeltaEncoder>,
 }
 
 impl<'d, T: Instance> I2cMaster<'d, T> {
@@ -22,35 +117,271 @@ impl<'d, T: Instance> I2cMaster<'d, T> {
         scl: impl Peripheral<P = impl SclPin<T>> + 'd,
         sda: impl Peripheral<P = impl SdaPin<T>> + 'd,
         irq: impl Binding<T::Interrupt, InterruptHandler<T>>,
+        addr: u16,
+        trackball: Option<u16>,
         signal: &'d Signal<MutexType, KeyUpdate>,
+        link: &'d PeerLink,
+        delta_full_every: Option<u8>,
     ) -> Self {
         let config = Config::default();
         let bus = I2c::new_async(peri, scl, sda, irq, config);
 
-        I2cMaster { bus, signal }
+        I2cMaster {
+            bus,
+            addr,
+            trackball,
+            trackball_ok: true,
+            signal,
+            link,
+            arbitration_losses: 0,
+            last_sent: None,
+            suppressed: 0,
+            stalled: 0,
+            seq: 0,
+            missed_heartbeats: 0,
+            delta: delta_full_every.map(DeltaEncoder::new),
+        }
     }
 
     pub async fn run(&mut self) -> ! {
+        // Updates are only sent on changes, so a frame that failed to send
+        // is retried until it goes through or a newer update replaces it.
+        // After MAX_ATTEMPTS it is kept and retried slowly until the bus
+        // recovers.
+        let mut pending: Option<KeyUpdate> = None;
+        let mut attempts = 0u32;
         loop {
-            let ku = self.signal.wait().await;
-            let buffer: Vec<u8, { KeyUpdate::CS_MAX_SIZE }> = match proto_impl::cs_encode(&ku) {
+            let (ku, retry) = match pending.take() {
+                Some(failed) => {
+                    let newer = if attempts < MAX_ATTEMPTS {
+                        Timer::after_millis(RETRY_MS << (attempts - 1)).await;
+                        self.signal.try_take()
+                    } else {
+                        let poll = Duration::from_millis(RECOVERY_POLL_MS);
+                        with_timeout(poll, self.signal.wait()).await.ok()
+                    };
+                    match newer {
+                        Some(ku) => {
+                            attempts = 0;
+                            (ku, false)
+                        }
+                        None => (failed, true),
+                    }
+                }
+                None => {
+                    attempts = 0;
+                    match self.wait_update().await {
+                        Some(ku) => (ku, false),
+                        None => {
+                            // The slave released our keys while we were
+                            // gone, so send them again once it's back. Same
+                            // if it lost track of the deltas.
+                            let resend = self.heartbeat().await;
+                            match self.last_sent.clone() {
+                                Some(last) if resend => (last, true),
+                                _ => continue,
+                            }
+                        }
+                    }
+                }
+            };
+
+            if !retry && self.last_sent.as_ref() == Some(&ku) {
+                self.suppressed = self.suppressed.wrapping_add(1);
+                if self.suppressed % SUPPRESSED_LOG_EVERY == 0 {
+                    info!("Suppressed {} duplicate key updates", self.suppressed);
+                }
+                continue;
+            }
+
+            if !retry {
+                self.seq = self.seq.wrapping_add(1);
+            }
+            let frame = match &self.delta {
+                Some(delta) => delta.encode(self.seq, &ku),
+                None => LinkFrame::Keys(KeyFrame {
+                    seq: self.seq,
+                    update: ku.clone(),
+                }),
+            };
+            let buffer: Vec<u8, { LinkFrame::CS_MAX_SIZE }> = match proto_impl::cs_encode(&frame) {
                 Ok(b) => b,
                 Err(e) => {
-                    defmt::error!("I2C Encode Error: {:?}", e);
+                    error!("I2C Encode Error: {:?}", e);
                     continue;
                 }
             };
-            if let Err(e) = self.bus.write_async(0x55u16, buffer).await {
-                defmt::warn!("I2C Error: {:?}", e);
-                continue;
+            match self.bus.write_async(self.addr, buffer).await {
+                Ok(()) => {
+                    if self.arbitration_losses >= CONFLICT_THRESHOLD {
+                        info!("I2C bus conflict cleared");
+                    }
+                    self.arbitration_losses = 0;
+                    if self.link.heard() {
+                        info!("Other half connected");
+                    }
+                    if let Some(delta) = &mut self.delta {
+                        delta.acked(&frame, &ku);
+                    }
+                    self.last_sent = Some(ku);
+                }
+                Err(Error::Abort(AbortReason::ArbitrationLoss)) => {
+                    self.arbitration_losses += 1;
+                    if self.arbitration_losses == CONFLICT_THRESHOLD {
+                        warn!("I2C bus conflict, another master is on the bus - check the handedness jumper (PIN_10 low = left, high = right) and any hand override");
+                    }
+                    if self.arbitration_losses >= CONFLICT_THRESHOLD {
+                        Timer::after_millis(CONFLICT_BACKOFF_MS).await;
+                    }
+                    self.retry_later(&mut pending, &mut attempts, ku);
+                }
+                Err(e) => {
+                    warn!("I2C Error: {:?}", e);
+                    self.retry_later(&mut pending, &mut attempts, ku);
+                }
+            }
+        }
+    }
+
+    /// Wait up to KEEPALIVE_MS for a key update, polling the trackball in the
+    /// meantime. A poll only starts while no update is waiting and takes one
+    /// short transfer, so neither can hold up the other for long.
+    async fn wait_update(&mut self) -> Option<KeyUpdate> {
+        let deadline = Instant::now() + Duration::from_millis(KEEPALIVE_MS);
+        let Some(trackball) = self.trackball else {
+            return with_deadline(deadline, self.signal.wait()).await.ok();
+        };
+
+        loop {
+            let poll = Instant::now() + Duration::from_millis(crate::TRACKBALL_POLL_MS);
+            match with_deadline(poll.min(deadline), self.signal.wait()).await {
+                Ok(ku) => return Some(ku),
+                Err(_) if Instant::now() >= deadline => return None,
+                Err(_) => self.poll_trackball(trackball).await,
+            }
+        }
+    }
+
+    /// Read the trackball and pass any motion on to the slave
+    async fn poll_trackball(&mut self, addr: u16) {
+        let mut regs = [0u8; trackball::MOTION_REGS];
+        let res = self
+            .bus
+            .write_read_async(addr, [trackball::REG_LEFT], &mut regs)
+            .await;
+        if let Err(e) = res {
+            if self.trackball_ok {
+                warn!("Trackball isn't answering: {:?}", e);
+                self.trackball_ok = false;
+            }
+            return;
+        }
+        if !self.trackball_ok {
+            info!("Trackball answering again");
+            self.trackball_ok = true;
+        }
+
+        let motion = trackball::motion(&regs);
+        if motion == (PointerMotion { dx: 0, dy: 0 }) {
+            return;
+        }
+        let frame: Vec<u8, { LinkFrame::CS_MAX_SIZE }> =
+            match proto_impl::cs_encode(&LinkFrame::Pointer(motion)) {
+                Ok(b) => b,
+                Err(e) => {
+                    error!("I2C Encode Error: {:?}", e);
+                    return;
+                }
+            };
+        if let Err(e) = self.bus.write_async(self.addr, frame).await {
+            warn!("I2C Error: {:?}", e);
+        }
+    }
+
+    /// Exchange heartbeats with the slave, returns true if the last update
+    /// has to be sent again because this brought the link back up or the
+    /// slave asked for a resync
+    async fn heartbeat(&mut self) -> bool {
+        let frame: Vec<u8, { LinkFrame::CS_MAX_SIZE }> =
+            match proto_impl::cs_encode(&LinkFrame::Heartbeat) {
+                Ok(b) => b,
+                Err(e) => {
+                    error!("I2C Encode Error: {:?}", e);
+                    return false;
+                }
+            };
+        // The slave echoes the same frame back, or a Resync of the same
+        // length
+        let mut reply = [0u8; LinkFrame::CS_MAX_SIZE];
+        let reply = &mut reply[..frame.len()];
+        let res = self.bus.write_read_async(self.addr, frame, reply).await;
+
+        let reply = match res {
+            Ok(()) => proto_impl::cs_decode::<LinkFrame>(reply).ok(),
+            Err(_) => None,
+        };
+        if let Some(LinkFrame::Heartbeat | LinkFrame::Resync) = reply {
+            self.missed_heartbeats = 0;
+            let reconnected = self.link.heard();
+            if reconnected {
+                info!("Other half connected");
             }
+            let resync = reply == Some(LinkFrame::Resync);
+            if resync {
+                info!("Other half lost track of the key deltas, resending the keys in full");
+            }
+            if reconnected || resync {
+                if let Some(delta) = &mut self.delta {
+                    delta.resync();
+                }
+            }
+            reconnected || resync
+        } else {
+            self.missed_heartbeats = self.missed_heartbeats.saturating_add(1);
+            if self.missed_heartbeats == MAX_ATTEMPTS && self.link.set(false) {
+                warn!("Other half stopped answering heartbeats");
+            }
+            false
+        }
+    }
+
+    fn retry_later(&mut self, pending: &mut Option<KeyUpdate>, attempts: &mut u32, ku: KeyUpdate) {
+        // The slave may have taken the frame anyway, so no delta can build
+        // on either update
+        if let Some(delta) = &mut self.delta {
+            delta.resync();
         }
+        *attempts = attempts.saturating_add(1);
+        if *attempts == MAX_ATTEMPTS {
+            if self.link.set(false) {
+                warn!("Other half disconnected");
+            }
+            self.stalled = self.stalled.wrapping_add(1);
+            warn!(
+                "Key update failed {} times, retrying every {}ms ({} stalled updates so far)",
+                MAX_ATTEMPTS, RECOVERY_POLL_MS, self.stalled
+            );
+        }
+        *pending = Some(ku);
     }
 }
 
 pub struct I2cSlave<'d, T: Instance> {
     bus: i2c_slave::I2cSlave<'d, T>,
     signal: &'d Signal<MutexType, KeyUpdate>,
+    /// Collects the master's trackball motion for the HID interface
+    pointer: &'d PointerDelta,
+    link: &'d PeerLink,
+    /// The master's keys keep this half awake too
+    idle: &'d IdleState,
+    /// Without a frame for this long the master is taken to be disconnected
+    peer_timeout: Duration,
+    /// Sequence number of the last frame received
+    last_seq: Option<u8>,
+    /// Updates the master sent that never arrived
+    lost: u32,
+    /// Rebuilds the updates the master sends as deltas
+    deltas: DeltaDecoder,
 }
 
 impl<'d, T: Instance> I2cSlave<'d, T> {
@@ -59,42 +390,172 @@ impl<'d, T: Instance> I2cSlave<'d, T> {
         scl: impl Peripheral<P = impl SclPin<T>> + 'd,
         sda: impl Peripheral<P = impl SdaPin<T>> + 'd,
         irq: impl Binding<T::Interrupt, InterruptHandler<T>>,
+        addr: u16,
         signal: &'d Signal<MutexType, KeyUpdate>,
+        pointer: &'d PointerDelta,
+        link: &'d PeerLink,
+        idle: &'d IdleState,
+        peer_timeout_ms: u64,
     ) -> Self {
         let mut config = i2c_slave::Config::default();
-        config.addr = 0x55u16;
+        config.addr = addr;
         let bus = i2c_slave::I2cSlave::new(peri, scl, sda, irq, config);
 
-        I2cSlave { bus, signal }
+        I2cSlave {
+            bus,
+            signal,
+            pointer,
+            link,
+            idle,
+            peer_timeout: Duration::from_millis(peer_timeout_ms),
+            last_seq: None,
+            lost: 0,
+            deltas: DeltaDecoder::new(),
+        }
     }
 
     pub async fn run(&mut self) -> ! {
-        let mut buffer = [0u8; KeyUpdate::CS_MAX_SIZE];
+        let mut buffer = [0u8; LinkFrame::CS_MAX_SIZE];
 
         loop {
-            match self.bus.listen(&mut buffer).await {
+            // The master sends at least every KEEPALIVE_MS, so this only
+            // times out once the bus has been quiet for a while
+            let res = with_timeout(self.peer_timeout, self.bus.listen(&mut buffer)).await;
+            let Ok(res) = res else {
+                if self.link.set(false) {
+                    warn!("Other half disconnected, releasing its keys");
+                    self.signal.signal(KeyUpdate::no_keys());
+                    // Take whatever the master sends next, even a repeat
+                    self.last_seq = None;
+                    self.deltas.reset();
+                }
+                continue;
+            };
+
+            match res {
                 Ok(event) => match event {
-                    Command::GeneralCall(_) | Command::WriteRead(_) | Command::Read => {
+                    Command::GeneralCall(_) | Command::Read => {
                         warn!("Rv'd unexpected I2C")
                     }
+                    Command::Write(len) | Command::WriteRead(len) if len > buffer.len() => {
+                        error!("I2C frame overflow ({} bytes, max {})", len, buffer.len());
+                    }
                     Command::Write(len) => {
-                        let key_update: KeyUpdate = match proto_impl::cs_decode(&mut buffer[..len])
-                        {
-                            Ok(ku) => ku,
-                            Err(e) => {
-                                defmt::error!("I2C Decode Error: {:?}", e);
-                                continue;
-                            }
+                        let Some(frame) = self.decode(&mut buffer[..len]) else {
+                            continue;
                         };
-
-                        self.signal.signal(key_update);
+                        match frame {
+                            LinkFrame::Keys(frame) => {
+                                if self.check_seq(frame.seq) {
+                                    self.signal.signal(self.deltas.full(frame));
+                                    self.idle.activity();
+                                }
+                            }
+                            LinkFrame::KeyDelta(delta) => {
+                                if self.check_seq(delta.seq) {
+                                    self.key_delta(&delta);
+                                }
+                            }
+                            LinkFrame::Heartbeat | LinkFrame::Resync => {}
+                            LinkFrame::Pointer(motion) => self.pointer.add(motion),
+                        }
+                    }
+                    Command::WriteRead(len) => {
+                        if let Some(LinkFrame::Heartbeat) = self.decode(&mut buffer[..len]) {
+                            self.answer_heartbeat().await;
+                        } else {
+                            warn!("Rv'd unexpected I2C read request");
+                        }
                     }
                 },
+                Err(i2c_slave::Error::PartialWrite(len)) => {
+                    error!("I2C frame overflow (more than {} bytes)", len);
+                }
                 Err(e) => {
-                    defmt::error!("I2C Slave Error: {:?}", e);
+                    error!("I2C Slave Error: {:?}", e);
                     continue;
                 }
             }
         }
     }
+
+    /// Decode a frame, any valid frame counts as a sign of life
+    fn decode(&mut self, buffer: &mut [u8]) -> Option<LinkFrame> {
+        match proto_impl::cs_decode(buffer) {
+            Ok(frame) => {
+                if self.link.heard() {
+                    info!("Other half connected");
+                }
+                Some(frame)
+            }
+            Err(e) => {
+                // A short frame points at framing, a full length one at
+                // corruption on the wire
+                error!("I2C Decode Error: {:?} ({} bytes)", e, buffer.len());
+                None
+            }
+        }
+    }
+
+    /// Apply a delta, or drop it and wait for the master to resend the keys in
+    /// full
+    fn key_delta(&mut self, delta: &KeyDelta) {
+        let resyncing = self.deltas.needs_resync();
+        match self.deltas.delta(delta) {
+            Some(update) => {
+                self.signal.signal(update);
+                self.idle.activity();
+            }
+            None => {
+                if !resyncing {
+                    warn!(
+                        "Key delta {} builds on update {} which isn't the last one, asking for a resync",
+                        delta.seq, delta.base
+                    );
+                }
+                // The resent update reuses the newest sequence number
+                self.last_seq = None;
+            }
+        }
+    }
+
+    /// Echo the heartbeat, or ask for a resync if a delta was dropped
+    async fn answer_heartbeat(&mut self) {
+        let answer = if self.deltas.needs_resync() {
+            LinkFrame::Resync
+        } else {
+            LinkFrame::Heartbeat
+        };
+        let reply: Vec<u8, { LinkFrame::CS_MAX_SIZE }> = match proto_impl::cs_encode(&answer) {
+            Ok(b) => b,
+            Err(e) => {
+                error!("I2C Encode Error: {:?}", e);
+                return;
+            }
+        };
+        match self.bus.respond_and_fill(&reply, 0).await {
+            Ok(ReadStatus::Done) | Ok(ReadStatus::NeedMoreBytes) => {}
+            Ok(ReadStatus::LeftoverBytes(n)) => warn!("Heartbeat reply cut short by {} bytes", n),
+            Err(e) => error!("I2C Slave Error: {:?}", e),
+        }
+    }
+
+    /// Track the frame sequence numbers, returns false for a repeat of the
+    /// last frame (the master retries if it missed our ack)
+    fn check_seq(&mut self, seq: u8) -> bool {
+        let last = self.last_seq.replace(seq);
+        match last.map(|last| seq.wrapping_sub(last)) {
+            Some(0) => false,
+            Some(1) | None => true,
+            Some(gap) => {
+                self.lost = self.lost.wrapping_add(u32::from(gap - 1));
+                warn!(
+                    "Lost {} key updates from the other half ({} total)",
+                    gap - 1,
+                    self.lost
+                );
+                true
+            }
+        }
+    }
 }
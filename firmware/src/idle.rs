@@ -0,0 +1,96 @@
+//! Idle mode
+//!
+//! Once no key has changed for `Config::idle_timeout_s`, the LEDs go dark
+//! and the matrix is scanned at its idle rate. The next key change wakes
+//! everything up again. A half only sees its own keys, and on the left half
+//! the keys the right half sends across.
+
+use core::{cell::Cell, sync::atomic::Ordering};
+
+use embassy_sync::{blocking_mutex::Mutex, signal::Signal};
+use embassy_time::{Duration, Instant, Timer};
+use portable_atomic::AtomicBool;
+
+use crate::{settings::SharedConfig, util::MutexType};
+
+/// How often the timeout is checked again while idling is turned off
+const DISABLED_POLL_MS: u64 = 1000;
+
+/// Shared by the tasks that see key changes
+pub struct IdleState {
+    /// When a key last changed
+    last_activity: Mutex<MutexType, Cell<Instant>>,
+    idle: AtomicBool,
+    wake: Signal<MutexType, ()>,
+}
+
+impl IdleState {
+    pub const fn new() -> Self {
+        IdleState {
+            last_activity: Mutex::new(Cell::new(Instant::from_ticks(0))),
+            idle: AtomicBool::new(false),
+            wake: Signal::new(),
+        }
+    }
+
+    /// Note that a key changed, waking up if idle
+    pub fn activity(&self) {
+        self.last_activity.lock(|last| last.set(Instant::now()));
+        if self.idle.swap(false, Ordering::Relaxed) {
+            self.wake.signal(());
+        }
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.idle.load(Ordering::Relaxed)
+    }
+}
+
+/// Enters idle once the timeout has passed and dims the LEDs while idle
+pub struct IdleManager<'d> {
+    state: &'d IdleState,
+    config: &'d SharedConfig,
+    brightness_signal: &'d Signal<MutexType, u8>,
+}
+
+impl<'d> IdleManager<'d> {
+    pub fn new(
+        state: &'d IdleState,
+        config: &'d SharedConfig,
+        brightness_signal: &'d Signal<MutexType, u8>,
+    ) -> Self {
+        IdleManager {
+            state,
+            config,
+            brightness_signal,
+        }
+    }
+
+    pub async fn run(self) -> ! {
+        loop {
+            let timeout_s = self.config.lock(Cell::get).idle_timeout_s;
+            if timeout_s == 0 {
+                Timer::after_millis(DISABLED_POLL_MS).await;
+                continue;
+            }
+
+            // Keys that changed in the meantime push the deadline back
+            let idle_at =
+                self.state.last_activity.lock(Cell::get) + Duration::from_secs(timeout_s.into());
+            if Instant::now() < idle_at {
+                Timer::at(idle_at).await;
+                continue;
+            }
+
+            info!("Idle after {}s without a key, dimming the LEDs", timeout_s);
+            self.state.wake.reset();
+            self.state.idle.store(true, Ordering::Relaxed);
+            self.brightness_signal.signal(0);
+
+            self.state.wake.wait().await;
+            info!("Key pressed, waking up");
+            let brightness = self.config.lock(Cell::get).led_brightness;
+            self.brightness_signal.signal(brightness);
+        }
+    }
+}
@@ -1,27 +1,93 @@
-use defmt::{info, warn};
-use embassy_futures::join::join;
-use embassy_sync::signal::Signal;
-use embassy_time::Timer;
+use core::cell::Cell;
+
+use embassy_futures::{
+    join::join,
+    select::{select3, Either3},
+};
+use embassy_sync::{blocking_mutex, signal::Signal};
+use embassy_time::{Instant, Timer};
 use embassy_usb::{
     class::hid::{Config, HidReader, HidReaderWriter, HidWriter, ReportId, RequestHandler, State},
     control::OutResponse,
     driver::Driver,
     Builder,
 };
-use picodox_proto::{KeyState, KeyUpdate};
-use usbd_hid::descriptor::{KeyboardReport, SerializedDescriptor as _};
+use picodox_proto::{KeyState, KeyUpdate, ENCODER_PRESS};
+use usbd_hid::descriptor::{
+    KeyboardReport, KeyboardUsage, MediaKey, MediaKeyboardReport, MouseReport,
+    SerializedDescriptor as _, SystemControlReport,
+};
 
-use crate::util::MutexType;
+use crate::{encoder::EncoderDelta, trackball::PointerDelta, util::MutexType};
+
+/// The keys pressed on both halves and the encoder switch at the last HID
+/// update, before the keymap
+pub type SharedKeyState = blocking_mutex::Mutex<MutexType, Cell<KeyState>>;
+
+/// What turning the rotary encoder does
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum EncoderMode {
+    /// Clockwise scrolls down
+    Scroll,
+    /// Clockwise turns the volume up
+    Volume,
+    /// Clockwise taps the down arrow
+    Arrows,
+}
 
 pub trait Keymap {
-    fn get_report(&mut self, state: &KeyState) -> KeyboardReport;
+    /// Returns the keyboard report, and the consumer control usage (media
+    /// key) and system control usage (power key) that are held, if any.
+    /// `now_ms` is a monotonic timestamp for timing dependent keys.
+    fn get_report(
+        &mut self,
+        state: &KeyState,
+        now_ms: u64,
+    ) -> (KeyboardReport, Option<u16>, Option<u8>);
+
+    /// How the encoder is mapped, checked after every `get_report`
+    fn encoder_mode(&self) -> EncoderMode {
+        EncoderMode::Scroll
+    }
+}
+
+/// Turns detents into key taps. A tap is pressed for one report and released
+/// in the next, so at most one goes out every other update.
+#[derive(Default)]
+struct TapQueue {
+    pending: i32,
+    pressed: bool,
+}
+
+impl TapQueue {
+    /// Queue `detents` and return the direction to press in this report, if
+    /// any (true for clockwise)
+    fn next(&mut self, detents: i32) -> Option<bool> {
+        self.pending = self.pending.saturating_add(detents);
+        if self.pressed || self.pending == 0 {
+            self.pressed = false;
+            return None;
+        }
+        self.pressed = true;
+        let clockwise = self.pending > 0;
+        self.pending -= self.pending.signum();
+        Some(clockwise)
+    }
 }
 
 pub struct KeyboardIf<'d, D: Driver<'d>, K: Keymap> {
     reader: HidReader<'d, D, 1>,
     writer: HidWriter<'d, D, 8>,
+    media_writer: HidWriter<'d, D, 8>,
+    mouse_writer: HidWriter<'d, D, 8>,
+    system_writer: HidWriter<'d, D, 8>,
+    encoder: &'d EncoderDelta,
+    pointer: &'d PointerDelta,
     left_signal: &'d Signal<MutexType, KeyUpdate>,
     right_signal: &'d Signal<MutexType, KeyUpdate>,
+    matrix: &'d SharedKeyState,
+    /// Longest a report waits when no key changes, for timing dependent keys
     update_freq_ms: u32,
     keymap: K,
 }
@@ -30,25 +96,62 @@ impl<'d, D: Driver<'d>, K: Keymap> KeyboardIf<'d, D, K> {
     pub fn new(
         builder: &mut Builder<'d, D>,
         state: &'d mut State<'d>,
+        media_state: &'d mut State<'d>,
+        mouse_state: &'d mut State<'d>,
+        system_state: &'d mut State<'d>,
+        encoder: &'d EncoderDelta,
+        pointer: &'d PointerDelta,
         left_signal: &'d Signal<MutexType, KeyUpdate>,
         right_signal: &'d Signal<MutexType, KeyUpdate>,
+        matrix: &'d SharedKeyState,
+        poll_ms: u8,
         update_freq_ms: u32,
         keymap: K,
     ) -> Self {
         let config = Config {
             report_descriptor: KeyboardReport::desc(),
             request_handler: None,
-            poll_ms: 60,
+            poll_ms,
             max_packet_size: 64,
         };
         let hid = HidReaderWriter::<_, 1, 8>::new(builder, state, config);
         let (reader, writer) = hid.split();
 
+        let media_config = Config {
+            report_descriptor: MediaKeyboardReport::desc(),
+            request_handler: None,
+            poll_ms,
+            max_packet_size: 8,
+        };
+        let media_writer = HidWriter::<_, 8>::new(builder, media_state, media_config);
+
+        let mouse_config = Config {
+            report_descriptor: MouseReport::desc(),
+            request_handler: None,
+            poll_ms,
+            max_packet_size: 8,
+        };
+        let mouse_writer = HidWriter::<_, 8>::new(builder, mouse_state, mouse_config);
+
+        let system_config = Config {
+            report_descriptor: SystemControlReport::desc(),
+            request_handler: None,
+            poll_ms,
+            max_packet_size: 8,
+        };
+        let system_writer = HidWriter::<_, 8>::new(builder, system_state, system_config);
+
         KeyboardIf {
             reader,
             writer,
+            media_writer,
+            mouse_writer,
+            system_writer,
+            encoder,
+            pointer,
             left_signal,
             right_signal,
+            matrix,
             update_freq_ms,
             keymap,
         }
@@ -59,27 +162,117 @@ impl<'d, D: Driver<'d>, K: Keymap> KeyboardIf<'d, D, K> {
             let mut left = KeyUpdate::no_keys();
             let mut right = KeyUpdate::no_keys();
             let mut state;
+            let mut last_media = 0u16;
+            let mut last_system = 0u8;
+            let mut encoder_taps = TapQueue::default();
 
             loop {
                 if let Some(new_left) = self.left_signal.try_take() {
-                    info!("Left Update: {}", new_left.0.len());
+                    trace!("Left Update: {}", new_left.0.len());
                     left = new_left;
                 }
 
                 if let Some(new_right) = self.right_signal.try_take() {
-                    info!("Right Update: {}", new_right.0.len());
+                    trace!("Right Update: {}", new_right.0.len());
                     right = new_right;
                 }
 
                 state = KeyState::from_update(&left, &right);
-                let report = self.keymap.get_report(&state);
+                if self.encoder.is_pressed() {
+                    state.press(ENCODER_PRESS);
+                }
+                self.matrix.lock(|m| m.set(state));
+                let (mut report, mut media, system) =
+                    self.keymap.get_report(&state, Instant::now().as_millis());
+
+                // Detents and trackball motion are collected between updates,
+                // so they are reported at the same rate as the keys
+                let detents = self.encoder.take();
+                let (x, y) = self.pointer.take();
+                let mut wheel = 0;
+                match self.keymap.encoder_mode() {
+                    EncoderMode::Scroll => {
+                        encoder_taps = TapQueue::default();
+                        wheel = (-detents).clamp(i8::MIN.into(), i8::MAX.into()) as i8;
+                    }
+                    EncoderMode::Volume => {
+                        if let Some(clockwise) = encoder_taps.next(detents) {
+                            let key = if clockwise {
+                                MediaKey::VolumeIncrement
+                            } else {
+                                MediaKey::VolumeDecrement
+                            };
+                            media = Some(key as u16);
+                        }
+                    }
+                    EncoderMode::Arrows => {
+                        if let Some(clockwise) = encoder_taps.next(detents) {
+                            let key = if clockwise {
+                                KeyboardUsage::KeyboardDownArrow
+                            } else {
+                                KeyboardUsage::KeyboardUpArrow
+                            };
+                            if let Some(slot) = report.keycodes.iter_mut().find(|c| **c == 0) {
+                                *slot = key as u8;
+                            }
+                        }
+                    }
+                }
+
+                if wheel != 0 || x != 0 || y != 0 {
+                    let mouse_report = MouseReport {
+                        buttons: 0,
+                        x,
+                        y,
+                        wheel,
+                        pan: 0,
+                    };
+                    if let Err(e) = self.mouse_writer.write_serialize(&mouse_report).await {
+                        warn!("Failed to send mouse report: {:?}", e);
+                    }
+                }
 
                 match self.writer.write_serialize(&report).await {
                     Ok(()) => {}
                     Err(e) => warn!("Failed to send report: {:?}", e),
                 };
 
-                Timer::after_millis(self.update_freq_ms.into()).await;
+                // Consumer reports are only sent on changes, a usage of 0
+                // releases the previous key
+                let media = media.unwrap_or(0);
+                if media != last_media {
+                    let media_report = MediaKeyboardReport { usage_id: media };
+                    match self.media_writer.write_serialize(&media_report).await {
+                        Ok(()) => last_media = media,
+                        Err(e) => warn!("Failed to send media report: {:?}", e),
+                    };
+                }
+
+                // Same for system control, 0 is outside the usage range and
+                // releases the key
+                let system = system.unwrap_or(0);
+                if system != last_system {
+                    let system_report = SystemControlReport { usage_id: system };
+                    match self.system_writer.write_serialize(&system_report).await {
+                        Ok(()) => last_system = system,
+                        Err(e) => warn!("Failed to send system report: {:?}", e),
+                    };
+                }
+
+                // Key updates go out right away instead of waiting for the
+                // next update, which only matters for timing dependent keys
+                let idle = Timer::after_millis(self.update_freq_ms.into());
+                match select3(self.left_signal.wait(), self.right_signal.wait(), idle).await {
+                    Either3::First(new_left) => {
+                        trace!("Left Update: {}", new_left.0.len());
+                        left = new_left;
+                    }
+                    Either3::Second(new_right) => {
+                        trace!("Right Update: {}", new_right.0.len());
+                        right = new_right;
+                    }
+                    Either3::Third(()) => {}
+                }
             }
         };
 
@@ -100,7 +293,7 @@ impl RequestHandler for MyRequestHandler {
     }
 
     fn set_report(&mut self, id: ReportId, data: &[u8]) -> OutResponse {
-        info!("Set report for {:?}: {=[u8]}", id, data);
+        info!("Set report for {:?}: {:?}", id, data);
         OutResponse::Accepted
     }
 
@@ -1,9 +1,22 @@
-use defmt::info;
+use core::cell::Cell;
+
 use heapless::Vec;
-use picodox_proto::{KeyState, NUM_KEYS};
+use picodox_proto::{
+    caps_word::CapsWord,
+    combo::{combo, Combo, ComboResolver},
+    key_codes::*,
+    keymap::{LayerTable, NUM_LAYERS},
+    leader::{sequence, LeaderResolver, Sequence},
+    macro_player::MacroPlayer,
+    one_shot::OneShotMods,
+    KeyState, ENCODER_PRESS, NUM_KEYS,
+};
 use usbd_hid::descriptor::KeyboardReport;
 
-use crate::{key_codes::*, key_hid::Keymap};
+use crate::{
+    key_hid::{EncoderMode, Keymap},
+    settings::{SharedConfig, SharedKeymap, SharedMacros},
+};
 
 const fn l(idx: usize) -> usize {
     idx - 1
@@ -13,8 +26,8 @@ const fn r(idx: usize) -> usize {
     NUM_KEYS + idx - 1
 }
 
-const fn from_pairs(pairs: &[(usize, Key)]) -> [Key; 2 * NUM_KEYS] {
-    let mut result = [KEY_NONE; 2 * NUM_KEYS];
+const fn from_pairs(pairs: &[(usize, Key)]) -> [Key; KeyState::LEN] {
+    let mut result = [KEY_NONE; KeyState::LEN];
     let mut arr_idx = 0;
     while arr_idx < pairs.len() {
         let (idx, code) = pairs[arr_idx];
@@ -25,7 +38,7 @@ const fn from_pairs(pairs: &[(usize, Key)]) -> [Key; 2 * NUM_KEYS] {
     result
 }
 
-const KEY_MATRIX: [Key; 2 * NUM_KEYS] = [
+const KEY_MATRIX: [Key; KeyState::LEN] = [
     // -- LEFT Side --
     // K1-K7
     KEY_NONE,
@@ -103,60 +116,483 @@ const KEY_MATRIX: [Key; 2 * NUM_KEYS] = [
     // K29-K35
     KEY_ENTER,
     KEY_SPACE,
-    KEY_NONE,
+    mo(NAV),
     KEY_LEFT,
     KEY_DOWN,
     KEY_UP,
     KEY_RIGHT,
+    // -- Encoder push --
+    KEY_MEDIA_MUTE,
 ];
 
-const NAV_MATRIX: [Key; 2 * NUM_KEYS] = from_pairs(&[
+/// KEY_NONE entries are transparent, the key falls through to lower layers
+const NAV_MATRIX: [Key; KeyState::LEN] = from_pairs(&[
+    (r(29), tg(NAV)),
     (r(16), KEY_LEFT),
     (r(17), KEY_DOWN),
     (r(18), KEY_UP),
     (r(19), KEY_RIGHT),
+    // Media keys on 6-9
+    (r(2), KEY_MEDIA_MUTE),
+    (r(3), KEY_MEDIA_VOLUMEDOWN),
+    (r(4), KEY_MEDIA_VOLUMEUP),
+    (r(5), KEY_MEDIA_PLAYPAUSE),
+    // The encoder turns the volume on this layer
+    (ENCODER_PRESS, KEY_MEDIA_PLAYPAUSE),
 ]);
 
-#[derive(Default)]
-pub struct BasicKeymap {
-    last_lparen: bool,
-    last_rparen: bool,
+/// Key pairs that send another key when pressed together, by matrix
+/// position. They only start on the base layer, on the others the same keys
+/// are arrows and such. Only plain, modifier, consumer and system keys can
+/// be sent.
+const COMBOS: [Combo<Key>; 1] = [
+    // J+K
+    combo(r(17), r(18), KEY_ESC),
+];
+
+/// Keys typed after `KEY_LEADER` and what they do, by the keys they resolve
+/// to. Key codes, modifiers, consumer and system keys are tapped, macro keys
+/// play their macro and layer keys toggle their layer. There is no leader key
+/// on the default layers, put `leader` in an uploaded keymap to use these.
+const LEADER_SEQUENCES: [Sequence<'static, Key, Key>; 3] = [
+    sequence(&[KEY_N], tg(NAV)),
+    sequence(&[KEY_M, KEY_M], KEY_MEDIA_MUTE),
+    sequence(&[KEY_M, KEY_P], KEY_MEDIA_PLAYPAUSE),
+];
+
+/// Firmware auto-repeat for a held key: after `delay_ms` it is released for
+/// one report and pressed again every `interval_ms`.
+///
+/// Reports only go out every UPDATE_RATE_MS, and no faster than the host
+/// polls the HID endpoint (`poll_ms` in key_hid.rs), so both times are
+/// rounded up to that and the shortest interval is two reports. The host
+/// repeats held keys on its own as well, so `delay_ms` and `interval_ms`
+/// should be shorter than its repeat delay for the firmware to be in control.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+struct Repeat {
+    delay_ms: u64,
+    interval_ms: u64,
 }
 
-impl Keymap for BasicKeymap {
-    fn get_report(&mut self, state: &KeyState) -> KeyboardReport {
-        let mut code_vec: Vec<u8, 6> = Vec::new();
-        let mut modifier = 0u8;
+const fn repeat_from_pairs(pairs: &[(usize, Repeat)]) -> [Option<Repeat>; KeyState::LEN] {
+    let mut result = [None; KeyState::LEN];
+    let mut arr_idx = 0;
+    while arr_idx < pairs.len() {
+        let (idx, repeat) = pairs[arr_idx];
+        result[idx] = Some(repeat);
 
-        let nav_pressed = state.0[r(31)];
-        info!("Nav Pressed: {}", nav_pressed);
+        arr_idx += 1;
+    }
+    result
+}
 
-        let matrix = if nav_pressed {
-            &NAV_MATRIX
+/// Key positions repeated by the firmware, only key codes are repeated. Off
+/// for every key by default, leaving it to the host. For example
+/// `(r(33), Repeat { delay_ms: 200, interval_ms: 40 })` for the down arrow.
+const KEY_REPEAT: [Option<Repeat>; KeyState::LEN] = repeat_from_pairs(&[]);
+
+const BASE: u8 = 0;
+const NAV: u8 = 1;
+
+const LAYER_NAMES: [&str; NUM_LAYERS] = ["base", "nav"];
+
+/// Used until a keymap is uploaded. Later layers take priority over earlier
+/// ones when active. The base layer and the default layer from the config are
+/// always active.
+const LAYERS: LayerTable = [KEY_MATRIX, NAV_MATRIX];
+
+/// Bitmask of active layers
+type LayerMask = u32;
+
+const _: () = assert!(NUM_LAYERS <= LayerMask::BITS as usize);
+
+/// The key at `idx` on the highest active layer that defines it
+fn resolve(layers: &LayerTable, active: LayerMask, idx: usize) -> Key {
+    layers
+        .iter()
+        .enumerate()
+        .rev()
+        .filter(|(layer, _)| active & (1 << layer) != 0)
+        .map(|(_, keys)| keys[idx])
+        .find(|&key| key != KEY_NONE)
+        .unwrap_or(KEY_NONE)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum TapHoldPhase {
+    Idle,
+    /// Pressed, not yet known to be a tap or a hold
+    Undecided {
+        since_ms: u64,
+        tap: u8,
+        hold: u8,
+    },
+    Hold {
+        hold: u8,
+    },
+    /// Released within the tapping term, the tap is sent in one report
+    Tapped {
+        tap: u8,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum RepeatPhase {
+    Idle,
+    Held {
+        next_ms: u64,
+    },
+    /// Left out of this report so the next one is a new press
+    Lifted,
+}
+
+pub struct BasicKeymap<'d> {
+    macros: &'d SharedMacros,
+    player: Option<MacroPlayer>,
+    /// How long each press and release of a macro lasts
+    macro_step_ms: u64,
+    combos: ComboResolver<'static, Key>,
+    leader: LeaderResolver<'static, Key, Key>,
+    /// Sent for one report after a leader sequence that taps a key
+    leader_tap: Option<Key>,
+    one_shot: OneShotMods,
+    caps_word: CapsWord,
+    last_state: KeyState,
+    toggled: LayerMask,
+    active: LayerMask,
+    config: &'d SharedConfig,
+    keymap: &'d SharedKeymap,
+    /// The uploaded keymap or `LAYERS`, picked up at each report
+    layers: LayerTable,
+    tap_hold: [TapHoldPhase; KeyState::LEN],
+    /// Keys pressed while a tap-hold was undecided. They are held back until
+    /// it is decided so a quick roll comes out in order, and each one is
+    /// sent at least once even if it was already released.
+    held_back: [bool; KeyState::LEN],
+    repeat: [RepeatPhase; KeyState::LEN],
+}
+
+impl<'d> BasicKeymap<'d> {
+    pub fn new(
+        macros: &'d SharedMacros,
+        config: &'d SharedConfig,
+        keymap: &'d SharedKeymap,
+        macro_step_ms: u64,
+        combo_term_ms: u64,
+        leader_timeout_ms: u64,
+        one_shot_timeout_ms: u64,
+        caps_word_term_ms: u64,
+    ) -> Self {
+        BasicKeymap {
+            macros,
+            player: None,
+            macro_step_ms,
+            combos: ComboResolver::new(&COMBOS, combo_term_ms),
+            leader: LeaderResolver::new(&LEADER_SEQUENCES, KEY_LEADER, leader_timeout_ms),
+            leader_tap: None,
+            one_shot: OneShotMods::new(one_shot_timeout_ms),
+            caps_word: CapsWord::new(caps_word_term_ms),
+            last_state: KeyState::no_keys(),
+            toggled: 0,
+            active: 1 << BASE,
+            config,
+            keymap,
+            layers: LAYERS,
+            tap_hold: [TapHoldPhase::Idle; KeyState::LEN],
+            held_back: [false; KeyState::LEN],
+            repeat: [RepeatPhase::Idle; KeyState::LEN],
+        }
+    }
+
+    /// Advance the tap-hold keys, returns true if any is still undecided
+    fn update_tap_hold(&mut self, state: &KeyState, now_ms: u64, tapping_term_ms: u64) -> bool {
+        for (idx, pressed) in state.iter().enumerate() {
+            let phase = &mut self.tap_hold[idx];
+            *phase = match *phase {
+                TapHoldPhase::Idle if pressed && !self.last_state.is_pressed(idx) => {
+                    match resolve(&self.layers, self.active, idx) {
+                        Key::TapHold {
+                            tap: KeyCode(tap),
+                            hold: KeyMod(hold),
+                        } => TapHoldPhase::Undecided {
+                            since_ms: now_ms,
+                            tap,
+                            hold,
+                        },
+                        _ => TapHoldPhase::Idle,
+                    }
+                }
+                TapHoldPhase::Undecided { tap, .. } if !pressed => TapHoldPhase::Tapped { tap },
+                TapHoldPhase::Undecided { since_ms, hold, .. }
+                    if now_ms - since_ms >= tapping_term_ms =>
+                {
+                    TapHoldPhase::Hold { hold }
+                }
+                TapHoldPhase::Hold { .. } if !pressed => TapHoldPhase::Idle,
+                TapHoldPhase::Tapped { .. } => TapHoldPhase::Idle,
+                phase => phase,
+            };
+        }
+
+        self.tap_hold
+            .iter()
+            .any(|phase| matches!(phase, TapHoldPhase::Undecided { .. }))
+    }
+
+    /// Work out which layers are active. Layer keys are looked up on the
+    /// layers they activate, so a momentary key can reveal another layer key
+    /// that has to be applied too.
+    fn update_layers(&mut self, state: &KeyState, default_layer: u8) {
+        for (idx, pressed) in state.iter().enumerate() {
+            if pressed && !self.last_state.is_pressed(idx) {
+                if let Key::LayerToggle(layer) = resolve(&self.layers, self.active, idx) {
+                    self.toggled ^= 1 << layer;
+                }
+            }
+        }
+
+        let default = if usize::from(default_layer) < NUM_LAYERS {
+            1 << default_layer
         } else {
-            &KEY_MATRIX
+            0
         };
+        let mut active = (1 << BASE) | default | self.toggled;
+        loop {
+            let mut next = active;
+            for (idx, pressed) in state.iter().enumerate() {
+                if !pressed {
+                    continue;
+                }
+                if let Key::LayerMomentary(layer) = resolve(&self.layers, active, idx) {
+                    next |= 1 << layer;
+                }
+            }
+            if next == active {
+                break;
+            }
+            active = next;
+        }
 
-        for (key, code) in state.0.iter().cloned().zip(matrix.iter().cloned()) {
-            if !key || code == KEY_NONE {
+        if active != self.active {
+            for (idx, name) in LAYER_NAMES.iter().enumerate() {
+                if (active ^ self.active) & (1 << idx) != 0 {
+                    let on = active & (1 << idx) != 0;
+                    info!("Layer {} {}", name, if on { "on" } else { "off" });
+                }
+            }
+            self.active = active;
+        }
+    }
+
+    /// Advance the keys with firmware repeat
+    fn update_repeat(&mut self, state: &KeyState, now_ms: u64) {
+        for (idx, pressed) in state.iter().enumerate() {
+            let Some(repeat) = KEY_REPEAT[idx] else {
                 continue;
             };
+            let phase = &mut self.repeat[idx];
+            *phase = match *phase {
+                _ if !pressed => RepeatPhase::Idle,
+                RepeatPhase::Idle => RepeatPhase::Held {
+                    next_ms: now_ms + repeat.delay_ms,
+                },
+                RepeatPhase::Held { next_ms } if now_ms >= next_ms => RepeatPhase::Lifted,
+                RepeatPhase::Lifted => RepeatPhase::Held {
+                    next_ms: now_ms + repeat.interval_ms,
+                },
+                phase => phase,
+            };
+        }
+    }
+
+    /// Run the action of a finished leader sequence
+    fn run_leader(&mut self, action: Key) {
+        match action {
+            Key::Code(_) | Key::Mod(_) | Key::Consumer(_) | Key::System(_) => {
+                self.leader_tap = Some(action);
+            }
+            Key::Macro(slot) => self.start_macro(slot),
+            Key::LayerMomentary(layer) | Key::LayerToggle(layer) => self.toggled ^= 1 << layer,
+            // Keys that only make sense held
+            Key::TapHold { .. } | Key::OneShot(_) | Key::Leader => {}
+        }
+    }
+
+    fn start_macro(&mut self, slot: u8) {
+        if self.player.is_some() {
+            info!("Ignoring macro {} while another is playing", slot);
+            return;
+        }
+        let steps = self
+            .macros
+            .lock(|m| m.borrow().get(slot).cloned().unwrap_or_default());
+        if !steps.is_empty() {
+            self.player = Some(MacroPlayer::new(steps, self.macro_step_ms));
+        }
+    }
+}
+
+impl<'d> Keymap for BasicKeymap<'d> {
+    fn get_report(
+        &mut self,
+        state: &KeyState,
+        now_ms: u64,
+    ) -> (KeyboardReport, Option<u16>, Option<u8>) {
+        let mut code_vec: Vec<u8, 6> = Vec::new();
+        let mut modifier = 0u8;
+        let mut media = None;
+        let mut system = None;
+
+        // Everything below sees the keys of a firing combo, and those typed
+        // after the leader, as released
+        let config = self.config.lock(Cell::get);
+        self.layers = self.keymap.lock(Cell::get).unwrap_or(LAYERS);
+        self.combos.set_enabled(self.active == 1 << BASE);
+        let state = &self.combos.update(state, now_ms);
+        let (state, leader_action) = self
+            .leader
+            .update(state, now_ms, |idx| resolve(&self.layers, self.active, idx));
+        let state = &state;
+        if let Some(action) = leader_action {
+            self.run_leader(action);
+        }
+        self.update_layers(state, config.default_layer);
+        let deciding = self.update_tap_hold(state, now_ms, config.tapping_term_ms.into());
+        self.update_repeat(state, now_ms);
+
+        // Keys that use up an armed one-shot modifier
+        let mut one_shot_keys = KeyState::no_keys();
+        let mut one_shot_down = 0u8;
+
+        let mut tapped = false;
+        for (idx, phase) in self.tap_hold.into_iter().enumerate() {
+            match phase {
+                TapHoldPhase::Hold { hold } => modifier |= hold,
+                TapHoldPhase::Tapped { tap } => {
+                    let _ = code_vec.push(tap);
+                    one_shot_keys.press(idx);
+                    tapped = true;
+                }
+                _ => {}
+            }
+        }
+        // A tap goes out in a report of its own, ahead of the keys that
+        // were pressed after it
+        let release_held_back = !deciding && !tapped;
+
+        for (idx, key) in state.iter().enumerate() {
+            let code = resolve(&self.layers, self.active, idx);
+            let held_back_key = matches!(code, Key::Mod(_) | Key::OneShot(_) | Key::Code(_));
+            if held_back_key && key && !self.last_state.is_pressed(idx) && deciding {
+                self.held_back[idx] = true;
+            }
+
+            let send = if self.held_back[idx] {
+                if release_held_back {
+                    self.held_back[idx] = false;
+                    true
+                } else {
+                    false
+                }
+            } else {
+                key
+            };
+            if !send || code == KEY_NONE {
+                continue;
+            }
+            if matches!(code, Key::Code(_)) && self.repeat[idx] == RepeatPhase::Lifted {
+                continue;
+            }
+
             match code {
+                Key::Mod(KeyMod(m)) => modifier |= m,
+                Key::OneShot(KeyMod(m)) => one_shot_down |= m,
+                Key::Code(KeyCode(c)) => {
+                    one_shot_keys.press(idx);
+                    if !code_vec.contains(&c) {
+                        let _ = code_vec.push(c);
+                    }
+                }
+                Key::Macro(slot) => {
+                    one_shot_keys.press(idx);
+                    if !self.last_state.is_pressed(idx) {
+                        self.start_macro(slot);
+                    }
+                }
+                // Only one consumer usage fits in a report, the first held wins
+                Key::Consumer(ConsumerCode(c)) => {
+                    one_shot_keys.press(idx);
+                    media.get_or_insert(c);
+                }
+                Key::System(SystemCode(c)) => {
+                    system.get_or_insert(c);
+                }
+                // Handled by update_layers, update_tap_hold and the leader
+                Key::LayerMomentary(_)
+                | Key::LayerToggle(_)
+                | Key::TapHold { .. }
+                | Key::Leader => {}
+            }
+        }
+        self.last_state = *state;
+        modifier |= self.one_shot.update(one_shot_down, &one_shot_keys, now_ms);
+
+        let leader_tap = self.leader_tap.take();
+        for key in self.combos.active().copied().chain(leader_tap) {
+            match key {
                 Key::Mod(KeyMod(m)) => modifier |= m,
                 Key::Code(KeyCode(c)) => {
-                    let _ = code_vec.push(c);
+                    if !code_vec.contains(&c) {
+                        let _ = code_vec.push(c);
+                    }
+                }
+                Key::Consumer(ConsumerCode(c)) => {
+                    media.get_or_insert(c);
                 }
+                Key::System(SystemCode(c)) => {
+                    system.get_or_insert(c);
+                }
+                _ => {}
+            }
+        }
+
+        // Only the keys that are held count towards caps word, not the macro
+        // being typed
+        modifier |= self.caps_word.update(modifier, &code_vec, now_ms);
+
+        // Macro keystrokes are layered on top of any held keys, so held
+        // modifiers also apply to the macro
+        if let Some(player) = &mut self.player {
+            match player.next(now_ms) {
+                Some((m, c)) => {
+                    modifier |= m;
+                    if c != 0 && !code_vec.contains(&c) {
+                        let _ = code_vec.push(c);
+                    }
+                }
+                None => self.player = None,
             }
         }
 
         let mut keycodes = [0u8; 6];
         keycodes[..code_vec.len()].copy_from_slice(&code_vec);
 
-        KeyboardReport {
+        let report = KeyboardReport {
             keycodes,
             leds: 0,
             modifier,
             reserved: 0,
+        };
+
+        (report, media, system)
+    }
+
+    fn encoder_mode(&self) -> EncoderMode {
+        if self.active & (1 << NAV) != 0 {
+            EncoderMode::Volume
+        } else {
+            EncoderMode::Scroll
         }
     }
 }
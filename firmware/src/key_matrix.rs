@@ -1,57 +1,233 @@
-use embassy_rp::gpio::{AnyPin, Input, Level, Output, Pull};
+use core::cell::Cell;
+
+use embassy_rp::gpio::{AnyPin, Flex, Pull};
 use embassy_sync::signal::Signal;
 use embassy_time::Timer;
 use heapless::Vec;
-use picodox_proto::{KeyUpdate, MatrixLoc};
+use picodox_proto::{ghost, KeyUpdate, MatrixLoc};
+
+use crate::{idle::IdleState, settings::SharedConfig, util::MutexType};
+
+/// Lets another task read back which rows are connected to a single column,
+/// whichever side the matrix drives
+pub struct ColumnTest {
+    request: Signal<MutexType, u8>,
+    result: Signal<MutexType, u8>,
+}
 
-use crate::util::MutexType;
+impl ColumnTest {
+    pub const fn new() -> Self {
+        ColumnTest {
+            request: Signal::new(),
+            result: Signal::new(),
+        }
+    }
+
+    /// Returns a bitmask of the rows connected to `col` on the next scan
+    pub async fn test(&self, col: u8) -> u8 {
+        self.result.reset();
+        self.request.signal(col);
+        self.result.wait().await
+    }
+}
+
+/// Which way the switch diodes point. Current flows from the driven side to
+/// the side that is read, so the firmware has to match the board.
+///
+/// The diodes are what let any combination of keys be held. A board without
+/// them ghosts in either direction: holding three corners of a rectangle makes
+/// the fourth one read pressed. See `picodox_proto::ghost` for blocking those.
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum DiodeDirection {
+    /// Anodes towards the columns, columns are driven and rows are read
+    Col2Row,
+    /// Anodes towards the rows, rows are driven and columns are read
+    Row2Col,
+}
 
 pub struct KeyMatrix<'d, const R: usize, const C: usize> {
-    col_pins: [Output<'d>; C],
-    row_pins: [Input<'d>; R],
+    col_pins: [Flex<'d>; C],
+    row_pins: [Flex<'d>; R],
+    direction: DiodeDirection,
+    /// Hold back keys that could be ghosts, for boards without diodes
+    block_ghosts: bool,
     signal: &'d Signal<MutexType, KeyUpdate>,
+    /// Also gets every update, for the LEDs under the keys
+    led_keys: &'d Signal<MutexType, KeyUpdate>,
+    column_test: &'d ColumnTest,
     update_freq_ms: u32,
+    /// Scan interval while idle
+    idle_freq_ms: u32,
+    idle: &'d IdleState,
+    /// Debounce interval, see `debounce_scans`
+    config: &'d SharedConfig,
+    /// Consecutive scans each key has read differently from `pressed`
+    bounce: [[u8; C]; R],
+    pressed: [[bool; C]; R],
 }
 
 impl<'d, const R: usize, const C: usize> KeyMatrix<'d, R, C> {
     pub fn new(
         col_pins: [AnyPin; C],
         row_pins: [AnyPin; R],
+        direction: DiodeDirection,
+        block_ghosts: bool,
         signal: &'d Signal<MutexType, KeyUpdate>,
+        led_keys: &'d Signal<MutexType, KeyUpdate>,
+        column_test: &'d ColumnTest,
         update_freq_ms: u32,
+        idle_freq_ms: u32,
+        idle: &'d IdleState,
+        config: &'d SharedConfig,
     ) -> Self {
-        let col_pins = col_pins.map(|pin| Output::new(pin, Level::Low));
-        let row_pins = row_pins.map(|pin| Input::new(pin, Pull::Down));
+        let mut col_pins = col_pins.map(Flex::new);
+        let mut row_pins = row_pins.map(Flex::new);
+        let (driven, read) = match direction {
+            DiodeDirection::Col2Row => (&mut col_pins[..], &mut row_pins[..]),
+            DiodeDirection::Row2Col => (&mut row_pins[..], &mut col_pins[..]),
+        };
+        // Driven lines are high one at a time, the lines that are read are
+        // pulled low so only a pressed switch pulls them up
+        for pin in driven {
+            pin.set_low();
+            pin.set_as_output();
+        }
+        for pin in read {
+            pin.set_pull(Pull::Down);
+            pin.set_as_input();
+        }
 
         KeyMatrix {
             col_pins,
             row_pins,
+            direction,
+            block_ghosts,
             signal,
+            led_keys,
+            column_test,
             update_freq_ms,
+            idle_freq_ms,
+            idle,
+            config,
+            bounce: [[0; C]; R],
+            pressed: [[false; C]; R],
+        }
+    }
+
+    /// Time between scans, longer while idle
+    fn scan_ms(&self) -> u32 {
+        if self.idle.is_idle() {
+            self.idle_freq_ms
+        } else {
+            self.update_freq_ms
         }
     }
 
+    /// Scans a key has to read differently before its state changes, when
+    /// scanning every `scan_ms`
+    fn debounce_scans(&self, scan_ms: u32) -> u8 {
+        let debounce_ms = u32::from(self.config.lock(Cell::get).debounce_ms);
+        debounce_ms.div_ceil(scan_ms).clamp(1, u8::MAX as u32) as u8
+    }
+
+    /// Drive one line and return a bitmask of the `read` lines that are high
+    async fn drive_line(driven: &mut Flex<'d>, read: &[Flex<'d>]) -> u8 {
+        driven.set_high();
+        Timer::after_micros(20).await;
+        let mut high = 0u8;
+        for (idx, pin) in read.iter().enumerate() {
+            if pin.is_high() {
+                high |= 1 << idx;
+            }
+        }
+        driven.set_low();
+
+        high
+    }
+
+    /// Read every switch once, by row and column
+    async fn scan(&mut self) -> [[bool; C]; R] {
+        let mut raw = [[false; C]; R];
+        match self.direction {
+            DiodeDirection::Col2Row => {
+                for col in 0..C {
+                    let rows = Self::drive_line(&mut self.col_pins[col], &self.row_pins).await;
+                    for (row, cols) in raw.iter_mut().enumerate() {
+                        cols[col] = rows & (1 << row) != 0;
+                    }
+                }
+            }
+            DiodeDirection::Row2Col => {
+                for (row, pressed) in raw.iter_mut().enumerate() {
+                    let cols = Self::drive_line(&mut self.row_pins[row], &self.col_pins).await;
+                    for (col, pressed) in pressed.iter_mut().enumerate() {
+                        *pressed = cols & (1 << col) != 0;
+                    }
+                }
+            }
+        }
+
+        raw
+    }
+
     pub async fn run(mut self) -> ! {
         loop {
-            // Create a report
-            let mut code_vec = Vec::new();
-
-            for (col, col_pin) in self.col_pins.iter_mut().enumerate() {
-                col_pin.set_high();
-                Timer::after_micros(20).await;
-                for (row, row_pin) in self.row_pins.iter_mut().enumerate() {
-                    if row_pin.is_high() {
-                        // TODO: Ignore NKRO for now
-                        let _ = code_vec.push(MatrixLoc::new(row, col));
+            let mut scan = self.scan().await;
+
+            if let Some(col) = self.column_test.request.try_take() {
+                let col = usize::from(col);
+                let rows = (0..R)
+                    .filter(|&row| scan[row][col])
+                    .fold(0u8, |rows, row| rows | (1 << row));
+                self.column_test.result.signal(rows);
+            }
+
+            // Before debouncing, so a ghost never starts counting towards a
+            // press. Keys already pressed stay pressed.
+            if self.block_ghosts {
+                ghost::block_ghosts(&mut scan, &self.pressed);
+            }
+
+            // A press that wakes the matrix up is debounced at the idle rate,
+            // so it isn't held back by the faster scans that follow
+            let scan_ms = self.scan_ms();
+            let debounce_scans = self.debounce_scans(scan_ms);
+            let mut changed = false;
+            for col in 0..C {
+                for row in 0..R {
+                    let raw = scan[row][col];
+                    if raw == self.pressed[row][col] {
+                        self.bounce[row][col] = 0;
+                        continue;
+                    }
+
+                    self.bounce[row][col] += 1;
+                    if self.bounce[row][col] >= debounce_scans {
+                        self.bounce[row][col] = 0;
+                        self.pressed[row][col] = raw;
+                        changed = true;
                     }
                 }
-                col_pin.set_low();
             }
 
-            let update = KeyUpdate::from_vec(code_vec);
-            self.signal.signal(update);
+            if changed {
+                let mut code_vec = Vec::new();
+                for (row, cols) in self.pressed.iter().enumerate() {
+                    for (col, &pressed) in cols.iter().enumerate() {
+                        if pressed {
+                            // TODO: Ignore NKRO for now
+                            let _ = code_vec.push(MatrixLoc::new(row, col));
+                        }
+                    }
+                }
+                let update = KeyUpdate::from_vec(code_vec);
+                self.led_keys.signal(update.clone());
+                self.signal.signal(update);
+                self.idle.activity();
+            }
 
-            Timer::after_millis(self.update_freq_ms.into()).await;
+            Timer::after_millis(scan_ms.into()).await;
         }
     }
 }
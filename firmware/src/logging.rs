@@ -1,4 +1,10 @@
-use core::{cell::RefCell, ptr::addr_of_mut, sync::atomic::Ordering};
+#[cfg(feature = "plain-log")]
+use core::fmt::{self, Write};
+use core::{
+    cell::RefCell,
+    ptr::{addr_of, addr_of_mut},
+    sync::atomic::Ordering,
+};
 
 use circular_buffer::CircularBuffer;
 use critical_section;
@@ -6,12 +12,16 @@ use embassy_sync::{
     blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
     signal::Signal,
 };
+use embassy_time::{with_timeout, Duration, Timer};
 use embassy_usb::{
     class::cdc_acm::{CdcAcmClass, Receiver, Sender, State},
     driver::Driver,
     Builder,
 };
-use portable_atomic::AtomicBool;
+use picodox_proto::LogLevel;
+#[cfg(feature = "plain-log")]
+use portable_atomic::AtomicU8;
+use portable_atomic::{AtomicBool, AtomicU32, AtomicUsize};
 
 const MAX_PACKET_SIZE: usize = 64;
 
@@ -29,6 +39,61 @@ static GLOBAL_COMS: LoggerComs = LoggerComs {
 static TAKEN: AtomicBool = AtomicBool::new(false);
 static mut CS_RESTORE: critical_section::RestoreState = critical_section::RestoreState::invalid();
 static mut ENCODER: defmt::Encoder = defmt::Encoder::new();
+static mut FRAME: FrameState = FrameState::Pending;
+
+/// Frames whose interned string lies between the start of the trace
+/// messages and this address are dropped, see `set_level`
+static DROP_BELOW: AtomicUsize = AtomicUsize::new(0);
+/// Frames that didn't fit in the buffer since the last warning about it
+static DROPPED_FRAMES: AtomicU32 = AtomicU32::new(0);
+/// Lines below this `LogLevel` are dropped, the plain-log `DROP_BELOW`
+#[cfg(feature = "plain-log")]
+static MIN_LEVEL: AtomicU8 = AtomicU8::new(0);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FrameState {
+    /// Nothing written yet, so the level of the frame isn't known
+    Pending,
+    /// Being queued, the frame starts at this length of the buffer
+    Forwarded { start: usize },
+    /// Below the log level, nothing is encoded
+    Dropped,
+    /// Didn't fit in the buffer. The rest of the frame still goes through the
+    /// encoder to keep its state consistent, but the output is discarded.
+    Overflowed,
+}
+
+// The defmt linker script sorts the interned strings of log messages by
+// level, with these markers in between
+extern "C" {
+    static __DEFMT_MARKER_TRACE_START: u8;
+    static __DEFMT_MARKER_DEBUG_START: u8;
+    static __DEFMT_MARKER_INFO_START: u8;
+    static __DEFMT_MARKER_WARN_START: u8;
+    static __DEFMT_MARKER_ERROR_START: u8;
+}
+
+/// Only forward log frames of `level` and above from now on
+pub fn set_level(level: LogLevel) {
+    let start = match level {
+        LogLevel::Trace => addr_of!(__DEFMT_MARKER_TRACE_START),
+        LogLevel::Debug => addr_of!(__DEFMT_MARKER_DEBUG_START),
+        LogLevel::Info => addr_of!(__DEFMT_MARKER_INFO_START),
+        LogLevel::Warn => addr_of!(__DEFMT_MARKER_WARN_START),
+        LogLevel::Error => addr_of!(__DEFMT_MARKER_ERROR_START),
+    };
+    DROP_BELOW.store(start as usize, Ordering::Relaxed);
+    #[cfg(feature = "plain-log")]
+    MIN_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Whether a frame for the interned string `tag` passes the level filter.
+/// Tags outside of the log levels, like `println!` and primitive formats,
+/// are always forwarded.
+fn is_forwarded(tag: u16) -> bool {
+    let trace_start = addr_of!(__DEFMT_MARKER_TRACE_START) as usize;
+    !(trace_start..DROP_BELOW.load(Ordering::Relaxed)).contains(&(tag as usize))
+}
 
 #[defmt::global_logger]
 struct Logger;
@@ -49,8 +114,9 @@ unsafe impl defmt::Logger for Logger {
         // safety: accessing the `static mut` is OK because we have acquired a critical section.
         unsafe { CS_RESTORE = restore };
 
+        // The frame is started on the first write, once its level is known
         // safety: accessing the `static mut` is OK because we have acquired a critical section.
-        unsafe { (*addr_of_mut!(ENCODER)).start_frame(do_write) }
+        unsafe { FRAME = FrameState::Pending };
     }
 
     unsafe fn flush() {
@@ -60,7 +126,9 @@ unsafe impl defmt::Logger for Logger {
 
     unsafe fn release() {
         // safety: accessing the `static mut` is OK because we have acquired a critical section.
-        (*addr_of_mut!(ENCODER)).end_frame(do_write);
+        if matches!(FRAME, FrameState::Forwarded { .. } | FrameState::Overflowed) {
+            (*addr_of_mut!(ENCODER)).end_frame(do_write);
+        }
 
         // safety: accessing the `static mut` is OK because we have acquired a critical section.
         TAKEN.store(false, Ordering::Release);
@@ -74,14 +142,51 @@ unsafe impl defmt::Logger for Logger {
 
     unsafe fn write(bytes: &[u8]) {
         // safety: accessing the `static mut` is OK because we have acquired a critical section.
-        (*addr_of_mut!(ENCODER)).write(bytes, do_write);
+        let encoder = &mut *addr_of_mut!(ENCODER);
+
+        // safety: accessing the `static mut` is OK because we have acquired a critical section.
+        if matches!(FRAME, FrameState::Pending) {
+            // The first write of a frame is the interned string of its message.
+            // Plain text builds drop the frames of dependencies, they can't
+            // be read without the elf.
+            let forward = match bytes {
+                _ if cfg!(feature = "plain-log") => false,
+                [lo, hi, ..] => is_forwarded(u16::from_le_bytes([*lo, *hi])),
+                _ => true,
+            };
+            if forward {
+                let start = GLOBAL_COMS.buf.lock(|buf_cell| buf_cell.borrow().len());
+                FRAME = FrameState::Forwarded { start };
+                encoder.start_frame(do_write);
+            } else {
+                FRAME = FrameState::Dropped;
+            }
+        }
+
+        if matches!(FRAME, FrameState::Forwarded { .. } | FrameState::Overflowed) {
+            encoder.write(bytes, do_write);
+        }
     }
 }
 
 fn do_write(bytes: &[u8]) {
+    // safety: the encoder only calls this while the logger holds the critical section
+    let frame = unsafe { &mut *addr_of_mut!(FRAME) };
+    let FrameState::Forwarded { start } = *frame else {
+        return;
+    };
+
     let fullness = GLOBAL_COMS.buf.lock(|buf_cell| {
         let mut buf = buf_cell.borrow_mut();
-        buf.extend_from_slice(bytes);
+        if buf.len() + bytes.len() > buf.capacity() {
+            // The buffer would overwrite its oldest bytes, leaving a partial
+            // frame that desyncs the decoder. Drop this frame instead.
+            buf.truncate_back(start);
+            *frame = FrameState::Overflowed;
+            DROPPED_FRAMES.fetch_add(1, Ordering::Relaxed);
+        } else {
+            buf.extend_from_slice(bytes);
+        }
         buf.len()
     });
 
@@ -94,6 +199,74 @@ fn flush() {
     GLOBAL_COMS.sig.signal(());
 }
 
+/// Formats straight into the log buffer, failing once it is full
+#[cfg(feature = "plain-log")]
+struct LineWriter<'a> {
+    buf: &'a mut CircularBuffer<{ 10 * MAX_PACKET_SIZE }, u8>,
+}
+
+#[cfg(feature = "plain-log")]
+impl fmt::Write for LineWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.buf.len() + s.len() > self.buf.capacity() {
+            return Err(fmt::Error);
+        }
+        self.buf.extend_from_slice(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Queue a log message as a line of text, the plain-log feature's
+/// replacement for defmt frames. `level` is None for `println!`.
+#[cfg(feature = "plain-log")]
+pub fn write_line(level: Option<LogLevel>, args: fmt::Arguments) {
+    if level.is_some_and(|level| (level as u8) < MIN_LEVEL.load(Ordering::Relaxed)) {
+        return;
+    }
+
+    GLOBAL_COMS.buf.lock(|buf_cell| {
+        let mut buf = buf_cell.borrow_mut();
+        let start = buf.len();
+        let mut line = LineWriter { buf: &mut buf };
+        let prefix = match level {
+            Some(LogLevel::Trace) => "TRACE ",
+            Some(LogLevel::Debug) => "DEBUG ",
+            Some(LogLevel::Info) => "INFO  ",
+            Some(LogLevel::Warn) => "WARN  ",
+            Some(LogLevel::Error) => "ERROR ",
+            None => "",
+        };
+        let written = line
+            .write_str(prefix)
+            .and_then(|_| line.write_fmt(args))
+            .and_then(|_| line.write_char('\n'));
+        // Same as a frame, a partial line would run into the next one
+        if written.is_err() {
+            buf.truncate_back(start);
+            DROPPED_FRAMES.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+    flush();
+}
+
+/// Wait up to `timeout_ms` for the buffered log output to be handed to USB,
+/// so the last messages before a reset aren't lost
+pub async fn drain(timeout_ms: u64) {
+    let is_empty = || {
+        GLOBAL_COMS
+            .buf
+            .lock(|buf_cell| buf_cell.borrow().is_empty())
+    };
+    let _ = with_timeout(Duration::from_millis(timeout_ms), async {
+        // The logger task only wakes up when signalled, so keep at it
+        while !is_empty() {
+            flush();
+            Timer::after_millis(1).await;
+        }
+    })
+    .await;
+}
+
 pub struct LoggerIf<'d, D: Driver<'d>> {
     sender: Sender<'d, D>,
     send_buf: [u8; MAX_PACKET_SIZE],
@@ -122,6 +295,14 @@ impl<'d, D: Driver<'d>> LoggerIf<'d, D> {
             // Since this is the error reporting mechanism, just fail silently
             let _ = self.sender.write_packet(&self.send_buf[..send_len]).await;
 
+            // Report dropped frames once the buffer has room for the warning
+            if is_empty {
+                let dropped = DROPPED_FRAMES.swap(0, Ordering::Relaxed);
+                if dropped > 0 {
+                    warn!("Dropped {} log frames, the host isn't keeping up", dropped);
+                }
+            }
+
             // Add the ZLP to flush buffer if no more data is waiting
             if is_all && send_len == MAX_PACKET_SIZE && is_empty {
                 // Since this is the error reporting mechanism, just fail silently
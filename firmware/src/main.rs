@@ -13,8 +13,13 @@
 #[macro_use]
 mod util;
 
+mod bootsel;
+mod dfu;
+mod double_reset;
+mod encoder;
+mod heartbeat;
 mod i2c;
-mod key_codes;
+mod idle;
 mod key_hid;
 mod key_map;
 mod key_matrix;
@@ -22,35 +27,49 @@ mod logging;
 mod neopixel;
 mod panic_handler;
 mod serial;
+mod settings;
+mod trackball;
 
+use bootsel::{BootselAction, BootselButton};
+use core::cell::{Cell, RefCell};
 use core::sync::atomic::Ordering;
 
-use defmt::{info, println};
+use dfu::{FirmwareRecvr, FirmwareState};
 use embassy_futures::select::select;
 use embassy_rp::dma::AnyChannel;
+use embassy_rp::flash::Flash;
 use embassy_rp::gpio::{Input, Level, Pin, Pull};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
 use embassy_sync::signal::Signal;
 use embassy_sync::watch::Watch;
 use embassy_time::Timer;
-use i2c::{I2cMaster, I2cSlave};
-use key_hid::KeyboardIf;
+use encoder::{Encoder, EncoderDelta};
+use heartbeat::Heartbeat;
+use i2c::{I2cMaster, I2cSlave, PeerLink};
+use idle::{IdleManager, IdleState};
+use key_hid::{KeyboardIf, SharedKeyState};
 use key_map::BasicKeymap;
-use key_matrix::KeyMatrix;
+use key_matrix::{ColumnTest, DiodeDirection, KeyMatrix};
 use logging::{LoggerIf, LoggerRxSink};
-use neopixel::{Color, Neopixel};
+use neopixel::{Color, LedUpdate, Neopixel};
 
 use embassy_executor::Spawner;
 use embassy_rp::bind_interrupts;
 use embassy_rp::peripherals::{I2C1, PIO0, USB};
 use embassy_rp::pio::{self, Pio};
+use embassy_rp::rom_data;
 use embassy_rp::usb::{self, Driver};
+use embassy_rp::watchdog::Watchdog;
 use embassy_usb::class::{cdc_acm, hid};
 use embassy_usb::{Config, Handler, UsbDevice};
-use picodox_proto::{KeyUpdate, NUM_COLS, NUM_ROWS};
+use picodox_proto::settings::MacroStore;
+use picodox_proto::{DeviceId, Hand, KeyState, KeyUpdate, NUM_COLS, NUM_KEYS, NUM_ROWS};
 use portable_atomic::AtomicBool;
 use serial::SerialIf;
+use settings::{SettingsStore, SharedConfig, SharedFlash, SharedKeymap, SharedMacros};
 use static_cell::StaticCell;
+use trackball::PointerDelta;
 use util::MutexType;
 
 bind_interrupts!(struct Irqs {
@@ -61,15 +80,71 @@ bind_interrupts!(struct Irqs {
 
 static INITIATE_SHUTDOWN: Watch<CriticalSectionRawMutex, (), 1> = Watch::new();
 static USB_SHUTDOWN: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// Set while the host has the USB device configured
+static USB_CONFIGURED: AtomicBool = AtomicBool::new(false);
 
 const UPDATE_RATE_MS: u32 = 20;
-
-#[allow(dead_code)]
-#[derive(PartialEq, Eq)]
-enum Hand {
-    Left,
-    Right,
-}
+/// Interval the host polls the HID endpoints at, fixed when the keyboard
+/// enumerates. Reports go out as soon as a scan changes, so this bounds the
+/// latency on top of `SCAN_RATE_MS` and debouncing. 1ms is the fastest full
+/// speed USB allows and costs the host a poll every millisecond, while
+/// `UPDATE_RATE_MS` only sets how often reports are resent for timing
+/// dependent keys when nothing changes.
+const HID_POLL_MS: u8 = 1;
+/// The matrix only reports debounced changes, so it can scan much faster than
+/// the HID update rate
+const SCAN_RATE_MS: u32 = 1;
+/// Scan interval once the keyboard has gone idle, see `Config::idle_timeout_s`.
+/// Also how late the key that wakes it up can be.
+const IDLE_SCAN_RATE_MS: u32 = 10;
+/// Which way the switch diodes on the PCB point
+const DIODE_DIRECTION: DiodeDirection = DiodeDirection::Col2Row;
+/// Set for a matrix without diodes, where three keys held at the corners of a
+/// rectangle make the fourth one read pressed too
+const BLOCK_GHOSTS: bool = false;
+/// How long each press and release of a macro lasts. Reports also go out
+/// whenever a key changes, so macros keep their own pace.
+const MACRO_STEP_MS: u64 = UPDATE_RATE_MS as u64;
+/// How close together the keys of a combo have to be pressed
+const COMBO_TERM_MS: u64 = 50;
+/// How long the keys of a leader sequence may take after the leader key
+const LEADER_TIMEOUT_MS: u64 = 1000;
+/// How long a tapped one-shot modifier waits for the next key
+const ONE_SHOT_TIMEOUT_MS: u64 = 1000;
+/// Shift has to be tapped again this soon after the first tap to turn on
+/// caps word
+const CAPS_WORD_TERM_MS: u64 = 300;
+/// Number of neopixels chained on PIN_17
+const NUM_LEDS: usize = 1;
+/// Neopixel under each key, by `MatrixLoc::index`, the same on both halves.
+/// Per-key LEDs are chained after the status LED, this board has none.
+const KEY_LEDS: [Option<usize>; NUM_KEYS] = [None; NUM_KEYS];
+/// Color of the liveness pulse on the neopixel, set to None to keep the
+/// watchdog running without touching the LED
+const HEARTBEAT_COLOR: Option<Color> = Some(Color::new(0, 0, 128));
+/// Heartbeat color while the other half isn't heard from
+const LINK_DOWN_COLOR: Color = Color::new(128, 0, 0);
+/// The other half is taken to be unplugged after this long without a key
+/// update or keepalive, which it sends every 100ms
+const PEER_TIMEOUT_MS: u64 = 25 * UPDATE_RATE_MS as u64;
+/// Pressing reset twice within this long of boot enters the USB bootloader,
+/// None boots normally every time
+const DOUBLE_RESET_MS: Option<u64> = Some(500);
+/// I2C address the left half answers on and the right half sends to. Change
+/// it if the bus has another device at 0x55.
+const PEER_I2C_ADDR: u16 = 0x55;
+/// I2C address of a Pimoroni trackball on the right half's bus, usually
+/// `Some(trackball::DEFAULT_ADDR)`. None for boards without one.
+const TRACKBALL_ADDR: Option<u16> = None;
+/// How often the right half polls the trackball while idle
+const TRACKBALL_POLL_MS: u64 = 10;
+/// Send the right half's key updates as the keys that changed, where that is
+/// shorter, with every this many updates sent in full. Both halves need
+/// firmware that knows key deltas. None sends every update in full.
+const LINK_DELTA_FULL_EVERY: Option<u8> = None;
+/// Action to run when BOOTSEL is held. Off by default since polling the
+/// button stalls flash access, see bootsel.rs
+const BOOTSEL_ACTION: Option<BootselAction> = None;
 
 enum I2cDir<P: embassy_rp::i2c::Instance + 'static> {
     Master(I2cMaster<'static, P>),
@@ -78,28 +153,85 @@ enum I2cDir<P: embassy_rp::i2c::Instance + 'static> {
 
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
-    // TODO: Cleanup below
     let p = embassy_rp::init(Default::default());
-    //// Disable the watchdog from the bootloader
+    // Stopped until the heartbeat task starts feeding it, it can still be
+    // running from before a reset
     embassy_rp::pac::WATCHDOG
         .ctrl()
         .write(|w| w.set_enable(false));
-    //let reason = embassy_rp::pac::WATCHDOG.reason().read();
-    //if reason.timer() {
-    //    // Watchdog triggered last reset, go to dfu mode
-    //    rom_data::reset_to_usb_boot(0, 0);
-    //    loop {}
-    //}
-    //let mut watchdog = Watchdog::new(p.WATCHDOG);
-    //watchdog.start(Duration::from_secs(1));
+    let mut watchdog = Watchdog::new(p.WATCHDOG);
+    if heartbeat::watchdog_fired(&mut watchdog) {
+        // The firmware hung, booting it again would likely hang the same way.
+        // Wait in the bootloader for a fixed firmware instead.
+        rom_data::reset_to_usb_boot(0, 0);
+        loop {}
+    }
+    if let Some(window_ms) = DOUBLE_RESET_MS {
+        double_reset::check();
+        spawner.must_spawn(double_reset_task(window_ms));
+    }
 
     // Create the driver, from the HAL.
     let driver = usb::Driver::new(p.USB, Irqs);
 
+    static FLASH: StaticCell<SharedFlash> = StaticCell::new();
+    let flash = &*FLASH.init(Mutex::new(Flash::new(p.FLASH, p.DMA_CH1)));
+
+    // Reading the IDs stops XIP, the flash driver runs the read from RAM
+    // with interrupts off and waits for flash DMA first. They never change,
+    // so they are read once here, before USB is up and would miss its
+    // interrupts.
+    let device_id = {
+        let mut flash = flash.lock().await;
+        let mut unique = [0u8; 8];
+        if let Err(e) = flash.blocking_unique_id(&mut unique) {
+            warn!("Failed to read the flash unique ID: {:?}", e);
+        }
+        let jedec = flash.blocking_jedec_id().unwrap_or_else(|e| {
+            warn!("Failed to read the flash JEDEC ID: {:?}", e);
+            0
+        });
+        let [_, jedec @ ..] = jedec.to_be_bytes();
+        DeviceId { unique, jedec }
+    };
+
+    // The unique ID in hex tells boards apart on the host
+    static SERIAL_NUMBER: StaticCell<[u8; 16]> = StaticCell::new();
+    let serial_number = SERIAL_NUMBER.init([0; 16]);
+    for (hex, byte) in serial_number.chunks_exact_mut(2).zip(device_id.unique) {
+        const DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+        hex[0] = DIGITS[usize::from(byte >> 4)];
+        hex[1] = DIGITS[usize::from(byte & 0xf)];
+    }
+    let serial_number = core::str::from_utf8(serial_number).unwrap_or("0000-0001");
+
+    static MACROS: StaticCell<SharedMacros> = StaticCell::new();
+    let macros = &*MACROS.init(embassy_sync::blocking_mutex::Mutex::new(RefCell::new(
+        MacroStore::default(),
+    )));
+
+    static SHARED_CONFIG: StaticCell<SharedConfig> = StaticCell::new();
+    let shared_config = &*SHARED_CONFIG.init(embassy_sync::blocking_mutex::Mutex::new(Cell::new(
+        Default::default(),
+    )));
+
+    static KEYMAP: StaticCell<SharedKeymap> = StaticCell::new();
+    let keymap = &*KEYMAP.init(embassy_sync::blocking_mutex::Mutex::new(Cell::new(None)));
+
+    let settings = SettingsStore::new(flash, macros, shared_config, keymap);
+    settings.load().await;
+    let led_brightness = settings.config().led_brightness;
+
+    // The jumper on PIN_10 is low on the left half, unless the host stored
+    // an override
     let hand_pin = Input::new(p.PIN_10, Pull::None);
-    let this_hand = match hand_pin.get_level() {
-        Level::Low => Hand::Left,
-        Level::High => Hand::Right,
+    let this_hand = match (settings.hand(), hand_pin.get_level()) {
+        (Some(hand), _) => {
+            info!("Using the stored hand override {:?}", hand);
+            hand
+        }
+        (None, Level::Low) => Hand::Left,
+        (None, Level::High) => Hand::Right,
     };
 
     // Create embassy-usb Config
@@ -114,7 +246,7 @@ async fn main(spawner: Spawner) {
             Hand::Left => config.product = Some("Picodox Keyboard (Left)"),
             Hand::Right => config.product = Some("Picodox Keyboard (Right)"),
         }
-        config.serial_number = Some("0000-0001");
+        config.serial_number = Some(serial_number);
         config.max_power = 100; // mA
         config.max_packet_size_0 = 64;
 
@@ -122,7 +254,11 @@ async fn main(spawner: Spawner) {
     };
 
     // Create embassy-usb DeviceBuilder using the driver and config.
-    // It needs some buffers for building the descriptors.
+    // It needs some buffers for building the descriptors. With two CDC ACM
+    // and four HID interfaces the config descriptor takes 232 bytes (9 for
+    // the header, 58 per CDC ACM, 32 for the keyboard with its OUT endpoint
+    // and 25 for each other HID), and the interfaces use all 8 of
+    // max-interface-count-8.
     let mut builder = {
         static CONFIG_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
         static BOS_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
@@ -139,11 +275,47 @@ async fn main(spawner: Spawner) {
         builder
     };
 
+    let bootsel = BOOTSEL_ACTION.map(|action| BootselButton::new(p.BOOTSEL, flash, action));
+
+    static FIRMWARE: StaticCell<FirmwareState> = StaticCell::new();
+    let firmware = &*FIRMWARE.init(FirmwareState::new());
+    let firmware_recvr = FirmwareRecvr::new(flash, firmware);
+
+    static COLUMN_TEST: StaticCell<ColumnTest> = StaticCell::new();
+    let column_test = &*COLUMN_TEST.init(ColumnTest::new());
+
+    static LED_SIGNAL: StaticCell<Signal<MutexType, LedUpdate<NUM_LEDS>>> = StaticCell::new();
+    let led_signal = &*LED_SIGNAL.init(Signal::new());
+    static BRIGHTNESS_SIGNAL: StaticCell<Signal<MutexType, u8>> = StaticCell::new();
+    let brightness_signal = &*BRIGHTNESS_SIGNAL.init(Signal::new());
+    static MATRIX_STATE: StaticCell<SharedKeyState> = StaticCell::new();
+    let matrix_state = &*MATRIX_STATE.init(embassy_sync::blocking_mutex::Mutex::new(Cell::new(
+        KeyState::no_keys(),
+    )));
+    static KEY_LED_SIGNAL: StaticCell<Signal<MutexType, KeyUpdate>> = StaticCell::new();
+    let key_led_signal = &*KEY_LED_SIGNAL.init(Signal::new());
+    static PEER_LINK: StaticCell<PeerLink> = StaticCell::new();
+    let peer_link = &*PEER_LINK.init(PeerLink::new());
+    static IDLE_STATE: StaticCell<IdleState> = StaticCell::new();
+    let idle_state = &*IDLE_STATE.init(IdleState::new());
+
     // Create classes on the builder.
     let serial = {
         static STATE: StaticCell<cdc_acm::State> = StaticCell::new();
         let state = STATE.init(Default::default());
-        SerialIf::new(&mut builder, state)
+        SerialIf::new(
+            &mut builder,
+            state,
+            settings,
+            column_test,
+            firmware.get_intf(flash),
+            led_signal,
+            brightness_signal,
+            matrix_state,
+            peer_link,
+            &USB_CONFIGURED,
+            device_id,
+        )
     };
 
     let (logger, logger_rx) = {
@@ -152,8 +324,6 @@ async fn main(spawner: Spawner) {
         logging::new(&mut builder, state)
     };
 
-    static LED_SIGNAL: StaticCell<Signal<MutexType, Color>> = StaticCell::new();
-    let led_signal = &*LED_SIGNAL.init(Signal::new());
     let neopixel = {
         let pio0 = Pio::new(p.PIO0, Irqs);
         Neopixel::new(
@@ -162,11 +332,23 @@ async fn main(spawner: Spawner) {
             p.PIN_25,
             AnyChannel::from(p.DMA_CH0),
             led_signal,
+            brightness_signal,
+            key_led_signal,
+            &KEY_LEDS,
+            led_brightness,
         )
     };
-    led_signal.signal(Color::new(0, 0, 0));
+    led_signal.signal(LedUpdate::Frame([Color::new(0, 0, 0); NUM_LEDS]));
+
+    let heartbeat = Heartbeat::new(
+        watchdog,
+        led_signal,
+        HEARTBEAT_COLOR,
+        peer_link,
+        LINK_DOWN_COLOR,
+    );
 
-    // p.PIN_19 is rotary encoder momentary switch
+    let idle = IdleManager::new(idle_state, shared_config, brightness_signal);
 
     static LEFT_SIGNAL: StaticCell<Signal<MutexType, KeyUpdate>> = StaticCell::new();
     let left_signal = &*LEFT_SIGNAL.init(Signal::new());
@@ -198,27 +380,72 @@ async fn main(spawner: Spawner) {
             Hand::Left => left_signal,
             Hand::Right => right_signal,
         };
-        KeyMatrix::new(col_pins, row_pins, my_signal, UPDATE_RATE_MS)
+        KeyMatrix::new(
+            col_pins,
+            row_pins,
+            DIODE_DIRECTION,
+            BLOCK_GHOSTS,
+            my_signal,
+            key_led_signal,
+            column_test,
+            SCAN_RATE_MS,
+            IDLE_SCAN_RATE_MS,
+            idle_state,
+            shared_config,
+        )
     };
 
+    // Trackball motion the right half sends across, for the HID interface
+    static POINTER_DELTA: StaticCell<PointerDelta> = StaticCell::new();
+    let pointer_delta = &*POINTER_DELTA.init(PointerDelta::new());
+
     let key_hid = if this_hand == Hand::Left {
         static STATE: StaticCell<hid::State> = StaticCell::new();
         let state = STATE.init(Default::default());
-
-        Some(KeyboardIf::new(
+        static MEDIA_STATE: StaticCell<hid::State> = StaticCell::new();
+        let media_state = MEDIA_STATE.init(Default::default());
+        static MOUSE_STATE: StaticCell<hid::State> = StaticCell::new();
+        let mouse_state = MOUSE_STATE.init(Default::default());
+        static SYSTEM_STATE: StaticCell<hid::State> = StaticCell::new();
+        let system_state = SYSTEM_STATE.init(Default::default());
+
+        static ENCODER_DELTA: StaticCell<EncoderDelta> = StaticCell::new();
+        let encoder_delta = &*ENCODER_DELTA.init(EncoderDelta::new());
+        // PIN_18 and PIN_20 are the encoder's A and B channels, PIN_19 is
+        // its push switch
+        let encoder = Encoder::new(p.PIN_18, p.PIN_20, p.PIN_19, encoder_delta, shared_config);
+
+        let keyboard = KeyboardIf::new(
             &mut builder,
             state,
+            media_state,
+            mouse_state,
+            system_state,
+            encoder_delta,
+            pointer_delta,
             left_signal,
             right_signal,
+            matrix_state,
+            HID_POLL_MS,
             UPDATE_RATE_MS,
-            BasicKeymap::default(),
-        ))
+            BasicKeymap::new(
+                macros,
+                shared_config,
+                keymap,
+                MACRO_STEP_MS,
+                COMBO_TERM_MS,
+                LEADER_TIMEOUT_MS,
+                ONE_SHOT_TIMEOUT_MS,
+                CAPS_WORD_TERM_MS,
+            ),
+        );
+        Some((keyboard, encoder))
     } else {
         None
     };
 
     static DEVICE_HANDLER: StaticCell<MyDeviceHandler> = StaticCell::new();
-    builder.handler(DEVICE_HANDLER.init(MyDeviceHandler::new()));
+    builder.handler(DEVICE_HANDLER.init(MyDeviceHandler::new(&USB_CONFIGURED)));
 
     // Build the usb device
     let usb = builder.build();
@@ -229,27 +456,56 @@ async fn main(spawner: Spawner) {
 
         match this_hand {
             Hand::Left => {
-                let i2c = I2cSlave::new(p.I2C1, scl, sda, Irqs, right_signal);
+                let i2c = I2cSlave::new(
+                    p.I2C1,
+                    scl,
+                    sda,
+                    Irqs,
+                    PEER_I2C_ADDR,
+                    right_signal,
+                    pointer_delta,
+                    peer_link,
+                    idle_state,
+                    PEER_TIMEOUT_MS,
+                );
                 I2cDir::Slave(i2c)
             }
             Hand::Right => {
-                let i2c = I2cMaster::new(p.I2C1, scl, sda, Irqs, right_signal);
+                let i2c = I2cMaster::new(
+                    p.I2C1,
+                    scl,
+                    sda,
+                    Irqs,
+                    PEER_I2C_ADDR,
+                    TRACKBALL_ADDR,
+                    right_signal,
+                    peer_link,
+                    LINK_DELTA_FULL_EVERY,
+                );
                 I2cDir::Master(i2c)
             }
         }
     };
 
     spawner.must_spawn(serial_task(serial));
+    spawner.must_spawn(firmware_task(firmware_recvr));
     spawner.must_spawn(logger_task(logger));
     spawner.must_spawn(logger_rx_task(logger_rx));
     spawner.must_spawn(usb_task(usb));
     spawner.must_spawn(neopixel_task(neopixel));
+    spawner.must_spawn(heartbeat_task(heartbeat));
+    spawner.must_spawn(idle_task(idle));
     //spawner.must_spawn(hello_task(&led_signal));
     spawner.must_spawn(key_mat_task(key_mat));
     //spawner.must_spawn(busy_task());
 
-    if let Some(key_hid) = key_hid {
+    if let Some((key_hid, encoder)) = key_hid {
         spawner.must_spawn(key_hid_task(key_hid));
+        spawner.must_spawn(encoder_task(encoder));
+    };
+
+    if let Some(bootsel) = bootsel {
+        spawner.must_spawn(bootsel_task(bootsel));
     };
 
     match i2c {
@@ -259,6 +515,7 @@ async fn main(spawner: Spawner) {
 }
 
 async fn shutdown() {
+    double_reset::disarm();
     let shutdown_sender = INITIATE_SHUTDOWN.sender();
     shutdown_sender.send(());
     USB_SHUTDOWN.wait().await;
@@ -269,6 +526,11 @@ async fn serial_task(mut serial: SerialIf<'static, Driver<'static, USB>>) {
     serial.run().await;
 }
 
+#[embassy_executor::task]
+async fn firmware_task(firmware: FirmwareRecvr<'static>) -> ! {
+    firmware.run().await
+}
+
 #[embassy_executor::task]
 async fn logger_task(mut logger: LoggerIf<'static, Driver<'static, USB>>) -> ! {
     logger.run().await
@@ -288,22 +550,47 @@ async fn usb_task(mut usb: UsbDevice<'static, Driver<'static, USB>>) {
 }
 
 #[embassy_executor::task]
-async fn neopixel_task(mut neopixel: Neopixel<'static, PIO0>) -> ! {
+async fn neopixel_task(mut neopixel: Neopixel<'static, PIO0, NUM_LEDS>) -> ! {
     neopixel.run().await
 }
 
 #[embassy_executor::task]
-async fn key_hid_task(keyboard: KeyboardIf<'static, Driver<'static, USB>, BasicKeymap>) {
+async fn heartbeat_task(heartbeat: Heartbeat<'static, NUM_LEDS>) -> ! {
+    heartbeat.run().await
+}
+
+#[embassy_executor::task]
+async fn idle_task(idle: IdleManager<'static>) -> ! {
+    idle.run().await
+}
+
+#[embassy_executor::task]
+async fn double_reset_task(window_ms: u64) {
+    double_reset::disarm_after(window_ms).await;
+}
+
+#[embassy_executor::task]
+async fn bootsel_task(bootsel: BootselButton<'static>) -> ! {
+    bootsel.run().await
+}
+
+#[embassy_executor::task]
+async fn key_hid_task(keyboard: KeyboardIf<'static, Driver<'static, USB>, BasicKeymap<'static>>) {
     keyboard.run().await;
 }
 
+#[embassy_executor::task]
+async fn encoder_task(encoder: Encoder<'static>) -> ! {
+    encoder.run().await
+}
+
 #[embassy_executor::task]
 async fn key_mat_task(keyboard: KeyMatrix<'static, { NUM_ROWS }, { NUM_COLS }>) {
     keyboard.run().await;
 }
 
 #[embassy_executor::task]
-async fn hello_task(led_signal: &'static Signal<MutexType, Color>) -> ! {
+async fn hello_task(led_signal: &'static Signal<MutexType, LedUpdate<NUM_LEDS>>) -> ! {
     let mut i = 0usize;
     let mut b = false;
     loop {
@@ -313,11 +600,11 @@ async fn hello_task(led_signal: &'static Signal<MutexType, Color>) -> ! {
             b = !b;
         }
 
-        led_signal.signal(if b {
-            Color::wheel(i as u8)
+        led_signal.signal(LedUpdate::Frame(if b {
+            [Color::wheel(i as u8); NUM_LEDS]
         } else {
-            Color::new(0, 0, 0)
-        });
+            [Color::new(0, 0, 0); NUM_LEDS]
+        }));
         i = i.wrapping_add(1);
         Timer::after_millis(100).await;
     }
@@ -342,14 +629,12 @@ async fn busy_task() -> ! {
 
 //TODO: Cleanup Below
 struct MyDeviceHandler {
-    configured: AtomicBool,
+    configured: &'static AtomicBool,
 }
 
 impl MyDeviceHandler {
-    fn new() -> Self {
-        MyDeviceHandler {
-            configured: AtomicBool::new(false),
-        }
+    fn new(configured: &'static AtomicBool) -> Self {
+        MyDeviceHandler { configured }
     }
 }
 
@@ -1,3 +1,6 @@
+use core::future::pending;
+
+use embassy_futures::select::{select4, Either4};
 use embassy_rp::{
     clocks,
     dma::AnyChannel,
@@ -6,12 +9,22 @@ use embassy_rp::{
     Peripheral, PeripheralRef,
 };
 use embassy_sync::signal::Signal;
-use embassy_time::Timer;
+use embassy_time::{Duration, Ticker, Timer};
 use fixed::types::U24F8;
+use picodox_proto::{color, KeyUpdate, LedAnimation, MatrixLoc, NUM_KEYS};
 use pio::{Assembler, JmpCondition, OutDestination, SetDestination};
 
 use crate::util::MutexType;
 
+/// Animations advance once per tick, so a `Rainbow { speed: 1 }` takes
+/// 256 * 20ms = 5.1s to go around the wheel
+pub const ANIMATION_TICK_MS: u64 = 20;
+/// Length of one breath in ticks, 100 * 20ms = 2s
+const BREATHE_TICKS: u32 = 100;
+/// How much a `Reactive` key dims per tick once released, it goes dark
+/// after 255 / 10 * 20ms = 0.5s
+const REACTIVE_FADE: u8 = 10;
+
 mod timing {
     pub const T1: u8 = 2; // start bit
     pub const T2: u8 = 5; // data bit
@@ -42,6 +55,7 @@ fn assemble_program() -> pio::Program<32> {
     a.assemble_with_wrap(wrap_source, wrap_target)
 }
 
+#[derive(Clone, Copy)]
 pub struct Color {
     r: u8,
     g: u8,
@@ -49,44 +63,121 @@ pub struct Color {
 }
 
 impl Color {
-    pub fn new(r: u8, g: u8, b: u8) -> Self {
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
         Color { r, g, b }
     }
 
-    pub fn wheel(mut wheel_pos: u8) -> Self {
-        wheel_pos = 255 - wheel_pos;
-        if wheel_pos < 85 {
-            Self::new(255 - wheel_pos * 3, 0, wheel_pos * 3)
-        } else if wheel_pos < 170 {
-            wheel_pos -= 85;
-            Self::new(0, wheel_pos * 3, 255 - wheel_pos * 3)
-        } else {
-            wheel_pos -= 170;
-            Self::new(wheel_pos * 3, 255 - wheel_pos * 3, 0)
+    /// Scale each channel by `level / 255`, linearly, the color values are
+    /// not gamma corrected anywhere in the chain
+    pub fn scaled(&self, level: u8) -> Self {
+        let scale = |c: u8| ((u16::from(c) * u16::from(level)) / 255) as u8;
+        Self::new(scale(self.r), scale(self.g), scale(self.b))
+    }
+
+    /// See `picodox_proto::color::wheel`
+    pub fn wheel(wheel_pos: u8) -> Self {
+        let [r, g, b] = color::wheel(wheel_pos);
+        Self::new(r, g, b)
+    }
+}
+
+/// Colors that change over time, applied to every LED in the chain
+#[derive(Clone, Copy)]
+pub enum NeopixelAnimation {
+    Off,
+    Solid(Color),
+    /// Fades in and out along a triangle wave, see `BREATHE_TICKS`
+    Breathe(Color),
+    /// Sweeps `Color::wheel`, moving `speed` wheel positions per tick
+    Rainbow {
+        speed: u8,
+    },
+    /// Lights the LED of each pressed key, see `REACTIVE_FADE`
+    Reactive(Color),
+}
+
+impl NeopixelAnimation {
+    /// `level` is how lit up the key of the LED is, only `Reactive` uses it
+    fn color(&self, tick: u32, level: u8) -> Color {
+        match *self {
+            NeopixelAnimation::Off => Color::new(0, 0, 0),
+            NeopixelAnimation::Solid(color) => color,
+            NeopixelAnimation::Breathe(color) => {
+                let half = BREATHE_TICKS / 2;
+                let pos = tick % BREATHE_TICKS;
+                let ramp = if pos < half { pos } else { BREATHE_TICKS - pos };
+                color.scaled((ramp * 255 / half) as u8)
+            }
+            NeopixelAnimation::Rainbow { speed } => {
+                Color::wheel(tick.wrapping_mul(u32::from(speed)) as u8)
+            }
+            NeopixelAnimation::Reactive(color) => color.scaled(level),
+        }
+    }
+}
+
+impl From<LedAnimation> for NeopixelAnimation {
+    fn from(animation: LedAnimation) -> Self {
+        match animation {
+            LedAnimation::Off => NeopixelAnimation::Off,
+            LedAnimation::Solid { r, g, b } => NeopixelAnimation::Solid(Color::new(r, g, b)),
+            LedAnimation::Breathe { r, g, b } => NeopixelAnimation::Breathe(Color::new(r, g, b)),
+            LedAnimation::Rainbow { speed } => NeopixelAnimation::Rainbow { speed },
+            LedAnimation::Reactive { r, g, b } => NeopixelAnimation::Reactive(Color::new(r, g, b)),
         }
     }
 }
 
+/// What the neopixel task is asked to show. Updates go through a `Signal`,
+/// so a `Pixel` update that is overwritten before the task runs is lost, send
+/// a `Frame` when several LEDs change at once.
+#[derive(Clone, Copy)]
+pub enum LedUpdate<const N: usize> {
+    Frame([Color; N]),
+    Pixel(usize, Color),
+    /// Like `Pixel`, for the LED under a key
+    Key(MatrixLoc, Color),
+    /// Runs until the next `Frame` or `Pixel` update
+    Animation(NeopixelAnimation),
+}
+
 impl From<Color> for u32 {
     fn from(color: Color) -> Self {
         (u32::from(color.g) << 24) | (u32::from(color.r) << 16) | (u32::from(color.b) << 8)
     }
 }
 
-pub struct Neopixel<'d, P: Instance> {
+/// A chain of `N` WS2812 LEDs driven from one state machine
+pub struct Neopixel<'d, P: Instance, const N: usize> {
     dma: PeripheralRef<'d, AnyChannel>,
     sm: StateMachine<'d, P, 0>,
-    signal: &'d Signal<MutexType, Color>,
+    signal: &'d Signal<MutexType, LedUpdate<N>>,
+    brightness_signal: &'d Signal<MutexType, u8>,
+    key_signal: &'d Signal<MutexType, KeyUpdate>,
+    /// LED under each key, by `MatrixLoc::index`
+    key_leds: &'d [Option<usize>; NUM_KEYS],
+    /// Applied to every color before it is sent, 0 turns the LED off
+    brightness: u8,
     spare_pin: Output<'d>,
+    frame: [Color; N],
+    animation: Option<NeopixelAnimation>,
+    /// LEDs whose key is held down
+    held: [bool; N],
+    /// How lit up each LED is by its key, faded out after the key is released
+    levels: [u8; N],
 }
 
-impl<'d, P: Instance> Neopixel<'d, P> {
+impl<'d, P: Instance, const N: usize> Neopixel<'d, P, N> {
     pub fn new(
         pio: Pio<'d, P>,
         sig_pin: impl PioPin,
         spare_pin: impl Pin,
         dma: impl Peripheral<P = AnyChannel> + 'd,
-        color_signal: &'d Signal<MutexType, Color>,
+        led_signal: &'d Signal<MutexType, LedUpdate<N>>,
+        brightness_signal: &'d Signal<MutexType, u8>,
+        key_signal: &'d Signal<MutexType, KeyUpdate>,
+        key_leds: &'d [Option<usize>; NUM_KEYS],
+        brightness: u8,
     ) -> Self {
         let Pio {
             mut common,
@@ -126,22 +217,138 @@ impl<'d, P: Instance> Neopixel<'d, P> {
         Neopixel {
             sm: sm0,
             dma: dma.into_ref(),
-            signal: color_signal,
+            signal: led_signal,
+            brightness_signal,
+            key_signal,
+            key_leds,
+            brightness,
             spare_pin: Output::new(spare_pin.degrade().into_ref(), Level::Low),
+            frame: [Color::new(0, 0, 0); N],
+            animation: None,
+            held: [false; N],
+            levels: [0; N],
+        }
+    }
+
+    fn key_led(&self, loc: MatrixLoc) -> Option<usize> {
+        self.key_leds
+            .get(loc.index())
+            .copied()
+            .flatten()
+            .filter(|&idx| idx < N)
+    }
+
+    fn set_pixel(&mut self, idx: usize, color: Color) {
+        self.animation = None;
+        match self.frame.get_mut(idx) {
+            Some(pixel) => *pixel = color,
+            None => warn!("Neopixel index {} is out of range ({} LEDs)", idx, N),
+        }
+    }
+
+    fn press_keys(&mut self, update: &KeyUpdate) {
+        self.held = [false; N];
+        for &loc in update.0.iter() {
+            if let Some(idx) = self.key_led(loc) {
+                self.held[idx] = true;
+                self.levels[idx] = u8::MAX;
+            }
+        }
+    }
+
+    fn fade_keys(&mut self) {
+        for (level, held) in self.levels.iter_mut().zip(self.held) {
+            if !held {
+                *level = level.saturating_sub(REACTIVE_FADE);
+            }
+        }
+    }
+
+    /// Whether the frame changes without any update coming in
+    fn animating(&self) -> bool {
+        // Nothing to show while the LEDs are off, a brightness update
+        // pushes the frame again
+        if self.brightness == 0 {
+            return false;
+        }
+        match self.animation {
+            None | Some(NeopixelAnimation::Off | NeopixelAnimation::Solid(_)) => false,
+            // Nothing left to fade out until a key is pressed
+            Some(NeopixelAnimation::Reactive(_)) => self.levels.iter().any(|&l| l > 0),
+            Some(_) => true,
         }
     }
 
     pub async fn run(&mut self) -> ! {
+        let mut ticker = Ticker::every(Duration::from_millis(ANIMATION_TICK_MS));
+        let mut tick = 0u32;
         loop {
-            let color = self.signal.wait().await;
-            self.spare_pin
-                .set_level(if (color.r | color.g | color.b) > 0 {
-                    Level::High
+            // Static frames are only pushed when something changes
+            let animating = self.animating();
+            let next_tick = async {
+                if animating {
+                    ticker.next().await
                 } else {
-                    Level::Low
-                });
-            let words: [u32; 1] = [color.into()];
+                    pending().await
+                }
+            };
+
+            let update = select4(
+                self.signal.wait(),
+                self.brightness_signal.wait(),
+                self.key_signal.wait(),
+                next_tick,
+            )
+            .await;
+            match update {
+                Either4::First(LedUpdate::Frame(frame)) => {
+                    self.animation = None;
+                    self.frame = frame;
+                }
+                Either4::First(LedUpdate::Pixel(idx, color)) => self.set_pixel(idx, color),
+                Either4::First(LedUpdate::Key(loc, color)) => match self.key_led(loc) {
+                    Some(idx) => self.set_pixel(idx, color),
+                    None => {
+                        warn!("Key {} has no LED", loc.index());
+                        continue;
+                    }
+                },
+                Either4::First(LedUpdate::Animation(animation)) => {
+                    self.animation = Some(animation);
+                    tick = 0;
+                    ticker.reset();
+                }
+                Either4::Second(brightness) => self.brightness = brightness,
+                Either4::Third(update) => {
+                    self.press_keys(&update);
+                    // Only the reactive animation shows key presses
+                    if !matches!(self.animation, Some(NeopixelAnimation::Reactive(_))) {
+                        continue;
+                    }
+                }
+                Either4::Fourth(()) => {
+                    tick = tick.wrapping_add(1);
+                    self.fade_keys();
+                }
+            }
+
+            if let Some(animation) = self.animation {
+                for (pixel, level) in self.frame.iter_mut().zip(self.levels) {
+                    *pixel = animation.color(tick, level);
+                }
+            }
+
+            let mut words = [0u32; N];
+            let mut lit = false;
+            for (word, color) in words.iter_mut().zip(self.frame) {
+                let color = color.scaled(self.brightness);
+                lit |= (color.r | color.g | color.b) > 0;
+                *word = color.into();
+            }
+            self.spare_pin
+                .set_level(if lit { Level::High } else { Level::Low });
             self.sm.tx().dma_push(self.dma.reborrow(), &words).await;
+            // Reset time, after the whole chain has been sent
             Timer::after_micros(55).await;
         }
     }
@@ -1,5 +1,6 @@
 use core::{
     fmt::{self, Write},
+    mem::MaybeUninit,
     panic::PanicInfo,
     ptr::addr_of_mut,
     sync::atomic::{AtomicUsize, Ordering},
@@ -7,11 +8,51 @@ use core::{
 
 use cortex_m_rt::{exception, ExceptionFrame};
 use embassy_rp::rom_data;
+use embassy_time::Instant;
+use heapless::Vec;
+use picodox_proto::PANIC_CHUNK;
 
-const BUFFER_SIZE: usize = 1024;
+pub const BUFFER_SIZE: usize = 1024;
 
+/// Marks the panic store as holding a message, anything else is leftover RAM
+const PANIC_MAGIC: u32 = 0x5041_4e43;
+
+// The message comes first so `just panic` can still dump it from BOOTSEL mode
+#[repr(C)]
+struct PanicStore {
+    buf: [u8; BUFFER_SIZE],
+    len: u32,
+    magic: u32,
+}
+
+// Kept in .uninit, which the runtime doesn't zero, so the message survives
+// the reset that follows a panic
 #[no_mangle]
-static mut PANIC_BUFFER: [u8; BUFFER_SIZE] = [0u8; BUFFER_SIZE];
+#[link_section = ".uninit.PANIC_BUFFER"]
+static mut PANIC_BUFFER: MaybeUninit<PanicStore> = MaybeUninit::uninit();
+
+/// Read part of the message stored by the last panic. Once the end of the
+/// message has been read it is cleared, so a crash is only reported once.
+pub fn read_panic(offset: usize) -> Vec<u8, PANIC_CHUNK> {
+    critical_section::with(|_| {
+        // Safety: every bit pattern is a valid PanicStore, and the magic
+        // check rejects anything the panic handler didn't write
+        let store = unsafe { (*addr_of_mut!(PANIC_BUFFER)).assume_init_mut() };
+        let mut chunk = Vec::new();
+        if store.magic != PANIC_MAGIC || store.len as usize > BUFFER_SIZE {
+            return chunk;
+        }
+
+        let len = store.len as usize;
+        let start = offset.min(len);
+        let end = (start + PANIC_CHUNK).min(len);
+        let _ = chunk.extend_from_slice(&store.buf[start..end]);
+        if end == len {
+            store.magic = 0;
+        }
+        chunk
+    })
+}
 
 struct PanicBuffer<'a> {
     buf: &'a mut [u8],
@@ -33,8 +74,11 @@ impl<'a> fmt::Write for PanicBuffer<'a> {
 #[inline(never)]
 #[panic_handler]
 fn panic_handler(panic_info: &PanicInfo<'_>) -> ! {
+    // Safety: the panic handler is the only writer and never returns
+    let store = unsafe { (*addr_of_mut!(PANIC_BUFFER)).assume_init_mut() };
+    store.magic = 0;
     let mut buffer = PanicBuffer {
-        buf: unsafe { &mut *addr_of_mut!(PANIC_BUFFER) },
+        buf: &mut store.buf,
         offset: 0,
     };
     //for i in 0u8..=255 {
@@ -43,6 +87,8 @@ fn panic_handler(panic_info: &PanicInfo<'_>) -> ! {
     //const TEST_STR: &str = "Hello world!\r\n";
     //buffer[..TEST_STR.len()].clone_from_slice(TEST_STR.as_bytes());
     let _ = writeln!(buffer, "Panic: {:#}", panic_info);
+    store.len = buffer.offset as u32;
+    store.magic = PANIC_MAGIC;
     rom_data::reset_to_usb_boot(0, 0);
 
     loop {}
@@ -64,6 +110,12 @@ static TRACE_OFFSET: AtomicUsize = AtomicUsize::new(0);
 
 struct TraceBuffer;
 
+/// Snapshot of the trace ring buffer. The byte after the newest record is
+/// the `@` sentinel, everything after it is older than everything before it.
+pub fn read_trace() -> [u8; BUFFER_SIZE] {
+    critical_section::with(|_| unsafe { *addr_of_mut!(TRACE_BUFFER) })
+}
+
 impl<'a> fmt::Write for TraceBuffer {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         let mut s = s;
@@ -96,29 +148,58 @@ impl<'a> fmt::Write for TraceBuffer {
 #[no_mangle]
 fn _embassy_trace_task_new(executor_id: u32, task_id: u32) {
     let mut tb = TraceBuffer;
-    let _ = writeln!(tb, "{{NT|exid:{},tid:{}}}", executor_id, task_id);
+    let _ = writeln!(
+        tb,
+        "{{NT|exid:{},tid:{},us:{}}}",
+        executor_id,
+        task_id,
+        Instant::now().as_micros()
+    );
 }
 
 #[no_mangle]
 fn _embassy_trace_task_exec_begin(executor_id: u32, task_id: u32) {
     let mut tb = TraceBuffer;
-    let _ = writeln!(tb, "{{TB|exid:{},tid:{}}}", executor_id, task_id);
+    let _ = writeln!(
+        tb,
+        "{{TB|exid:{},tid:{},us:{}}}",
+        executor_id,
+        task_id,
+        Instant::now().as_micros()
+    );
 }
 
 #[no_mangle]
 fn _embassy_trace_task_exec_end(executor_id: u32, task_id: u32) {
     let mut tb = TraceBuffer;
-    let _ = writeln!(tb, "{{TE|exid:{},tid:{}}}", executor_id, task_id);
+    let _ = writeln!(
+        tb,
+        "{{TE|exid:{},tid:{},us:{}}}",
+        executor_id,
+        task_id,
+        Instant::now().as_micros()
+    );
 }
 
 #[no_mangle]
 fn _embassy_trace_task_ready_begin(executor_id: u32, task_id: u32) {
     let mut tb = TraceBuffer;
-    let _ = writeln!(tb, "{{TR|exid:{},tid:{}}}", executor_id, task_id);
+    let _ = writeln!(
+        tb,
+        "{{TR|exid:{},tid:{},us:{}}}",
+        executor_id,
+        task_id,
+        Instant::now().as_micros()
+    );
 }
 
 #[no_mangle]
 fn _embassy_trace_executor_idle(executor_id: u32) {
     let mut tb = TraceBuffer;
-    let _ = writeln!(tb, "{{EI|exid:{}}}", executor_id);
+    let _ = writeln!(
+        tb,
+        "{{EI|exid:{},us:{}}}",
+        executor_id,
+        Instant::now().as_micros()
+    );
 }
@@ -1,25 +1,92 @@
+use core::{cell::Cell, sync::atomic::Ordering};
+
 use circular_buffer::CircularBuffer;
-use defmt::error;
 use embassy_rp::{peripherals::WATCHDOG, rom_data, watchdog::Watchdog};
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Duration};
 use embassy_usb::{
     class::cdc_acm::{CdcAcmClass, State},
-    driver::Driver,
+    driver::{Driver, EndpointError},
     Builder,
 };
-use picodox_proto::{Command, NackType, Response, WireSize, DATA_COUNT};
+use heapless::Vec;
+use picodox_proto::{
+    errors::ProtoError,
+    keymap::{Layout, NUM_LAYERS},
+    settings::MacroError,
+    AckType, BuildInfo, Command, DataChunk, DeviceId, FlashCrc, KeyState, NackType, Response,
+    SelfTestCheck, SelfTestResults, CURRENT_VERSION, DATA_COUNT, FLASH_RESYNC_MS, NO_TAG, NUM_COLS,
+};
+use portable_atomic::AtomicBool;
 // USB Communications Class Device support
 
-use picodox_proto::proto_impl;
+use picodox_proto::proto_impl::{self, Crc8, CrcKind, Framing};
 
-//use crate::dfu::{FirmwareIntf, FirmwareSession};
+use crate::{
+    dfu::{self, FirmwareIntf, FirmwareSession},
+    heartbeat,
+    i2c::PeerLink,
+    key_hid::SharedKeyState,
+    key_matrix::ColumnTest,
+    logging,
+    neopixel::{Color, LedUpdate},
+    panic_handler,
+    settings::SettingsStore,
+    util::MutexType,
+    NUM_LEDS, PEER_TIMEOUT_MS, UPDATE_RATE_MS,
+};
 
 const MAX_PACKET_SIZE: usize = 64;
+/// A packet the host doesn't pick up within this long is dropped along with
+/// the rest of its response, so a host that stops reading can't stall the
+/// command loop
+const SEND_TIMEOUT_MS: u64 = 1000;
+/// A transfer whose next `Data` packet doesn't arrive within this long is
+/// dropped, so a host that gave up part-way can't hold up the command loop
+const DATA_TIMEOUT_MS: u64 = 2000;
+/// Longest wait for buffered log output to go out before a reset
+const LOG_DRAIN_MS: u64 = 50;
+/// How commands and responses are framed, the host has to be told with
+/// `--framing len` when built with the `len-framing` feature
+const FRAMING: Framing = if cfg!(feature = "len-framing") {
+    Framing::LenPrefix
+} else {
+    Framing::Cobs
+};
+
+/// The build this is, as build.rs found it
+fn build_info() -> BuildInfo {
+    let mut git_hash = heapless::String::new();
+    // build.rs keeps it short, anything past what fits is cut off
+    for c in env!("PICODOX_GIT_HASH").chars() {
+        if git_hash.push(c).is_err() {
+            break;
+        }
+    }
+    BuildInfo {
+        git_hash,
+        built: env!("PICODOX_BUILT").parse().unwrap_or(0),
+    }
+}
 
 pub struct SerialIf<'d, D>
 where
     D: Driver<'d>,
 {
     packet: Packetizer<'d, D>,
+    settings: SettingsStore<'d>,
+    column_test: &'d ColumnTest,
+    firmware: FirmwareIntf<'d>,
+    led_signal: &'d Signal<MutexType, LedUpdate<NUM_LEDS>>,
+    brightness_signal: &'d Signal<MutexType, u8>,
+    matrix: &'d SharedKeyState,
+    peer_link: &'d PeerLink,
+    /// Whether the host has configured the USB device
+    usb_configured: &'d AtomicBool,
+    /// Read at boot, see main
+    device_id: DeviceId,
+    /// The matrix state last streamed in test mode, None outside of it
+    test_mode: Option<KeyState>,
 }
 
 pub struct Packetizer<'d, D>
@@ -29,27 +96,98 @@ where
     class: CdcAcmClass<'d, D>,
     coms_buf: CircularBuffer<{ 2 * MAX_PACKET_SIZE }, u8>,
     pack_buf: [u8; MAX_PACKET_SIZE],
+    /// Tag of the command being answered, every response goes out with it
+    tag: u8,
+    /// A command that broke off a transfer, it is handled next
+    pending: Option<(Command, u8)>,
+}
+
+/// Why `recv_data` stopped before it had every byte
+#[derive(Debug, defmt::Format)]
+enum TransferError {
+    /// The host went quiet for `DATA_TIMEOUT_MS`
+    Timeout,
+    /// The host sent another command, it is kept in `pending`
+    Aborted,
 }
 
 impl<'d, D: Driver<'d>> Packetizer<'d, D> {
-    async fn recv_cmd(&mut self) -> Result<Command, NackType> {
+    /// Receive a command and its tag
+    async fn recv_cmd(&mut self) -> Result<(Command, u8), NackType> {
+        if let Some(pending) = self.pending.take() {
+            return Ok(pending);
+        }
+        self.recv_cmd_with::<Crc8>().await
+    }
+
+    async fn recv_cmd_with<C: CrcKind>(&mut self) -> Result<(Command, u8), NackType> {
+        let frame_len = self.recv_frame().await?;
+        let contig = self.coms_buf.make_contiguous();
+        let decoded = Command::decode_framed::<C>(&mut contig[..frame_len], FRAMING);
+        // Remove the decoded bytes from the circular buffer
+        self.coms_buf
+            .truncate_front(self.coms_buf.len() - frame_len);
+
+        decoded
+    }
+
+    /// Like `recv_cmd_with`, for the packets of a transfer. A frame that
+    /// doesn't check out with `C` is tried as a command checksummed with
+    /// `Crc8`, that is how the host sends anything but `Data`.
+    async fn recv_transfer_cmd<C: CrcKind>(&mut self) -> Result<(Command, u8), NackType> {
+        let frame_len = self.recv_frame().await?;
+        let contig = self.coms_buf.make_contiguous();
+        // Unframing works in place, the second try needs the frame as it was
+        let mut copy = [0u8; 2 * MAX_PACKET_SIZE];
+        copy[..frame_len].copy_from_slice(&contig[..frame_len]);
+        let decoded =
+            Command::decode_framed::<C>(&mut contig[..frame_len], FRAMING).or_else(|reason| {
+                match Command::decode_framed::<Crc8>(&mut copy[..frame_len], FRAMING) {
+                    Ok((command, tag)) if !matches!(command, Command::Data(_)) => {
+                        Ok((command, tag))
+                    }
+                    _ => Err(reason),
+                }
+            });
+        self.coms_buf
+            .truncate_front(self.coms_buf.len() - frame_len);
+
+        decoded
+    }
+
+    /// Wait until `coms_buf` starts with a whole frame, returns its length
+    async fn recv_frame(&mut self) -> Result<usize, NackType> {
         let mut lost_bytes = false;
-        let line_end = loop {
+        let capacity = self.coms_buf.capacity();
+        loop {
+            let buffered: &[u8] = self.coms_buf.make_contiguous();
+
+            // There is no sentinel to find the next frame by, so a length
+            // prefixed frame that lost bytes or won't fit takes everything
+            // buffered with it
+            if FRAMING == Framing::LenPrefix
+                && (lost_bytes
+                    || proto_impl::len_frame_size(buffered).is_some_and(|size| size > capacity))
+            {
+                self.coms_buf.clear();
+                return Err(NackType::BufferOverflow);
+            }
+
             // Check if we have enough bytes already
-            if let Some(line_end) = self.coms_buf.iter().position(|&x| x == 0u8) {
+            if let Some(frame_len) = FRAMING.frame_len(buffered) {
                 if lost_bytes {
                     // Remove truncated packet from the buffer
                     self.coms_buf
-                        .truncate_front(self.coms_buf.len() - line_end - 1);
+                        .truncate_front(self.coms_buf.len() - frame_len);
                     return Err(NackType::BufferOverflow);
                 } else {
-                    break line_end;
+                    return Ok(frame_len);
                 }
             }
 
             // Otherwise, wait for another packet
             let count = async_unwrap!(res self.class.read_packet(&mut self.pack_buf).await,
-                "Usb read_packet error: {}");
+                "Usb read_packet error: {:?}");
 
             // Log an error if we overflow our buffer
             let open_cap = self.coms_buf.capacity() - self.coms_buf.len();
@@ -59,117 +197,579 @@ impl<'d, D: Driver<'d>> Packetizer<'d, D> {
                 lost_bytes = true;
             }
             self.coms_buf.extend_from_slice(&self.pack_buf[..count]);
-        };
-
-        // Now we have at least a whole packet in the buffer
-        let contig = self.coms_buf.make_contiguous();
-        let message_buf = &mut contig[..=line_end];
-
-        let decoded = proto_impl::wire_decode::<Command>(message_buf);
-        // Remove the decoded bytes from the circular buffer
-        self.coms_buf
-            .truncate_front(self.coms_buf.len() - line_end - 1);
-
-        decoded.map_err(|err| NackType::PacketErr(err))
+        }
     }
 
-    async fn recv_data<F>(&mut self, count: u32, callback: &mut F)
+    /// Receive `count` bytes of `Data` packets checksummed with `C`. A bad
+    /// packet is nacked and not counted, the sender is expected to retransmit
+    /// it once the link has resynced. The packets are part of the command's
+    /// exchange, the responses keep its tag.
+    ///
+    /// Any other command ends the transfer, as does the host going quiet,
+    /// nothing is sent for the transfer then. The host has either moved on
+    /// or is gone.
+    async fn recv_data<C, F>(&mut self, count: u32, callback: &mut F) -> Result<(), TransferError>
     where
+        C: CrcKind,
         F: DataRecvr<'d, D>,
     {
-        for _bytes_recieved in (0..count).step_by(DATA_COUNT) {
-            let res = self.recv_cmd().await.and_then(|cmd| match cmd {
-                Command::Data(data) => Ok(data),
-                _ => Err(NackType::Unexpected),
-            });
+        let timeout = Duration::from_millis(DATA_TIMEOUT_MS);
+        let mut bytes_received = 0;
+        while bytes_received < count {
+            let Ok(res) = with_timeout(timeout, self.recv_transfer_cmd::<C>()).await else {
+                // Whatever was left of a frame won't be finished
+                self.coms_buf.clear();
+                return Err(TransferError::Timeout);
+            };
+            let res = match res {
+                Ok((Command::Data(data), _)) if data.len() as u32 > count - bytes_received => {
+                    Err(NackType::OutOfRange)
+                }
+                Ok((Command::Data(data), _)) => Ok(data),
+                Ok(command) => {
+                    self.pending = Some(command);
+                    return Err(TransferError::Aborted);
+                }
+                Err(reason) => Err(reason),
+            };
             match res {
-                Ok(data) => callback.callback(self, &data).await,
+                Ok(data) => {
+                    callback.callback(self, &data).await;
+                    bytes_received += data.len() as u32;
+                }
                 Err(reason) => {
                     self.send_packet(&Response::Nack(reason)).await;
-                    continue;
+                    self.resync::<C>().await;
                 }
             }
         }
+
+        Ok(())
+    }
+
+    /// Nack everything until the host stops sending, then drop any partial
+    /// packet left in the buffer
+    async fn resync<C: CrcKind>(&mut self) {
+        let quiet = Duration::from_millis(FLASH_RESYNC_MS);
+        while with_timeout(quiet, self.recv_cmd_with::<C>()).await.is_ok() {
+            self.send_packet(&Response::Nack(NackType::Unexpected))
+                .await;
+        }
+        self.coms_buf.clear();
     }
 
     async fn send_packet(&mut self, response: &Response) {
-        match proto_impl::wire_encode::<_, { Response::WIRE_MAX_SIZE }>(response) {
+        const N: usize = FRAMING.tagged_max_size::<Crc8, Response>();
+        match FRAMING.encode_tagged_with::<Crc8, _, N>(response, self.tag) {
             Ok(buf) => self.send_buf(&buf).await,
             Err(_err) => self.send_buf(&[0xBE, 0xEF, 0x00]).await,
         };
     }
 
-    async fn send_buf(&mut self, buf: &[u8]) {
-        let mut chunks_exact = buf.chunks_exact(MAX_PACKET_SIZE);
+    /// Send `data` as a `DataStream`
+    async fn send_stream(&mut self, data: &[u8]) {
+        self.send_packet(&Response::DataStream {
+            total: data.len() as u32,
+        })
+        .await;
+        for chunk in data.chunks(DATA_COUNT) {
+            let data = chunk.iter().copied().collect();
+            self.send_packet(&Response::Data(data)).await;
+        }
+        self.send_packet(&Response::DataEnd).await;
+    }
 
-        // These two errors will only occur if an endpoint is disabled or
-        // if the send/recv buffer is too small, both should be considered
-        // unrecoverable as they require a recompile to fix
-        for chunk in chunks_exact.by_ref() {
-            async_unwrap!(res self.class.write_packet(chunk).await, "Error sending buffer: {}");
+    async fn send_buf(&mut self, buf: &[u8]) {
+        for packet in proto_impl::usb_packets(buf, MAX_PACKET_SIZE) {
+            if !self.write_packet(packet).await {
+                warn!("Dropped the rest of a {} byte response", buf.len());
+                return;
+            }
         }
+    }
 
-        async_unwrap!(res self.class.write_packet(chunks_exact.remainder()).await, "Error sending buffer: {}");
+    /// Wait for the host to take the last packet sent, returns false if it
+    /// didn't. The endpoint only takes the zero length packet once it is
+    /// free, and it adds nothing to the data the host reads.
+    async fn drain(&mut self) -> bool {
+        self.write_packet(&[]).await
+    }
+
+    /// Send one packet, returns false if it couldn't be delivered
+    async fn write_packet(&mut self, packet: &[u8]) -> bool {
+        let timeout = Duration::from_millis(SEND_TIMEOUT_MS);
+        match with_timeout(timeout, self.class.write_packet(packet)).await {
+            Ok(Ok(())) => true,
+            // The endpoint buffer is sized at compile time, only a recompile
+            // fixes this
+            Ok(Err(EndpointError::BufferOverflow)) => {
+                async_panic!("Packet of {} bytes overflows the endpoint", packet.len())
+            }
+            // Unplugged or reset by the host, nobody is waiting for the rest
+            Ok(Err(EndpointError::Disabled)) => false,
+            // The previous packet is still waiting for the host. Giving up
+            // before it was replaced leaves the endpoint as it was.
+            Err(_) => false,
+        }
     }
 }
 
 pub trait DataRecvr<'d, D: Driver<'d>> {
-    async fn callback(&mut self, p: &mut Packetizer<'d, D>, data: &[u8; DATA_COUNT]);
+    async fn callback(&mut self, p: &mut Packetizer<'d, D>, data: &DataChunk);
 }
 
 struct EchoRecvr;
 
 impl<'d, D: Driver<'d>> DataRecvr<'d, D> for EchoRecvr {
-    async fn callback(&mut self, p: &mut Packetizer<'d, D>, data: &[u8; DATA_COUNT]) {
-        p.send_packet(&Response::Data(*data)).await;
+    async fn callback(&mut self, p: &mut Packetizer<'d, D>, data: &DataChunk) {
+        p.send_packet(&Response::Data(data.clone())).await;
+    }
+}
+
+/// Collects a whole payload before the command acts on it, acking each chunk
+/// so the host can send the next one
+struct BufferRecvr<const N: usize> {
+    buf: Vec<u8, N>,
+}
+
+impl<const N: usize> BufferRecvr<N> {
+    /// None if `count` bytes won't fit, the command should be nacked with
+    /// `OutOfRange` before any data is sent
+    fn new(count: u32) -> Option<Self> {
+        (count as usize <= N).then(|| BufferRecvr { buf: Vec::new() })
+    }
+
+    /// The assembled payload, once `recv_data` has returned
+    fn into_inner(self) -> Vec<u8, N> {
+        self.buf
+    }
+}
+
+impl<'d, D: Driver<'d>, const N: usize> DataRecvr<'d, D> for BufferRecvr<N> {
+    async fn callback(&mut self, p: &mut Packetizer<'d, D>, data: &DataChunk) {
+        // recv_data never passes more than the `count` checked in `new`, the
+        // last chunk is just shorter than DATA_COUNT
+        async_unwrap!(res self.buf.extend_from_slice(data), "Payload overflowed its buffer: {:?}");
+        p.send_packet(&Response::Ack(AckType::AckData)).await;
+    }
+}
+
+/// Passes each firmware chunk to the updater, then acks it so the host can
+/// send the next one
+struct FlashRecvr<'s, 'a, 'f> {
+    session: &'s mut FirmwareSession<'a, 'f>,
+}
+
+impl<'d, D: Driver<'d>> DataRecvr<'d, D> for FlashRecvr<'_, '_, '_> {
+    async fn callback(&mut self, p: &mut Packetizer<'d, D>, data: &DataChunk) {
+        self.session.write(data).await;
+        p.send_packet(&Response::Ack(AckType::AckData)).await;
     }
 }
 
 impl<'d, D: Driver<'d>> SerialIf<'d, D> {
-    pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State<'d>) -> Self {
+    pub fn new(
+        builder: &mut Builder<'d, D>,
+        state: &'d mut State<'d>,
+        settings: SettingsStore<'d>,
+        column_test: &'d ColumnTest,
+        firmware: FirmwareIntf<'d>,
+        led_signal: &'d Signal<MutexType, LedUpdate<NUM_LEDS>>,
+        brightness_signal: &'d Signal<MutexType, u8>,
+        matrix: &'d SharedKeyState,
+        peer_link: &'d PeerLink,
+        usb_configured: &'d AtomicBool,
+        device_id: DeviceId,
+    ) -> Self {
         let packet = Packetizer {
             class: CdcAcmClass::new(builder, state, MAX_PACKET_SIZE as u16),
             coms_buf: CircularBuffer::new(),
             pack_buf: [0u8; MAX_PACKET_SIZE],
+            tag: NO_TAG,
+            pending: None,
         };
 
-        SerialIf { packet }
+        SerialIf {
+            packet,
+            settings,
+            column_test,
+            firmware,
+            led_signal,
+            brightness_signal,
+            matrix,
+            peer_link,
+            usb_configured,
+            device_id,
+            test_mode: None,
+        }
     }
 
     pub async fn run(&mut self) -> ! {
         loop {
-            let res = self.packet.recv_cmd().await;
+            let res = match self.test_mode {
+                None => self.packet.recv_cmd().await,
+                Some(last) => {
+                    // The matrix state is updated once per report, so that
+                    // is how often it is checked for changes
+                    let state = self.matrix.lock(Cell::get);
+                    if state != last {
+                        // Not the answer to any one command
+                        self.packet.tag = NO_TAG;
+                        self.packet.send_packet(&Response::Matrix(state)).await;
+                        self.test_mode = Some(state);
+                    }
+                    let poll = Duration::from_millis(UPDATE_RATE_MS.into());
+                    match with_timeout(poll, self.packet.recv_cmd()).await {
+                        Ok(res) => res,
+                        Err(_) => continue,
+                    }
+                }
+            };
             let message = match res {
-                Ok(cmd) => cmd,
+                Ok((cmd, tag)) => {
+                    self.packet.tag = tag;
+                    cmd
+                }
                 Err(reason) => {
-                    // In case of an error here, just respond with an error
+                    // In case of an error here, just respond with an error.
+                    // The tag can't be told apart from the rest of a frame
+                    // that doesn't decode.
+                    self.packet.tag = NO_TAG;
                     self.packet.send_packet(&Response::Nack(reason)).await;
                     continue;
                 }
             };
+            // Every command has its own arm and there is no wildcard, so a
+            // command added to the protocol doesn't build until it is
+            // handled here. Commands that are only valid in the middle of an
+            // exchange nack `Unexpected`.
             match message {
                 Command::Reset => {
-                    crate::shutdown().await;
+                    self.ack_shutdown(AckType::AckReset).await;
                     // Safety: this is safe as code will never return from this function
                     let mut watchdog = Watchdog::new(unsafe { WATCHDOG::steal() });
                     watchdog.trigger_reset();
                     loop {}
                 }
                 Command::UsbDfu => {
-                    crate::shutdown().await;
+                    self.ack_shutdown(AckType::AckUsbDfu).await;
                     rom_data::reset_to_usb_boot(0, 0);
                     loop {}
                 }
                 Command::EchoMsg { count } => {
                     self.packet.send_packet(&Response::EchoMsg { count }).await;
-                    self.packet.recv_data(count as u32, &mut EchoRecvr).await;
+                    let res = self
+                        .packet
+                        .recv_data::<Crc8, _>(count as u32, &mut EchoRecvr)
+                        .await;
+                    if let Err(e) = res {
+                        warn!("Echo of {} bytes broken off: {:?}", count, e);
+                    }
                 }
                 Command::Data(_data) => {
                     self.packet
                         .send_packet(&Response::Nack(NackType::Unexpected))
                         .await;
                 }
+                Command::SetMacro { slot, data } => {
+                    let res = self
+                        .settings
+                        .macros()
+                        .lock(|m| m.borrow_mut().set(slot, data));
+                    let response = match res {
+                        Ok(()) => match self.settings.store().await {
+                            Ok(()) => Response::Ack(AckType::AckMacro),
+                            Err(_) => Response::Nack(NackType::StoreFailed),
+                        },
+                        Err(MacroError::BadSlot) => Response::Nack(NackType::OutOfRange),
+                        Err(MacroError::StoreFull) => Response::Nack(NackType::StoreFull),
+                    };
+                    self.packet.send_packet(&response).await;
+                }
+                Command::GetMacro { slot } => {
+                    let data = self
+                        .settings
+                        .macros()
+                        .lock(|m| m.borrow().get(slot).cloned());
+                    let response = match data {
+                        Some(data) => Response::Macro { slot, data },
+                        None => Response::Nack(NackType::OutOfRange),
+                    };
+                    self.packet.send_packet(&response).await;
+                }
+                Command::TestColumn { col } => {
+                    let response = if (col as usize) < NUM_COLS {
+                        let rows = self.column_test.test(col).await;
+                        Response::ColumnTest { col, rows }
+                    } else {
+                        Response::Nack(NackType::OutOfRange)
+                    };
+                    self.packet.send_packet(&response).await;
+                }
+                Command::FlashFw { count, .. } if count > dfu::max_fw_size() => {
+                    warn!(
+                        "Firmware of {} bytes doesn't fit in {} bytes",
+                        count,
+                        dfu::max_fw_size()
+                    );
+                    self.packet
+                        .send_packet(&Response::Nack(NackType::OutOfRange))
+                        .await;
+                }
+                Command::FlashFw { count, crc } => {
+                    info!("Receiving {} bytes of firmware", count);
+                    self.packet
+                        .send_packet(&Response::Ack(AckType::AckFlashFw))
+                        .await;
+                    let mut session = self.firmware.lock().await;
+                    session.begin().await;
+                    let res = self
+                        .packet
+                        .recv_data::<FlashCrc, _>(
+                            count,
+                            &mut FlashRecvr {
+                                session: &mut session,
+                            },
+                        )
+                        .await;
+                    if let Err(e) = res {
+                        warn!("Firmware transfer broken off: {:?}", e);
+                        session.abort().await;
+                        continue;
+                    }
+                    let response = match session.finish(crc).await {
+                        Ok(()) => Response::Ack(AckType::AckFlashFw),
+                        Err(calculated) => {
+                            warn!("Firmware CRC is {:x}, expected {:x}", calculated, crc);
+                            Response::Nack(NackType::ImageCrcMismatch {
+                                calculated,
+                                expected: crc,
+                            })
+                        }
+                    };
+                    self.packet.send_packet(&response).await;
+                }
+                Command::Ping { seq } => {
+                    self.packet.send_packet(&Response::Pong { seq }).await;
+                }
+                Command::GetFlashInfo => {
+                    self.packet
+                        .send_packet(&Response::FlashInfo {
+                            max_fw_size: dfu::max_fw_size(),
+                            data_count: DATA_COUNT as u16,
+                        })
+                        .await;
+                }
+                Command::VerifyFw { offset, len } => {
+                    let response = match self.firmware.crc(offset, len).await {
+                        Some(crc) => Response::FwCrc(crc),
+                        None => Response::Nack(NackType::OutOfRange),
+                    };
+                    self.packet.send_packet(&response).await;
+                }
+                Command::ReadFlash { offset, len } if !dfu::in_flash(offset, len.into()) => {
+                    self.packet
+                        .send_packet(&Response::Nack(NackType::OutOfRange))
+                        .await;
+                }
+                Command::ReadFlash { offset, len } => {
+                    self.packet
+                        .send_packet(&Response::DataStream { total: len.into() })
+                        .await;
+                    let end = offset + u32::from(len);
+                    let mut buf = [0u8; DATA_COUNT];
+                    let mut pos = offset;
+                    while pos < end {
+                        let count = core::cmp::min(DATA_COUNT as u32, end - pos) as usize;
+                        self.firmware.read(pos, &mut buf[..count]).await;
+                        let data = buf[..count].iter().copied().collect();
+                        self.packet.send_packet(&Response::Data(data)).await;
+                        pos += count as u32;
+                    }
+                    self.packet.send_packet(&Response::DataEnd).await;
+                }
+                Command::ReadTrace => {
+                    let trace = panic_handler::read_trace();
+                    self.packet.send_stream(&trace).await;
+                }
+                Command::GetPanic { offset } => {
+                    let chunk = panic_handler::read_panic(offset as usize);
+                    self.packet.send_packet(&Response::Panic(chunk)).await;
+                }
+                Command::SetLed { r, g, b } => {
+                    heartbeat::release_led();
+                    self.led_signal
+                        .signal(LedUpdate::Frame([Color::new(r, g, b); NUM_LEDS]));
+                    self.packet
+                        .send_packet(&Response::Ack(AckType::AckLed))
+                        .await;
+                }
+                Command::SetKeyLed { loc, r, g, b } => {
+                    heartbeat::release_led();
+                    self.led_signal
+                        .signal(LedUpdate::Key(loc, Color::new(r, g, b)));
+                    self.packet
+                        .send_packet(&Response::Ack(AckType::AckLed))
+                        .await;
+                }
+                Command::SetAnimation(animation) => {
+                    heartbeat::release_led();
+                    self.led_signal
+                        .signal(LedUpdate::Animation(animation.into()));
+                    self.packet
+                        .send_packet(&Response::Ack(AckType::AckLed))
+                        .await;
+                }
+                Command::GetMatrix => {
+                    let state = self.matrix.lock(Cell::get);
+                    self.packet.send_packet(&Response::Matrix(state)).await;
+                }
+                Command::EnterTestMode => {
+                    self.packet
+                        .send_packet(&Response::Ack(AckType::AckTestMode))
+                        .await;
+                    // Only changes are streamed, the host starts out with no
+                    // keys pressed
+                    self.test_mode = Some(KeyState::no_keys());
+                }
+                Command::ExitTestMode => {
+                    self.test_mode = None;
+                    self.packet
+                        .send_packet(&Response::Ack(AckType::AckTestMode))
+                        .await;
+                }
+                Command::SelfTest => {
+                    let mut results = SelfTestResults::default();
+                    results.set(SelfTestCheck::Flash, self.firmware.self_test().await);
+                    let peer_timeout = Duration::from_millis(PEER_TIMEOUT_MS);
+                    let peer = self.peer_link.wait_heard(peer_timeout).await;
+                    results.set(SelfTestCheck::Peer, peer);
+                    // The HID interfaces are part of the only configuration,
+                    // so their endpoints are enabled along with it
+                    let usb = self.usb_configured.load(Ordering::Relaxed);
+                    results.set(SelfTestCheck::Usb, usb);
+                    self.packet.send_packet(&Response::SelfTest(results)).await;
+                }
+                Command::GetDeviceId => {
+                    self.packet
+                        .send_packet(&Response::DeviceId(self.device_id))
+                        .await;
+                }
+                Command::EraseFw => {
+                    info!("Erasing the DFU partition");
+                    self.firmware.erase().await;
+                    self.packet
+                        .send_packet(&Response::Ack(AckType::AckErase))
+                        .await;
+                }
+                Command::SetHand(hand) => {
+                    let previous = self.settings.hand();
+                    self.settings.set_hand(hand);
+                    if self.settings.store().await.is_err() {
+                        // Resetting would only come back as the same half
+                        self.settings.set_hand(previous);
+                        self.packet
+                            .send_packet(&Response::Nack(NackType::StoreFailed))
+                            .await;
+                        continue;
+                    }
+                    info!("Hand override set to {:?}, resetting", hand);
+                    self.ack_shutdown(AckType::AckHand).await;
+                    // Safety: this is safe as code will never return from this function
+                    let mut watchdog = Watchdog::new(unsafe { WATCHDOG::steal() });
+                    watchdog.trigger_reset();
+                    loop {}
+                }
+                Command::GetConfig => {
+                    self.packet
+                        .send_packet(&Response::Config(self.settings.config()))
+                        .await;
+                }
+                Command::SetConfig(config) if usize::from(config.default_layer) >= NUM_LAYERS => {
+                    self.packet
+                        .send_packet(&Response::Nack(NackType::OutOfRange))
+                        .await;
+                }
+                Command::SetConfig(config) => {
+                    self.settings.set_config(config);
+                    self.brightness_signal.signal(config.led_brightness);
+                    let response = match self.settings.store().await {
+                        Ok(()) => Response::Ack(AckType::AckConfig),
+                        Err(_) => Response::Nack(NackType::StoreFailed),
+                    };
+                    self.packet.send_packet(&response).await;
+                }
+                Command::UploadKeymap { count } => {
+                    let Some(mut recvr) = BufferRecvr::<{ Layout::POSTCARD_MAX_SIZE }>::new(count)
+                    else {
+                        self.packet
+                            .send_packet(&Response::Nack(NackType::OutOfRange))
+                            .await;
+                        continue;
+                    };
+                    self.packet
+                        .send_packet(&Response::Ack(AckType::AckKeymap))
+                        .await;
+                    let res = self
+                        .packet
+                        .recv_data::<FlashCrc, _>(count, &mut recvr)
+                        .await;
+                    if let Err(e) = res {
+                        // The keymap in use stays
+                        warn!("Keymap upload broken off: {:?}", e);
+                        continue;
+                    }
+
+                    let layout = postcard::from_bytes::<Layout>(&recvr.into_inner());
+                    let response = match layout {
+                        Ok(layout) => match layout.table() {
+                            Ok(table) => {
+                                info!("Applying uploaded keymap");
+                                self.settings.set_keymap(Some(table));
+                                match self.settings.store().await {
+                                    Ok(()) => Response::Ack(AckType::AckKeymap),
+                                    Err(_) => Response::Nack(NackType::StoreFailed),
+                                }
+                            }
+                            Err(e) => {
+                                warn!("Uploaded keymap is invalid: {:?}", e);
+                                Response::Nack(NackType::OutOfRange)
+                            }
+                        },
+                        Err(e) => Response::Nack(NackType::PacketErr(ProtoError::from(e))),
+                    };
+                    self.packet.send_packet(&response).await;
+                }
+                Command::SetLogLevel(level) => {
+                    logging::set_level(level);
+                    self.packet
+                        .send_packet(&Response::Ack(AckType::AckLogLevel))
+                        .await;
+                }
+                Command::GetVersion => {
+                    self.packet
+                        .send_packet(&Response::Version(CURRENT_VERSION))
+                        .await;
+                }
+                Command::GetBuildInfo => {
+                    self.packet
+                        .send_packet(&Response::BuildInfo(build_info()))
+                        .await;
+                }
             }
         }
     }
+
+    /// Get everything out to the host before a reset, in order: the log
+    /// output queued so far, then `ack`, waiting until the host has taken
+    /// it, and only then the USB shutdown. Each wait is bounded, a host that
+    /// stopped reading doesn't hold up the reset.
+    async fn ack_shutdown(&mut self, ack: AckType) {
+        logging::drain(LOG_DRAIN_MS).await;
+        self.packet.send_packet(&Response::Ack(ack)).await;
+        if !self.packet.drain().await {
+            warn!("The host didn't read the reset ack");
+        }
+        crate::shutdown().await;
+    }
 }
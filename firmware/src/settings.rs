@@ -0,0 +1,182 @@
+use core::{
+    cell::{Cell, RefCell},
+    ptr::addr_of,
+};
+
+use embassy_rp::{
+    flash::{Async, Flash, ERASE_SIZE},
+    peripherals::FLASH,
+};
+use embassy_sync::{blocking_mutex, mutex::Mutex};
+use picodox_proto::{
+    keymap::{LayerTable, Layout},
+    settings::{
+        settings_decode, settings_encode, Config, MacroStore, Settings, SETTINGS_BLOB_SIZE,
+    },
+    Hand,
+};
+
+use crate::util::MutexType;
+
+pub const FLASH_SIZE: usize = 8 * 1024 * 1024;
+// Async flash reads must be a multiple of 4 bytes
+const SETTINGS_READ_SIZE: usize = (SETTINGS_BLOB_SIZE + 3) & !3;
+
+// Start of the SETTINGS region from memory.x, the symbol address is the
+// flash offset
+extern "C" {
+    static __settings_offset: u8;
+}
+
+fn settings_offset() -> u32 {
+    addr_of!(__settings_offset) as u32
+}
+
+pub type FlashType = Flash<'static, FLASH, Async, FLASH_SIZE>;
+pub type SharedFlash = Mutex<MutexType, FlashType>;
+pub type SharedMacros = blocking_mutex::Mutex<MutexType, RefCell<MacroStore>>;
+/// Read by the tasks that use it whenever they need a value, so a new config
+/// takes effect without restarting anything
+pub type SharedConfig = blocking_mutex::Mutex<MutexType, Cell<Config>>;
+/// The uploaded keymap, or None for the one built into the firmware. Read
+/// the same way as the config.
+pub type SharedKeymap = blocking_mutex::Mutex<MutexType, Cell<Option<LayerTable>>>;
+
+/// Why `store` couldn't persist the settings, the details are logged
+#[derive(Debug)]
+pub enum StoreError {
+    Encode,
+    Erase,
+    Write,
+}
+
+pub struct SettingsStore<'d> {
+    flash: &'d SharedFlash,
+    macros: &'d SharedMacros,
+    config: &'d SharedConfig,
+    keymap: &'d SharedKeymap,
+    /// Only read on boot, kept so `store` writes it back
+    hand: Cell<Option<Hand>>,
+}
+
+impl<'d> SettingsStore<'d> {
+    pub fn new(
+        flash: &'d SharedFlash,
+        macros: &'d SharedMacros,
+        config: &'d SharedConfig,
+        keymap: &'d SharedKeymap,
+    ) -> Self {
+        SettingsStore {
+            flash,
+            macros,
+            config,
+            keymap,
+            hand: Cell::new(None),
+        }
+    }
+
+    pub fn macros(&self) -> &'d SharedMacros {
+        self.macros
+    }
+
+    pub fn config(&self) -> Config {
+        self.config.lock(Cell::get)
+    }
+
+    /// Replace the config, `store` persists it
+    pub fn set_config(&self, config: Config) {
+        self.config.lock(|c| c.set(config));
+    }
+
+    /// Replace the keymap, `store` persists it
+    pub fn set_keymap(&self, keymap: Option<LayerTable>) {
+        self.keymap.lock(|k| k.set(keymap));
+    }
+
+    /// The stored hand override, None to read the jumper
+    pub fn hand(&self) -> Option<Hand> {
+        self.hand.get()
+    }
+
+    /// Replace the hand override, `store` persists it and it takes effect on
+    /// the next boot
+    pub fn set_hand(&self, hand: Option<Hand>) {
+        self.hand.set(hand);
+    }
+
+    /// Load the settings from flash, falling back to the defaults if the
+    /// settings page is empty or corrupt
+    pub async fn load(&self) {
+        let mut buf = [0u8; SETTINGS_READ_SIZE];
+        let read = self
+            .flash
+            .lock()
+            .await
+            .read(settings_offset(), &mut buf)
+            .await;
+        let settings = match read {
+            Ok(()) => match settings_decode(&mut buf) {
+                Ok(settings) => settings,
+                Err(e) => {
+                    warn!("Stored settings are invalid, using defaults: {:?}", e);
+                    Settings::default()
+                }
+            },
+            Err(e) => {
+                warn!("Failed to read settings page: {:?}", e);
+                Settings::default()
+            }
+        };
+
+        info!("Loaded settings ({} macro bytes)", settings.macros.used());
+        self.macros.lock(|m| m.replace(settings.macros));
+        self.set_config(settings.config);
+        self.set_hand(settings.hand);
+
+        // Checked on upload, but the firmware it was uploaded to may have had
+        // other layers or macro slots
+        let keymap = settings.keymap.and_then(|layout| match layout.table() {
+            Ok(table) => Some(table),
+            Err(e) => {
+                warn!("Stored keymap is invalid, using the built in one: {:?}", e);
+                None
+            }
+        });
+        if keymap.is_some() {
+            info!("Using the uploaded keymap");
+        }
+        self.set_keymap(keymap);
+    }
+
+    /// Write the current settings to flash
+    pub async fn store(&self) -> Result<(), StoreError> {
+        let settings = Settings {
+            macros: self.macros.lock(|m| m.borrow().clone()),
+            config: self.config(),
+            keymap: self
+                .keymap
+                .lock(Cell::get)
+                .map(|table| Layout::from_table(&table)),
+            hand: self.hand(),
+        };
+        let blob = match settings_encode(&settings) {
+            Ok(blob) => blob,
+            Err(e) => {
+                warn!("Failed to encode settings: {:?}", e);
+                return Err(StoreError::Encode);
+            }
+        };
+
+        let offset = settings_offset();
+        let mut flash = self.flash.lock().await;
+        if let Err(e) = flash.blocking_erase(offset, offset + ERASE_SIZE as u32) {
+            warn!("Failed to erase settings page: {:?}", e);
+            return Err(StoreError::Erase);
+        }
+        if let Err(e) = flash.blocking_write(offset, &blob) {
+            warn!("Failed to write settings page: {:?}", e);
+            return Err(StoreError::Write);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,65 @@
+//! Pimoroni trackball breakout on the split link's I2C bus
+//!
+//! Only the master can poll it, so it goes on the right half. The motion is
+//! sent across as `LinkFrame::Pointer` and collected in a `PointerDelta` on
+//! the left half, which reports it with the next mouse report.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::Mutex;
+use picodox_proto::PointerMotion;
+
+use crate::util::MutexType;
+
+/// The breakout's address unless it was changed
+#[allow(dead_code)]
+pub const DEFAULT_ADDR: u16 = 0x0a;
+/// First of the left, right, up and down registers. Each counts the steps
+/// the ball rolled that way since it was last read, and reading clears it.
+pub const REG_LEFT: u8 = 0x04;
+pub const MOTION_REGS: usize = 4;
+
+/// The motion in a read of the `MOTION_REGS` registers
+pub fn motion(regs: &[u8; MOTION_REGS]) -> PointerMotion {
+    let [left, right, up, down] = regs.map(i16::from);
+    let clamp = |steps: i16| steps.clamp(i8::MIN.into(), i8::MAX.into()) as i8;
+    PointerMotion {
+        dx: clamp(right - left),
+        dy: clamp(down - up),
+    }
+}
+
+/// Pointer motion that has not been reported yet
+pub struct PointerDelta {
+    delta: Mutex<MutexType, Cell<(i32, i32)>>,
+}
+
+impl PointerDelta {
+    pub const fn new() -> Self {
+        PointerDelta {
+            delta: Mutex::new(Cell::new((0, 0))),
+        }
+    }
+
+    pub fn add(&self, motion: PointerMotion) {
+        self.delta.lock(|d| {
+            let (x, y) = d.get();
+            d.set((
+                x.saturating_add(motion.dx.into()),
+                y.saturating_add(motion.dy.into()),
+            ));
+        });
+    }
+
+    /// Take as much of the motion as one mouse report holds, the rest waits
+    /// for the next one
+    pub fn take(&self) -> (i8, i8) {
+        self.delta.lock(|d| {
+            let (x, y) = d.get();
+            let clamp = |v: i32| v.clamp(i8::MIN.into(), i8::MAX.into());
+            let (dx, dy) = (clamp(x), clamp(y));
+            d.set((x - dx, y - dy));
+            (dx as i8, dy as i8)
+        })
+    }
+}
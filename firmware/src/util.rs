@@ -2,6 +2,66 @@ use embassy_sync::blocking_mutex::raw::NoopRawMutex;
 
 pub type MutexType = NoopRawMutex;
 
+// Firmware logs go through these rather than the defmt macros, so the
+// plain-log feature can turn them into text lines, see logging.rs. The
+// arguments have to format both ways, which `{:?}` does for most types.
+macro_rules! trace {
+    ($($args:tt)*) => {{
+        #[cfg(not(feature = "plain-log"))]
+        defmt::trace!($($args)*);
+        #[cfg(feature = "plain-log")]
+        $crate::logging::write_line(
+            Some(picodox_proto::LogLevel::Trace),
+            format_args!($($args)*),
+        );
+    }};
+}
+
+macro_rules! info {
+    ($($args:tt)*) => {{
+        #[cfg(not(feature = "plain-log"))]
+        defmt::info!($($args)*);
+        #[cfg(feature = "plain-log")]
+        $crate::logging::write_line(
+            Some(picodox_proto::LogLevel::Info),
+            format_args!($($args)*),
+        );
+    }};
+}
+
+macro_rules! warn {
+    ($($args:tt)*) => {{
+        #[cfg(not(feature = "plain-log"))]
+        defmt::warn!($($args)*);
+        #[cfg(feature = "plain-log")]
+        $crate::logging::write_line(
+            Some(picodox_proto::LogLevel::Warn),
+            format_args!($($args)*),
+        );
+    }};
+}
+
+macro_rules! error {
+    ($($args:tt)*) => {{
+        #[cfg(not(feature = "plain-log"))]
+        defmt::error!($($args)*);
+        #[cfg(feature = "plain-log")]
+        $crate::logging::write_line(
+            Some(picodox_proto::LogLevel::Error),
+            format_args!($($args)*),
+        );
+    }};
+}
+
+macro_rules! println {
+    ($($args:tt)*) => {{
+        #[cfg(not(feature = "plain-log"))]
+        defmt::println!($($args)*);
+        #[cfg(feature = "plain-log")]
+        $crate::logging::write_line(None, format_args!($($args)*));
+    }};
+}
+
 macro_rules! async_unwrap {
     (op $option:expr, $($error_args:expr),*) => {{
         match $option {
@@ -20,7 +80,6 @@ macro_rules! async_unwrap {
 macro_rules! async_panic {
     ($($error_args:expr),*) => {{
         use core::future::pending;
-        use defmt::error;
         error!($($error_args),*);
         pending().await
     }};
@@ -0,0 +1,197 @@
+//! Caps word
+//!
+//! Tapping shift twice turns on caps word. Letters are shifted, digits and
+//! backspace go through as they are, and any other key turns it off again and
+//! goes through unshifted, so a space ends the word. Tapping shift twice
+//! again also turns it off.
+
+use heapless::Vec;
+
+/// Left and right shift in the report modifiers
+const SHIFT: u8 = 0x22;
+const LEFT_SHIFT: u8 = 0x02;
+
+const USAGE_A: u8 = 0x04;
+const USAGE_Z: u8 = 0x1d;
+const USAGE_1: u8 = 0x1e;
+const USAGE_0: u8 = 0x27;
+const USAGE_BACKSPACE: u8 = 0x2a;
+
+fn is_letter(code: u8) -> bool {
+    (USAGE_A..=USAGE_Z).contains(&code)
+}
+
+fn is_digit(code: u8) -> bool {
+    (USAGE_1..=USAGE_0).contains(&code)
+}
+
+pub struct CapsWord {
+    term_ms: u64,
+    active: bool,
+    /// Shift held at the last update
+    shift_down: bool,
+    /// Shift held down with no key pressed since
+    tapping: bool,
+    /// The shift held down is the second tap
+    second_tap: bool,
+    /// When the last shift tap was released, while another tap can double it
+    tapped_ms: Option<u64>,
+    /// Key codes of the last update
+    last: Vec<u8, 6>,
+}
+
+impl CapsWord {
+    /// The second shift tap has to start within `term_ms` of the first one
+    /// being released
+    pub fn new(term_ms: u64) -> Self {
+        CapsWord {
+            term_ms,
+            active: false,
+            shift_down: false,
+            tapping: false,
+            second_tap: false,
+            tapped_ms: None,
+            last: Vec::new(),
+        }
+    }
+
+    /// `modifier` and `codes` are the report so far. Returns the modifiers to
+    /// add to it.
+    pub fn update(&mut self, modifier: u8, codes: &[u8], now_ms: u64) -> u8 {
+        let shift = modifier & SHIFT != 0;
+        let mut pressed = codes.iter().filter(|code| !self.last.contains(code));
+        let any_pressed = pressed.clone().next().is_some();
+
+        if any_pressed {
+            self.tapping = false;
+            self.tapped_ms = None;
+        }
+        if shift && !self.shift_down {
+            self.second_tap = self
+                .tapped_ms
+                .take()
+                .is_some_and(|tapped_ms| now_ms - tapped_ms < self.term_ms);
+            self.tapping = !any_pressed;
+        } else if !shift && self.shift_down && self.tapping {
+            self.tapping = false;
+            if self.second_tap {
+                self.active = !self.active;
+            } else {
+                self.tapped_ms = Some(now_ms);
+            }
+        }
+        self.shift_down = shift;
+
+        // Shortcuts with other modifiers end the word as well
+        let ends = pressed
+            .any(|&code| !is_letter(code) && !is_digit(code) && code != USAGE_BACKSPACE)
+            || (any_pressed && modifier & !SHIFT != 0);
+        if self.active && ends {
+            self.active = false;
+        }
+        self.last = codes.iter().copied().take(self.last.capacity()).collect();
+
+        // Shift applies to the whole report, a digit rolled together with a
+        // letter would turn into a symbol
+        let shifted = self.active
+            && codes.iter().any(|&code| is_letter(code))
+            && !codes.iter().any(|&code| is_digit(code));
+        if shifted {
+            LEFT_SHIFT
+        } else {
+            0
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TERM: u64 = 200;
+    const A: u8 = USAGE_A;
+    const B: u8 = USAGE_A + 1;
+    const ONE: u8 = USAGE_1;
+    const SPACE: u8 = 0x2c;
+    const CTRL: u8 = 0x01;
+
+    /// Tap shift twice starting at `now_ms`
+    fn double_tap(caps: &mut CapsWord, now_ms: u64) {
+        for (step, shift) in [LEFT_SHIFT, 0, LEFT_SHIFT, 0].into_iter().enumerate() {
+            caps.update(shift, &[], now_ms + 20 * step as u64);
+        }
+    }
+
+    #[test]
+    fn enter() {
+        let mut caps = CapsWord::new(TERM);
+        double_tap(&mut caps, 0);
+        assert!(caps.is_active());
+
+        // Too slow
+        let mut caps = CapsWord::new(TERM);
+        caps.update(LEFT_SHIFT, &[], 0);
+        caps.update(0, &[], 20);
+        caps.update(LEFT_SHIFT, &[], 20 + TERM);
+        caps.update(0, &[], 40 + TERM);
+        assert!(!caps.is_active());
+
+        // Shift used as a modifier in between
+        let mut caps = CapsWord::new(TERM);
+        caps.update(LEFT_SHIFT, &[], 0);
+        caps.update(LEFT_SHIFT, &[A], 20);
+        caps.update(0, &[], 40);
+        caps.update(LEFT_SHIFT, &[], 60);
+        caps.update(0, &[], 80);
+        assert!(!caps.is_active());
+    }
+
+    #[test]
+    fn shift_letters() {
+        let mut caps = CapsWord::new(TERM);
+        assert_eq!(caps.update(0, &[A], 0), 0);
+        caps.update(0, &[], 20);
+
+        double_tap(&mut caps, 100);
+        assert_eq!(caps.update(0, &[A], 200), LEFT_SHIFT);
+        assert_eq!(caps.update(0, &[A, B], 220), LEFT_SHIFT);
+        assert_eq!(caps.update(0, &[], 240), 0);
+        assert!(caps.is_active());
+    }
+
+    #[test]
+    fn digits_pass() {
+        let mut caps = CapsWord::new(TERM);
+        double_tap(&mut caps, 0);
+        assert_eq!(caps.update(0, &[ONE], 100), 0);
+        assert_eq!(caps.update(0, &[], 120), 0);
+        assert!(caps.is_active());
+        assert_eq!(caps.update(0, &[B], 140), LEFT_SHIFT);
+    }
+
+    #[test]
+    fn exit() {
+        let mut caps = CapsWord::new(TERM);
+        double_tap(&mut caps, 0);
+        caps.update(0, &[A], 100);
+        assert_eq!(caps.update(0, &[A, SPACE], 120), 0);
+        assert!(!caps.is_active());
+        assert_eq!(caps.update(0, &[B], 140), 0);
+
+        // A shortcut
+        let mut caps = CapsWord::new(TERM);
+        double_tap(&mut caps, 0);
+        assert_eq!(caps.update(CTRL, &[A], 100), 0);
+        assert!(!caps.is_active());
+
+        // Tapping shift twice again
+        let mut caps = CapsWord::new(TERM);
+        double_tap(&mut caps, 0);
+        double_tap(&mut caps, 100);
+        assert!(!caps.is_active());
+    }
+}
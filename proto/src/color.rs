@@ -0,0 +1,55 @@
+//! Color math for the LED animations
+//!
+//! Kept free of the LED driver so it can be tested on the host.
+
+/// Positions in each third of the wheel
+const RAMP_LEN: u16 = 85;
+
+/// RGB of a position on the color wheel. It goes from red through green and
+/// blue back to red, and every channel moves by at most 3 per position, also
+/// where 255 wraps around to 0.
+pub fn wheel(pos: u8) -> [u8; 3] {
+    let pos = u16::from(u8::MAX - pos);
+    // At most 84 * 3, so neither ramp can leave the u8 range
+    let rise = (pos % RAMP_LEN * 3) as u8;
+    let fall = u8::MAX - rise;
+    match pos / RAMP_LEN {
+        // 255 is the end of the last third, the same red as the start
+        0 | 3 => [fall, 0, rise],
+        1 => [0, rise, fall],
+        _ => [rise, fall, 0],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wheel_colors() {
+        assert_eq!(wheel(0), [255, 0, 0]);
+        assert_eq!(wheel(85), [0, 255, 0]);
+        assert_eq!(wheel(170), [0, 0, 255]);
+        assert_eq!(wheel(255), [255, 0, 0]);
+    }
+
+    #[test]
+    fn wheel_is_smooth() {
+        for pos in 0..=u8::MAX {
+            let color = wheel(pos);
+            let next = wheel(pos.wrapping_add(1));
+            for (a, b) in color.into_iter().zip(next) {
+                assert!(
+                    a.abs_diff(b) <= 3,
+                    "{} -> {}: {:?} {:?}",
+                    pos,
+                    pos.wrapping_add(1),
+                    color,
+                    next
+                );
+            }
+            // Always at full brightness
+            assert_eq!(color.iter().map(|&c| u16::from(c)).sum::<u16>(), 255);
+        }
+    }
+}
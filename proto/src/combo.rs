@@ -0,0 +1,258 @@
+//! Chords: pairs of keys that produce something else when pressed together
+//!
+//! A key that is part of a combo is held back when it is pressed. If the
+//! rest of a combo is pressed within the combo term the combo fires, and its
+//! keys stay out of the report until they are released. Otherwise the held
+//! back keys are passed on late, ahead of any key pressed after them, so a
+//! fast roll over two combo keys still types both.
+
+use crate::KeyState;
+
+/// Two keys, by `KeyState` index, that send `action` when pressed together
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Combo<T> {
+    pub keys: [usize; 2],
+    pub action: T,
+}
+
+impl<T> Combo<T> {
+    fn mask(&self) -> u128 {
+        (1 << self.keys[0]) | (1 << self.keys[1])
+    }
+}
+
+/// Define a combo, bad key indices fail the build when used in a const
+pub const fn combo<T: Copy>(a: usize, b: usize, action: T) -> Combo<T> {
+    assert!(a < KeyState::LEN && b < KeyState::LEN);
+    assert!(a != b);
+    Combo {
+        keys: [a, b],
+        action,
+    }
+}
+
+pub struct ComboResolver<'c, T> {
+    combos: &'c [Combo<T>],
+    term_ms: u64,
+    /// Every key that is part of a combo
+    members: u128,
+    /// Keys pressed at the last update
+    last: u128,
+    /// Combo keys waiting for the rest of their combo
+    pending: u128,
+    /// When the first of the pending keys was pressed
+    since_ms: u64,
+    /// Keys of fired combos, left out of the report until released
+    consumed: u128,
+    /// Keys pressed when pending keys were let through, they are sent from
+    /// the next update on
+    deferred: u128,
+    /// Bitmask of the combos that are firing
+    active: u32,
+    /// New combos only start while this is set
+    enabled: bool,
+}
+
+impl<'c, T> ComboResolver<'c, T> {
+    /// Earlier combos win when several could fire at once
+    pub fn new(combos: &'c [Combo<T>], term_ms: u64) -> Self {
+        assert!(combos.len() <= u32::BITS as usize);
+        ComboResolver {
+            combos,
+            term_ms,
+            members: combos.iter().fold(0, |mask, combo| mask | combo.mask()),
+            last: 0,
+            pending: 0,
+            since_ms: 0,
+            consumed: 0,
+            deferred: 0,
+            active: 0,
+            enabled: true,
+        }
+    }
+
+    /// Turn combos off for keys pressed from the next update on, for when
+    /// their keys mean something else. Keys already held back are let
+    /// through then, a combo that is firing lasts until it is released.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Feed the keys currently pressed, returns the keys the rest of the
+    /// keymap should see. Keys that were held back are reported pressed for
+    /// at least one update, even if they have been released since.
+    pub fn update(&mut self, state: &KeyState, now_ms: u64) -> KeyState {
+        let physical = state.0;
+        let pressed = physical & !self.last;
+        self.last = physical;
+
+        // A combo ends when either of its keys is released, the other one
+        // stays out of the report until it is released too
+        for (idx, combo) in self.combos.iter().enumerate() {
+            if combo.mask() & !physical != 0 {
+                self.active &= !(1 << idx);
+            }
+        }
+        self.consumed &= physical;
+
+        let joining = if self.enabled {
+            pressed & self.members & !self.consumed
+        } else {
+            0
+        };
+        if self.pending == 0 {
+            self.since_ms = now_ms;
+        }
+        self.pending |= joining;
+
+        for (idx, combo) in self.combos.iter().enumerate() {
+            let keys = combo.mask();
+            if self.pending & keys == keys {
+                self.pending &= !keys;
+                self.consumed |= keys;
+                self.active |= 1 << idx;
+            }
+        }
+
+        // Pending keys are let through once the term is up, once one of
+        // them is released, once a key outside of any combo is pressed, or
+        // once combos are turned off
+        let others = pressed & !joining;
+        let mut flushed = 0;
+        if self.pending != 0
+            && (!self.enabled
+                || now_ms - self.since_ms >= self.term_ms
+                || self.pending & !physical != 0
+                || others != 0)
+        {
+            flushed = self.pending;
+            self.pending = 0;
+        }
+        let deferred =
+            core::mem::replace(&mut self.deferred, if flushed != 0 { others } else { 0 });
+
+        KeyState((physical & !self.pending & !self.consumed & !self.deferred) | flushed | deferred)
+    }
+
+    /// Actions of the combos that are firing
+    pub fn active(&self) -> impl Iterator<Item = &'c T> + '_ {
+        self.combos
+            .iter()
+            .enumerate()
+            .filter(|(idx, _)| self.active & (1 << idx) != 0)
+            .map(|(_, combo)| &combo.action)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use heapless::Vec;
+
+    const TERM: u64 = 50;
+    const COMBOS: [Combo<char>; 2] = [combo(0, 1, 'a'), combo(1, 2, 'b')];
+
+    fn keys(pressed: &[usize]) -> KeyState {
+        let mut state = KeyState::no_keys();
+        for &idx in pressed {
+            state.press(idx);
+        }
+        state
+    }
+
+    fn active(resolver: &ComboResolver<char>) -> Vec<char, 2> {
+        resolver.active().copied().collect()
+    }
+
+    #[test]
+    fn combo_fires() {
+        let mut resolver = ComboResolver::new(&COMBOS, TERM);
+        assert_eq!(resolver.update(&keys(&[0]), 0), keys(&[]));
+        assert_eq!(resolver.update(&keys(&[0, 1]), 20), keys(&[]));
+        assert_eq!(active(&resolver), ['a']);
+        assert_eq!(resolver.update(&keys(&[0, 1]), 200), keys(&[]));
+        assert_eq!(active(&resolver), ['a']);
+
+        // Releasing either key ends the combo, the other stays suppressed
+        assert_eq!(resolver.update(&keys(&[1]), 220), keys(&[]));
+        assert!(active(&resolver).is_empty());
+        assert_eq!(resolver.update(&keys(&[]), 240), keys(&[]));
+
+        // Keys outside of a combo are not held back
+        let mut resolver = ComboResolver::new(&COMBOS, TERM);
+        assert_eq!(resolver.update(&keys(&[5]), 0), keys(&[5]));
+    }
+
+    #[test]
+    fn partial_press() {
+        // Held past the term
+        let mut resolver = ComboResolver::new(&COMBOS, TERM);
+        assert_eq!(resolver.update(&keys(&[0]), 0), keys(&[]));
+        assert_eq!(resolver.update(&keys(&[0]), 40), keys(&[]));
+        assert_eq!(resolver.update(&keys(&[0]), 60), keys(&[0]));
+        assert_eq!(resolver.update(&keys(&[0, 1]), 80), keys(&[0]));
+        assert!(active(&resolver).is_empty());
+
+        // Tapped within the term, still sent once
+        let mut resolver = ComboResolver::new(&COMBOS, TERM);
+        assert_eq!(resolver.update(&keys(&[0]), 0), keys(&[]));
+        assert_eq!(resolver.update(&keys(&[]), 20), keys(&[0]));
+        assert_eq!(resolver.update(&keys(&[]), 40), keys(&[]));
+
+        // A roll onto another key lets the combo key through first
+        let mut resolver = ComboResolver::new(&COMBOS, TERM);
+        assert_eq!(resolver.update(&keys(&[0]), 0), keys(&[]));
+        assert_eq!(resolver.update(&keys(&[]), 10), keys(&[0]));
+        assert_eq!(resolver.update(&keys(&[1]), 20), keys(&[]));
+        assert_eq!(resolver.update(&keys(&[1, 5]), 30), keys(&[1]));
+        assert_eq!(resolver.update(&keys(&[]), 40), keys(&[5]));
+        assert_eq!(resolver.update(&keys(&[]), 50), keys(&[]));
+    }
+
+    #[test]
+    fn overlapping_combos() {
+        let mut resolver = ComboResolver::new(&COMBOS, TERM);
+        assert_eq!(resolver.update(&keys(&[1]), 0), keys(&[]));
+        assert_eq!(resolver.update(&keys(&[1, 2]), 20), keys(&[]));
+        assert_eq!(active(&resolver), ['b']);
+        // A key can only be in one firing combo
+        assert_eq!(resolver.update(&keys(&[0, 1, 2]), 40), keys(&[]));
+        assert_eq!(active(&resolver), ['b']);
+        assert_eq!(resolver.update(&keys(&[0, 1, 2]), 100), keys(&[0]));
+
+        // The first combo wins when all three land together, the leftover
+        // key falls through after the term
+        let mut resolver = ComboResolver::new(&COMBOS, TERM);
+        assert_eq!(resolver.update(&keys(&[0, 1, 2]), 0), keys(&[]));
+        assert_eq!(active(&resolver), ['a']);
+        assert_eq!(resolver.update(&keys(&[0, 1, 2]), 60), keys(&[2]));
+    }
+
+    #[test]
+    fn disabled() {
+        // Combo keys pass straight through
+        let mut resolver = ComboResolver::new(&COMBOS, TERM);
+        resolver.set_enabled(false);
+        assert_eq!(resolver.update(&keys(&[0]), 0), keys(&[0]));
+        assert_eq!(resolver.update(&keys(&[0, 1]), 10), keys(&[0, 1]));
+        assert!(active(&resolver).is_empty());
+
+        // A held back key is let through, a firing combo carries on
+        let mut resolver = ComboResolver::new(&COMBOS, TERM);
+        assert_eq!(resolver.update(&keys(&[2]), 0), keys(&[]));
+        resolver.set_enabled(false);
+        assert_eq!(resolver.update(&keys(&[2]), 10), keys(&[2]));
+        let mut resolver = ComboResolver::new(&COMBOS, TERM);
+        assert_eq!(resolver.update(&keys(&[0, 1]), 0), keys(&[]));
+        resolver.set_enabled(false);
+        assert_eq!(resolver.update(&keys(&[0, 1]), 10), keys(&[]));
+        assert_eq!(active(&resolver), ['a']);
+
+        // And back on they work again
+        resolver.set_enabled(true);
+        assert_eq!(resolver.update(&keys(&[]), 20), keys(&[]));
+        assert_eq!(resolver.update(&keys(&[1, 2]), 30), keys(&[]));
+        assert_eq!(active(&resolver), ['b']);
+    }
+}
@@ -1,14 +1,46 @@
+use core::fmt;
+
 use postcard::experimental::max_size::MaxSize;
 use serde::{Deserialize, Serialize};
 
+/// The `kind` of each `ProtoError::Invariant`, where the framing went wrong
+pub mod invariant {
+    /// The checksum didn't fit after the serialized message
+    pub const CRC_OVERFLOW: u8 = 0x1;
+    /// The COBS buffer couldn't be sized
+    pub const COBS_BUFFER: u8 = 0x2;
+    /// COBS encoding ran out of buffer
+    pub const COBS_ENCODE: u8 = 0x3;
+    /// The sentinel didn't fit after the COBS frame
+    pub const SENTINEL_OVERFLOW: u8 = 0x4;
+    /// A received frame didn't end in the sentinel
+    pub const MISSING_SENTINEL: u8 = 0x5;
+    /// A received frame wasn't valid COBS
+    pub const COBS_DECODE: u8 = 0x6;
+
+    /// What went wrong, for an invariant `kind`
+    pub fn describe(kind: u8) -> &'static str {
+        match kind {
+            CRC_OVERFLOW => "checksum doesn't fit in the buffer",
+            COBS_BUFFER => "COBS buffer couldn't be allocated",
+            COBS_ENCODE => "COBS encoding overflowed",
+            SENTINEL_OVERFLOW => "frame sentinel doesn't fit in the buffer",
+            MISSING_SENTINEL => "frame doesn't end in a sentinel",
+            COBS_DECODE => "frame isn't valid COBS",
+            _ => "unknown",
+        }
+    }
+}
+
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
 pub enum ProtoError {
     BufferSize,
     PostcardError(u8),
-    CrcMismatch { calculated: u8, actual: u8 },
+    CrcMismatch { calculated: u16, actual: u16 },
     BadLength { len: u8 },
     Invariant { kind: u8 },
+    BadMagic,
 }
 
 impl ProtoError {
@@ -16,7 +48,7 @@ impl ProtoError {
         ProtoError::BufferSize
     }
 
-    pub fn crc_mismatch(calculated: u8, actual: u8) -> Self {
+    pub fn crc_mismatch(calculated: u16, actual: u16) -> Self {
         ProtoError::CrcMismatch { calculated, actual }
     }
 
@@ -31,6 +63,30 @@ impl ProtoError {
     }
 }
 
+impl fmt::Display for ProtoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            ProtoError::BufferSize => write!(f, "buffer too small for the message"),
+            ProtoError::PostcardError(code) => {
+                write!(f, "message didn't (de)serialize (postcard error {})", code)
+            }
+            ProtoError::CrcMismatch { calculated, actual } => write!(
+                f,
+                "CRC mismatch (calculated {:#x}, got {:#x})",
+                calculated, actual
+            ),
+            ProtoError::BadLength { len } => write!(f, "bad length {}", len),
+            ProtoError::Invariant { kind } => write!(
+                f,
+                "framing error {:#x} ({})",
+                kind,
+                invariant::describe(kind)
+            ),
+            ProtoError::BadMagic => write!(f, "bad magic number"),
+        }
+    }
+}
+
 impl From<postcard::Error> for ProtoError {
     fn from(err: postcard::Error) -> Self {
         ProtoError::PostcardError(err as u8)
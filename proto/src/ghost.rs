@@ -0,0 +1,114 @@
+//! Ghost blocking for matrices without a diode per switch
+//!
+//! Without diodes, current can detour through three pressed switches at the
+//! corners of a rectangle, so the fourth corner reads pressed as well. A scan
+//! can't tell that apart from four real presses, so keys that newly complete
+//! a rectangle are held back until it breaks up. Keys that were already
+//! pressed stay pressed, which lets any two keys be held together and
+//! blocks the third one that would be ambiguous.
+
+/// Clear the keys of `scan` that are part of a rectangle of pressed keys and
+/// weren't pressed in `last`, both indexed by row and column. Returns
+/// whether any key was held back.
+pub fn block_ghosts<const R: usize, const C: usize>(
+    scan: &mut [[bool; C]; R],
+    last: &[[bool; C]; R],
+) -> bool {
+    const { assert!(C <= u32::BITS as usize) };
+    let mask = |row: &[bool; C]| {
+        row.iter()
+            .enumerate()
+            .filter(|(_, &pressed)| pressed)
+            .fold(0u32, |mask, (col, _)| mask | (1 << col))
+    };
+    let rows = scan.each_ref().map(mask);
+
+    // Two rows that share two or more pressed columns form a rectangle
+    let mut ambiguous = [0u32; R];
+    for first in 0..R {
+        for second in first + 1..R {
+            let shared = rows[first] & rows[second];
+            if shared.count_ones() >= 2 {
+                ambiguous[first] |= shared;
+                ambiguous[second] |= shared;
+            }
+        }
+    }
+
+    let mut blocked = false;
+    for (row, cols) in scan.iter_mut().enumerate() {
+        let new = ambiguous[row] & !mask(&last[row]);
+        for (col, pressed) in cols.iter_mut().enumerate() {
+            if new & (1 << col) != 0 {
+                *pressed = false;
+                blocked = true;
+            }
+        }
+    }
+
+    blocked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Grid = [[bool; 4]; 3];
+
+    fn grid(pressed: &[(usize, usize)]) -> Grid {
+        let mut grid = [[false; 4]; 3];
+        for &(row, col) in pressed {
+            grid[row][col] = true;
+        }
+        grid
+    }
+
+    #[test]
+    fn no_rectangle() {
+        // Whole rows, whole columns and diagonals can't ghost
+        for keys in [
+            &[(0, 0), (0, 1), (0, 2), (0, 3)][..],
+            &[(0, 1), (1, 1), (2, 1)],
+            &[(0, 0), (1, 1), (2, 2), (0, 3)],
+            &[(0, 0), (0, 1), (1, 0)],
+        ] {
+            let mut scan = grid(keys);
+            assert!(!block_ghosts(&mut scan, &grid(&[])));
+            assert_eq!(scan, grid(keys));
+        }
+    }
+
+    #[test]
+    fn third_key_blocked() {
+        // Two keys held, the third one on their rows and columns brings in
+        // a ghost at the last corner
+        let last = grid(&[(0, 0), (0, 2)]);
+        let mut scan = grid(&[(0, 0), (0, 2), (1, 0), (1, 2)]);
+        assert!(block_ghosts(&mut scan, &last));
+        assert_eq!(scan, last);
+
+        // Keys away from the rectangle still come through
+        let mut scan = grid(&[(0, 0), (0, 2), (1, 0), (1, 2), (2, 3)]);
+        block_ghosts(&mut scan, &last);
+        assert_eq!(scan, grid(&[(0, 0), (0, 2), (2, 3)]));
+
+        // Pressed all at once there is nothing to go by
+        let mut scan = grid(&[(0, 0), (0, 2), (1, 0), (1, 2)]);
+        block_ghosts(&mut scan, &grid(&[]));
+        assert_eq!(scan, grid(&[]));
+    }
+
+    #[test]
+    fn rectangles_across_rows() {
+        // Rows 0 and 2 share columns 1 and 3, row 1 only shares one of them
+        let last = grid(&[(0, 1), (0, 3), (1, 1)]);
+        let mut scan = grid(&[(0, 1), (0, 3), (1, 1), (2, 1), (2, 3)]);
+        assert!(block_ghosts(&mut scan, &last));
+        assert_eq!(scan, last);
+
+        // Once the rectangle breaks up the remaining key goes through
+        let mut scan = grid(&[(0, 1), (1, 1), (2, 3)]);
+        assert!(!block_ghosts(&mut scan, &last));
+        assert_eq!(scan, grid(&[(0, 1), (1, 1), (2, 3)]));
+    }
+}
@@ -1,12 +1,37 @@
-#![allow(dead_code)]
+//! Keys a keymap is made of, with the HID usages of the keyboard page as
+//! `KEY_*` constants
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+use postcard::experimental::max_size::MaxSize;
+use serde::{Deserialize, Serialize};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
 pub enum Key {
     Mod(KeyMod),
     Code(KeyCode),
+    /// Play back the macro stored in the given slot
+    Macro(u8),
+    /// Sent on the consumer control page instead of the keyboard page
+    Consumer(ConsumerCode),
+    /// Sent on the system control page (generic desktop), for power keys
+    System(SystemCode),
+    /// Activate the layer while held
+    LayerMomentary(u8),
+    /// Switch the layer on or off with each press
+    LayerToggle(u8),
+    /// Sends `tap` when tapped, or acts as `hold` when held past the tapping
+    /// term
+    TapHold {
+        tap: KeyCode,
+        hold: KeyMod,
+    },
+    /// Tapped, the modifier applies to the next key pressed. Held, it acts
+    /// as a normal modifier.
+    OneShot(KeyMod),
+    /// Starts a leader sequence, see `leader`
+    Leader,
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
 pub struct KeyMod(pub u8);
 
 const fn kmod(key_mod: u8) -> Key {
@@ -22,7 +47,33 @@ pub const KEY_MOD_RSHIFT: Key = kmod(0x20);
 pub const KEY_MOD_RALT: Key = kmod(0x40);
 pub const KEY_MOD_RMETA: Key = kmod(0x80);
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub const KEY_LEADER: Key = Key::Leader;
+
+pub const fn mo(layer: u8) -> Key {
+    Key::LayerMomentary(layer)
+}
+
+pub const fn tg(layer: u8) -> Key {
+    Key::LayerToggle(layer)
+}
+
+/// Mod-tap, e.g. `mt(KEY_MOD_LSHIFT, KEY_A)` for a home row shift
+pub const fn mt(hold: Key, tap: Key) -> Key {
+    match (hold, tap) {
+        (Key::Mod(hold), Key::Code(tap)) => Key::TapHold { tap, hold },
+        _ => panic!("mt takes a modifier and a key code"),
+    }
+}
+
+/// One-shot modifier, e.g. `osm(KEY_MOD_LSHIFT)` for a sticky shift
+pub const fn osm(key_mod: Key) -> Key {
+    match key_mod {
+        Key::Mod(key_mod) => Key::OneShot(key_mod),
+        _ => panic!("osm takes a modifier"),
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
 pub struct KeyCode(pub u8);
 
 const fn kcode(key_code: u8) -> Key {
@@ -291,9 +342,171 @@ pub const KEY_COPY: Key = kcode(0x7c);
 pub const KEY_PASTE: Key = kcode(0x7d);
 /// Keyboard Find
 pub const KEY_FIND: Key = kcode(0x7e);
-/// Keyboard Mute
+/// Keyboard Mute, most hosts ignore this usage, see KEY_MEDIA_MUTE
 pub const KEY_MUTE: Key = kcode(0x7f);
-/// Keyboard Volume Up
+/// Keyboard Volume Up, most hosts ignore this usage, see KEY_MEDIA_VOLUMEUP
 pub const KEY_VOLUMEUP: Key = kcode(0x80);
-/// Keyboard Volume Down
+/// Keyboard Volume Down, most hosts ignore this usage, see KEY_MEDIA_VOLUMEDOWN
 pub const KEY_VOLUMEDOWN: Key = kcode(0x81);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub struct ConsumerCode(pub u16);
+
+const fn ccode(usage: u16) -> Key {
+    Key::Consumer(ConsumerCode(usage))
+}
+
+/// Consumer Play/Pause
+pub const KEY_MEDIA_PLAYPAUSE: Key = ccode(0xcd);
+/// Consumer Scan Next Track
+pub const KEY_MEDIA_NEXT: Key = ccode(0xb5);
+/// Consumer Scan Previous Track
+pub const KEY_MEDIA_PREV: Key = ccode(0xb6);
+/// Consumer Stop
+pub const KEY_MEDIA_STOP: Key = ccode(0xb7);
+/// Consumer Mute
+pub const KEY_MEDIA_MUTE: Key = ccode(0xe2);
+/// Consumer Volume Increment
+pub const KEY_MEDIA_VOLUMEUP: Key = ccode(0xe9);
+/// Consumer Volume Decrement
+pub const KEY_MEDIA_VOLUMEDOWN: Key = ccode(0xea);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub struct SystemCode(pub u8);
+
+const fn scode(usage: u8) -> Key {
+    Key::System(SystemCode(usage))
+}
+
+/// System Power Down
+pub const KEY_SYSTEM_POWER: Key = scode(0x81);
+/// System Sleep
+pub const KEY_SYSTEM_SLEEP: Key = scode(0x82);
+/// System Wake Up
+pub const KEY_SYSTEM_WAKE: Key = scode(0x83);
+
+/// Keys named like their `KEY_*` constant without the prefix, as layout
+/// files and the cli write them. Letters, digits and F keys are worked out
+/// from their usage instead.
+pub const NAMED: &[(&str, Key)] = &[
+    ("ENTER", KEY_ENTER),
+    ("ESC", KEY_ESC),
+    ("BACKSPACE", KEY_BACKSPACE),
+    ("TAB", KEY_TAB),
+    ("SPACE", KEY_SPACE),
+    ("MINUS", KEY_MINUS),
+    ("EQUAL", KEY_EQUAL),
+    ("LEFTBRACE", KEY_LEFTBRACE),
+    ("RIGHTBRACE", KEY_RIGHTBRACE),
+    ("BACKSLASH", KEY_BACKSLASH),
+    ("HASHTILDE", KEY_HASHTILDE),
+    ("SEMICOLON", KEY_SEMICOLON),
+    ("APOSTROPHE", KEY_APOSTROPHE),
+    ("GRAVE", KEY_GRAVE),
+    ("COMMA", KEY_COMMA),
+    ("DOT", KEY_DOT),
+    ("SLASH", KEY_SLASH),
+    ("CAPSLOCK", KEY_CAPSLOCK),
+    ("SYSRQ", KEY_SYSRQ),
+    ("SCROLLLOCK", KEY_SCROLLLOCK),
+    ("PAUSE", KEY_PAUSE),
+    ("INSERT", KEY_INSERT),
+    ("HOME", KEY_HOME),
+    ("PAGEUP", KEY_PAGEUP),
+    ("DELETE", KEY_DELETE),
+    ("END", KEY_END),
+    ("PAGEDOWN", KEY_PAGEDOWN),
+    ("RIGHT", KEY_RIGHT),
+    ("LEFT", KEY_LEFT),
+    ("DOWN", KEY_DOWN),
+    ("UP", KEY_UP),
+    ("NUMLOCK", KEY_NUMLOCK),
+    ("102ND", KEY_102ND),
+    ("COMPOSE", KEY_COMPOSE),
+    ("LCTRL", KEY_MOD_LCTRL),
+    ("LSHIFT", KEY_MOD_LSHIFT),
+    ("LALT", KEY_MOD_LALT),
+    ("LMETA", KEY_MOD_LMETA),
+    ("RCTRL", KEY_MOD_RCTRL),
+    ("RSHIFT", KEY_MOD_RSHIFT),
+    ("RALT", KEY_MOD_RALT),
+    ("RMETA", KEY_MOD_RMETA),
+    ("MEDIA_PLAYPAUSE", KEY_MEDIA_PLAYPAUSE),
+    ("MEDIA_NEXT", KEY_MEDIA_NEXT),
+    ("MEDIA_PREV", KEY_MEDIA_PREV),
+    ("MEDIA_STOP", KEY_MEDIA_STOP),
+    ("MEDIA_MUTE", KEY_MEDIA_MUTE),
+    ("MEDIA_VOLUMEUP", KEY_MEDIA_VOLUMEUP),
+    ("MEDIA_VOLUMEDOWN", KEY_MEDIA_VOLUMEDOWN),
+    ("SYSTEM_POWER", KEY_SYSTEM_POWER),
+    ("SYSTEM_SLEEP", KEY_SYSTEM_SLEEP),
+    ("SYSTEM_WAKE", KEY_SYSTEM_WAKE),
+    ("LEADER", KEY_LEADER),
+];
+
+const LETTERS: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const DIGITS: &str = "1234567890";
+const F_KEYS: [&str; 24] = [
+    "F1", "F2", "F3", "F4", "F5", "F6", "F7", "F8", "F9", "F10", "F11", "F12", "F13", "F14", "F15",
+    "F16", "F17", "F18", "F19", "F20", "F21", "F22", "F23", "F24",
+];
+
+/// The name of a key code, None for usages without one
+pub fn code_name(code: KeyCode) -> Option<&'static str> {
+    let usage = usize::from(code.0);
+    let name = match usage {
+        0x04..=0x1d => &LETTERS[usage - 0x04..][..1],
+        0x1e..=0x27 => &DIGITS[usage - 0x1e..][..1],
+        0x3a..=0x45 => F_KEYS[usage - 0x3a],
+        0x68..=0x73 => F_KEYS[usage - 0x68 + 12],
+        _ => {
+            return NAMED
+                .iter()
+                .find(|&&(_, key)| key == Key::Code(code))
+                .map(|&(name, _)| name)
+        }
+    };
+    Some(name)
+}
+
+/// The names of the modifiers set in the modifier byte of a keyboard report
+pub fn mod_names(modifier: u8) -> impl Iterator<Item = &'static str> {
+    NAMED.iter().filter_map(move |&(name, key)| match key {
+        Key::Mod(KeyMod(bit)) if modifier & bit != 0 => Some(name),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(key: Key) -> Option<&'static str> {
+        match key {
+            Key::Code(code) => code_name(code),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn code_names() {
+        assert_eq!(name(KEY_A), Some("A"));
+        assert_eq!(name(KEY_Z), Some("Z"));
+        assert_eq!(name(KEY_1), Some("1"));
+        assert_eq!(name(KEY_0), Some("0"));
+        assert_eq!(name(KEY_F12), Some("F12"));
+        assert_eq!(name(KEY_F13), Some("F13"));
+        assert_eq!(name(KEY_F24), Some("F24"));
+        assert_eq!(name(KEY_ENTER), Some("ENTER"));
+        assert_eq!(name(KEY_NONE), None);
+    }
+
+    #[test]
+    fn modifier_names() {
+        let mut names = mod_names(0x22);
+        assert_eq!(names.next(), Some("LSHIFT"));
+        assert_eq!(names.next(), Some("RSHIFT"));
+        assert_eq!(names.next(), None);
+        assert_eq!(mod_names(0).next(), None);
+    }
+}
@@ -0,0 +1,299 @@
+//! Delta frames for the key updates on the I2C link
+//!
+//! Instead of the whole set of pressed keys, the master can send the keys
+//! that changed since the last update the slave acknowledged, and the slave
+//! rebuilds the full set from its copy. A delta names the update it builds
+//! on, so a slave that missed one notices instead of drifting. It then drops
+//! deltas until the next full update, and asks for one by answering the next
+//! heartbeat with `LinkFrame::Resync`. The master also sends every
+//! `full_every`th update in full, in case the slave never gets to ask.
+
+use heapless::Vec;
+
+use crate::{KeyDelta, KeyFrame, KeyUpdate, LinkFrame, MatrixLoc, MAX_DELTA_KEYS};
+
+/// Keys pressed in one of `from` and `to` but not the other, None if there
+/// are more than fit in a delta
+fn toggled(from: &KeyUpdate, to: &KeyUpdate) -> Option<Vec<MatrixLoc, MAX_DELTA_KEYS>> {
+    let released = from.0.iter().filter(|key| !to.0.contains(key));
+    let pressed = to.0.iter().filter(|key| !from.0.contains(key));
+    let mut toggled = Vec::new();
+    for &key in released.chain(pressed) {
+        toggled.push(key).ok()?;
+    }
+    Some(toggled)
+}
+
+/// Picks full or delta frames on the master
+pub struct DeltaEncoder {
+    full_every: u8,
+    /// Deltas acknowledged since the last full update
+    deltas: u8,
+    /// The last update the slave acknowledged and its sequence number, None
+    /// while the next one has to go in full
+    base: Option<(u8, KeyUpdate)>,
+}
+
+impl DeltaEncoder {
+    /// At least every `full_every`th update goes in full
+    pub const fn new(full_every: u8) -> Self {
+        DeltaEncoder {
+            full_every,
+            deltas: 0,
+            base: None,
+        }
+    }
+
+    /// The frame that sends `update` as number `seq`. A delta is only used
+    /// while it is smaller than the full update, which it is once several
+    /// keys are held. Retries get the same frame as long as nothing was
+    /// acknowledged in between.
+    pub fn encode(&self, seq: u8, update: &KeyUpdate) -> LinkFrame {
+        let delta = match &self.base {
+            Some((base, last)) if self.deltas + 1 < self.full_every => toggled(last, update)
+                .filter(|toggled| toggled.len() + 1 < update.0.len())
+                .map(|toggled| KeyDelta {
+                    seq,
+                    base: *base,
+                    toggled,
+                }),
+            _ => None,
+        };
+        match delta {
+            Some(delta) => LinkFrame::KeyDelta(delta),
+            None => LinkFrame::Keys(KeyFrame {
+                seq,
+                update: update.clone(),
+            }),
+        }
+    }
+
+    /// The slave acknowledged `frame`, which carried `update`. Later deltas
+    /// build on it.
+    pub fn acked(&mut self, frame: &LinkFrame, update: &KeyUpdate) {
+        let seq = match frame {
+            LinkFrame::Keys(frame) => {
+                self.deltas = 0;
+                frame.seq
+            }
+            LinkFrame::KeyDelta(delta) => {
+                self.deltas = self.deltas.saturating_add(1);
+                delta.seq
+            }
+            _ => return,
+        };
+        self.base = Some((seq, update.clone()));
+    }
+
+    /// Send the next update in full. Needed when the slave asks, and after a
+    /// failed write, which the slave may or may not have taken.
+    pub fn resync(&mut self) {
+        self.base = None;
+    }
+}
+
+/// Rebuilds the full updates on the slave
+pub struct DeltaDecoder {
+    /// The last update and its sequence number, None while out of sync
+    last: Option<(u8, KeyUpdate)>,
+    /// A delta was dropped since the last full update
+    dropped: bool,
+}
+
+impl DeltaDecoder {
+    pub const fn new() -> Self {
+        DeltaDecoder {
+            last: None,
+            dropped: false,
+        }
+    }
+
+    /// Take a full update
+    pub fn full(&mut self, frame: KeyFrame) -> KeyUpdate {
+        self.last = Some((frame.seq, frame.update.clone()));
+        self.dropped = false;
+        frame.update
+    }
+
+    /// The full update a delta leads to, None if it doesn't build on the
+    /// last update. Deltas are dropped from then on until a full update.
+    pub fn delta(&mut self, delta: &KeyDelta) -> Option<KeyUpdate> {
+        let update = self.apply(delta);
+        self.dropped |= update.is_none();
+        update
+    }
+
+    fn apply(&mut self, delta: &KeyDelta) -> Option<KeyUpdate> {
+        let update = match self.last.take() {
+            Some((seq, mut update)) if seq == delta.base => {
+                for key in &delta.toggled {
+                    match update.0.iter().position(|pressed| pressed == key) {
+                        Some(idx) => {
+                            update.0.swap_remove(idx);
+                        }
+                        None => update.0.push(*key).ok()?,
+                    }
+                }
+                update
+            }
+            _ => return None,
+        };
+        self.last = Some((delta.seq, update.clone()));
+        Some(update)
+    }
+
+    /// Whether a delta was dropped and the master has to send a full update.
+    /// Only set by a dropped delta, a master that sends no deltas is never
+    /// asked.
+    pub fn needs_resync(&self) -> bool {
+        self.dropped
+    }
+
+    /// Forget the last update once the master is gone, it starts over with a
+    /// full update when it is back
+    pub fn reset(&mut self) {
+        self.last = None;
+        self.dropped = false;
+    }
+}
+
+impl Default for DeltaDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(keys: &[usize]) -> KeyUpdate {
+        let mut update = KeyUpdate::no_keys();
+        for &key in keys {
+            update.0.push(MatrixLoc(key as u8)).unwrap();
+        }
+        update
+    }
+
+    /// Send `update` from `enc` to `dec` as number `seq`, the way the link
+    /// does
+    fn send(
+        enc: &mut DeltaEncoder,
+        dec: &mut DeltaDecoder,
+        seq: u8,
+        keys: &[usize],
+    ) -> (LinkFrame, Option<KeyUpdate>) {
+        let update = update(keys);
+        let frame = enc.encode(seq, &update);
+        enc.acked(&frame, &update);
+        let got = match &frame {
+            LinkFrame::Keys(full) => Some(dec.full(KeyFrame {
+                seq: full.seq,
+                update: full.update.clone(),
+            })),
+            LinkFrame::KeyDelta(delta) => dec.delta(delta),
+            _ => None,
+        };
+        (frame, got)
+    }
+
+    fn same_keys(a: &KeyUpdate, b: &KeyUpdate) -> bool {
+        a.0.len() == b.0.len() && a.0.iter().all(|key| b.0.contains(key))
+    }
+
+    #[test]
+    fn deltas_rebuild_updates() {
+        let mut enc = DeltaEncoder::new(16);
+        let mut dec = DeltaDecoder::new();
+
+        let (frame, got) = send(&mut enc, &mut dec, 1, &[1, 2, 3, 4]);
+        assert!(matches!(frame, LinkFrame::Keys(_)));
+        assert_eq!(got, Some(update(&[1, 2, 3, 4])));
+
+        let (frame, got) = send(&mut enc, &mut dec, 2, &[1, 2, 3, 4, 5]);
+        let LinkFrame::KeyDelta(delta) = frame else {
+            panic!("expected a delta, got {:?}", frame);
+        };
+        assert_eq!((delta.seq, delta.base), (2, 1));
+        assert_eq!(&delta.toggled[..], &[MatrixLoc(5)]);
+        assert!(same_keys(&got.unwrap(), &update(&[1, 2, 3, 4, 5])));
+
+        let (frame, got) = send(&mut enc, &mut dec, 3, &[2, 3, 4, 5, 6]);
+        assert!(matches!(frame, LinkFrame::KeyDelta(_)));
+        assert!(same_keys(&got.unwrap(), &update(&[2, 3, 4, 5, 6])));
+    }
+
+    #[test]
+    fn small_updates_go_full() {
+        let mut enc = DeltaEncoder::new(16);
+        let mut dec = DeltaDecoder::new();
+        send(&mut enc, &mut dec, 1, &[1]);
+
+        // A delta would be no smaller
+        let (frame, _) = send(&mut enc, &mut dec, 2, &[1, 2]);
+        assert!(matches!(frame, LinkFrame::Keys(_)));
+        let (frame, _) = send(&mut enc, &mut dec, 3, &[]);
+        assert!(matches!(frame, LinkFrame::Keys(_)));
+
+        // Too many changes at once
+        send(&mut enc, &mut dec, 4, &[1, 2, 3, 4, 5, 6, 7, 8]);
+        let (frame, _) = send(&mut enc, &mut dec, 5, &[5, 6, 7, 8, 9, 10, 11, 12]);
+        assert!(matches!(frame, LinkFrame::Keys(_)));
+    }
+
+    #[test]
+    fn periodic_full() {
+        let mut enc = DeltaEncoder::new(4);
+        let mut dec = DeltaDecoder::new();
+        let mut kinds = [false; 8];
+        for (seq, full) in kinds.iter_mut().enumerate() {
+            let keys = [1, 2, 3, 4, 5 + seq % 2];
+            let (frame, _) = send(&mut enc, &mut dec, seq as u8, &keys);
+            *full = matches!(frame, LinkFrame::Keys(_));
+        }
+        assert_eq!(
+            kinds,
+            [true, false, false, false, true, false, false, false]
+        );
+    }
+
+    #[test]
+    fn resync_after_lost_delta() {
+        let mut enc = DeltaEncoder::new(16);
+        let mut dec = DeltaDecoder::new();
+        send(&mut enc, &mut dec, 1, &[1, 2, 3, 4]);
+        assert!(!dec.needs_resync());
+
+        // The slave never sees this one, though the master thinks it did
+        let lost = update(&[1, 2, 3, 4, 5]);
+        let frame = enc.encode(2, &lost);
+        assert!(matches!(frame, LinkFrame::KeyDelta(_)));
+        enc.acked(&frame, &lost);
+
+        // The next delta builds on the lost one and is dropped
+        let (frame, got) = send(&mut enc, &mut dec, 3, &[1, 2, 3, 4, 5, 6]);
+        assert!(matches!(frame, LinkFrame::KeyDelta(_)));
+        assert_eq!(got, None);
+        assert!(dec.needs_resync());
+
+        // Even one that builds on an update the slave did see, since the
+        // keys in between are unknown
+        let stale = KeyDelta {
+            seq: 4,
+            base: 1,
+            toggled: Vec::new(),
+        };
+        assert_eq!(dec.delta(&stale), None);
+
+        // The slave asks for a resync with the next heartbeat
+        enc.resync();
+        let (frame, got) = send(&mut enc, &mut dec, 4, &[1, 2, 3, 4, 5, 6, 7]);
+        assert!(matches!(frame, LinkFrame::Keys(_)));
+        assert_eq!(got, Some(update(&[1, 2, 3, 4, 5, 6, 7])));
+        assert!(!dec.needs_resync());
+
+        let (frame, got) = send(&mut enc, &mut dec, 5, &[1, 2, 3, 4, 5, 6]);
+        assert!(matches!(frame, LinkFrame::KeyDelta(_)));
+        assert!(same_keys(&got.unwrap(), &update(&[1, 2, 3, 4, 5, 6])));
+    }
+}
@@ -0,0 +1,128 @@
+//! Keymaps uploaded by the host, replacing the layers built into the firmware
+
+use heapless::Vec;
+use postcard::experimental::max_size::MaxSize;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    key_codes::{Key, KEY_NONE},
+    settings::MACRO_SLOTS,
+    KeyState,
+};
+
+/// Layers the firmware has, an uploaded keymap sets the keys of all of them
+pub const NUM_LAYERS: usize = 2;
+/// Keys per layer, indexed like `KeyState`
+pub const LAYER_KEYS: usize = KeyState::LEN;
+
+/// The keys of every layer. `KEY_NONE` is transparent, the key falls
+/// through to lower layers.
+pub type LayerTable = [[Key; LAYER_KEYS]; NUM_LAYERS];
+
+/// A `LayerTable` as it is uploaded and stored. Serde doesn't handle arrays
+/// this long, so the layers are vecs and `table` checks their lengths.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub struct Layout {
+    layers: Vec<Vec<Key, LAYER_KEYS>, NUM_LAYERS>,
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, PartialEq, Eq)]
+pub enum LayoutError {
+    /// Every layer has to be there, with a key for every position
+    WrongSize,
+    /// A layer key refers to a layer the firmware doesn't have
+    BadLayer(u8),
+    /// A macro key refers to a slot that doesn't exist
+    BadMacro(u8),
+}
+
+impl Layout {
+    pub fn from_table(table: &LayerTable) -> Self {
+        Layout {
+            layers: table
+                .iter()
+                .map(|layer| layer.iter().copied().collect())
+                .collect(),
+        }
+    }
+
+    /// The layers as the keymap uses them, if every key is valid
+    pub fn table(&self) -> Result<LayerTable, LayoutError> {
+        if self.layers.len() != NUM_LAYERS {
+            return Err(LayoutError::WrongSize);
+        }
+
+        let mut table = [[KEY_NONE; LAYER_KEYS]; NUM_LAYERS];
+        for (keys, layer) in table.iter_mut().zip(&self.layers) {
+            if layer.len() != LAYER_KEYS {
+                return Err(LayoutError::WrongSize);
+            }
+            for (key, &new) in keys.iter_mut().zip(layer) {
+                match new {
+                    Key::LayerMomentary(layer) | Key::LayerToggle(layer)
+                        if usize::from(layer) >= NUM_LAYERS =>
+                    {
+                        return Err(LayoutError::BadLayer(layer));
+                    }
+                    Key::Macro(slot) if usize::from(slot) >= MACRO_SLOTS => {
+                        return Err(LayoutError::BadMacro(slot));
+                    }
+                    _ => *key = new,
+                }
+            }
+        }
+
+        Ok(table)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_codes::*;
+
+    fn table() -> LayerTable {
+        let mut table = [[KEY_NONE; LAYER_KEYS]; NUM_LAYERS];
+        table[0][0] = KEY_A;
+        table[0][1] = mt(KEY_MOD_LSHIFT, KEY_S);
+        table[0][LAYER_KEYS - 1] = mo(1);
+        table[1][0] = KEY_MEDIA_MUTE;
+        table[1][1] = Key::Macro(0);
+        table[1][LAYER_KEYS - 1] = tg(1);
+        table
+    }
+
+    #[test]
+    fn layout_round_trip() {
+        let layout = Layout::from_table(&table());
+        let bytes = postcard::to_stdvec(&layout).unwrap();
+        assert!(bytes.len() <= Layout::POSTCARD_MAX_SIZE);
+
+        let decoded: Layout = postcard::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.table(), Ok(table()));
+    }
+
+    #[test]
+    fn layout_checks() {
+        let mut bad = table();
+        bad[1][2] = mo(NUM_LAYERS as u8);
+        assert_eq!(
+            Layout::from_table(&bad).table(),
+            Err(LayoutError::BadLayer(NUM_LAYERS as u8))
+        );
+
+        let mut bad = table();
+        bad[0][2] = Key::Macro(MACRO_SLOTS as u8);
+        assert_eq!(
+            Layout::from_table(&bad).table(),
+            Err(LayoutError::BadMacro(MACRO_SLOTS as u8))
+        );
+
+        let mut short = Layout::from_table(&table());
+        short.layers[1].pop();
+        assert_eq!(short.table(), Err(LayoutError::WrongSize));
+        short.layers.pop();
+        assert_eq!(short.table(), Err(LayoutError::WrongSize));
+    }
+}
@@ -0,0 +1,192 @@
+//! Leader sequences, Vim style
+//!
+//! Pressing the leader key starts a sequence, and the keys typed after it are
+//! matched against a table instead of being sent. Once they spell out a
+//! sequence its action runs. A key that doesn't continue any sequence aborts
+//! it, and so does not finishing it within the timeout. Keys that went to
+//! the leader stay out of the report until they are released.
+
+use heapless::Vec;
+
+use crate::KeyState;
+
+/// Most keys a sequence can have after the leader
+pub const MAX_SEQUENCE: usize = 4;
+
+/// Keys typed after the leader that run `action`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sequence<'s, K, T> {
+    pub keys: &'s [K],
+    pub action: T,
+}
+
+/// Define a sequence, a bad length fails the build when used in a const
+pub const fn sequence<'s, K, T: Copy>(keys: &'s [K], action: T) -> Sequence<'s, K, T> {
+    assert!(!keys.is_empty() && keys.len() <= MAX_SEQUENCE);
+    Sequence { keys, action }
+}
+
+pub struct LeaderResolver<'s, K, T> {
+    sequences: &'s [Sequence<'s, K, T>],
+    /// The key that starts a sequence
+    leader: K,
+    timeout_ms: u64,
+    /// Keys typed since the leader, None while no sequence is started
+    typed: Option<Vec<K, MAX_SEQUENCE>>,
+    /// When the leader was pressed
+    since_ms: u64,
+    /// Keys pressed at the last update
+    last: u128,
+    /// Keys that went to the leader, left out of the report until released
+    consumed: u128,
+}
+
+impl<'s, K: Copy + PartialEq, T: Copy> LeaderResolver<'s, K, T> {
+    /// A sequence has to be finished within `timeout_ms` of the leader. When
+    /// a sequence is the start of a longer one, the shorter one wins.
+    pub fn new(sequences: &'s [Sequence<'s, K, T>], leader: K, timeout_ms: u64) -> Self {
+        LeaderResolver {
+            sequences,
+            leader,
+            timeout_ms,
+            typed: None,
+            since_ms: 0,
+            last: 0,
+            consumed: 0,
+        }
+    }
+
+    /// Feed the keys currently pressed, `key` gives the key at an index.
+    /// Returns the keys the rest of the keymap should see, and the action of
+    /// the sequence finished by this update, if any.
+    pub fn update(
+        &mut self,
+        state: &KeyState,
+        now_ms: u64,
+        key: impl Fn(usize) -> K,
+    ) -> (KeyState, Option<T>) {
+        let pressed = state.0 & !self.last;
+        self.last = state.0;
+        self.consumed &= state.0;
+
+        if self.typed.is_some() && now_ms - self.since_ms >= self.timeout_ms {
+            self.typed = None;
+        }
+
+        let mut action = None;
+        for idx in (0..KeyState::LEN).filter(|idx| pressed & (1 << idx) != 0) {
+            let key = key(idx);
+            let Some(typed) = &mut self.typed else {
+                if key == self.leader {
+                    self.typed = Some(Vec::new());
+                    self.since_ms = now_ms;
+                    self.consumed |= 1 << idx;
+                }
+                continue;
+            };
+
+            self.consumed |= 1 << idx;
+            if typed.push(key).is_err() {
+                self.typed = None;
+                continue;
+            }
+            if let Some(sequence) = self.sequences.iter().find(|s| s.keys == &typed[..]) {
+                action = Some(sequence.action);
+                self.typed = None;
+            } else if !self.sequences.iter().any(|s| s.keys.starts_with(typed)) {
+                self.typed = None;
+            }
+        }
+
+        (KeyState(state.0 & !self.consumed), action)
+    }
+
+    /// Whether a sequence is started and waiting for more keys
+    pub fn is_active(&self) -> bool {
+        self.typed.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: u64 = 1000;
+    const LEADER: char = '*';
+    const SEQUENCES: [Sequence<char, u8>; 2] =
+        [sequence(&['g', 'c'], 1), sequence(&['g', 'g', 'x'], 2)];
+    // Index of each key in the tests
+    const KEYS: [char; 5] = ['*', 'g', 'c', 'x', 'q'];
+
+    fn keys(pressed: &[char]) -> KeyState {
+        let mut state = KeyState::no_keys();
+        for &key in pressed {
+            state.press(KEYS.iter().position(|&k| k == key).unwrap());
+        }
+        state
+    }
+
+    fn update(
+        resolver: &mut LeaderResolver<char, u8>,
+        pressed: &[char],
+        now_ms: u64,
+    ) -> (KeyState, Option<u8>) {
+        resolver.update(&keys(pressed), now_ms, |idx| KEYS[idx])
+    }
+
+    #[test]
+    fn sequence_completes() {
+        let mut resolver = LeaderResolver::new(&SEQUENCES, LEADER, TIMEOUT);
+        assert_eq!(update(&mut resolver, &['*'], 0), (keys(&[]), None));
+        assert_eq!(update(&mut resolver, &[], 20), (keys(&[]), None));
+        assert!(resolver.is_active());
+        assert_eq!(update(&mut resolver, &['g'], 40), (keys(&[]), None));
+        assert_eq!(update(&mut resolver, &['g', 'c'], 60), (keys(&[]), Some(1)));
+        assert!(!resolver.is_active());
+        // The keys stay out of the report until they are released
+        assert_eq!(update(&mut resolver, &['g', 'c'], 80), (keys(&[]), None));
+        assert_eq!(update(&mut resolver, &['c'], 100), (keys(&[]), None));
+        assert_eq!(
+            update(&mut resolver, &['c', 'x'], 120),
+            (keys(&['x']), None)
+        );
+
+        // A repeated key
+        update(&mut resolver, &['*'], 200);
+        update(&mut resolver, &['g'], 220);
+        update(&mut resolver, &[], 240);
+        update(&mut resolver, &['g'], 260);
+        assert_eq!(update(&mut resolver, &['x'], 280), (keys(&[]), Some(2)));
+    }
+
+    #[test]
+    fn sequence_times_out() {
+        let mut resolver = LeaderResolver::new(&SEQUENCES, LEADER, TIMEOUT);
+        update(&mut resolver, &['*'], 0);
+        update(&mut resolver, &['g'], 20);
+        assert_eq!(update(&mut resolver, &[], TIMEOUT), (keys(&[]), None));
+        assert!(!resolver.is_active());
+        // Typed normally again
+        assert_eq!(
+            update(&mut resolver, &['c'], TIMEOUT + 20),
+            (keys(&['c']), None)
+        );
+    }
+
+    #[test]
+    fn invalid_key_aborts() {
+        let mut resolver = LeaderResolver::new(&SEQUENCES, LEADER, TIMEOUT);
+        update(&mut resolver, &['*'], 0);
+        update(&mut resolver, &['g'], 20);
+        // No sequence goes g, q. The q is swallowed along with the sequence.
+        assert_eq!(update(&mut resolver, &['g', 'q'], 40), (keys(&[]), None));
+        assert!(!resolver.is_active());
+        assert_eq!(update(&mut resolver, &[], 60), (keys(&[]), None));
+        assert_eq!(update(&mut resolver, &['c'], 80), (keys(&['c']), None));
+
+        // A key that starts no sequence aborts right away
+        update(&mut resolver, &['*'], 100);
+        assert_eq!(update(&mut resolver, &['*', 'x'], 120), (keys(&[]), None));
+        assert!(!resolver.is_active());
+    }
+}
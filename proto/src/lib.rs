@@ -1,40 +1,297 @@
 #![no_std]
 
+use core::fmt;
+
 use errors::ProtoError;
 use heapless::Vec;
 use postcard::experimental::max_size::MaxSize;
+use proto_impl::Crc8;
 use serde::{Deserialize, Serialize};
+use settings::{Config, MacroData};
 
+pub mod caps_word;
+pub mod color;
+pub mod combo;
 pub mod errors;
+pub mod ghost;
+pub mod key_codes;
+pub mod key_delta;
+pub mod keymap;
+pub mod leader;
+pub mod macro_player;
+pub mod one_shot;
 pub mod proto_impl;
+pub mod settings;
 
 pub trait WireSize {
     const WIRE_MAX_SIZE: usize;
     const CS_MAX_SIZE: usize;
+    const LEN_MAX_SIZE: usize;
 }
 
-const fn cobs_max_length(source_len: usize) -> usize {
-    source_len + (source_len / 254) + if source_len % 254 > 0 { 1 } else { 0 }
+/// Longest COBS encoding of `source_len` bytes. The encoder always ends with
+/// a code byte, even right after a full block of 254 non-zero bytes, so this
+/// is one more than `cobs::max_encoding_length` for multiples of 254.
+pub(crate) const fn cobs_max_length(source_len: usize) -> usize {
+    source_len + (source_len / 254) + 1
 }
 
+// Sizes use the default CRC-8, see `proto_impl::wire_max_size` and
+// `proto_impl::cs_max_size` for other checksums
 impl<T: MaxSize> WireSize for T {
     // Wire is postcard with a CRC that is then COBS encoded and has a \0 sentinel
-    // Pre COBS length is the max postcard length plus the CRC byte
+    // Pre COBS length is the max postcard length plus the CRC bytes
     // Then we add one more byte for the sentinel
-    const WIRE_MAX_SIZE: usize = cobs_max_length(T::POSTCARD_MAX_SIZE + 1) + 1;
+    const WIRE_MAX_SIZE: usize = proto_impl::wire_max_size::<Crc8, T>();
     // If there is no cobs encoding (not necessary in a framed format such as I2C), then
-    // the only overhead on top of postcard is the CRC byte
-    const CS_MAX_SIZE: usize = T::POSTCARD_MAX_SIZE + 1;
+    // the only overhead on top of postcard is the CRC bytes
+    const CS_MAX_SIZE: usize = proto_impl::cs_max_size::<Crc8, T>();
+    // Length prefixed framing puts the length of the checksummed message in
+    // front, in place of COBS and the sentinel
+    const LEN_MAX_SIZE: usize = proto_impl::len_max_size::<Crc8, T>();
+}
+
+/// Largest `Data` payload. A full `Data` frame checksummed with `FlashCrc`
+/// fits in one 64 byte usb packet.
+pub const DATA_COUNT: usize = 56;
+
+/// Payload of a `Data` packet, only the last chunk of a transfer is expected
+/// to be shorter than the chunk size in use
+pub type DataChunk = Vec<u8, DATA_COUNT>;
+
+/// Checksum used for firmware flashing packets, where a corrupted byte that
+/// slips past the CRC would end up in flash
+pub type FlashCrc = proto_impl::Crc16;
+
+/// After nacking a firmware `Data` packet, the device nacks everything that
+/// follows until the link has been quiet for this long, so the chunks that
+/// were already in flight can't be mistaken for the retransmission
+pub const FLASH_RESYNC_MS: u64 = 50;
+
+/// Bytes of the stored panic message returned per `GetPanic`
+pub const PANIC_CHUNK: usize = 32;
+
+/// Tag of a command sent without one. The host can tag each `Command` with
+/// a byte after the message, and every `Response` to it is sent back with
+/// the same tag, so several commands can be in flight at once. `NO_TAG` is
+/// left out of the frame, and firmware that predates tags ignores the byte
+/// and answers untagged, so untagged exchanges are unchanged.
+pub const NO_TAG: u8 = 0;
+
+/// Protocol version, the major version is bumped whenever a change breaks
+/// compatibility with existing messages, the minor version when messages are
+/// added
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub struct Version {
+    pub major: u8,
+    pub minor: u8,
 }
 
-pub const DATA_COUNT: usize = 8;
+pub const CURRENT_VERSION: Version = Version { major: 7, minor: 2 };
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
 pub enum Command {
     Reset,
     UsbDfu,
-    EchoMsg { count: u16 },
-    Data([u8; DATA_COUNT]),
+    EchoMsg {
+        count: u16,
+    },
+    Data(DataChunk),
+    SetMacro {
+        slot: u8,
+        data: MacroData,
+    },
+    GetMacro {
+        slot: u8,
+    },
+    TestColumn {
+        col: u8,
+    },
+    GetVersion,
+    /// Stream `count` bytes of firmware as `Data` packets checksummed with
+    /// `FlashCrc`, each one is acked before the next is expected. `crc` is
+    /// the `FW_CRC` of the whole image, the update is only marked for the
+    /// next boot if what was written matches it.
+    FlashFw {
+        count: u32,
+        crc: u32,
+    },
+    /// Ask for the `FW_CRC` of `len` bytes of flash starting at `offset`
+    VerifyFw {
+        offset: u32,
+        len: u32,
+    },
+    /// Read the executor trace ring buffer, the raw buffer is sent back as a
+    /// `DataStream`
+    ReadTrace,
+    /// Read the message left by the last panic, starting at `offset`. The
+    /// message is cleared once its end has been read.
+    GetPanic {
+        offset: u16,
+    },
+    /// Set every LED to one color
+    SetLed {
+        r: u8,
+        g: u8,
+        b: u8,
+    },
+    SetAnimation(LedAnimation),
+    /// Only forward firmware logs of this level and above
+    SetLogLevel(LogLevel),
+    /// Ask how large a firmware image `FlashFw` accepts
+    GetFlashInfo,
+    /// Answered right away with a `Pong` carrying the same `seq`
+    Ping {
+        seq: u16,
+    },
+    /// Set the LED under one key of the half the host is connected to
+    SetKeyLed {
+        loc: MatrixLoc,
+        r: u8,
+        g: u8,
+        b: u8,
+    },
+    /// Read `len` bytes of flash starting at `offset`, sent back as a
+    /// `DataStream`
+    ReadFlash {
+        offset: u32,
+        len: u16,
+    },
+    GetConfig,
+    /// Apply and persist a new config, acked with `AckConfig`
+    SetConfig(Config),
+    /// Ask for the raw matrix of both halves, before the keymap sees it.
+    /// Only the half that runs the keyboard interface sees both halves.
+    GetMatrix,
+    /// Replace the keymap with a postcard encoded `keymap::Layout` of
+    /// `count` bytes, streamed as `Data` packets checksummed with `FlashCrc`
+    /// that are each acked. Acked with `AckKeymap` before the data, and again
+    /// once the keymap is stored and applied.
+    UploadKeymap {
+        count: u32,
+    },
+    /// Stream a `Matrix` response whenever the raw matrix changes, until
+    /// `ExitTestMode`. Both are acked with `AckTestMode`, other commands keep
+    /// working in between.
+    EnterTestMode,
+    ExitTestMode,
+    /// Run a quick diagnostic of the half the host is connected to,
+    /// answered with `SelfTest`
+    SelfTest,
+    /// Ask for the IDs of the flash chip, answered with `DeviceId`
+    GetDeviceId,
+    /// Erase the DFU partition without writing an image, acked with
+    /// `AckErase` once it is blank
+    EraseFw,
+    /// Store which half this is, None to go back to the jumper, acked with
+    /// `AckHand`. The half then resets to take on its new role.
+    SetHand(Option<Hand>),
+    /// Ask which build of the firmware is running, answered with `BuildInfo`
+    GetBuildInfo,
+}
+
+/// Lighting effects the host can select, applied to every LED
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub enum LedAnimation {
+    Off,
+    Solid {
+        r: u8,
+        g: u8,
+        b: u8,
+    },
+    Breathe {
+        r: u8,
+        g: u8,
+        b: u8,
+    },
+    Rainbow {
+        speed: u8,
+    },
+    /// Lights the LED under each key while it is held, fading out once it is
+    /// released
+    Reactive {
+        r: u8,
+        g: u8,
+        b: u8,
+    },
+}
+
+/// Severity of a firmware log message, in increasing order
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, MaxSize)]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+/// Identifies a board by its flash chip, the RP2040 has no ID of its own
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub struct DeviceId {
+    /// Factory programmed unique ID of the flash chip
+    pub unique: [u8; 8],
+    /// JEDEC manufacturer, memory type and capacity bytes
+    pub jedec: [u8; 3],
+}
+
+/// Longest git hash a `BuildInfo` carries, a short hash with `-dirty` after
+/// it fits
+pub const GIT_HASH_LEN: usize = 16;
+
+/// Which build of the firmware is running, baked in when it was compiled
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub struct BuildInfo {
+    /// Short hash of the commit the firmware was built from, ending in
+    /// `-dirty` if the tree had uncommitted changes
+    pub git_hash: heapless::String<GIT_HASH_LEN>,
+    /// When the firmware was built, in seconds since the Unix epoch
+    pub built: u64,
+}
+
+/// The subsystems a `SelfTest` checks
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SelfTestCheck {
+    /// A spare sector of the update partition can be erased, written and
+    /// read back
+    Flash,
+    /// The other half answered over I2C
+    Peer,
+    /// The host configured the USB device, which enables the HID endpoints
+    Usb,
+}
+
+impl SelfTestCheck {
+    pub const ALL: [SelfTestCheck; 3] = [
+        SelfTestCheck::Flash,
+        SelfTestCheck::Peer,
+        SelfTestCheck::Usb,
+    ];
+}
+
+/// Bitfield of the `SelfTestCheck`s that passed
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub struct SelfTestResults(u8);
+
+impl SelfTestResults {
+    pub fn set(&mut self, check: SelfTestCheck, passed: bool) {
+        let bit = 1 << check as u8;
+        if passed {
+            self.0 |= bit;
+        } else {
+            self.0 &= !bit;
+        }
+    }
+
+    pub fn passed(&self, check: SelfTestCheck) -> bool {
+        self.0 & (1 << check as u8) != 0
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
@@ -50,6 +307,15 @@ pub enum AckType {
     AckReset,
     AckUsbDfu,
     AckFlashFw,
+    AckMacro,
+    AckData,
+    AckLed,
+    AckLogLevel,
+    AckConfig,
+    AckKeymap,
+    AckTestMode,
+    AckErase,
+    AckHand,
 }
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
@@ -57,15 +323,130 @@ pub enum NackType {
     Unexpected,
     PacketErr(ProtoError),
     BufferOverflow,
+    OutOfRange,
+    StoreFull,
+    /// The command arrived intact but isn't one this firmware knows, it was
+    /// most likely sent by a newer host. Carries the variant index of the
+    /// command.
+    UnsupportedCommand(u32),
+    /// The firmware image that was flashed doesn't match the `FlashFw` CRC,
+    /// the update was dropped
+    ImageCrcMismatch {
+        calculated: u32,
+        expected: u32,
+    },
+    /// The change was applied but couldn't be written to flash, it is lost
+    /// on the next reset
+    StoreFailed,
+}
+
+impl Command {
+    /// Number of variants, an index from here on is a command this firmware
+    /// doesn't know. Must be bumped along with new commands.
+    const VARIANT_COUNT: u32 = 30;
+
+    /// Decode a command framed with `C` and its tag, with the reason to nack
+    /// it if that fails
+    pub fn decode_with<C: proto_impl::CrcKind>(buf: &mut [u8]) -> Result<(Self, u8), NackType> {
+        Self::decode_framed::<C>(buf, proto_impl::Framing::Cobs)
+    }
+
+    /// Like `decode_with`, for a frame in `framing`
+    pub fn decode_framed<C: proto_impl::CrcKind>(
+        buf: &mut [u8],
+        framing: proto_impl::Framing,
+    ) -> Result<(Self, u8), NackType> {
+        let bytes = framing
+            .unframe_with::<C>(buf)
+            .map_err(NackType::PacketErr)?;
+        let (command, rest) = postcard::take_from_bytes(bytes).map_err(|err| match err {
+            // An enum variant index that is out of range, only a newer
+            // command if it is the command's own and not that of an enum
+            // inside a known one
+            postcard::Error::SerdeDeCustom | postcard::Error::DeserializeBadEnum => {
+                match postcard::take_from_bytes::<u32>(bytes) {
+                    Ok((variant, _)) if variant >= Self::VARIANT_COUNT => {
+                        NackType::UnsupportedCommand(variant)
+                    }
+                    Ok(_) => NackType::PacketErr(err.into()),
+                    Err(err) => NackType::PacketErr(err.into()),
+                }
+            }
+            err => NackType::PacketErr(err.into()),
+        })?;
+        let tag = proto_impl::take_tag(rest).map_err(NackType::PacketErr)?;
+        Ok((command, tag))
+    }
+}
+
+impl fmt::Display for NackType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NackType::Unexpected => write!(f, "unexpected message"),
+            NackType::PacketErr(err) => write!(f, "bad packet: {}", err),
+            NackType::BufferOverflow => write!(f, "buffer overflow"),
+            NackType::OutOfRange => write!(f, "value out of range"),
+            NackType::StoreFull => write!(f, "settings store full"),
+            NackType::UnsupportedCommand(variant) => {
+                write!(f, "unsupported command (variant {})", variant)
+            }
+            NackType::ImageCrcMismatch {
+                calculated,
+                expected,
+            } => write!(
+                f,
+                "firmware image CRC mismatch (calculated 0x{:08x}, expected 0x{:08x})",
+                calculated, expected
+            ),
+            NackType::StoreFailed => write!(f, "settings couldn't be saved to flash"),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
 pub enum Response {
     Ack(AckType),
     Nack(NackType),
-    EchoMsg { count: u16 },
-    Data([u8; DATA_COUNT]),
+    EchoMsg {
+        count: u16,
+    },
+    Data(DataChunk),
     TimerDebug(TimerDebug),
+    Macro {
+        slot: u8,
+        data: MacroData,
+    },
+    /// Bitmask of the rows that read high while `col` was driven
+    ColumnTest {
+        col: u8,
+        rows: u8,
+    },
+    Version(Version),
+    FwCrc(u32),
+    /// Starts a transfer of `total` bytes from the device. They follow as
+    /// `Data` packets, and a `DataEnd` comes after the last one.
+    DataStream {
+        total: u32,
+    },
+    DataEnd,
+    /// Part of the last panic message, shorter than `PANIC_CHUNK` once the
+    /// end is reached and empty if no panic was recorded
+    Panic(Vec<u8, PANIC_CHUNK>),
+    /// Largest `count` a `FlashFw` is accepted with, larger images are
+    /// nacked with `OutOfRange`. Firmware `Data` chunks can be up to
+    /// `data_count` bytes.
+    FlashInfo {
+        max_fw_size: u32,
+        data_count: u16,
+    },
+    Pong {
+        seq: u16,
+    },
+    Config(Config),
+    Matrix(KeyState),
+    SelfTest(SelfTestResults),
+    DeviceId(DeviceId),
+    BuildInfo(BuildInfo),
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
@@ -77,16 +458,88 @@ impl MatrixLoc {
         assert!(col < NUM_COLS);
         MatrixLoc((row * NUM_COLS + col) as u8)
     }
+
+    pub fn row(&self) -> usize {
+        self.index() / NUM_COLS
+    }
+
+    pub fn col(&self) -> usize {
+        self.index() % NUM_COLS
+    }
+
+    /// Index of the key within its half, `row * NUM_COLS + col`
+    pub fn index(&self) -> usize {
+        self.0 as usize
+    }
 }
 
 pub const NUM_ROWS: usize = 5;
 pub const NUM_COLS: usize = 7;
 pub const NUM_HANDS: usize = 2;
+
+/// Which half a board is, selected by the jumper on PIN_10 unless
+/// overridden with `SetHand`. The left half hosts the HID interface and is
+/// the I2C slave, the right half is the I2C master.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub enum Hand {
+    Left,
+    Right,
+}
 pub const NUM_KEYS: usize = NUM_ROWS * NUM_COLS;
+/// `KeyState` index of the rotary encoder's push switch, after the keys of
+/// both halves
+pub const ENCODER_PRESS: usize = NUM_KEYS * NUM_HANDS;
 
-#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
 pub struct KeyUpdate(pub Vec<MatrixLoc, NUM_KEYS>);
 
+/// A `KeyUpdate` as sent from one half to the other. `seq` goes up by one
+/// for each new update and stays the same on retries, so the receiver can
+/// tell lost updates from repeated ones.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub struct KeyFrame {
+    pub seq: u8,
+    pub update: KeyUpdate,
+}
+
+/// Most keys a `KeyDelta` can change, more changes go as a full `KeyFrame`
+pub const MAX_DELTA_KEYS: usize = 4;
+
+/// The keys that changed since the update numbered `base`, each one flips
+/// between pressed and released. Numbered like a `KeyFrame`, see
+/// key_delta.rs.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub struct KeyDelta {
+    pub seq: u8,
+    pub base: u8,
+    pub toggled: Vec<MatrixLoc, MAX_DELTA_KEYS>,
+}
+
+/// Motion of a pointing device on the master's bus since the last frame,
+/// right and down are positive
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub struct PointerMotion {
+    pub dx: i8,
+    pub dy: i8,
+}
+
+/// Everything the halves send each other over I2C
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub enum LinkFrame {
+    Keys(KeyFrame),
+    /// Sent by the master when it has had nothing to send for a while. The
+    /// slave answers with a `Heartbeat` of its own, so both halves know the
+    /// other is alive.
+    Heartbeat,
+    /// Not retried, a lost frame only loses a little motion
+    Pointer(PointerMotion),
+    KeyDelta(KeyDelta),
+    /// The slave's answer to a heartbeat while it has lost track of the
+    /// deltas, the master sends its next update in full
+    Resync,
+}
+
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
 pub enum KeyResponse {
     Response(Response),
@@ -110,23 +563,46 @@ impl KeyUpdate {
     }
 }
 
-pub struct KeyState(pub [bool; NUM_KEYS * NUM_HANDS]);
+/// The pressed keys of both halves as a bitmap. Left half keys are at
+/// `MatrixLoc::index`, right half keys at `NUM_KEYS + MatrixLoc::index`.
+/// Inputs that aren't in the matrix come after them, starting with
+/// `ENCODER_PRESS`, so the keymap handles them like any other key.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub struct KeyState(u128);
+
+const _: () = assert!(KeyState::LEN <= u128::BITS as usize);
 
 impl KeyState {
+    pub const LEN: usize = ENCODER_PRESS + 1;
+
     pub fn from_update(left: &KeyUpdate, right: &KeyUpdate) -> Self {
         let mut result = KeyState::no_keys();
         for key in &left.0 {
-            result.0[key.0 as usize] = true;
+            result.press(key.index());
         }
         for key in &right.0 {
-            result.0[key.0 as usize + NUM_KEYS] = true;
+            result.press(NUM_KEYS + key.index());
         }
 
         result
     }
 
     pub const fn no_keys() -> Self {
-        KeyState([false; NUM_KEYS * NUM_HANDS])
+        KeyState(0)
+    }
+
+    pub fn press(&mut self, idx: usize) {
+        assert!(idx < Self::LEN);
+        self.0 |= 1 << idx;
+    }
+
+    pub fn is_pressed(&self, idx: usize) -> bool {
+        idx < Self::LEN && self.0 & (1 << idx) != 0
+    }
+
+    /// Whether each key is pressed, in index order
+    pub fn iter(&self) -> impl Iterator<Item = bool> + '_ {
+        (0..Self::LEN).map(|idx| self.is_pressed(idx))
     }
 }
 
@@ -135,15 +611,168 @@ mod tests {
     use super::*;
 
     use postcard::to_stdvec;
+    use proto_impl::CrcKind;
 
     #[test]
     fn check_enum_size() {
         let short = Response::EchoMsg { count: 12 };
         let short_bytes = to_stdvec(&short).expect("Cannot serialize short response");
-        let long = Response::Data([1u8; DATA_COUNT]);
+        let long = Response::Data(Vec::from_slice(&[1u8; DATA_COUNT]).unwrap());
         let long_bytes = to_stdvec(&long).expect("Cannot serialize long response");
         assert_eq!(short_bytes.len(), 2);
-        assert_eq!(long_bytes.len(), 9);
+        assert_eq!(long_bytes.len(), DATA_COUNT + 2);
+    }
+
+    #[test]
+    fn data_frame_size() {
+        // A full firmware chunk goes out in a single usb packet
+        let max = proto_impl::wire_max_size::<FlashCrc, Command>();
+        assert!(max <= 64, "Flash frames are {} bytes", max);
+        let chunk = Command::Data(Vec::from_slice(&[0u8; DATA_COUNT]).unwrap());
+        let frame = proto_impl::wire_encode_with::<FlashCrc, _, 64>(&chunk).unwrap();
+        assert!(frame.len() <= max);
+        // The length prefix of a full chunk is a single byte
+        assert_eq!(to_stdvec(&chunk).unwrap().len(), DATA_COUNT + 2);
+    }
+
+    #[test]
+    fn unsupported_command() {
+        let frame = |payload: &[u8]| {
+            proto_impl::wire_frame_with::<Crc8, { Command::WIRE_MAX_SIZE }>(payload).unwrap()
+        };
+
+        let mut known = frame(&to_stdvec(&Command::Ping { seq: 300 }).unwrap());
+        assert_eq!(
+            Command::decode_with::<Crc8>(&mut known),
+            Ok((Command::Ping { seq: 300 }, NO_TAG))
+        );
+
+        // A variant index past the end of `Command`, two bytes as a varint
+        let mut newer = frame(&[0xc8, 0x01, 0x05]);
+        assert_eq!(
+            Command::decode_with::<Crc8>(&mut newer),
+            Err(NackType::UnsupportedCommand(200))
+        );
+
+        // The last command is still known
+        let last = to_stdvec(&Command::GetBuildInfo).unwrap();
+        assert_eq!(last, [Command::VARIANT_COUNT as u8 - 1]);
+
+        // A known command with an enum inside it that is out of range, here
+        // `SetHand(Some(_))` with a hand past `Right`, is a packet error
+        let mut bad_hand = to_stdvec(&Command::SetHand(Some(Hand::Right))).unwrap();
+        *bad_hand.last_mut().unwrap() = 2;
+        let mut bad_hand = frame(&bad_hand);
+        assert!(matches!(
+            Command::decode_with::<Crc8>(&mut bad_hand),
+            Err(NackType::PacketErr(ProtoError::PostcardError(_)))
+        ));
+
+        // A known command that is cut short is still a packet error
+        let mut short = frame(&to_stdvec(&Command::Ping { seq: 300 }).unwrap()[..2]);
+        assert!(matches!(
+            Command::decode_with::<Crc8>(&mut short),
+            Err(NackType::PacketErr(ProtoError::PostcardError(_)))
+        ));
+
+        let mut damaged = frame(&[0xc8, 0x01, 0x05]);
+        damaged[2] ^= 0x10;
+        assert!(matches!(
+            Command::decode_with::<Crc8>(&mut damaged),
+            Err(NackType::PacketErr(_))
+        ));
+    }
+
+    #[test]
+    fn tagged_frames() {
+        const N: usize = proto_impl::tagged_wire_max_size::<Crc8, Command>();
+        let command = Command::Ping { seq: 300 };
+
+        // Untagged frames are the same as before tags
+        let untagged = proto_impl::wire_encode_tagged_with::<Crc8, _, N>(&command, NO_TAG).unwrap();
+        let plain = proto_impl::wire_encode_with::<Crc8, _, N>(&command).unwrap();
+        assert_eq!(untagged, plain);
+
+        let mut tagged = proto_impl::wire_encode_tagged_with::<Crc8, _, N>(&command, 7).unwrap();
+        assert_eq!(
+            Command::decode_with::<Crc8>(&mut tagged.clone()),
+            Ok((Command::Ping { seq: 300 }, 7))
+        );
+        // A decoder that predates tags ignores the byte
+        assert_eq!(
+            proto_impl::wire_decode_with::<Crc8, Command>(&mut tagged),
+            Ok(command)
+        );
+
+        assert_eq!(
+            proto_impl::take_tag(&[1, 2]),
+            Err(ProtoError::bad_length(2))
+        );
+    }
+
+    #[test]
+    fn nack_display() {
+        extern crate std;
+        use std::string::ToString;
+
+        let crc = NackType::PacketErr(ProtoError::crc_mismatch(0x3f, 0xa1));
+        assert_eq!(
+            crc.to_string(),
+            "bad packet: CRC mismatch (calculated 0x3f, got 0xa1)"
+        );
+
+        // Framing errors say which step failed
+        let mut unterminated = [0x02, 0x05];
+        let err = proto_impl::wire_unframe_with::<Crc8>(&mut unterminated).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "framing error 0x5 (frame doesn't end in a sentinel)"
+        );
+
+        assert_eq!(
+            NackType::UnsupportedCommand(200).to_string(),
+            "unsupported command (variant 200)"
+        );
+        assert_eq!(
+            NackType::ImageCrcMismatch {
+                calculated: 0x1234,
+                expected: 0xcbf4_3926
+            }
+            .to_string(),
+            "firmware image CRC mismatch (calculated 0x00001234, expected 0xcbf43926)"
+        );
+        assert_eq!(
+            NackType::StoreFailed.to_string(),
+            "settings couldn't be saved to flash"
+        );
+    }
+
+    #[test]
+    fn cobs_worst_case() {
+        // Runs of non-zero bytes have the most overhead
+        let src = [0xa5u8; 600];
+        let mut dest = [0u8; 700];
+        for len in 0..src.len() {
+            let encoded = cobs::try_encode(&src[..len], &mut dest).unwrap();
+            assert!(
+                encoded <= cobs_max_length(len),
+                "{} bytes encode to {}",
+                len,
+                encoded
+            );
+        }
+
+        // A message that fills a whole COBS block, with no zero bytes even
+        // in the CRC, still fits in a WIRE_MAX_SIZE buffer
+        type Block = Vec<u8, 251>;
+        assert_eq!(Block::CS_MAX_SIZE, 254);
+        let block = (1..=u8::MAX)
+            .map(|fill| Block::from_slice(&[fill; 251]).unwrap())
+            .find(|block| Crc8::checksum(&to_stdvec(block).unwrap()) != 0)
+            .unwrap();
+        let mut frame = proto_impl::wire_encode::<_, { Block::WIRE_MAX_SIZE }>(&block).unwrap();
+        assert_eq!(frame.len(), Block::WIRE_MAX_SIZE);
+        assert_eq!(proto_impl::wire_decode::<Block>(&mut frame).unwrap(), block);
     }
 
     #[derive(Debug, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
@@ -163,4 +792,152 @@ mod tests {
         assert_eq!(short_bytes.len(), 3);
         assert_eq!(long_bytes.len(), 10);
     }
+
+    #[test]
+    fn matrix_loc_index() {
+        let loc = MatrixLoc::new(NUM_ROWS - 1, 2);
+        assert_eq!(loc.row(), NUM_ROWS - 1);
+        assert_eq!(loc.col(), 2);
+        assert_eq!(loc.index(), (NUM_ROWS - 1) * NUM_COLS + 2);
+    }
+
+    #[test]
+    fn key_state_from_update() {
+        let left = KeyUpdate::keys([MatrixLoc::new(0, 0), MatrixLoc::new(4, 6)]);
+        let right = KeyUpdate::keys([MatrixLoc::new(0, 1)]);
+        let state = KeyState::from_update(&left, &right);
+
+        let pressed: Vec<usize, 4> = state
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, pressed)| pressed.then_some(idx))
+            .collect();
+        assert_eq!(pressed, [0, NUM_KEYS - 1, NUM_KEYS + 1]);
+        assert!(!state.is_pressed(ENCODER_PRESS));
+        assert!(!state.is_pressed(KeyState::LEN));
+
+        let mut state = state;
+        state.press(ENCODER_PRESS);
+        assert_eq!(state.iter().last(), Some(true));
+        assert_eq!(
+            KeyState::from_update(&KeyUpdate::no_keys(), &KeyUpdate::no_keys()),
+            KeyState::no_keys()
+        );
+    }
+
+    #[test]
+    fn key_update_round_trip() {
+        let mut all = Vec::new();
+        for row in 0..NUM_ROWS {
+            for col in 0..NUM_COLS {
+                all.push(MatrixLoc::new(row, col)).unwrap();
+            }
+        }
+        let update = KeyUpdate::from_vec(all);
+
+        let mut buf = proto_impl::cs_encode::<_, { KeyUpdate::CS_MAX_SIZE }>(&update).unwrap();
+        assert_eq!(buf.len(), KeyUpdate::CS_MAX_SIZE);
+        let decoded: KeyUpdate = proto_impl::cs_decode(&mut buf).unwrap();
+        assert_eq!(decoded, update);
+    }
+
+    #[test]
+    fn raw_frame_round_trip() {
+        use proto_impl::{wire_encode, wire_frame_with, wire_unframe_with};
+
+        let payload = [0u8, 1, 0, 2];
+        let mut frame = wire_frame_with::<Crc8, 16>(&payload).unwrap();
+        assert_eq!(wire_unframe_with::<Crc8>(&mut frame).unwrap(), payload);
+        assert!(wire_frame_with::<Crc8, 6>(&payload).is_err());
+
+        // Framing the postcard bytes of a message is the same as encoding it
+        let bytes = to_stdvec(&Command::GetVersion).unwrap();
+        let encoded = wire_encode::<_, { Command::WIRE_MAX_SIZE }>(&Command::GetVersion).unwrap();
+        let framed = wire_frame_with::<Crc8, { Command::WIRE_MAX_SIZE }>(&bytes).unwrap();
+        assert_eq!(framed, encoded);
+    }
+
+    #[test]
+    fn len_frames() {
+        extern crate std;
+        use proto_impl::{len_decode, len_encode, len_frame_with, len_unframe_with, Framing};
+
+        let command = Command::Ping { seq: 300 };
+        let frame = len_encode::<_, { Command::LEN_MAX_SIZE }>(&command).unwrap();
+        let body = proto_impl::cs_encode::<_, { Command::CS_MAX_SIZE }>(&command).unwrap();
+        assert_eq!(frame[..2], (body.len() as u16).to_le_bytes());
+        assert_eq!(frame[2..], body);
+        assert_eq!(
+            len_decode::<Command>(&frame),
+            Ok(Command::Ping { seq: 300 })
+        );
+        assert!(len_encode::<_, 4>(&command).is_err());
+
+        // Framing the postcard bytes is the same as encoding
+        let bytes = to_stdvec(&command).unwrap();
+        let framed = len_frame_with::<Crc8, { Command::LEN_MAX_SIZE }>(&bytes).unwrap();
+        assert_eq!(framed, frame);
+
+        // The prefix has to match the bytes that follow
+        assert_eq!(
+            len_unframe_with::<Crc8>(&frame[..frame.len() - 1]),
+            Err(ProtoError::bad_length(frame.len() - 1))
+        );
+        let mut damaged = frame.clone();
+        damaged[3] ^= 0x01;
+        assert!(matches!(
+            len_decode::<Command>(&damaged),
+            Err(ProtoError::CrcMismatch { .. })
+        ));
+
+        // Tagged, through `Framing` the way the serial link uses it
+        const N: usize = Framing::LenPrefix.tagged_max_size::<Crc8, Command>();
+        let mut tagged = Framing::LenPrefix
+            .encode_tagged_with::<Crc8, _, N>(&command, 7)
+            .unwrap();
+        assert_eq!(
+            Command::decode_framed::<Crc8>(&mut tagged, Framing::LenPrefix),
+            Ok((command, 7))
+        );
+
+        // Finding the end of the first frame in a stream
+        let mut stream = std::vec::Vec::from(&frame[..]);
+        assert_eq!(Framing::LenPrefix.frame_len(&stream[..1]), None);
+        assert_eq!(Framing::LenPrefix.frame_len(&stream[..4]), None);
+        stream.extend_from_slice(&frame);
+        assert_eq!(Framing::LenPrefix.frame_len(&stream), Some(frame.len()));
+        assert_eq!(Framing::Cobs.frame_len(&[3, 1, 2, 0, 5]), Some(4));
+        assert_eq!(Framing::Cobs.frame_len(&[3, 1, 2]), None);
+    }
+
+    #[test]
+    fn check_crc_width() {
+        use proto_impl::{cs_max_size, Crc16};
+
+        assert_eq!(cs_max_size::<Crc8, Command>(), Command::CS_MAX_SIZE);
+        assert_eq!(cs_max_size::<Crc16, Command>(), Command::CS_MAX_SIZE + 1);
+
+        let cmd = Command::EchoMsg { count: 300 };
+        let mut buf =
+            proto_impl::cs_encode_with::<Crc16, _, { cs_max_size::<Crc16, Command>() }>(&cmd)
+                .unwrap();
+        let len = buf.len();
+        buf[len - 1] ^= 0x80;
+        assert!(matches!(
+            proto_impl::cs_decode_with::<Crc16, Command>(&mut buf),
+            Err(ProtoError::CrcMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn self_test_results() {
+        let mut results = SelfTestResults::default();
+        results.set(SelfTestCheck::Flash, true);
+        results.set(SelfTestCheck::Usb, true);
+        results.set(SelfTestCheck::Usb, false);
+        assert!(results.passed(SelfTestCheck::Flash));
+        assert!(!results.passed(SelfTestCheck::Peer));
+        assert!(!results.passed(SelfTestCheck::Usb));
+        assert_eq!(to_stdvec(&results).unwrap(), [0x01]);
+    }
 }
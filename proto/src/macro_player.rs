@@ -0,0 +1,132 @@
+//! Macro playback
+//!
+//! A macro is typed one keystroke at a time, with a release between each
+//! keystroke so repeated keys register. Every press and release lasts at
+//! least the step time, however often reports go out.
+
+use crate::settings::{MacroData, MACRO_MOD_FIRST, MACRO_MOD_LAST};
+
+pub struct MacroPlayer {
+    steps: MacroData,
+    pos: usize,
+    pressed: bool,
+    step_ms: u64,
+    /// The (modifier, keycode) of the current step
+    current: (u8, u8),
+    /// When the current step is over
+    next_ms: u64,
+}
+
+impl MacroPlayer {
+    pub fn new(steps: MacroData, step_ms: u64) -> Self {
+        MacroPlayer {
+            steps,
+            pos: 0,
+            pressed: false,
+            step_ms,
+            current: (0, 0),
+            next_ms: 0,
+        }
+    }
+
+    /// Returns the (modifier, keycode) to add to the report at `now_ms`, or
+    /// None once the macro is finished. The first call starts the first
+    /// keystroke.
+    pub fn next(&mut self, now_ms: u64) -> Option<(u8, u8)> {
+        if now_ms < self.next_ms {
+            return Some(self.current);
+        }
+
+        self.current = self.advance()?;
+        self.next_ms = now_ms + self.step_ms;
+        Some(self.current)
+    }
+
+    fn advance(&mut self) -> Option<(u8, u8)> {
+        if self.pressed {
+            self.pressed = false;
+            return Some((0, 0));
+        }
+
+        let mut modifier = 0u8;
+        while let Some(&usage) = self.steps.get(self.pos) {
+            self.pos += 1;
+            if (MACRO_MOD_FIRST..=MACRO_MOD_LAST).contains(&usage) {
+                modifier |= 1 << (usage - MACRO_MOD_FIRST);
+            } else {
+                self.pressed = true;
+                return Some((modifier, usage));
+            }
+        }
+
+        // Trailing modifiers are tapped on their own
+        if modifier != 0 {
+            self.pressed = true;
+            Some((modifier, 0))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use heapless::Vec;
+
+    const STEP: u64 = 20;
+    const SHIFT: u8 = MACRO_MOD_FIRST + 1;
+    const CTRL: u8 = MACRO_MOD_FIRST;
+
+    /// Every step of `player`, advancing the time by a full step each call
+    fn play(player: &mut MacroPlayer) -> Vec<(u8, u8), 16> {
+        let mut steps = Vec::new();
+        let mut now_ms = 0;
+        while let Some(step) = player.next(now_ms) {
+            steps.push(step).unwrap();
+            now_ms += STEP;
+        }
+        steps
+    }
+
+    #[test]
+    fn multiple_keys() {
+        // "aab"
+        let data = Vec::from_slice(&[0x04, 0x04, 0x05]).unwrap();
+        let mut player = MacroPlayer::new(data, STEP);
+        assert_eq!(
+            play(&mut player),
+            [(0, 0x04), (0, 0), (0, 0x04), (0, 0), (0, 0x05), (0, 0)]
+        );
+        assert_eq!(player.next(1000), None);
+    }
+
+    #[test]
+    fn modifiers() {
+        // "Ab", then ctrl+shift tapped on their own
+        let data = Vec::from_slice(&[SHIFT, 0x04, 0x05, CTRL, SHIFT]).unwrap();
+        let mut player = MacroPlayer::new(data, STEP);
+        assert_eq!(
+            play(&mut player),
+            [(0x02, 0x04), (0, 0), (0, 0x05), (0, 0), (0x03, 0), (0, 0)]
+        );
+    }
+
+    #[test]
+    fn step_time() {
+        let data = Vec::from_slice(&[0x04, 0x05]).unwrap();
+        let mut player = MacroPlayer::new(data, STEP);
+        assert_eq!(player.next(100), Some((0, 0x04)));
+        // Reports in between repeat the step until it is over
+        assert_eq!(player.next(105), Some((0, 0x04)));
+        assert_eq!(player.next(119), Some((0, 0x04)));
+        assert_eq!(player.next(120), Some((0, 0)));
+        assert_eq!(player.next(130), Some((0, 0)));
+        // A late report starts the next step late
+        assert_eq!(player.next(175), Some((0, 0x05)));
+        assert_eq!(player.next(190), Some((0, 0x05)));
+        assert_eq!(player.next(195), Some((0, 0)));
+        assert_eq!(player.next(215), None);
+    }
+}
@@ -0,0 +1,146 @@
+//! One-shot (sticky) modifiers
+//!
+//! Tapping a one-shot key arms its modifier for the next key press, so
+//! shortcuts can be typed one key at a time. Holding it down while pressing
+//! another key works like a normal modifier instead.
+
+use crate::KeyState;
+
+pub struct OneShotMods {
+    timeout_ms: u64,
+    /// One-shot modifiers held down at the last update
+    down: u8,
+    /// Held down with no other key pressed since, armed when released
+    tapping: u8,
+    /// Tapped and waiting for the next key press
+    armed: u8,
+    /// When the last one-shot modifier was tapped
+    armed_ms: u64,
+    /// Used up by a key press, applied until `applied_keys` are released
+    applied: u8,
+    applied_keys: u128,
+    /// Keys pressed at the last update
+    last_keys: u128,
+}
+
+impl OneShotMods {
+    /// Armed modifiers are dropped if no key is pressed within `timeout_ms`
+    pub fn new(timeout_ms: u64) -> Self {
+        OneShotMods {
+            timeout_ms,
+            down: 0,
+            tapping: 0,
+            armed: 0,
+            armed_ms: 0,
+            applied: 0,
+            applied_keys: 0,
+            last_keys: 0,
+        }
+    }
+
+    /// `down` are the modifiers of the one-shot keys held down and `keys`
+    /// the other keys that are pressed, not counting plain modifiers.
+    /// Returns the modifiers to add to the report.
+    pub fn update(&mut self, down: u8, keys: &KeyState, now_ms: u64) -> u8 {
+        let pressed = keys.0 & !self.last_keys;
+        self.last_keys = keys.0;
+        let released = self.down & !down;
+        self.tapping |= down & !self.down;
+        self.down = down;
+
+        if self.applied_keys & keys.0 == 0 {
+            self.applied = 0;
+            self.applied_keys = 0;
+        }
+
+        if self.armed != 0 && now_ms - self.armed_ms >= self.timeout_ms {
+            self.armed = 0;
+        }
+
+        if pressed != 0 {
+            // Pressing a key while a one-shot key is down makes it a hold
+            self.tapping = 0;
+            if self.armed != 0 {
+                self.applied |= self.armed;
+                self.applied_keys |= pressed;
+                self.armed = 0;
+            }
+        }
+
+        // Tapping another one-shot key adds to the armed modifiers
+        let tapped = released & self.tapping;
+        if tapped != 0 {
+            self.tapping &= !tapped;
+            self.armed |= tapped;
+            self.armed_ms = now_ms;
+        }
+
+        down | self.applied
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: u64 = 1000;
+    const SHIFT: u8 = 0x02;
+    const CTRL: u8 = 0x01;
+
+    fn keys(pressed: &[usize]) -> KeyState {
+        let mut state = KeyState::no_keys();
+        for &idx in pressed {
+            state.press(idx);
+        }
+        state
+    }
+
+    #[test]
+    fn tap_then_key() {
+        let mut mods = OneShotMods::new(TIMEOUT);
+        assert_eq!(mods.update(SHIFT, &keys(&[]), 0), SHIFT);
+        // Armed, but not sent until a key is pressed
+        assert_eq!(mods.update(0, &keys(&[]), 20), 0);
+        assert_eq!(mods.update(0, &keys(&[4]), 40), SHIFT);
+        assert_eq!(mods.update(0, &keys(&[4]), 60), SHIFT);
+        assert_eq!(mods.update(0, &keys(&[]), 80), 0);
+        // Used up by the first key
+        assert_eq!(mods.update(0, &keys(&[4]), 100), 0);
+
+        // Tapping a second one-shot key stacks
+        let mut mods = OneShotMods::new(TIMEOUT);
+        mods.update(SHIFT, &keys(&[]), 0);
+        mods.update(0, &keys(&[]), 20);
+        mods.update(CTRL, &keys(&[]), 40);
+        mods.update(0, &keys(&[]), 60);
+        assert_eq!(mods.update(0, &keys(&[4]), 80), SHIFT | CTRL);
+    }
+
+    #[test]
+    fn hold() {
+        let mut mods = OneShotMods::new(TIMEOUT);
+        assert_eq!(mods.update(SHIFT, &keys(&[]), 0), SHIFT);
+        assert_eq!(mods.update(SHIFT, &keys(&[4]), 20), SHIFT);
+        assert_eq!(mods.update(SHIFT, &keys(&[4, 5]), 40), SHIFT);
+        assert_eq!(mods.update(SHIFT, &keys(&[]), 60), SHIFT);
+        // Released after being used as a hold, nothing is armed
+        assert_eq!(mods.update(0, &keys(&[]), 80), 0);
+        assert_eq!(mods.update(0, &keys(&[4]), 100), 0);
+    }
+
+    #[test]
+    fn timeout() {
+        let mut mods = OneShotMods::new(TIMEOUT);
+        mods.update(SHIFT, &keys(&[]), 0);
+        mods.update(0, &keys(&[]), 20);
+        assert_eq!(mods.update(0, &keys(&[4]), 20 + TIMEOUT), 0);
+
+        // Tapping again restarts the timeout
+        let mut mods = OneShotMods::new(TIMEOUT);
+        mods.update(SHIFT, &keys(&[]), 0);
+        mods.update(0, &keys(&[]), 20);
+        mods.update(CTRL, &keys(&[]), 900);
+        mods.update(0, &keys(&[]), 920);
+        assert_eq!(mods.update(0, &keys(&[4]), 1100), SHIFT | CTRL);
+    }
+}
@@ -1,11 +1,90 @@
-use crate::{errors::ProtoError, WireSize};
+use crate::{
+    errors::{invariant, ProtoError},
+    WireSize, NO_TAG,
+};
 use cobs;
-use crc::{Crc, CRC_8_BLUETOOTH};
+use crc::{Crc, CRC_16_IBM_3740, CRC_32_ISO_HDLC, CRC_8_BLUETOOTH};
 use heapless::Vec;
-use postcard;
+use postcard::{self, experimental::max_size::MaxSize};
 use serde::{de::DeserializeOwned, Serialize};
 
-const CRC: Crc<u8> = Crc::<u8>::new(&CRC_8_BLUETOOTH);
+/// The checksum appended to each packet
+pub trait CrcKind {
+    /// Number of checksum bytes appended after the postcard message
+    const WIDTH_BYTES: usize;
+
+    /// Checksum of `data`, widened to a u16. On the wire it is sent little
+    /// endian, truncated to `WIDTH_BYTES`
+    fn checksum(data: &[u8]) -> u16;
+
+    /// Check `crc` (as it appears on the wire) against the checksum of `data`
+    fn verify(data: &[u8], crc: &[u8]) -> Result<(), ProtoError> {
+        let mut actual = [0u8; 2];
+        actual[..crc.len()].copy_from_slice(crc);
+        let actual = u16::from_le_bytes(actual);
+        let calculated = Self::checksum(data);
+
+        if actual != calculated {
+            Err(ProtoError::crc_mismatch(calculated, actual))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// CRC-8/BLUETOOTH, the default checksum
+pub struct Crc8;
+
+impl CrcKind for Crc8 {
+    const WIDTH_BYTES: usize = 1;
+
+    fn checksum(data: &[u8]) -> u16 {
+        const CRC: Crc<u8> = Crc::<u8>::new(&CRC_8_BLUETOOTH);
+        CRC.checksum(data) as u16
+    }
+}
+
+/// CRC-16-CCITT (the 0xFFFF initialized "FALSE" variant), for the packets
+/// where a corrupted byte is expensive
+pub struct Crc16;
+
+impl CrcKind for Crc16 {
+    const WIDTH_BYTES: usize = 2;
+
+    fn checksum(data: &[u8]) -> u16 {
+        const CRC: Crc<u16> = Crc::<u16>::new(&CRC_16_IBM_3740);
+        CRC.checksum(data)
+    }
+}
+
+/// CRC over a region of firmware, computed by the device for `VerifyFw` and
+/// by the host over the image it flashed
+pub const FW_CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// Like `WireSize::CS_MAX_SIZE`, but for an arbitrary checksum
+pub const fn cs_max_size<C: CrcKind, T: MaxSize>() -> usize {
+    T::POSTCARD_MAX_SIZE + C::WIDTH_BYTES
+}
+
+/// Like `WireSize::WIRE_MAX_SIZE`, but for an arbitrary checksum
+pub const fn wire_max_size<C: CrcKind, T: MaxSize>() -> usize {
+    crate::cobs_max_length(cs_max_size::<C, T>()) + 1
+}
+
+/// Like `wire_max_size`, with room for the tag of `wire_encode_tagged_with`
+pub const fn tagged_wire_max_size<C: CrcKind, T: MaxSize>() -> usize {
+    crate::cobs_max_length(cs_max_size::<C, T>() + 1) + 1
+}
+
+/// Like `WireSize::LEN_MAX_SIZE`, but for an arbitrary checksum
+pub const fn len_max_size<C: CrcKind, T: MaxSize>() -> usize {
+    LEN_PREFIX_BYTES + cs_max_size::<C, T>()
+}
+
+/// Like `len_max_size`, with room for the tag of `len_encode_tagged_with`
+pub const fn tagged_len_max_size<C: CrcKind, T: MaxSize>() -> usize {
+    LEN_PREFIX_BYTES + cs_max_size::<C, T>() + 1
+}
 
 pub fn cs_encode<S: Serialize + WireSize, const N: usize>(
     value: &S,
@@ -14,10 +93,37 @@ pub fn cs_encode<S: Serialize + WireSize, const N: usize>(
         return Err(ProtoError::buffer_size());
     }
 
-    let mut buf = postcard::to_vec::<S, N>(&value)?;
+    cs_encode_unchecked::<Crc8, S, N>(value)
+}
+
+pub fn cs_encode_with<C: CrcKind, S: Serialize + MaxSize, const N: usize>(
+    value: &S,
+) -> Result<Vec<u8, N>, ProtoError> {
+    if N < cs_max_size::<C, S>() {
+        return Err(ProtoError::buffer_size());
+    }
+
+    cs_encode_unchecked::<C, S, N>(value)
+}
+
+fn cs_encode_unchecked<C: CrcKind, S: Serialize, const N: usize>(
+    value: &S,
+) -> Result<Vec<u8, N>, ProtoError> {
+    cs_encode_tagged_unchecked::<C, S, N>(value, NO_TAG)
+}
+
+fn cs_encode_tagged_unchecked<C: CrcKind, S: Serialize, const N: usize>(
+    value: &S,
+    tag: u8,
+) -> Result<Vec<u8, N>, ProtoError> {
+    let mut buf = postcard::to_vec::<S, N>(value)?;
+    if tag != NO_TAG {
+        buf.push(tag).map_err(|_| ProtoError::buffer_size())?;
+    }
 
-    let crc = CRC.checksum(&buf);
-    buf.push(crc).map_err(|_| ProtoError::invariant(0x1))?;
+    let crc = C::checksum(&buf).to_le_bytes();
+    buf.extend_from_slice(&crc[..C::WIDTH_BYTES])
+        .map_err(|_| ProtoError::invariant(invariant::CRC_OVERFLOW))?;
 
     Ok(buf)
 }
@@ -29,53 +135,307 @@ pub fn wire_encode<S: Serialize + WireSize, const N: usize>(
         return Err(ProtoError::buffer_size());
     }
 
-    let buf = cs_encode::<S, N>(value)?;
+    wire_encode_unchecked::<Crc8, S, N>(value)
+}
+
+pub fn wire_encode_with<C: CrcKind, S: Serialize + MaxSize, const N: usize>(
+    value: &S,
+) -> Result<Vec<u8, N>, ProtoError> {
+    if N < wire_max_size::<C, S>() {
+        return Err(ProtoError::buffer_size());
+    }
+
+    wire_encode_unchecked::<C, S, N>(value)
+}
+
+/// Like `wire_encode_with`, with `tag` after the message, see `NO_TAG`
+pub fn wire_encode_tagged_with<C: CrcKind, S: Serialize + MaxSize, const N: usize>(
+    value: &S,
+    tag: u8,
+) -> Result<Vec<u8, N>, ProtoError> {
+    if N < tagged_wire_max_size::<C, S>() {
+        return Err(ProtoError::buffer_size());
+    }
+
+    let buf = cs_encode_tagged_unchecked::<C, S, N>(value, tag)?;
+
+    cobs_frame(&buf)
+}
+
+fn wire_encode_unchecked<C: CrcKind, S: Serialize, const N: usize>(
+    value: &S,
+) -> Result<Vec<u8, N>, ProtoError> {
+    let buf = cs_encode_unchecked::<C, S, N>(value)?;
+
+    cobs_frame(&buf)
+}
+
+/// Frame bytes that aren't a postcard message the same way `wire_encode_with`
+/// frames one
+pub fn wire_frame_with<C: CrcKind, const N: usize>(
+    payload: &[u8],
+) -> Result<Vec<u8, N>, ProtoError> {
+    let mut buf: Vec<u8, N> = Vec::from_slice(payload).map_err(|_| ProtoError::buffer_size())?;
+    let crc = C::checksum(&buf).to_le_bytes();
+    buf.extend_from_slice(&crc[..C::WIDTH_BYTES])
+        .map_err(|_| ProtoError::buffer_size())?;
+    if N < crate::cobs_max_length(buf.len()) + 1 {
+        return Err(ProtoError::buffer_size());
+    }
+
+    cobs_frame(&buf)
+}
 
+/// COBS encode `buf` and add the sentinel
+fn cobs_frame<const N: usize>(buf: &[u8]) -> Result<Vec<u8, N>, ProtoError> {
     let mut cobs_buf: Vec<u8, N> = Vec::new();
     cobs_buf
         .resize(N, 0)
-        .map_err(|_| ProtoError::invariant(0x2))?;
-    let result_len =
-        cobs::try_encode(&buf, &mut cobs_buf).map_err(|_| ProtoError::invariant(0x3))?;
+        .map_err(|_| ProtoError::invariant(invariant::COBS_BUFFER))?;
+    let result_len = cobs::try_encode(buf, &mut cobs_buf)
+        .map_err(|_| ProtoError::invariant(invariant::COBS_ENCODE))?;
     cobs_buf.truncate(result_len);
-    cobs_buf.push(0).map_err(|_| ProtoError::invariant(0x4))?;
+    cobs_buf
+        .push(0)
+        .map_err(|_| ProtoError::invariant(invariant::SENTINEL_OVERFLOW))?;
 
     Ok(cobs_buf)
 }
 
 pub fn cs_decode<D: DeserializeOwned + WireSize>(buf: &mut [u8]) -> Result<D, ProtoError> {
+    cs_decode_with::<Crc8, D>(buf)
+}
+
+pub fn cs_decode_with<C: CrcKind, D: DeserializeOwned>(buf: &mut [u8]) -> Result<D, ProtoError> {
+    // Finally, decode the message
+    Ok(postcard::from_bytes(cs_check::<C>(buf)?)?)
+}
+
+/// Check the CRC at the end of `buf`, returning the message before it
+fn cs_check<C: CrcKind>(buf: &[u8]) -> Result<&[u8], ProtoError> {
     let new_len = buf.len();
 
-    if new_len == 0 {
+    if new_len < C::WIDTH_BYTES {
         return Err(ProtoError::bad_length(new_len));
     }
 
-    let actual_crc = buf[new_len - 1];
-    let message_buf = &mut buf[..new_len - 1];
+    // Split off and check the CRC
+    let (message_buf, crc) = buf.split_at(new_len - C::WIDTH_BYTES);
+    C::verify(message_buf, crc)?;
+
+    Ok(message_buf)
+}
+
+pub fn wire_decode<D: DeserializeOwned + WireSize>(buf: &mut [u8]) -> Result<D, ProtoError> {
+    wire_decode_with::<Crc8, D>(buf)
+}
+
+pub fn wire_decode_with<C: CrcKind, D: DeserializeOwned>(buf: &mut [u8]) -> Result<D, ProtoError> {
+    Ok(postcard::from_bytes(wire_unframe_with::<C>(buf)?)?)
+}
 
-    // Calculate and extract CRCs
-    let calc_crc = CRC.checksum(&message_buf);
+/// Like `wire_decode_with`, also returning the tag after the message
+pub fn wire_decode_tagged_with<C: CrcKind, D: DeserializeOwned>(
+    buf: &mut [u8],
+) -> Result<(D, u8), ProtoError> {
+    let (value, rest) = postcard::take_from_bytes(wire_unframe_with::<C>(buf)?)?;
+    Ok((value, take_tag(rest)?))
+}
 
-    if actual_crc != calc_crc {
-        Err(ProtoError::CrcMismatch {
-            calculated: calc_crc,
-            actual: actual_crc,
-        })
-    } else {
-        // Finally, decode the message
-        Ok(postcard::from_bytes(message_buf)?)
+/// The tag in the bytes left after a message, `NO_TAG` if there are none
+pub fn take_tag(rest: &[u8]) -> Result<u8, ProtoError> {
+    match *rest {
+        [] => Ok(NO_TAG),
+        [tag] => Ok(tag),
+        _ => Err(ProtoError::bad_length(rest.len())),
     }
 }
 
-pub fn wire_decode<D: DeserializeOwned + WireSize>(buf: &mut [u8]) -> Result<D, ProtoError> {
+/// Undo the framing of `wire_frame_with` or `wire_encode_with` in place,
+/// returning the bytes that were framed
+pub fn wire_unframe_with<C: CrcKind>(buf: &mut [u8]) -> Result<&[u8], ProtoError> {
     // COBS decode
     if buf.last() != Some(&0u8) {
-        return Err(ProtoError::invariant(0x5));
+        return Err(ProtoError::invariant(invariant::MISSING_SENTINEL));
     }
     let without_sentinel = buf.len() - 1;
     let no_sentinel_buf = &mut buf[..without_sentinel];
 
-    let new_len = cobs::decode_in_place(no_sentinel_buf).map_err(|_| ProtoError::invariant(0x6))?;
+    let new_len = cobs::decode_in_place(no_sentinel_buf)
+        .map_err(|_| ProtoError::invariant(invariant::COBS_DECODE))?;
+
+    cs_check::<C>(&no_sentinel_buf[..new_len])
+}
+
+/// Bytes of the little endian length in front of a `len_encode` frame
+pub const LEN_PREFIX_BYTES: usize = 2;
+
+pub fn len_encode<S: Serialize + WireSize, const N: usize>(
+    value: &S,
+) -> Result<Vec<u8, N>, ProtoError> {
+    if N < S::LEN_MAX_SIZE {
+        return Err(ProtoError::buffer_size());
+    }
+
+    len_encode_tagged_unchecked::<Crc8, S, N>(value, NO_TAG)
+}
+
+pub fn len_encode_with<C: CrcKind, S: Serialize + MaxSize, const N: usize>(
+    value: &S,
+) -> Result<Vec<u8, N>, ProtoError> {
+    if N < len_max_size::<C, S>() {
+        return Err(ProtoError::buffer_size());
+    }
+
+    len_encode_tagged_unchecked::<C, S, N>(value, NO_TAG)
+}
+
+/// Like `len_encode_with`, with `tag` after the message, see `NO_TAG`
+pub fn len_encode_tagged_with<C: CrcKind, S: Serialize + MaxSize, const N: usize>(
+    value: &S,
+    tag: u8,
+) -> Result<Vec<u8, N>, ProtoError> {
+    if N < tagged_len_max_size::<C, S>() {
+        return Err(ProtoError::buffer_size());
+    }
+
+    len_encode_tagged_unchecked::<C, S, N>(value, tag)
+}
+
+fn len_encode_tagged_unchecked<C: CrcKind, S: Serialize, const N: usize>(
+    value: &S,
+    tag: u8,
+) -> Result<Vec<u8, N>, ProtoError> {
+    // Leave room for the prefix, and fill it in once the length is known
+    let mut buf: Vec<u8, N> =
+        Vec::from_slice(&[0; LEN_PREFIX_BYTES]).map_err(|_| ProtoError::buffer_size())?;
+    let body = cs_encode_tagged_unchecked::<C, S, N>(value, tag)?;
+    buf.extend_from_slice(&body)
+        .map_err(|_| ProtoError::buffer_size())?;
+    buf[..LEN_PREFIX_BYTES].copy_from_slice(&(body.len() as u16).to_le_bytes());
+
+    Ok(buf)
+}
+
+/// Frame bytes that aren't a postcard message the same way `len_encode_with`
+/// frames one
+pub fn len_frame_with<C: CrcKind, const N: usize>(
+    payload: &[u8],
+) -> Result<Vec<u8, N>, ProtoError> {
+    let body_len = payload.len() + C::WIDTH_BYTES;
+    let mut buf: Vec<u8, N> =
+        Vec::from_slice(&(body_len as u16).to_le_bytes()).map_err(|_| ProtoError::buffer_size())?;
+    buf.extend_from_slice(payload)
+        .map_err(|_| ProtoError::buffer_size())?;
+    let crc = C::checksum(payload).to_le_bytes();
+    buf.extend_from_slice(&crc[..C::WIDTH_BYTES])
+        .map_err(|_| ProtoError::buffer_size())?;
+
+    Ok(buf)
+}
+
+pub fn len_decode<D: DeserializeOwned + WireSize>(buf: &[u8]) -> Result<D, ProtoError> {
+    len_decode_with::<Crc8, D>(buf)
+}
+
+pub fn len_decode_with<C: CrcKind, D: DeserializeOwned>(buf: &[u8]) -> Result<D, ProtoError> {
+    Ok(postcard::from_bytes(len_unframe_with::<C>(buf)?)?)
+}
+
+/// Undo the framing of `len_frame_with` or `len_encode_with`, returning the
+/// bytes that were framed. `buf` has to be exactly one frame.
+pub fn len_unframe_with<C: CrcKind>(buf: &[u8]) -> Result<&[u8], ProtoError> {
+    if len_frame_size(buf) != Some(buf.len()) {
+        return Err(ProtoError::bad_length(buf.len()));
+    }
+
+    cs_check::<C>(&buf[LEN_PREFIX_BYTES..])
+}
+
+/// The size of the `len_encode` frame at the start of `buf`, prefix included.
+/// None until the prefix is in, the rest of the frame may still be missing.
+pub fn len_frame_size(buf: &[u8]) -> Option<usize> {
+    match *buf {
+        [low, high, ..] => Some(LEN_PREFIX_BYTES + usize::from(u16::from_le_bytes([low, high]))),
+        _ => None,
+    }
+}
+
+/// How frames are told apart on the serial link. COBS is the default, length
+/// prefixed frames skip the encoding step on both ends but can't be resynced
+/// on after lost bytes without dropping everything that was buffered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Framing {
+    /// `wire_encode`, COBS with a \0 sentinel
+    Cobs,
+    /// `len_encode`, a little endian u16 length in front
+    LenPrefix,
+}
+
+impl Framing {
+    /// Room for a tagged message of type `T`, like `tagged_wire_max_size`
+    pub const fn tagged_max_size<C: CrcKind, T: MaxSize>(self) -> usize {
+        match self {
+            Framing::Cobs => tagged_wire_max_size::<C, T>(),
+            Framing::LenPrefix => tagged_len_max_size::<C, T>(),
+        }
+    }
+
+    pub fn encode_with<C: CrcKind, S: Serialize + MaxSize, const N: usize>(
+        self,
+        value: &S,
+    ) -> Result<Vec<u8, N>, ProtoError> {
+        match self {
+            Framing::Cobs => wire_encode_with::<C, S, N>(value),
+            Framing::LenPrefix => len_encode_with::<C, S, N>(value),
+        }
+    }
+
+    pub fn encode_tagged_with<C: CrcKind, S: Serialize + MaxSize, const N: usize>(
+        self,
+        value: &S,
+        tag: u8,
+    ) -> Result<Vec<u8, N>, ProtoError> {
+        match self {
+            Framing::Cobs => wire_encode_tagged_with::<C, S, N>(value, tag),
+            Framing::LenPrefix => len_encode_tagged_with::<C, S, N>(value, tag),
+        }
+    }
+
+    pub fn frame_with<C: CrcKind, const N: usize>(
+        self,
+        payload: &[u8],
+    ) -> Result<Vec<u8, N>, ProtoError> {
+        match self {
+            Framing::Cobs => wire_frame_with::<C, N>(payload),
+            Framing::LenPrefix => len_frame_with::<C, N>(payload),
+        }
+    }
+
+    /// Undo the framing of one whole frame, in place for COBS
+    pub fn unframe_with<C: CrcKind>(self, buf: &mut [u8]) -> Result<&[u8], ProtoError> {
+        match self {
+            Framing::Cobs => wire_unframe_with::<C>(buf),
+            Framing::LenPrefix => len_unframe_with::<C>(buf),
+        }
+    }
+
+    /// The size of the first frame in `buf`, None while it isn't all there
+    pub fn frame_len(self, buf: &[u8]) -> Option<usize> {
+        match self {
+            Framing::Cobs => buf.iter().position(|&b| b == 0).map(|idx| idx + 1),
+            Framing::LenPrefix => len_frame_size(buf).filter(|&size| size <= buf.len()),
+        }
+    }
+}
 
-    cs_decode::<D>(&mut no_sentinel_buf[..new_len])
+/// Split a frame into the packets a USB bulk endpoint sends it as. A frame
+/// that fills its last packet is followed by a zero length packet, without it
+/// the host waits for more of the transfer. An empty frame sends nothing.
+pub fn usb_packets(frame: &[u8], max_packet: usize) -> impl Iterator<Item = &[u8]> {
+    let zlp = !frame.is_empty() && frame.len().is_multiple_of(max_packet);
+    frame
+        .chunks(max_packet)
+        .chain(zlp.then_some(&frame[frame.len()..]))
 }
@@ -0,0 +1,438 @@
+use heapless::Vec;
+use postcard::experimental::max_size::MaxSize;
+use serde::{Deserialize, Serialize};
+
+use crate::{errors::ProtoError, keymap::Layout, proto_impl, Hand, WireSize};
+
+pub const MACRO_SLOTS: usize = 8;
+pub const MACRO_MAX_LEN: usize = 32;
+// The store as a whole is smaller than MACRO_SLOTS * MACRO_MAX_LEN, so a
+// macro can be rejected even if it fits in its own slot
+pub const MACRO_STORE_SIZE: usize = 128;
+
+/// First HID usage on the modifier range (Left Control)
+pub const MACRO_MOD_FIRST: u8 = 0xE0;
+/// Last HID usage on the modifier range (Right GUI)
+pub const MACRO_MOD_LAST: u8 = 0xE7;
+
+/// A macro is a sequence of HID keyboard usages that are tapped one after
+/// another. Modifier usages (0xE0 - 0xE7) are not tapped on their own, they
+/// are held down for the next non-modifier usage in the sequence.
+pub type MacroData = Vec<u8, MACRO_MAX_LEN>;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MacroError {
+    BadSlot,
+    StoreFull,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub struct MacroStore {
+    slots: [MacroData; MACRO_SLOTS],
+}
+
+impl MacroStore {
+    pub fn get(&self, slot: u8) -> Option<&MacroData> {
+        self.slots.get(slot as usize)
+    }
+
+    pub fn set(&mut self, slot: u8, data: MacroData) -> Result<(), MacroError> {
+        let slot = slot as usize;
+        if slot >= MACRO_SLOTS {
+            return Err(MacroError::BadSlot);
+        }
+
+        let others = self.used() - self.slots[slot].len();
+        if others + data.len() > MACRO_STORE_SIZE {
+            return Err(MacroError::StoreFull);
+        }
+
+        self.slots[slot] = data;
+        Ok(())
+    }
+
+    pub fn used(&self) -> usize {
+        self.slots.iter().map(|slot| slot.len()).sum()
+    }
+}
+
+/// Settings the host can change at runtime, applied to the half it is
+/// connected to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub struct Config {
+    /// Scales every LED color, 0 turns the LEDs off
+    pub led_brightness: u8,
+    /// How long a tap-hold key has to be held to act as its modifier
+    pub tapping_term_ms: u16,
+    /// How long a key has to read the same before a press or release counts
+    pub debounce_ms: u8,
+    /// Layer that is always active on top of the base layer
+    pub default_layer: u8,
+    /// Without a key changing for this long the LEDs go dark and the matrix
+    /// is scanned slower, 0 never idles
+    pub idle_timeout_s: u16,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            // Full intensity is blinding
+            led_brightness: 64,
+            tapping_term_ms: 200,
+            debounce_ms: 5,
+            default_layer: 0,
+            idle_timeout_s: 300,
+        }
+    }
+}
+
+/// Config as it was stored before the idle timeout was added
+#[derive(Clone, Copy, Serialize, Deserialize, MaxSize)]
+struct ConfigV1 {
+    led_brightness: u8,
+    tapping_term_ms: u16,
+    debounce_ms: u8,
+    default_layer: u8,
+}
+
+impl From<ConfigV1> for Config {
+    fn from(config: ConfigV1) -> Self {
+        Config {
+            led_brightness: config.led_brightness,
+            tapping_term_ms: config.tapping_term_ms,
+            debounce_ms: config.debounce_ms,
+            default_layer: config.default_layer,
+            ..Config::default()
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub struct Settings {
+    pub macros: MacroStore,
+    pub config: Config,
+    /// Uploaded by the host, None for the keymap built into the firmware
+    pub keymap: Option<Layout>,
+    /// Set with `SetHand`, None to read the jumper
+    pub hand: Option<Hand>,
+}
+
+/// Settings as they were stored before `Config` was added
+#[derive(Deserialize, MaxSize)]
+struct SettingsV1 {
+    macros: MacroStore,
+}
+
+/// Settings as they were stored before keymaps could be uploaded
+#[derive(Serialize, Deserialize, MaxSize)]
+struct SettingsV2 {
+    macros: MacroStore,
+    config: ConfigV1,
+}
+
+/// Settings as they were stored before the hand could be overridden
+#[derive(Serialize, Deserialize, MaxSize)]
+struct SettingsV3 {
+    macros: MacroStore,
+    config: ConfigV1,
+    keymap: Option<Layout>,
+}
+
+/// Settings as they were stored before the idle timeout was added
+#[derive(Serialize, Deserialize, MaxSize)]
+struct SettingsV4 {
+    macros: MacroStore,
+    config: ConfigV1,
+    keymap: Option<Layout>,
+    hand: Option<Hand>,
+}
+
+// Stored blob is the magic, a little endian u16 length, then the cs encoded settings
+const SETTINGS_MAGIC: [u8; 4] = *b"PDX5";
+const SETTINGS_V4_MAGIC: [u8; 4] = *b"PDX4";
+const SETTINGS_V3_MAGIC: [u8; 4] = *b"PDX3";
+const SETTINGS_V2_MAGIC: [u8; 4] = *b"PDX2";
+const SETTINGS_V1_MAGIC: [u8; 4] = *b"PDXS";
+const HEADER_LEN: usize = SETTINGS_MAGIC.len() + 2;
+
+pub const SETTINGS_BLOB_SIZE: usize = HEADER_LEN + Settings::CS_MAX_SIZE;
+
+pub fn settings_encode(settings: &Settings) -> Result<Vec<u8, SETTINGS_BLOB_SIZE>, ProtoError> {
+    let body = proto_impl::cs_encode::<_, { Settings::CS_MAX_SIZE }>(settings)?;
+
+    let mut blob = Vec::new();
+    blob.extend_from_slice(&SETTINGS_MAGIC)
+        .map_err(|_| ProtoError::buffer_size())?;
+    blob.extend_from_slice(&(body.len() as u16).to_le_bytes())
+        .map_err(|_| ProtoError::buffer_size())?;
+    blob.extend_from_slice(&body)
+        .map_err(|_| ProtoError::buffer_size())?;
+
+    Ok(blob)
+}
+
+pub fn settings_decode(blob: &mut [u8]) -> Result<Settings, ProtoError> {
+    if blob.len() < HEADER_LEN {
+        return Err(ProtoError::bad_length(blob.len()));
+    }
+    let magic = &blob[..SETTINGS_MAGIC.len()];
+    let version = if magic == SETTINGS_MAGIC {
+        5
+    } else if magic == SETTINGS_V4_MAGIC {
+        4
+    } else if magic == SETTINGS_V3_MAGIC {
+        3
+    } else if magic == SETTINGS_V2_MAGIC {
+        2
+    } else if magic == SETTINGS_V1_MAGIC {
+        1
+    } else {
+        return Err(ProtoError::BadMagic);
+    };
+
+    let len = u16::from_le_bytes([blob[4], blob[5]]) as usize;
+    if len > Settings::CS_MAX_SIZE || HEADER_LEN + len > blob.len() {
+        return Err(ProtoError::bad_length(len));
+    }
+
+    let body = &mut blob[HEADER_LEN..HEADER_LEN + len];
+    // Older settings are kept, what they didn't have starts out at the
+    // defaults
+    match version {
+        1 => {
+            let SettingsV1 { macros } = proto_impl::cs_decode(body)?;
+            Ok(Settings {
+                macros,
+                ..Settings::default()
+            })
+        }
+        2 => {
+            let SettingsV2 { macros, config } = proto_impl::cs_decode(body)?;
+            Ok(Settings {
+                macros,
+                config: config.into(),
+                ..Settings::default()
+            })
+        }
+        3 => {
+            let SettingsV3 {
+                macros,
+                config,
+                keymap,
+            } = proto_impl::cs_decode(body)?;
+            Ok(Settings {
+                macros,
+                config: config.into(),
+                keymap,
+                hand: None,
+            })
+        }
+        4 => {
+            let SettingsV4 {
+                macros,
+                config,
+                keymap,
+                hand,
+            } = proto_impl::cs_decode(body)?;
+            Ok(Settings {
+                macros,
+                config: config.into(),
+                keymap,
+                hand,
+            })
+        }
+        _ => proto_impl::cs_decode(body),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        key_codes::{KEY_NONE, KEY_Q},
+        keymap::{LAYER_KEYS, NUM_LAYERS},
+    };
+
+    fn macro_data(bytes: &[u8]) -> MacroData {
+        Vec::from_slice(bytes).unwrap()
+    }
+
+    fn config_v1() -> ConfigV1 {
+        ConfigV1 {
+            led_brightness: 64,
+            tapping_term_ms: 200,
+            debounce_ms: 5,
+            default_layer: 0,
+        }
+    }
+
+    #[test]
+    fn macro_store_limits() {
+        let mut store = MacroStore::default();
+        let full = macro_data(&[0x04; MACRO_MAX_LEN]);
+
+        for slot in 0..(MACRO_STORE_SIZE / MACRO_MAX_LEN) as u8 {
+            assert_eq!(store.set(slot, full.clone()), Ok(()));
+        }
+        assert_eq!(store.set(7, full.clone()), Err(MacroError::StoreFull));
+        assert_eq!(
+            store.set(MACRO_SLOTS as u8, macro_data(&[])),
+            Err(MacroError::BadSlot)
+        );
+
+        // Replacing a slot only counts the new contents
+        assert_eq!(store.set(0, macro_data(&[0x05])), Ok(()));
+        assert_eq!(store.used(), MACRO_STORE_SIZE - MACRO_MAX_LEN + 1);
+    }
+
+    #[test]
+    fn settings_round_trip() {
+        let mut settings = Settings::default();
+        settings
+            .macros
+            .set(2, macro_data(&[MACRO_MOD_FIRST + 1, 0x0b, 0x0c]))
+            .unwrap();
+        settings.config.tapping_term_ms = 180;
+        settings.config.default_layer = 1;
+        let mut table = [[KEY_NONE; LAYER_KEYS]; NUM_LAYERS];
+        table[1][3] = KEY_Q;
+        settings.keymap = Some(Layout::from_table(&table));
+        settings.hand = Some(Hand::Right);
+
+        let mut blob = settings_encode(&settings).unwrap();
+        assert_eq!(settings_decode(&mut blob), Ok(settings));
+    }
+
+    #[test]
+    fn settings_v1_blob() {
+        let mut macros = MacroStore::default();
+        macros.set(0, macro_data(&[0x04, 0x05])).unwrap();
+        let body = proto_impl::cs_encode::<_, { MacroStore::CS_MAX_SIZE }>(&macros).unwrap();
+
+        let mut blob: Vec<u8, SETTINGS_BLOB_SIZE> = Vec::new();
+        blob.extend_from_slice(&SETTINGS_V1_MAGIC).unwrap();
+        blob.extend_from_slice(&(body.len() as u16).to_le_bytes())
+            .unwrap();
+        blob.extend_from_slice(&body).unwrap();
+
+        assert_eq!(
+            settings_decode(&mut blob),
+            Ok(Settings {
+                macros,
+                ..Settings::default()
+            })
+        );
+    }
+
+    #[test]
+    fn settings_v2_blob() {
+        let mut settings = SettingsV2 {
+            macros: MacroStore::default(),
+            config: config_v1(),
+        };
+        settings.macros.set(1, macro_data(&[0x06])).unwrap();
+        settings.config.debounce_ms = 8;
+        let body = proto_impl::cs_encode::<_, { SettingsV2::CS_MAX_SIZE }>(&settings).unwrap();
+
+        let mut blob: Vec<u8, SETTINGS_BLOB_SIZE> = Vec::new();
+        blob.extend_from_slice(&SETTINGS_V2_MAGIC).unwrap();
+        blob.extend_from_slice(&(body.len() as u16).to_le_bytes())
+            .unwrap();
+        blob.extend_from_slice(&body).unwrap();
+
+        assert_eq!(
+            settings_decode(&mut blob),
+            Ok(Settings {
+                macros: settings.macros,
+                config: Config {
+                    debounce_ms: 8,
+                    ..Config::default()
+                },
+                ..Settings::default()
+            })
+        );
+    }
+
+    #[test]
+    fn settings_v3_blob() {
+        let mut table = [[KEY_NONE; LAYER_KEYS]; NUM_LAYERS];
+        table[0][5] = KEY_Q;
+        let settings = SettingsV3 {
+            macros: MacroStore::default(),
+            config: ConfigV1 {
+                default_layer: 1,
+                ..config_v1()
+            },
+            keymap: Some(Layout::from_table(&table)),
+        };
+        let body = proto_impl::cs_encode::<_, { SettingsV3::CS_MAX_SIZE }>(&settings).unwrap();
+
+        let mut blob: Vec<u8, SETTINGS_BLOB_SIZE> = Vec::new();
+        blob.extend_from_slice(&SETTINGS_V3_MAGIC).unwrap();
+        blob.extend_from_slice(&(body.len() as u16).to_le_bytes())
+            .unwrap();
+        blob.extend_from_slice(&body).unwrap();
+
+        assert_eq!(
+            settings_decode(&mut blob),
+            Ok(Settings {
+                macros: settings.macros,
+                config: settings.config.into(),
+                keymap: settings.keymap,
+                hand: None,
+            })
+        );
+    }
+
+    #[test]
+    fn settings_v4_blob() {
+        let settings = SettingsV4 {
+            macros: MacroStore::default(),
+            config: ConfigV1 {
+                tapping_term_ms: 150,
+                ..config_v1()
+            },
+            keymap: None,
+            hand: Some(Hand::Left),
+        };
+        let body = proto_impl::cs_encode::<_, { SettingsV4::CS_MAX_SIZE }>(&settings).unwrap();
+
+        let mut blob: Vec<u8, SETTINGS_BLOB_SIZE> = Vec::new();
+        blob.extend_from_slice(&SETTINGS_V4_MAGIC).unwrap();
+        blob.extend_from_slice(&(body.len() as u16).to_le_bytes())
+            .unwrap();
+        blob.extend_from_slice(&body).unwrap();
+
+        // The idle timeout starts out at the default
+        assert_eq!(
+            settings_decode(&mut blob),
+            Ok(Settings {
+                config: Config {
+                    tapping_term_ms: 150,
+                    ..Config::default()
+                },
+                hand: Some(Hand::Left),
+                ..Settings::default()
+            })
+        );
+    }
+
+    #[test]
+    fn settings_bad_crc() {
+        let mut settings = Settings::default();
+        settings.config.tapping_term_ms = 180;
+        let mut blob = settings_encode(&settings).unwrap();
+        // A bit flipped in the body
+        blob[HEADER_LEN] ^= 0x01;
+        assert!(matches!(
+            settings_decode(&mut blob),
+            Err(ProtoError::CrcMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn settings_erased_page() {
+        let mut erased = [0xFFu8; SETTINGS_BLOB_SIZE];
+        assert_eq!(settings_decode(&mut erased), Err(ProtoError::BadMagic));
+    }
+}